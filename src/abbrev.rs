@@ -0,0 +1,48 @@
+use crate::config::Config;
+use crate::file_syntax::FileType;
+use std::collections::HashMap;
+
+/// Words replaced by their expansion when a separator is typed after them:
+/// the `[abbrevs]` section of the config for every file, e.g.
+/// `teh = "the"`, and `[abbrevs.<filetype>]` sections for one file type,
+/// e.g. `[abbrevs.rust]` with `pln = "println!("{}", );"`.
+#[derive(Default)]
+pub struct Abbrevs {
+    /// Keyed by lowercase file type name, or None for every file type
+    table: HashMap<(Option<String>, String), String>,
+}
+
+impl Abbrevs {
+    pub fn from_config(config: &Config) -> Result<Abbrevs, String> {
+        let mut table = HashMap::new();
+        for (key, expansion) in config.section("abbrevs") {
+            let (ftype, word) = match key.rsplit_once('.') {
+                Some((ftype, word)) => (Some(ftype.to_lowercase()), word),
+                None => (None, key),
+            };
+            if word.is_empty() || !word.chars().all(is_word_char) {
+                return Err(format!("abbrevs: invalid abbreviation `{}`", key));
+            }
+            table.insert((ftype, word.to_string()), expansion.to_string());
+        }
+        Ok(Abbrevs { table })
+    }
+
+    /// The expansion of `word` in a file of type `ftype`, preferring one
+    /// defined for that type.
+    pub fn get(&self, ftype: FileType, word: &str) -> Option<&str> {
+        self.table
+            .get(&(Some(ftype.to_string().to_lowercase()), word.to_string()))
+            .or_else(|| self.table.get(&(None, word.to_string())))
+            .map(|expansion| expansion.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+}
+
+/// Characters an abbreviation is made of; any other character typed ends it.
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
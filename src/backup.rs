@@ -0,0 +1,68 @@
+use crate::config::Config;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Copies the on-disk content of `path` aside before it is overwritten.
+///
+/// Controlled by the `[backup]` section of the config:
+/// - `enabled`: make backups at all (default false)
+/// - `directory`: keep backups in this directory instead of next to the file;
+///   the full path is encoded in the name so files never collide
+/// - `numbered`: keep every backup as `name.~N~` instead of a single `name~`
+pub fn backup_file(config: &Config, path: &Path) -> io::Result<()> {
+    if !config.get_bool("backup.enabled").unwrap_or(false) || !path.is_file() {
+        return Ok(());
+    }
+    let (dir, name) = match config.get("backup.directory") {
+        Some(dir) => {
+            let dir = expand_home(dir);
+            fs::create_dir_all(&dir)?;
+            let full_path = fs::canonicalize(path)?;
+            (
+                dir,
+                full_path
+                    .to_string_lossy()
+                    .replace('!', "!!")
+                    .replace('/', "!"),
+            )
+        }
+        None => match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir.to_path_buf(), name.to_string_lossy().into_owned()),
+            _ => return Ok(()),
+        },
+    };
+    let backup_name = if config.get_bool("backup.numbered").unwrap_or(false) {
+        format!("{}.~{}~", name, next_backup_number(&dir, &name))
+    } else {
+        format!("{}~", name)
+    };
+    fs::copy(path, dir.join(backup_name))?;
+    Ok(())
+}
+
+fn next_backup_number(dir: &Path, name: &str) -> usize {
+    let prefix = format!("{}.~", name);
+    let highest = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.ok()?.file_name();
+            let number = file_name
+                .to_str()?
+                .strip_prefix(&prefix)?
+                .strip_suffix('~')?
+                .parse::<usize>()
+                .ok()?;
+            Some(number)
+        })
+        .max();
+    highest.unwrap_or(0) + 1
+}
+
+fn expand_home(dir: &str) -> PathBuf {
+    match (dir.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(dir),
+    }
+}
@@ -0,0 +1,371 @@
+use crate::backup::backup_file;
+use crate::buffer::{split_lines, BufferOptions, LineEnding};
+use crate::config::Config;
+use crate::lock;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// A line given to a command: a number counted from 1, or `$` for the last one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Address {
+    Number(usize),
+    Last,
+}
+
+/// The lines `from..=to` a command works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lines {
+    from: Address,
+    to: Address,
+}
+
+/// A command of `--batch`, in the syntax of ex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    /// `[LINES]s/FROM/TO/[g]`: replaces FROM, taken literally as the
+    /// editor's replace does, on every line or on LINES. Only the first
+    /// match of a line without `g`. `\n` in TO breaks the line.
+    Substitute {
+        lines: Option<Lines>,
+        from: String,
+        to: String,
+        global: bool,
+    },
+    /// `LINESd`: removes the lines.
+    Delete(Lines),
+    /// `w [PATH]`: saves to the file, or to PATH.
+    Write(Option<PathBuf>),
+}
+
+/// The file being edited by `--batch`.
+struct Text {
+    lines: Vec<String>,
+    endings: Vec<LineEnding>,
+    /// The line break of the file, taken from the first line
+    eol: LineEnding,
+}
+
+impl Text {
+    fn line(&self, address: Address) -> Result<usize, String> {
+        match address {
+            Address::Last if self.lines.is_empty() => Err("The file is empty".to_string()),
+            Address::Last => Ok(self.lines.len() - 1),
+            Address::Number(0) => Err("Lines are counted from 1".to_string()),
+            Address::Number(n) if n > self.lines.len() => Err(format!(
+                "Line {} is past the end ({} lines)",
+                n,
+                self.lines.len()
+            )),
+            Address::Number(n) => Ok(n - 1),
+        }
+    }
+
+    fn rows(&self, lines: Lines) -> Result<(usize, usize), String> {
+        let (from, to) = (self.line(lines.from)?, self.line(lines.to)?);
+        if from > to {
+            return Err(format!("Line {} comes after line {}", from + 1, to + 1));
+        }
+        Ok((from, to))
+    }
+
+    fn substitute(
+        &mut self,
+        lines: Option<Lines>,
+        from: &str,
+        to: &str,
+        global: bool,
+    ) -> Result<(), String> {
+        if from.is_empty() {
+            return Err("Nothing to replace".to_string());
+        }
+        let (first, last) = match lines {
+            Some(lines) => self.rows(lines)?,
+            None if self.lines.is_empty() => return Ok(()),
+            None => (0, self.lines.len() - 1),
+        };
+        // From the end, as replacements with line breaks add lines
+        for row in (first..=last).rev() {
+            let line = &self.lines[row];
+            if !line.contains(from) {
+                continue;
+            }
+            let line = if global {
+                line.replace(from, to)
+            } else {
+                line.replacen(from, to, 1)
+            };
+            let ending = self.endings[row];
+            let parts: Vec<String> = line.split('\n').map(str::to_string).collect();
+            let added = parts.len() - 1;
+            let mut endings = vec![self.eol; added];
+            endings.push(ending);
+            self.lines.splice(row..=row, parts);
+            self.endings.splice(row..=row, endings);
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, lines: Lines) -> Result<(), String> {
+        let (from, to) = self.rows(lines)?;
+        self.lines.drain(from..=to);
+        self.endings.drain(from..=to);
+        Ok(())
+    }
+
+    /// The text as the editor saves it.
+    fn bytes(&self, options: &BufferOptions) -> Vec<u8> {
+        let mut bytes = vec![];
+        for (row, line) in self.lines.iter().enumerate() {
+            bytes.extend(line.bytes());
+            let ending = match self.endings[row] {
+                _ if !options.keep_line_endings => self.eol,
+                LineEnding::Missing if row + 1 < self.lines.len() => self.eol,
+                ending => ending,
+            };
+            bytes.extend(ending.as_str().bytes());
+        }
+        bytes
+    }
+}
+
+/// Reads an address at the start of `text`, returning it and the rest.
+fn parse_address(text: &str) -> Result<(Option<Address>, &str), String> {
+    if let Some(rest) = text.strip_prefix('$') {
+        return Ok((Some(Address::Last), rest));
+    }
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if digits == 0 {
+        return Ok((None, text));
+    }
+    let number = text[..digits]
+        .parse()
+        .map_err(|_| format!("Bad line number {}", &text[..digits]))?;
+    Ok((Some(Address::Number(number)), &text[digits..]))
+}
+
+/// Reads `N`, `N,M` or `%` at the start of `text`.
+fn parse_lines(text: &str) -> Result<(Option<Lines>, &str), String> {
+    if let Some(rest) = text.strip_prefix('%') {
+        let lines = Lines {
+            from: Address::Number(1),
+            to: Address::Last,
+        };
+        return Ok((Some(lines), rest));
+    }
+    let (from, rest) = match parse_address(text)? {
+        (Some(from), rest) => (from, rest),
+        (None, rest) => return Ok((None, rest)),
+    };
+    let (to, rest) = match rest.strip_prefix(',') {
+        Some(rest) => match parse_address(rest)? {
+            (Some(to), rest) => (to, rest),
+            (None, _) => return Err(format!("Missing line after , in {}", text)),
+        },
+        None => (from, rest),
+    };
+    Ok((Some(Lines { from, to }), rest))
+}
+
+/// Splits `s/FROM/TO/FLAGS` after the `s` at the delimiter following it.
+/// A backslash escapes the delimiter and itself, and `\n` is a line break.
+fn parse_pattern(text: &str) -> Result<(String, String, String), String> {
+    let mut chars = text.chars();
+    let delimiter = match chars.next() {
+        Some(c) if !c.is_alphanumeric() && c != '\\' && !c.is_whitespace() => c,
+        _ => return Err("Expected s/FROM/TO/".to_string()),
+    };
+    let mut parts = vec![];
+    let mut part = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => part.push('\n'),
+                Some(c) if c == delimiter || c == '\\' => part.push(c),
+                Some(c) => {
+                    part.push('\\');
+                    part.push(c);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delimiter && parts.len() < 2 => parts.push(std::mem::take(&mut part)),
+            c => part.push(c),
+        }
+    }
+    parts.push(part);
+    match <[String; 3]>::try_from(parts) {
+        Ok([from, to, flags]) => Ok((from, to, flags)),
+        // The last delimiter may be left out
+        Err(mut parts) if parts.len() == 2 => {
+            let to = parts.pop().unwrap();
+            Ok((parts.pop().unwrap(), to, String::new()))
+        }
+        Err(_) => Err("Expected s/FROM/TO/".to_string()),
+    }
+}
+
+fn parse_command(text: &str) -> Result<Command, String> {
+    let (lines, rest) = parse_lines(text.trim())?;
+    let mut chars = rest.chars();
+    match (chars.next(), lines) {
+        (Some('s'), lines) => {
+            let (from, to, flags) = parse_pattern(chars.as_str())?;
+            let global = match flags.trim() {
+                "" => false,
+                "g" => true,
+                flags => return Err(format!("Unknown flags {}", flags)),
+            };
+            Ok(Command::Substitute {
+                lines,
+                from,
+                to,
+                global,
+            })
+        }
+        (Some('d'), Some(lines)) if chars.as_str().trim().is_empty() => Ok(Command::Delete(lines)),
+        (Some('d'), None) => Err("d needs the lines to delete, e.g. 3,5d".to_string()),
+        (Some('w'), None) => match chars.as_str().trim() {
+            "" => Ok(Command::Write(None)),
+            path => Ok(Command::Write(Some(PathBuf::from(path)))),
+        },
+        _ => Err(format!("Unknown command {}", text.trim())),
+    }
+}
+
+/// Writes `text` to `path` as saving in the editor does: with a backup
+/// if configured, and not while another instance edits the file.
+fn write(text: &Text, path: &Path, config: &Config, options: &BufferOptions) -> Result<(), String> {
+    if let Some(owner) = lock::owner(path) {
+        return Err(format!("{} is being edited by {}", path.display(), owner));
+    }
+    backup_file(config, path).map_err(|e| format!("Can't make backup: {}", e))?;
+    fs::write(path, text.bytes(options))
+        .map_err(|e| format!("Can't write {}: {}", path.display(), e))
+}
+
+/// `--batch FILE [COMMAND...]`: applies ex-like commands to FILE without
+/// the editor's screen, like a tiny sed. The commands are the arguments,
+/// or the lines of stdin when none are given:
+/// - `[N[,M]]s/FROM/TO/[g]` replaces text, on every line if no lines are given
+/// - `N[,M]d` deletes lines
+/// - `w [PATH]` saves the file, or writes it to PATH
+///
+/// Lines are counted from 1, `$` is the last one and `%` all of them. Each
+/// command sees the lines as the commands before it left them. Without a
+/// `w` the edited text is printed to stdout. Stops at the first error.
+pub fn run(path: &str, commands: &[String], config: &Config) -> Result<(), String> {
+    let commands: Vec<String> = if commands.is_empty() {
+        let stdin = io::stdin();
+        let lines = stdin.lock().lines().collect::<io::Result<Vec<String>>>();
+        lines.map_err(|e| format!("Can't read the commands: {}", e))?
+    } else {
+        commands.to_vec()
+    };
+    let commands = commands
+        .iter()
+        .filter(|command| !command.trim().is_empty())
+        .map(|command| match parse_command(command) {
+            Ok(parsed) => Ok((command.trim(), parsed)),
+            Err(e) => Err(format!("{}: {}", command.trim(), e)),
+        })
+        .collect::<Result<Vec<(&str, Command)>, String>>()?;
+    let path = Path::new(path);
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Can't read {}: {}", path.display(), e)),
+    };
+    let (lines, endings) = split_lines(&contents);
+    let eol = match endings.first() {
+        Some(LineEnding::CrLf) => LineEnding::CrLf,
+        _ => LineEnding::Lf,
+    };
+    let mut text = Text {
+        lines,
+        endings,
+        eol,
+    };
+    let options = BufferOptions::from_config(config);
+    let mut written = false;
+    for (source, command) in commands {
+        let done = match command {
+            Command::Substitute {
+                lines,
+                from,
+                to,
+                global,
+            } => text.substitute(lines, &from, &to, global),
+            Command::Delete(lines) => text.delete(lines),
+            Command::Write(target) => {
+                written = true;
+                write(&text, target.as_deref().unwrap_or(path), config, &options)
+            }
+        };
+        done.map_err(|e| format!("{}: {}", source, e))?;
+    }
+    if !written {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout
+            .write_all(&text.bytes(&options))
+            .and_then(|_| stdout.flush())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(text: &str) -> (String, String, String) {
+        parse_pattern(text).unwrap()
+    }
+
+    #[test]
+    fn patterns_split_at_their_delimiter() {
+        let owned = |parts: [&str; 3]| parts.map(String::from).into();
+        assert_eq!(pattern("/a/b/g"), owned(["a", "b", "g"]));
+        assert_eq!(pattern("/a/b"), owned(["a", "b", ""]));
+        assert_eq!(pattern("#/usr#/opt#"), owned(["/usr", "/opt", ""]));
+        assert_eq!(pattern(r"/a\/b/c\\d/"), owned(["a/b", r"c\d", ""]));
+        assert_eq!(pattern(r"/x/y\nz/"), owned(["x", "y\nz", ""]));
+        // Other escapes are kept for the text to match
+        assert_eq!(pattern(r"/\t/ /"), owned([r"\t", " ", ""]));
+        // Delimiters after the third are part of the flags
+        assert_eq!(pattern("/a/b/g/"), owned(["a", "b", "g/"]));
+        assert!(parse_pattern("/only").is_err());
+        assert!(parse_pattern("abc").is_err());
+        assert!(parse_pattern("").is_err());
+    }
+
+    #[test]
+    fn commands_take_their_lines() {
+        let lines = |from, to| Some(Lines { from, to });
+        assert_eq!(
+            parse_command("%s/a/b/g").unwrap(),
+            Command::Substitute {
+                lines: lines(Address::Number(1), Address::Last),
+                from: "a".to_string(),
+                to: "b".to_string(),
+                global: true,
+            }
+        );
+        assert_eq!(
+            parse_command(" 3,$d ").unwrap(),
+            Command::Delete(Lines {
+                from: Address::Number(3),
+                to: Address::Last,
+            })
+        );
+        assert_eq!(
+            parse_command("w out.txt").unwrap(),
+            Command::Write(Some(PathBuf::from("out.txt")))
+        );
+        assert!(parse_command("d").is_err());
+        assert!(parse_command("s/a/b/x").is_err());
+        assert!(parse_command("3,d").is_err());
+        assert!(parse_command("q").is_err());
+    }
+}
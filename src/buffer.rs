@@ -0,0 +1,590 @@
+use crate::cargo_check::Diagnostic;
+use crate::config::Config;
+use crate::crypt::Encrypted;
+use crate::fileio::FileIo;
+use crate::git::BlameLine;
+use crate::hex::HexView;
+use crate::highlight::Highlight;
+use crate::journal::Journal;
+use crate::peers::PeerCursor;
+use crate::remote::RemotePath;
+use crate::text_buffer::{GapBuffer, TextBuffer};
+use crate::undo::{Coalescing, UndoHistory};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+pub const KILO_TAB_STOP: usize = 8;
+
+/// Settings which can differ between buffers. They start out from the
+/// `[editor]` section of the config and are changed with the `set` command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferOptions {
+    pub tab_width: usize,
+    /// Insert spaces instead of a tab character
+    pub expand_tab: bool,
+    /// Continue long lines on the next screen line instead of scrolling sideways
+    pub wrap: bool,
+    pub line_numbers: bool,
+    /// Mark lines changed or added since the last save in the gutter
+    pub change_marks: bool,
+    /// Expand abbreviations typed before a separator
+    pub abbrevs: bool,
+    /// Start a new line with the indentation of the one broken
+    pub auto_indent: bool,
+    /// Start a new line broken in a comment with the comment leader, e.g.
+    /// `// ` or ` * ` in a block comment
+    pub continue_comments: bool,
+    /// Save each line with the line ending it was read with, and no final
+    /// line break if the file had none, instead of `\n` after every line
+    pub keep_line_endings: bool,
+    /// Draw a guide at every indent level in the leading whitespace of lines
+    pub indent_guides: bool,
+    /// Lines kept visible above and below the cursor when scrolling
+    pub scroll_margin: usize,
+    /// Columns kept visible left and right of the cursor when scrolling sideways
+    pub side_scroll_margin: usize,
+    pub undo_coalescing: Coalescing,
+}
+
+impl BufferOptions {
+    pub fn from_config(config: &Config) -> BufferOptions {
+        BufferOptions {
+            tab_width: config
+                .get("editor.tab_width")
+                .and_then(|v| v.parse().ok())
+                .filter(|width| *width > 0)
+                .unwrap_or(KILO_TAB_STOP),
+            expand_tab: config.get_bool("editor.expand_tab").unwrap_or(false),
+            wrap: config.get_bool("editor.wrap").unwrap_or(false),
+            line_numbers: config.get_bool("editor.line_numbers").unwrap_or(false),
+            change_marks: config.get_bool("editor.change_marks").unwrap_or(true),
+            abbrevs: config.get_bool("editor.abbrevs").unwrap_or(true),
+            auto_indent: config.get_bool("editor.auto_indent").unwrap_or(true),
+            continue_comments: config.get_bool("editor.continue_comments").unwrap_or(true),
+            keep_line_endings: config.get_bool("editor.keep_line_endings").unwrap_or(false),
+            indent_guides: config.get_bool("editor.indent_guides").unwrap_or(false),
+            scroll_margin: config
+                .get("editor.scroll_margin")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            side_scroll_margin: config
+                .get("editor.side_scroll_margin")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            undo_coalescing: Coalescing::from_config(config),
+        }
+    }
+
+    /// The text of one level of indentation.
+    pub fn indent_unit(&self) -> String {
+        if self.expand_tab {
+            " ".repeat(self.tab_width)
+        } else {
+            "\t".to_string()
+        }
+    }
+
+    /// Takes the settings of `new` which are still those of `old`, e.g.
+    /// when the config changes, keeping the ones changed with `set`.
+    pub fn rebase(&mut self, old: &BufferOptions, new: &BufferOptions) {
+        fn take<T: PartialEq + Copy>(value: &mut T, old: T, new: T) {
+            if *value == old {
+                *value = new;
+            }
+        }
+        take(&mut self.tab_width, old.tab_width, new.tab_width);
+        take(&mut self.expand_tab, old.expand_tab, new.expand_tab);
+        take(&mut self.wrap, old.wrap, new.wrap);
+        take(&mut self.line_numbers, old.line_numbers, new.line_numbers);
+        take(&mut self.change_marks, old.change_marks, new.change_marks);
+        take(&mut self.abbrevs, old.abbrevs, new.abbrevs);
+        take(&mut self.auto_indent, old.auto_indent, new.auto_indent);
+        take(
+            &mut self.continue_comments,
+            old.continue_comments,
+            new.continue_comments,
+        );
+        take(
+            &mut self.keep_line_endings,
+            old.keep_line_endings,
+            new.keep_line_endings,
+        );
+        take(
+            &mut self.indent_guides,
+            old.indent_guides,
+            new.indent_guides,
+        );
+        take(
+            &mut self.scroll_margin,
+            old.scroll_margin,
+            new.scroll_margin,
+        );
+        take(
+            &mut self.side_scroll_margin,
+            old.side_scroll_margin,
+            new.side_scroll_margin,
+        );
+        take(
+            &mut self.undo_coalescing,
+            old.undo_coalescing,
+            new.undo_coalescing,
+        );
+    }
+}
+
+/// A text being edited together with its cursor and scroll position.
+/// `Window` displays one buffer at a time and keeps the others in the background.
+pub struct Buffer {
+    pub id: usize,
+    pub cx: usize, // 文字列上でのカーソル位置
+    pub cy: usize,
+    pub row_offset: usize,
+    pub col_offset: usize,
+    /// The text, one string per line without its line ending. Rows are
+    /// looked up directly, so no separate line index is kept
+    pub content_buffer: TextBuffer,
+    pub render_buffer: GapBuffer<RenderRow>,
+    pub filename: Option<PathBuf>,
+    /// Where `filename` points to when it is a symbolic link
+    pub link_target: Option<PathBuf>,
+    /// Modification time of the file when it was last read or written
+    pub disk_mtime: Option<SystemTime>,
+    /// Whether the user may write the file, as of when it was last read or
+    /// written or the terminal got the focus
+    pub disk_writable: bool,
+    /// Name shown for buffers which are not backed by a file, e.g. `*Occur*`.
+    pub name: Option<String>,
+    pub dirty: bool,
+    pub read_only: bool,
+    /// For an occur buffer, the id of the buffer whose lines it lists.
+    pub occur_source: Option<usize>,
+    /// For an undo tree buffer, the id of the buffer whose history it shows.
+    pub undo_tree_source: Option<usize>,
+    /// For an old revision of a file, the id of the buffer of its working copy.
+    pub revision_source: Option<usize>,
+    /// The other end of the region, as (row, column); the cursor is the first end.
+    pub mark: Option<(usize, usize)>,
+    /// The rows display, search and editing are restricted to after C-x n n
+    pub narrow: Option<Range<usize>>,
+    pub highlight: Highlight,
+    pub options: BufferOptions,
+    /// Set while the buffer is edited as raw bytes in hex mode
+    pub hex: Option<HexView>,
+    pub undo: UndoHistory,
+    /// How each line differs from the saved file; rows past the end are unchanged
+    pub changes: GapBuffer<LineChange>,
+    /// Last commit of each line, shown in the gutter while blame is on
+    pub blame: Option<GapBuffer<Option<BlameLine>>>,
+    /// How each line ended in the file when it was read; rows past the end,
+    /// e.g. added ones, end with `eol`
+    pub endings: GapBuffer<LineEnding>,
+    /// The line break written after every line, unless each keeps its own
+    /// with `keep_line_endings`
+    pub eol: LineEnding,
+    /// Set while the file is read or written in the background; the buffer
+    /// can't be edited meanwhile
+    pub io: Option<FileIo>,
+    /// Edits since the last save, kept on disk to recover from a crash
+    pub journal: Journal,
+    /// Set for files encrypted with gpg or age
+    pub encrypted: Option<Encrypted>,
+    /// Set for files on another machine, copied with scp; `filename` is
+    /// then None
+    pub remote: Option<RemotePath>,
+    /// Where a buffer without a file is saved unless another path is
+    /// given, e.g. the `--title` of text read from a pipe
+    pub save_path: Option<PathBuf>,
+    /// Cursors of others editing the buffer along, e.g. through a plugin
+    pub peers: Vec<PeerCursor>,
+    /// Errors and warnings of the last `cargo check`, see `CargoCheck`
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// State of a line since the buffer was last loaded or saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineChange {
+    #[default]
+    Unchanged,
+    Changed,
+    Added,
+}
+
+/// The byte order mark some editors start UTF-8 files with.
+pub const BOM: char = '\u{feff}';
+
+/// The line break after a line of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    /// The last line of a file which doesn't end with a line break
+    Missing,
+}
+
+impl LineEnding {
+    /// The name shown in the status bar.
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Missing => "",
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Missing => "",
+        }
+    }
+}
+
+/// Splits `text` into lines without their line endings, and the endings.
+pub fn split_lines(text: &str) -> (Vec<String>, Vec<LineEnding>) {
+    let mut lines = vec![];
+    let mut endings = vec![];
+    for line in text.split_inclusive('\n') {
+        let (line, ending) = match line.strip_suffix('\n') {
+            Some(line) => match line.strip_suffix('\r') {
+                Some(line) => (line, LineEnding::CrLf),
+                None => (line, LineEnding::Lf),
+            },
+            None => (line, LineEnding::Missing),
+        };
+        lines.push(line.to_string());
+        endings.push(ending);
+    }
+    (lines, endings)
+}
+
+/// Cells `c` takes when drawn at the render column `column`: a tab reaches
+/// to the next tab stop, a control character is drawn as `^X` in one cell,
+/// wide characters, e.g. CJK, take two cells and combining marks none.
+pub fn char_cells(c: char, column: usize, tab_width: usize) -> usize {
+    match c {
+        '\t' => tab_width - column % tab_width,
+        c if c.is_control() => 1,
+        c => c.width().unwrap_or(1),
+    }
+}
+
+/// Cells a grapheme cluster, e.g. a letter with its accents or an emoji
+/// with its modifiers, takes when drawn at the render column `column`.
+pub fn cluster_cells(cluster: &str, column: usize, tab_width: usize) -> usize {
+    match cluster.chars().next() {
+        Some(c) if c.is_control() => char_cells(c, column, tab_width),
+        _ => cluster.width().max(1),
+    }
+}
+
+/// Cells between two entries of the cell index of a `RenderRow`.
+const CELL_INDEX_STEP: usize = 256;
+
+/// Where a grapheme cluster of a line starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CellMark {
+    byte: usize,
+    char_index: usize,
+    column: usize,
+}
+
+/// A grapheme cluster of a line as it is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cluster<'a> {
+    pub text: &'a str,
+    /// Char index of its first char, as highlights are indexed
+    pub char_index: usize,
+    /// Render column of its first cell
+    pub column: usize,
+    pub cells: usize,
+}
+
+impl Cluster<'_> {
+    pub fn is_tab(&self) -> bool {
+        self.text == "\t"
+    }
+}
+
+/// How a line is laid out on screen, with tabs expanded and wide
+/// characters taking two cells. Long rows carry an index of where clusters
+/// start, so that drawing from a column far to the right doesn't have to
+/// walk the row from its start on every frame.
+#[derive(Default)]
+pub struct RenderRow {
+    /// The first cluster of every `CELL_INDEX_STEP` cells
+    cell_index: Vec<CellMark>,
+    width: usize,
+    tab_width: usize,
+}
+
+impl RenderRow {
+    pub fn new(line: &str, tab_width: usize) -> RenderRow {
+        let mut cell_index = vec![];
+        let mut mark = CellMark {
+            byte: 0,
+            char_index: 0,
+            column: 0,
+        };
+        for (byte, cluster) in line.grapheme_indices(true) {
+            mark.byte = byte;
+            if mark.column >= cell_index.len() * CELL_INDEX_STEP {
+                cell_index.push(mark);
+            }
+            mark.column += cluster_cells(cluster, mark.column, tab_width);
+            mark.char_index += cluster.chars().count();
+        }
+        RenderRow {
+            cell_index,
+            width: mark.column,
+            tab_width,
+        }
+    }
+
+    /// Number of cells in the row.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// The clusters of `line`, which the row was made from, from the one
+    /// covering the cell `start` on.
+    pub fn clusters<'a>(&self, line: &'a str, start: usize) -> impl Iterator<Item = Cluster<'a>> {
+        let index = (start / CELL_INDEX_STEP).min(self.cell_index.len().saturating_sub(1));
+        // A mark is after the cell its step starts at if a wide cluster
+        // or a tab covered that cell
+        let mark = self.cell_index[..self.cell_index.len().min(index + 1)]
+            .iter()
+            .rev()
+            .find(|mark| mark.column <= start)
+            .copied();
+        let (mut char_index, mut column) = mark.map_or((0, 0), |m| (m.char_index, m.column));
+        let rest = mark.map_or("", |mark| &line[mark.byte..]);
+        let tab_width = self.tab_width;
+        rest.graphemes(true)
+            .map(move |text| {
+                let cluster = Cluster {
+                    text,
+                    char_index,
+                    column,
+                    cells: cluster_cells(text, column, tab_width),
+                };
+                char_index += text.chars().count();
+                column += cluster.cells;
+                cluster
+            })
+            .skip_while(move |cluster| cluster.column + cluster.cells <= start)
+    }
+}
+
+/// Text between two positions given as (row, column), with `start <= end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+impl Region {
+    /// The column range of `row` covered by the region, clamped to `line_len`.
+    pub fn columns(&self, row: usize, line_len: usize) -> Option<(usize, usize)> {
+        if row < self.start.0 || row > self.end.0 {
+            return None;
+        }
+        let from = if row == self.start.0 { self.start.1 } else { 0 };
+        let to = if row == self.end.0 {
+            self.end.1
+        } else {
+            line_len
+        };
+        Some((from.min(line_len), to.min(line_len)))
+    }
+}
+
+impl Buffer {
+    pub fn new(id: usize, options: BufferOptions) -> Buffer {
+        Buffer {
+            id,
+            cx: 0,
+            cy: 0,
+            row_offset: 0,
+            col_offset: 0,
+            content_buffer: TextBuffer::default(),
+            render_buffer: GapBuffer::default(),
+            filename: None,
+            link_target: None,
+            disk_mtime: None,
+            disk_writable: true,
+            name: None,
+            dirty: false,
+            read_only: false,
+            occur_source: None,
+            undo_tree_source: None,
+            revision_source: None,
+            mark: None,
+            narrow: None,
+            highlight: Highlight::new(&TextBuffer::default(), PathBuf::new()),
+            options,
+            hex: None,
+            undo: UndoHistory::new(options.undo_coalescing),
+            changes: GapBuffer::default(),
+            blame: None,
+            endings: GapBuffer::default(),
+            eol: LineEnding::Lf,
+            io: None,
+            journal: Journal::default(),
+            encrypted: None,
+            remote: None,
+            save_path: None,
+            peers: vec![],
+            diagnostics: vec![],
+        }
+    }
+
+    pub fn line_change(&self, row: usize) -> LineChange {
+        self.changes
+            .get(row)
+            .copied()
+            .unwrap_or(LineChange::Unchanged)
+    }
+
+    /// The line break written after `row` when the buffer is saved with
+    /// `keep_line_endings`. Only the last line may lack one.
+    pub fn line_ending(&self, row: usize) -> LineEnding {
+        match self.endings.get(row).copied() {
+            Some(LineEnding::Missing) if row + 1 < self.content_buffer.len() => self.eol,
+            Some(ending) => ending,
+            None => self.eol,
+        }
+    }
+
+    /// Takes the line break of the first line as that of the whole file.
+    pub fn detect_eol(&mut self) {
+        self.eol = match self.endings.first() {
+            Some(LineEnding::CrLf) => LineEnding::CrLf,
+            _ => LineEnding::Lf,
+        };
+    }
+
+    /// Ends every line with `eol`, except a last line without a line break.
+    pub fn convert_eol(&mut self, eol: LineEnding) {
+        self.eol = eol;
+        for ending in self.endings.iter_mut() {
+            if *ending != LineEnding::Missing {
+                *ending = eol;
+            }
+        }
+    }
+
+    /// Marks a line as edited. Lines added since the last save stay marked
+    /// as added. The line loses its blame annotation.
+    pub fn mark_changed(&mut self, row: usize) {
+        if let Some(line) = self.blame.as_mut().and_then(|blame| blame.get_mut(row)) {
+            *line = None;
+        }
+        if self.changes.len() <= row {
+            self.changes.resize(row + 1, LineChange::Unchanged);
+        }
+        if self.changes[row] == LineChange::Unchanged {
+            self.changes[row] = LineChange::Changed;
+        }
+    }
+
+    /// Records `count` new lines inserted at `row`, which have no blame
+    /// annotation either. Lines inserted within the narrowed rows widen them.
+    pub fn mark_added(&mut self, row: usize, count: usize) {
+        if let Some(narrow) = &mut self.narrow {
+            if row < narrow.start {
+                narrow.start += count;
+            }
+            if row <= narrow.end {
+                narrow.end += count;
+            }
+        }
+        if let Some(blame) = &mut self.blame {
+            let at = row.min(blame.len());
+            blame.insert_rows(at, std::iter::repeat_n(None, count));
+        }
+        if self.changes.len() < row {
+            self.changes.resize(row, LineChange::Unchanged);
+        }
+        self.changes
+            .insert_rows(row, std::iter::repeat_n(LineChange::Added, count));
+        if row < self.endings.len() {
+            self.endings
+                .insert_rows(row, std::iter::repeat_n(self.eol, count));
+        }
+        for peer in &mut self.peers {
+            peer.shift_added(row, count);
+        }
+        for diagnostic in &mut self.diagnostics {
+            diagnostic.shift_added(row, count);
+        }
+    }
+
+    /// Forgets the marks, blame annotations and line endings of the removed
+    /// lines `from..to`.
+    pub fn unmark_removed(&mut self, from: usize, to: usize) {
+        if let Some(narrow) = &mut self.narrow {
+            let removed_before = |row: usize| to.min(row).saturating_sub(from);
+            narrow.start -= removed_before(narrow.start);
+            narrow.end -= removed_before(narrow.end);
+            // Narrowing keeps at least a line
+            narrow.end = narrow.end.max(narrow.start + 1);
+        }
+        if let Some(blame) = &mut self.blame {
+            let to = to.min(blame.len());
+            if from < to {
+                blame.remove_rows(from..to);
+            }
+        }
+        let to_change = to.min(self.changes.len());
+        if from < to_change {
+            self.changes.remove_rows(from..to_change);
+        }
+        for peer in &mut self.peers {
+            peer.shift_removed(from, to);
+        }
+        self.diagnostics
+            .retain_mut(|diagnostic| diagnostic.shift_removed(from, to));
+        let to = to.min(self.endings.len());
+        if from < to {
+            self.endings.remove_rows(from..to);
+        }
+    }
+
+    /// Shows the cursor of a peer, in place of the one of the same name.
+    pub fn set_peer(&mut self, peer: PeerCursor) {
+        match self.peers.iter_mut().find(|other| other.name == peer.name) {
+            Some(other) => *other = peer,
+            None => self.peers.push(peer),
+        }
+    }
+
+    /// Stops showing the cursor of the peer `name`.
+    pub fn remove_peer(&mut self, name: &str) {
+        self.peers.retain(|peer| peer.name != name);
+    }
+
+    /// The region between the mark and the cursor, if the mark is set.
+    pub fn region(&self) -> Option<Region> {
+        let mark = self.mark?;
+        let point = (self.cy, self.cx);
+        Some(Region {
+            start: mark.min(point),
+            end: mark.max(point),
+        })
+    }
+
+    pub fn display_name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        match self.filename.as_ref().and_then(|path| path.file_name()) {
+            Some(osstr) => osstr.to_str().unwrap_or("[NO NAME]").to_string(),
+            None => "[NO NAME]".to_string(),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use crate::buffer::Region;
+use crate::text_buffer::Lines;
+use crate::undo::Position;
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Level {
+    fn parse(level: &str) -> Level {
+        match level {
+            "warning" => Level::Warning,
+            level if level.starts_with("error") => Level::Error,
+            _ => Level::Note,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Note => "note",
+        }
+    }
+
+    /// SGR foreground code of the underline of its spans
+    pub fn color(self) -> u8 {
+        match self {
+            Level::Error => 31,
+            Level::Warning => 33,
+            Level::Note => 36,
+        }
+    }
+}
+
+/// A diagnostic of the compiler as cargo reports it, with the primary span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// As cargo gives it, relative to the workspace
+    file: PathBuf,
+    level: Level,
+    message: String,
+    /// Lines and columns of the span, counted in characters from 1
+    start: (usize, usize),
+    end: (usize, usize),
+}
+
+impl Report {
+    /// Reads a line of `cargo check --message-format=json`, which is a
+    /// diagnostic if its reason is `compiler-message`. Diagnostics without
+    /// a span, like the count of warnings, are left out.
+    fn parse(line: &str) -> Option<Report> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        if value["reason"] != "compiler-message" {
+            return None;
+        }
+        let message = &value["message"];
+        let span = message["spans"]
+            .as_array()?
+            .iter()
+            .find(|span| span["is_primary"] == true)?;
+        let number = |key: &str| -> Option<usize> { Some(span[key].as_u64()? as usize) };
+        Some(Report {
+            file: PathBuf::from(span["file_name"].as_str()?),
+            level: Level::parse(message["level"].as_str()?),
+            message: message["message"].as_str()?.to_string(),
+            start: (number("line_start")?, number("column_start")?),
+            end: (number("line_end")?, number("column_end")?),
+        })
+    }
+
+    /// Whether it is about the file at the absolute `path`.
+    pub fn is_for(&self, path: &Path) -> bool {
+        path.ends_with(&self.file)
+    }
+
+    /// Where the span is in `lines`, the lines of the file, with byte
+    /// columns. An empty span covers the character at it.
+    pub fn locate(&self, lines: &(impl Lines + ?Sized)) -> Diagnostic {
+        let position = |(line, column): (usize, usize)| -> Position {
+            let row = line.saturating_sub(1);
+            let text = lines.get_line(row).unwrap_or("");
+            let col = text
+                .char_indices()
+                .nth(column.saturating_sub(1))
+                .map_or(text.len(), |(col, _)| col);
+            (row, col)
+        };
+        let start = position(self.start);
+        let mut end = position(self.end);
+        if end <= start {
+            let text = lines.get_line(start.0).unwrap_or("");
+            let next = text[start.1..].chars().next().map_or(0, char::len_utf8);
+            end = (start.0, start.1 + next);
+        }
+        Diagnostic {
+            level: self.level,
+            message: self.message.clone(),
+            start,
+            end,
+        }
+    }
+}
+
+/// A diagnostic placed in a buffer, drawn underlined in the color of its
+/// level until the next check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    /// Row and byte column of the span
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Diagnostic {
+    pub fn region(&self) -> Region {
+        Region {
+            start: self.start,
+            end: self.end,
+        }
+    }
+
+    /// Keeps the span on its lines when `count` lines are inserted at `row`.
+    pub fn shift_added(&mut self, row: usize, count: usize) {
+        for position in [&mut self.start, &mut self.end] {
+            if position.0 >= row {
+                position.0 += count;
+            }
+        }
+    }
+
+    /// Keeps the span on its lines when the lines `from..to` are removed.
+    /// False when the span was on them, as it is gone then.
+    pub fn shift_removed(&mut self, from: usize, to: usize) -> bool {
+        if self.start.0 < to && self.end.0 >= from {
+            return false;
+        }
+        for position in [&mut self.start, &mut self.end] {
+            if position.0 >= to {
+                position.0 -= to - from;
+            }
+        }
+        true
+    }
+}
+
+/// `cargo check` running in the background for the package of a file,
+/// whose diagnostics come in once it is done.
+pub struct CargoCheck {
+    result: Receiver<Result<Vec<Report>, String>>,
+}
+
+impl CargoCheck {
+    /// Checks the package with the manifest `manifest`.
+    pub fn start(manifest: &Path) -> io::Result<CargoCheck> {
+        let child = Command::new("cargo")
+            .args(["check", "--message-format=json", "--manifest-path"])
+            .arg(manifest)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let (sender, result) = mpsc::channel();
+        thread::spawn(move || {
+            let reports = match child.wait_with_output() {
+                Ok(output) => {
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    let mut reports: Vec<Report> = vec![];
+                    // The diagnostics of a file are given again for each
+                    // target it is built in
+                    for report in stdout.lines().filter_map(Report::parse) {
+                        if !reports.contains(&report) {
+                            reports.push(report);
+                        }
+                    }
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    match stderr.lines().find(|line| line.starts_with("error")) {
+                        // Failing before compiling, e.g. on a bad manifest
+                        Some(error) if reports.is_empty() && !output.status.success() => {
+                            Err(error.to_string())
+                        }
+                        _ => Ok(reports),
+                    }
+                }
+                Err(e) => Err(e.to_string()),
+            };
+            let _ = sender.send(reports);
+        });
+        Ok(CargoCheck { result })
+    }
+
+    /// The diagnostics once the check is done.
+    pub fn take_result(&mut self) -> Option<Result<Vec<Report>, String>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err("cargo check stopped".to_string())),
+        }
+    }
+}
+
+/// The manifest of the package the absolute `path` is in.
+pub fn find_manifest(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .map(|dir| dir.join("Cargo.toml"))
+        .find(|manifest| manifest.is_file())
+}
+
+/// The edition set in the `[package]` section of `manifest`. None when it
+/// is inherited from a workspace or not set.
+pub fn manifest_edition(manifest: &Path) -> Option<String> {
+    let text = fs::read_to_string(manifest).ok()?;
+    let mut in_package = false;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_package = line == "[package]";
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some(pair) if in_package => pair,
+            _ => continue,
+        };
+        if key.trim() == "edition" {
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            return Some(value.to_string()).filter(|v| v.chars().all(|c| c.is_ascii_digit()));
+        }
+    }
+    None
+}
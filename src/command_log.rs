@@ -0,0 +1,183 @@
+use crate::describe::{builtin_command, is_prefix, key_name};
+use crate::input::InputType;
+use crate::macros::Keys;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Bytes of keys the log keeps; the oldest lines are dropped beyond it.
+const MAX_LOG_BYTES: usize = 1024 * 1024;
+
+/// The keys of a command, in the chunks they were read in.
+#[derive(Default)]
+struct Command {
+    keys: Keys,
+    /// The keys choosing the command, e.g. `C-x C-s`
+    chosen_by: Vec<InputType>,
+    /// Text typed while it ran, e.g. into a prompt
+    typed: String,
+}
+
+/// A line of the log: the keys of a command, or of text typed in a row,
+/// and what they did.
+struct Line {
+    keys: Keys,
+    comment: String,
+    typing: bool,
+}
+
+/// The commands typed in the session, which M-x save-command-log writes
+/// as a script that `--script` replays, e.g. to reproduce a bug. A line
+/// has the chunks of keys of a command as JSON strings, or arrays of
+/// bytes when not UTF-8, and a comment telling the command and the text
+/// typed into its prompts. Text typed in a row is a line of its own. Only
+/// the last `MAX_LOG_BYTES` of keys are kept.
+#[derive(Default)]
+pub struct CommandLog {
+    lines: VecDeque<Line>,
+    current: Option<Command>,
+    /// Bytes of the keys of `lines`
+    size: usize,
+    /// Lines dropped to stay within `MAX_LOG_BYTES`
+    dropped: usize,
+}
+
+impl CommandLog {
+    /// Notes a chunk read from the terminal.
+    pub fn record(&mut self, chunk: &[u8]) {
+        self.current
+            .get_or_insert_with(Command::default)
+            .keys
+            .push(chunk.to_vec());
+    }
+
+    /// Notes a key read from the terminal, which chooses the command or
+    /// is typed while it runs.
+    pub fn key(&mut self, key: &InputType) {
+        use InputType::*;
+        let command = match &mut self.current {
+            Some(command) => command,
+            None => return,
+        };
+        if command.chosen_by.is_empty() || is_prefix(&command.chosen_by) {
+            command.chosen_by.push(key.clone());
+            return;
+        }
+        match key {
+            Char(c) if c.is_ascii_graphic() || *c == b' ' => command.typed.push(char::from(*c)),
+            Text(c) => command.typed.push(*c),
+            Paste(text) => command.typed.push_str(text),
+            _ => {}
+        }
+    }
+
+    /// Ends the command that ran, if any keys were read for it.
+    pub fn command_started(&mut self) {
+        let command = match self.current.take() {
+            Some(command) => command,
+            None => return,
+        };
+        let text = match command.chosen_by.as_slice() {
+            [InputType::Char(c)] if builtin_command(&command.chosen_by) == Some("self-insert") => {
+                Some(char::from(*c).to_string())
+            }
+            [InputType::Text(c)] => Some(c.to_string()),
+            _ => None,
+        };
+        self.size += command.keys.iter().map(Vec::len).sum::<usize>();
+        while self.size > MAX_LOG_BYTES && self.lines.len() > 1 {
+            if let Some(line) = self.lines.pop_front() {
+                self.size -= line.keys.iter().map(Vec::len).sum::<usize>();
+                self.dropped += 1;
+            }
+        }
+        if let Some(text) = text {
+            match self.lines.back_mut() {
+                Some(line) if line.typing => {
+                    line.keys.extend(command.keys);
+                    line.comment.push_str(&text);
+                }
+                _ => self.lines.push_back(Line {
+                    keys: command.keys,
+                    comment: format!("self-insert {}", text),
+                    typing: true,
+                }),
+            }
+            return;
+        }
+        let keys: Vec<String> = command.chosen_by.iter().map(key_name).collect();
+        let mut comment = keys.join(" ");
+        for part in [
+            builtin_command(&command.chosen_by).unwrap_or(""),
+            &command.typed,
+        ] {
+            if !part.is_empty() {
+                comment = format!("{} {}", comment, part);
+            }
+        }
+        self.lines.push_back(Line {
+            keys: command.keys,
+            comment,
+            typing: false,
+        });
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        writeln!(file, "# Replay with: kilo_rust --script SCRIPT FILE")?;
+        if self.dropped > 0 {
+            writeln!(file, "# The first {} commands were left out", self.dropped)?;
+        }
+        for line in &self.lines {
+            let chunks: Vec<String> = line
+                .keys
+                .iter()
+                .map(|chunk| match std::str::from_utf8(chunk) {
+                    Ok(text) => json!(text).to_string(),
+                    Err(_) => json!(chunk).to_string(),
+                })
+                .collect();
+            writeln!(file, "{}  # {}", chunks.join(" "), line.comment)?;
+        }
+        Ok(())
+    }
+}
+
+/// The keys of a script written by `CommandLog::save`.
+pub fn parse_script(text: &str) -> Result<Keys, String> {
+    let mut keys = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let mut values = serde_json::Deserializer::from_str(line).into_iter::<Value>();
+        loop {
+            let error = match values.next() {
+                None => break,
+                Some(Ok(Value::String(chunk))) => {
+                    keys.push(chunk.into_bytes());
+                    continue;
+                }
+                Some(Ok(Value::Array(bytes))) => {
+                    let chunk: Option<Vec<u8>> = bytes
+                        .iter()
+                        .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                        .collect();
+                    match chunk {
+                        Some(chunk) => {
+                            keys.push(chunk);
+                            continue;
+                        }
+                        None => "invalid bytes".to_string(),
+                    }
+                }
+                Some(Ok(value)) => format!("unexpected {}", value),
+                // The comment after the keys
+                Some(Err(_)) if line[values.byte_offset()..].trim_start().starts_with('#') => break,
+                Some(Err(e)) => e.to_string(),
+            };
+            return Err(format!("line {}: {}", number + 1, error));
+        }
+    }
+    Ok(keys)
+}
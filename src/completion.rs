@@ -0,0 +1,46 @@
+use crate::abbrev::is_word_char;
+use crate::picker::fuzzy_match;
+use crate::text_buffer::Lines;
+use crate::undo::Position;
+
+/// Lines the completion popup shows at most.
+pub const POPUP_HEIGHT: usize = 8;
+
+/// Words of `lines` which complete `prefix`, typed at `at`: words starting
+/// with the same character and containing the rest of it in order, the
+/// nearest ones to the cursor first.
+pub fn word_candidates(lines: &(impl Lines + ?Sized), at: Position, prefix: &str) -> Vec<String> {
+    let first = match prefix.chars().next() {
+        Some(c) => c,
+        None => return vec![],
+    };
+    let mut rows: Vec<usize> = (0..lines.line_count()).collect();
+    rows.sort_by_key(|row| (*row as isize - at.0 as isize).abs());
+    let mut words: Vec<String> = vec![];
+    for row in rows {
+        let line = lines.line(row);
+        let mut start = None;
+        for (index, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+            match (is_word_char(c), start) {
+                (true, None) => start = Some(index),
+                (false, Some(from)) => {
+                    start = None;
+                    // The word being completed itself
+                    if (row, from) == at {
+                        continue;
+                    }
+                    let word = &line[from..index];
+                    if word != prefix
+                        && word.starts_with(first)
+                        && fuzzy_match(prefix, word)
+                        && !words.iter().any(|w| w == word)
+                    {
+                        words.push(word.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    words
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the config file of a project, looked for in the directory of
+/// the file edited and its parents.
+const PROJECT_FILE: &str = ".kilo.toml";
+
+/// Settings any project config may set, as they only change how the
+/// editor looks and edits. Everything else, such as programs to run, hooks,
+/// how and where files are written and which projects are trusted, is only
+/// taken from trusted projects, so settings added later are left out by
+/// default.
+const PROJECT_KEYS: [&str; 9] = [
+    "editor.",
+    "abbrevs.",
+    "colors.",
+    "theme.",
+    "digraphs.",
+    "keys.",
+    "undo.",
+    "goto.",
+    "print.line_numbers",
+];
+
+/// User settings read from `~/.config/kilo_rust/config.toml`, with those
+/// of a project's `.kilo.toml` over them.
+///
+/// Only a small TOML subset is understood: `[section]` headers, `# comments`
+/// and `key = value` pairs whose value is a quoted string, a number or a
+/// boolean. Values are stored under `section.key`.
+#[derive(Default, Clone)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        let base = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(base.join("kilo_rust").join("config.toml"))
+    }
+
+    /// Loads the user config. A missing file is not an error and yields the defaults.
+    pub fn load() -> Result<Config, String> {
+        match Config::path() {
+            Some(path) => Config::load_from(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// The config file of the project `dir` belongs to, if it has one.
+    pub fn project_path(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(PROJECT_FILE))
+            .find(|path| path.is_file())
+    }
+
+    /// Loads the user config with the settings of the project config of
+    /// `dir` merged over it. Settings beyond `PROJECT_KEYS` are only taken from
+    /// projects listed in `project.trusted` of the user config, a
+    /// colon-separated list of directories; a warning names those left out.
+    pub fn load_for(dir: &Path) -> Result<(Config, Option<String>), String> {
+        let mut config = Config::load()?;
+        let path = match Config::project_path(dir) {
+            Some(path) => path,
+            None => return Ok((config, None)),
+        };
+        let project = Config::load_from(&path)?;
+        let warning = config.merge_project(&path, project);
+        Ok((config, warning))
+    }
+
+    /// Merges the settings of `project`, read from `path`, over these,
+    /// leaving out those beyond `PROJECT_KEYS` unless the project is
+    /// trusted. Returns a warning naming those left out.
+    fn merge_project(&mut self, path: &Path, project: Config) -> Option<String> {
+        let trusted = self.get("project.trusted").is_some_and(|dirs| {
+            dirs.split(':')
+                .any(|trusted| Some(Path::new(trusted)) == path.parent())
+        });
+        let mut ignored = vec![];
+        for (key, value) in project.values {
+            if !trusted && !PROJECT_KEYS.iter().any(|prefix| key.starts_with(prefix)) {
+                ignored.push(key);
+            } else {
+                self.values.insert(key, value);
+            }
+        }
+        ignored.sort();
+        Some(ignored)
+            .filter(|ignored| !ignored.is_empty())
+            .map(|ignored| {
+                format!(
+                    "{} is not trusted, left out {}",
+                    path.display(),
+                    ignored.join(", ")
+                )
+            })
+    }
+
+    pub fn load_from(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut values = HashMap::new();
+        let mut section = String::new();
+        for (index, raw_line) in text.lines().enumerate() {
+            let line = strip_comment(raw_line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(format!("line {}: unterminated section header", index + 1));
+                }
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+            // A quoted key may itself contain `=`
+            let key_end = match line.strip_prefix('"').and_then(|rest| rest.find('"')) {
+                Some(quote) => quote + 2,
+                None => 0,
+            };
+            let (key, value) = match line[key_end..].find('=').map(|eq| eq + key_end) {
+                Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+                None => return Err(format!("line {}: expected `key = value`", index + 1)),
+            };
+            let key = unquote(key).unwrap_or(key);
+            let value = match unquote(value) {
+                Some(v) => v,
+                None if value.starts_with('"') => {
+                    return Err(format!("line {}: unterminated string", index + 1))
+                }
+                None => value,
+            };
+            let full_key = if section.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", section, key)
+            };
+            values.insert(full_key, value.to_string());
+        }
+        Ok(Config { values })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.as_str())
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)? {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Every `(key, value)` pair, keys with their section prefix.
+    pub fn values(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Every `(key, value)` pair of a `[section]`, with the section prefix removed.
+    pub fn section<'a>(&'a self, name: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.values.iter().filter_map(move |(k, v)| {
+            let rest = k.strip_prefix(name)?.strip_prefix('.')?;
+            Some((rest, v.as_str()))
+        })
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, chr) in line.char_indices() {
+        match chr {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn unquote(value: &str) -> Option<&str> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        Some(&value[1..value.len() - 1])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROJECT: &str = "[editor]\ntab_width = 2\n[backup]\ndirectory = \"/tmp/x\"\n\
+                           [hooks]\nidle = \"autosave\"\n[print]\ncommand = \"sh\"\n\
+                           line_numbers = true\n[project]\ntrusted = \"/\"\n";
+
+    #[test]
+    fn untrusted_projects_only_set_what_is_allowed() {
+        let mut config = Config::parse("[backup]\nenabled = false\n").unwrap();
+        let path = Path::new("/src/app/.kilo.toml");
+        let warning = config.merge_project(path, Config::parse(PROJECT).unwrap());
+        assert_eq!(config.get("editor.tab_width"), Some("2"));
+        assert_eq!(config.get("print.line_numbers"), Some("true"));
+        assert_eq!(config.get("backup.enabled"), Some("false"));
+        for key in [
+            "backup.directory",
+            "hooks.idle",
+            "print.command",
+            "project.trusted",
+        ] {
+            assert_eq!(config.get(key), None, "{}", key);
+        }
+        assert_eq!(
+            warning.as_deref(),
+            Some(
+                "/src/app/.kilo.toml is not trusted, left out backup.directory, \
+                 hooks.idle, print.command, project.trusted"
+            )
+        );
+    }
+
+    #[test]
+    fn trusted_projects_set_everything() {
+        let mut config = Config::parse("[project]\ntrusted = \"/other:/src/app\"\n").unwrap();
+        let path = Path::new("/src/app/.kilo.toml");
+        let warning = config.merge_project(path, Config::parse(PROJECT).unwrap());
+        assert_eq!(warning, None);
+        assert_eq!(config.get("hooks.idle"), Some("autosave"));
+        assert_eq!(config.get("backup.directory"), Some("/tmp/x"));
+    }
+}
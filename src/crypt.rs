@@ -0,0 +1,238 @@
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// File descriptor gpg reads the passphrase from, so that stdin is free
+/// for the text to encrypt.
+const PASSPHRASE_FD: i32 = 3;
+
+/// The tool a file is encrypted with, told by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// `.gpg`: encryption with `gpg`, symmetric or to public keys
+    Gpg,
+    /// `.age`: passphrase encryption with `age`
+    Age,
+}
+
+impl Cipher {
+    pub fn of(path: &Path) -> Option<Cipher> {
+        match path.extension()?.to_str()? {
+            "gpg" => Some(Cipher::Gpg),
+            "age" => Some(Cipher::Age),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::Gpg => "gpg",
+            Cipher::Age => "age",
+        }
+    }
+
+    /// Whether the tool asks for the passphrase on the terminal itself, as
+    /// age does, instead of being given the one the editor asked for.
+    pub fn asks_itself(self) -> bool {
+        self == Cipher::Age
+    }
+
+    /// The text of the file at `path`, read into memory only.
+    pub fn decrypt(self, path: &Path, passphrase: Option<&str>) -> Result<Vec<u8>, String> {
+        let mut command = self.command(passphrase.is_some());
+        command.arg("--decrypt").arg(path);
+        run(command, passphrase, vec![])
+    }
+
+    /// `text` encrypted, to be written to the file instead of the text:
+    /// to the keys `recipients` if there are any, else with `passphrase`.
+    pub fn encrypt(
+        self,
+        text: Vec<u8>,
+        passphrase: Option<&str>,
+        recipients: &[String],
+    ) -> Result<Vec<u8>, String> {
+        if !recipients.is_empty() {
+            let mut command = self.command(false);
+            command.arg("--encrypt");
+            for key in recipients {
+                // `!` keeps the very subkey, not the newest of its key
+                command.arg("--recipient").arg(format!("{}!", key));
+            }
+            return run(command, None, text);
+        }
+        let mut command = self.command(passphrase.is_some());
+        command.arg(match self {
+            Cipher::Gpg => "--symmetric",
+            Cipher::Age => "--passphrase",
+        });
+        run(command, passphrase, text)
+    }
+
+    /// The keys a `.gpg` file at `path` is encrypted to, empty for one
+    /// encrypted with a passphrase. Files that can't be encrypted the same
+    /// way again, to hidden recipients or both to keys and with a
+    /// passphrase, are an error, so that saving doesn't change how.
+    pub fn recipients(self, path: &Path) -> Result<Vec<String>, String> {
+        if self != Cipher::Gpg {
+            return Ok(vec![]);
+        }
+        let output = Command::new("gpg")
+            .args(["--batch", "--quiet", "--list-only", "--list-packets"])
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Can't run gpg: {}", e))?;
+        parse_recipients(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn command(self, passphrase: bool) -> Command {
+        let mut command = Command::new(self.name());
+        if self == Cipher::Gpg {
+            command.args(["--batch", "--yes", "--quiet", "--output", "-"]);
+            if passphrase {
+                command.args(["--pinentry-mode", "loopback", "--passphrase-fd"]);
+                command.arg(PASSPHRASE_FD.to_string());
+            }
+        }
+        command
+    }
+}
+
+/// What a buffer of an encrypted file needs to save it encrypted again.
+/// Its text is never written anywhere in plain: not to the file, not to
+/// the journal and not by autosaves.
+pub struct Encrypted {
+    pub cipher: Cipher,
+    /// The passphrase the file was decrypted with, unless the tool asks
+    /// for it itself
+    pub passphrase: Option<String>,
+    /// The keys the file is encrypted to, empty for a passphrase, or why
+    /// saving can't encrypt it the same way again
+    pub recipients: Result<Vec<String>, String>,
+    /// Whether the text was decrypted; until then the buffer is empty and
+    /// read-only
+    pub decrypted: bool,
+    /// Whether decrypting was tried since the file was opened, so that
+    /// the passphrase is only asked once without M-x decrypt
+    pub tried: bool,
+}
+
+impl Encrypted {
+    pub fn new(cipher: Cipher) -> Encrypted {
+        Encrypted {
+            cipher,
+            passphrase: None,
+            recipients: Ok(vec![]),
+            decrypted: false,
+            tried: false,
+        }
+    }
+}
+
+/// The key ids of the `:pubkey enc packet:` lines of `gpg --list-packets`.
+fn parse_recipients(packets: &str) -> Result<Vec<String>, String> {
+    let mut recipients = vec![];
+    let mut symmetric = false;
+    for line in packets.lines() {
+        if line.starts_with(":symkey enc packet:") {
+            symmetric = true;
+        }
+        let packet = match line.strip_prefix(":pubkey enc packet:") {
+            Some(packet) => packet,
+            None => continue,
+        };
+        let key = packet
+            .split(',')
+            .find_map(|field| field.trim().strip_prefix("keyid "))
+            .ok_or("gpg lists a key without its id")?;
+        if key.bytes().all(|b| b == b'0') {
+            return Err("encrypted to a hidden recipient, which can't be kept".to_string());
+        }
+        recipients.push(key.to_string());
+    }
+    match (symmetric, recipients.is_empty()) {
+        (true, false) => Err("encrypted both to keys and with a passphrase".to_string()),
+        (false, true) => Err("gpg lists no way the file is encrypted".to_string()),
+        _ => Ok(recipients),
+    }
+}
+
+/// Runs `command` with `input` on stdin and the passphrase, if any, on
+/// `PASSPHRASE_FD`, returning what it writes to stdout.
+fn run(mut command: Command, passphrase: Option<&str>, input: Vec<u8>) -> Result<Vec<u8>, String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let cant_run = |e: io::Error| format!("Can't run {}: {}", program, e);
+    let mut pipe = None;
+    if passphrase.is_some() {
+        let (reader, writer) = io::pipe().map_err(cant_run)?;
+        let fd = reader.as_raw_fd();
+        // Safety: only calls async-signal-safe functions between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                let result = if fd == PASSPHRASE_FD {
+                    libc::fcntl(fd, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, PASSPHRASE_FD)
+                };
+                match result {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            });
+        }
+        pipe = Some((reader, writer));
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(cant_run)?;
+    if let (Some((reader, mut writer)), Some(passphrase)) = (pipe, passphrase) {
+        drop(reader);
+        // Much less than a pipe holds, so this doesn't wait for the tool
+        let _ = writeln!(writer, "{}", passphrase);
+    }
+    let mut stdin = child.stdin.take();
+    let feeder = thread::spawn(move || match &mut stdin {
+        Some(stdin) => stdin.write_all(&input),
+        None => Ok(()),
+    });
+    let output = child.wait_with_output().map_err(cant_run)?;
+    let _ = feeder.join();
+    if output.status.success() {
+        return Ok(output.stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) if line.starts_with(&format!("{}:", program)) => Err(line.trim().to_string()),
+        Some(line) => Err(format!("{}: {}", program, line.trim())),
+        None => Err(format!("{} failed: {}", program, output.status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recipients_are_read_from_the_packets() {
+        let packets = ":pubkey enc packet: version 3, algo 18, keyid 0123456789ABCDEF\n\
+                       \tdata: [263 bits]\n\
+                       :pubkey enc packet: version 3, algo 1, keyid FEDCBA9876543210\n\
+                       :encrypted data packet:\n";
+        assert_eq!(
+            parse_recipients(packets).unwrap(),
+            ["0123456789ABCDEF", "FEDCBA9876543210"]
+        );
+        let symmetric = ":symkey enc packet: version 4, cipher 9, aead 0, s2kmode 3, hash 2\n";
+        assert!(parse_recipients(symmetric).unwrap().is_empty());
+        let hidden = ":pubkey enc packet: version 3, algo 1, keyid 0000000000000000\n";
+        assert!(parse_recipients(hidden).is_err());
+        assert!(parse_recipients(&format!("{}{}", symmetric, packets)).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use crate::config::Config;
+
+/// Shape of the terminal cursor, set with DECSCUSR (`CSI Ps SP q`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Bar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorStyle {
+    pub shape: CursorShape,
+    pub blink: bool,
+}
+
+/// Puts the cursor back to the shape configured in the terminal.
+pub const RESET_CURSOR_STYLE: &str = "\x1b[0 q";
+
+impl CursorStyle {
+    /// Reads `editor.cursor_shape` (`block`, `underline` or `bar`) and `editor.cursor_blink`.
+    pub fn from_config(config: &Config) -> Result<CursorStyle, String> {
+        let shape = match config.get("editor.cursor_shape") {
+            None | Some("block") => CursorShape::Block,
+            Some("underline") => CursorShape::Underline,
+            Some("bar") => CursorShape::Bar,
+            Some(other) => {
+                return Err(format!("editor.cursor_shape: unknown shape `{}`", other));
+            }
+        };
+        Ok(CursorStyle {
+            shape,
+            blink: config.get_bool("editor.cursor_blink").unwrap_or(true),
+        })
+    }
+
+    /// The DECSCUSR sequence selecting this style.
+    pub fn sequence(&self) -> String {
+        let steady = !self.blink as u8;
+        let code = match self.shape {
+            CursorShape::Block => 1,
+            CursorShape::Underline => 3,
+            CursorShape::Bar => 5,
+        } + steady;
+        format!("\x1b[{} q", code)
+    }
+}
+
+impl Default for CursorStyle {
+    fn default() -> Self {
+        CursorStyle {
+            shape: CursorShape::Block,
+            blink: true,
+        }
+    }
+}
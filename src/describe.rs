@@ -0,0 +1,134 @@
+use crate::input::{
+    CursorMoveDirection, InputType, BACKSPACE, CTRL_K, CTRL_Q, CTRL_SPACE, CTRL_U, CTRL_UNDERSCORE,
+    CTRL_W, CTRL_Y,
+};
+
+/// How a key is written in messages, e.g. `C-x`, `M-%` or `Up`.
+pub fn key_name(key: &InputType) -> String {
+    use CursorMoveDirection::*;
+    use InputType::*;
+    match key {
+        Char(b'\x1b') => "ESC".to_string(),
+        Char(b'\r') => "RET".to_string(),
+        Char(b'\t') => "TAB".to_string(),
+        Char(b' ') => "SPC".to_string(),
+        Char(CTRL_SPACE) | ControlSpace => "C-SPC".to_string(),
+        Char(BACKSPACE) | Backspace => "DEL".to_string(),
+        Char(c) if *c < 0x20 => format!("C-{}", char::from(c + 0x60)),
+        Char(c) => char::from(*c).to_string(),
+        Text(c) => c.to_string(),
+        CursorMove(Up) => "Up".to_string(),
+        CursorMove(Down) => "Down".to_string(),
+        CursorMove(Left) => "Left".to_string(),
+        CursorMove(Right) => "Right".to_string(),
+        CursorMove(PageUp) => "PageUp".to_string(),
+        CursorMove(PageDown) => "PageDown".to_string(),
+        CursorMove(LineTop) => "Home".to_string(),
+        CursorMove(LineBottom) => "End".to_string(),
+        Del => "Delete".to_string(),
+        ControlS => "C-s".to_string(),
+        ControlR => "C-r".to_string(),
+        ControlX => "C-x".to_string(),
+        ControlL => "C-l".to_string(),
+        Meta(c) => format!("M-{}", char::from(*c)),
+        FocusIn => "<focus-in>".to_string(),
+        FocusOut => "<focus-out>".to_string(),
+        Mouse(_) => "<mouse>".to_string(),
+        Paste(_) => "<paste>".to_string(),
+        NoOp => String::new(),
+    }
+}
+
+/// Whether more keys follow `keys` before a command runs, as after C-x.
+pub fn is_prefix(keys: &[InputType]) -> bool {
+    use InputType::*;
+    matches!(
+        keys,
+        [ControlX] | [ControlX, Char(b'n')] | [Meta(b's')] | [Meta(b'g')]
+    )
+}
+
+/// The built-in command `keys` run in the text, if any. Keys bound in the
+/// config, by plugins or scripts are looked up by `Window::describe_key`.
+pub fn builtin_command(keys: &[InputType]) -> Option<&'static str> {
+    use CursorMoveDirection::*;
+    use InputType::*;
+    let command = match keys {
+        [ControlX, key] => match key {
+            ControlS => "save-buffer",
+            ControlR => "open-recent-file",
+            Char(CTRL_W) => "save-as",
+            Char(b'w') => "export",
+            Char(b'p') => "print",
+            Char(b'u') => "undo",
+            Char(b'U') => "undo-tree",
+            Char(b'v') => "show-line-commit",
+            Char(b't') => "terminal",
+            Char(b'g') => "goto-file",
+            Char(b'b') => "switch-buffer",
+            Char(b'k') => "kill-buffer",
+            Char(b']') => "next-file",
+            Char(b'[') => "previous-file",
+            Char(b'f') => "preview",
+            Char(b'y') => "kill-ring",
+            Char(b'o') => "other-window",
+            Char(b'\\') => "compose",
+            Char(b'8') => "insert-char",
+            Char(b'?') => "describe-key",
+            Char(b'=') => "what-cursor-position",
+            Char(b'(') => "start-macro",
+            Char(b')') => "end-macro",
+            Char(b'e') => "call-last-macro",
+            _ => return None,
+        },
+        [ControlX, Char(b'n'), key] => match key {
+            Char(b'n') => "narrow-to-region",
+            Char(b'w') => "widen",
+            Char(b's') => "scratch-buffer",
+            _ => return None,
+        },
+        [Meta(b's'), Char(b'o')] => "occur",
+        [Meta(b'g'), key] => match key {
+            Char(b'n') => "next-diagnostic",
+            Char(b'p') => "previous-diagnostic",
+            _ => return None,
+        },
+        [key] => match key {
+            Char(b'\r') => "newline",
+            Char(CTRL_Q) => "quit",
+            Char(CTRL_K) => "kill-line",
+            Char(CTRL_W) => "kill-region",
+            Char(CTRL_Y) => "yank",
+            Meta(b'w') => "copy-region",
+            Meta(b'y') => "yank-pop",
+            Char(CTRL_UNDERSCORE) => "undo",
+            Char(CTRL_U) => "universal-argument",
+            Meta(b'_') => "redo",
+            Backspace => "delete-backward-char",
+            Del => "delete-forward-char",
+            CursorMove(Up) => "previous-line",
+            CursorMove(Down) => "next-line",
+            CursorMove(Left) => "backward-char",
+            CursorMove(Right) => "forward-char",
+            CursorMove(PageUp) => "scroll-down",
+            CursorMove(PageDown) => "scroll-up",
+            CursorMove(LineTop) => "beginning-of-line",
+            CursorMove(LineBottom) => "end-of-line",
+            ControlS => "search-forward",
+            ControlR => "search-backward",
+            Meta(b'%') => "replace",
+            Meta(b'x') => "execute-command",
+            Meta(b'/') => "complete",
+            Meta(b'h') => "hover",
+            ControlSpace => "set-mark",
+            ControlL => "redraw",
+            Char(b'\x1b') => "ignore",
+            Char(c) if !c.is_ascii_control() || *c == b'\t' => "self-insert",
+            Text(_) => "self-insert",
+            Paste(_) => "paste",
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(command)
+}
@@ -0,0 +1,134 @@
+use crate::config::Config;
+use std::collections::HashMap;
+
+/// Two-key sequences typed after C-x \ which stand for an accented or
+/// special character, as in vim (RFC 1345).
+const DEFAULT_DIGRAPHS: [(&str, char); 74] = [
+    ("a'", 'á'),
+    ("e'", 'é'),
+    ("i'", 'í'),
+    ("o'", 'ó'),
+    ("u'", 'ú'),
+    ("y'", 'ý'),
+    ("A'", 'Á'),
+    ("E'", 'É'),
+    ("I'", 'Í'),
+    ("O'", 'Ó'),
+    ("U'", 'Ú'),
+    ("Y'", 'Ý'),
+    ("a!", 'à'),
+    ("e!", 'è'),
+    ("i!", 'ì'),
+    ("o!", 'ò'),
+    ("u!", 'ù'),
+    ("A!", 'À'),
+    ("E!", 'È'),
+    ("I!", 'Ì'),
+    ("O!", 'Ò'),
+    ("U!", 'Ù'),
+    ("a>", 'â'),
+    ("e>", 'ê'),
+    ("i>", 'î'),
+    ("o>", 'ô'),
+    ("u>", 'û'),
+    ("A>", 'Â'),
+    ("E>", 'Ê'),
+    ("I>", 'Î'),
+    ("O>", 'Ô'),
+    ("U>", 'Û'),
+    ("a:", 'ä'),
+    ("e:", 'ë'),
+    ("i:", 'ï'),
+    ("o:", 'ö'),
+    ("u:", 'ü'),
+    ("y:", 'ÿ'),
+    ("A:", 'Ä'),
+    ("E:", 'Ë'),
+    ("I:", 'Ï'),
+    ("O:", 'Ö'),
+    ("U:", 'Ü'),
+    ("a?", 'ã'),
+    ("n?", 'ñ'),
+    ("o?", 'õ'),
+    ("A?", 'Ã'),
+    ("N?", 'Ñ'),
+    ("O?", 'Õ'),
+    ("c,", 'ç'),
+    ("C,", 'Ç'),
+    ("aa", 'å'),
+    ("AA", 'Å'),
+    ("o/", 'ø'),
+    ("O/", 'Ø'),
+    ("ae", 'æ'),
+    ("AE", 'Æ'),
+    ("oe", 'œ'),
+    ("OE", 'Œ'),
+    ("ss", 'ß'),
+    ("s<", 'š'),
+    ("S<", 'Š'),
+    ("z<", 'ž'),
+    ("Z<", 'Ž'),
+    ("c<", 'č'),
+    ("C<", 'Č'),
+    ("l/", 'ł'),
+    ("L/", 'Ł'),
+    ("!I", '¡'),
+    ("?I", '¿'),
+    ("<<", '«'),
+    (">>", '»'),
+    ("Eu", '€'),
+    ("Pd", '£'),
+];
+
+/// The digraph table: the defaults plus the `[digraphs]` section of the
+/// config, e.g. `"e=" = "€"`.
+pub struct Digraphs {
+    table: HashMap<(char, char), char>,
+}
+
+impl Digraphs {
+    pub fn from_config(config: &Config) -> Result<Digraphs, String> {
+        let mut table = HashMap::new();
+        let entries = DEFAULT_DIGRAPHS
+            .iter()
+            .map(|(keys, c)| (keys.to_string(), c.to_string()))
+            .chain(
+                config
+                    .section("digraphs")
+                    .map(|(keys, c)| (keys.to_string(), c.to_string())),
+            );
+        for (keys, c) in entries {
+            let mut key_chars = keys.chars();
+            let mut value_chars = c.chars();
+            match (
+                key_chars.next(),
+                key_chars.next(),
+                key_chars.next(),
+                value_chars.next(),
+                value_chars.next(),
+            ) {
+                (Some(first), Some(second), None, Some(c), None) => {
+                    table.insert((first, second), c);
+                }
+                _ => return Err(format!("digraphs: invalid entry `{}` = `{}`", keys, c)),
+            }
+        }
+        Ok(Digraphs { table })
+    }
+
+    /// The character for two keys, which may also be typed in reverse order.
+    pub fn get(&self, first: char, second: char) -> Option<char> {
+        self.table
+            .get(&(first, second))
+            .or_else(|| self.table.get(&(second, first)))
+            .copied()
+    }
+}
+
+impl Default for Digraphs {
+    fn default() -> Self {
+        Digraphs::from_config(&Config::default()).unwrap_or(Digraphs {
+            table: HashMap::new(),
+        })
+    }
+}
@@ -0,0 +1,176 @@
+use crate::buffer::char_cells;
+use crate::highlight::{Highlight, HighlightColor};
+use crate::text_buffer::Lines;
+use crate::theme::{Style, Theme};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Output format of an exported buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Text with SGR escape sequences, for a terminal
+    Ansi,
+    /// A standalone HTML page
+    Html,
+}
+
+impl Format {
+    /// HTML for `.html`/`.htm` paths, ANSI for anything else.
+    pub fn for_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("html") | Some("htm") => Format::Html,
+            _ => Format::Ansi,
+        }
+    }
+}
+
+/// Renders `lines` with the colors of `highlight` as `theme` draws them.
+pub fn render(
+    lines: &(impl Lines + ?Sized),
+    highlight: &Highlight,
+    theme: &Theme,
+    tab_width: usize,
+    format: Format,
+    title: &str,
+) -> String {
+    let mut out = String::new();
+    if format == Format::Html {
+        out.push_str(&format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<pre style=\"background-color: #1e1e1e; color: #d0d0d0;\">",
+            escape_html(title)
+        ));
+    }
+    for (row, line) in lines.all_lines().enumerate() {
+        let mut current: Option<Style> = None;
+        let mut column = 0;
+        for (ci, chr) in line.chars().enumerate() {
+            let style = match highlight.kind(row, ci) {
+                Some(HighlightColor::Normal) | None => None,
+                Some(kind) => Some(theme.style(kind)),
+            };
+            if style != current {
+                close(&mut out, format, current);
+                open(&mut out, format, style);
+                current = style;
+            }
+            if chr == '\t' {
+                let width = char_cells(chr, column, tab_width);
+                out.push_str(&" ".repeat(width));
+                column += width;
+                continue;
+            }
+            match format {
+                Format::Html => out.push_str(&escape_html(&chr.to_string())),
+                Format::Ansi => out.push(chr),
+            }
+            column += char_cells(chr, column, tab_width);
+        }
+        close(&mut out, format, current);
+        out.push('\n');
+    }
+    if format == Format::Html {
+        out.push_str("</pre>\n</body>\n</html>\n");
+    }
+    out
+}
+
+/// Reads a file and renders it with its highlighting, without opening the editor.
+pub fn render_file(
+    path: &str,
+    theme: &Theme,
+    tab_width: usize,
+    format: Format,
+) -> io::Result<String> {
+    let lines: Vec<String> = fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.to_string())
+        .collect();
+    let highlight = Highlight::new(&lines, PathBuf::from(path));
+    Ok(render(&lines, &highlight, theme, tab_width, format, path))
+}
+
+/// `--cat` / `--html`: prints a highlighted file to stdout.
+pub fn cat(path: &str, theme: &Theme, tab_width: usize, format: Format) -> io::Result<()> {
+    let rendered = render_file(path, theme, tab_width, format)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(rendered.as_bytes())?;
+    stdout.flush()
+}
+
+fn open(out: &mut String, format: Format, style: Option<Style>) {
+    let style = match style {
+        Some(style) => style,
+        None => return,
+    };
+    match format {
+        Format::Ansi => out.push_str(&style.sgr()),
+        Format::Html => out.push_str(&format!("<span style=\"{}\">", css(&style))),
+    }
+}
+
+fn close(out: &mut String, format: Format, style: Option<Style>) {
+    if style.is_none() {
+        return;
+    }
+    match format {
+        Format::Ansi => out.push_str("\x1b[m"),
+        Format::Html => out.push_str("</span>"),
+    }
+}
+
+fn css(style: &Style) -> String {
+    let mut declarations = vec![];
+    let color = css_color(style.fg);
+    if style.reverse {
+        declarations.push("color: #1e1e1e".to_string());
+        declarations.push(format!("background-color: {}", color.unwrap_or("#d0d0d0")));
+    } else {
+        if let Some(color) = color {
+            declarations.push(format!("color: {}", color));
+        }
+        if let Some(background) = css_color(style.bg) {
+            declarations.push(format!("background-color: {}", background));
+        }
+    }
+    if style.bold {
+        declarations.push("font-weight: bold".to_string());
+    }
+    if style.italic {
+        declarations.push("font-style: italic".to_string());
+    }
+    if style.underline {
+        declarations.push("text-decoration: underline".to_string());
+    }
+    declarations.join("; ")
+}
+
+/// CSS color of an SGR foreground code, in the xterm palette.
+fn css_color(fg: u8) -> Option<&'static str> {
+    const NORMAL: [&str; 8] = [
+        "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    ];
+    const BRIGHT: [&str; 8] = [
+        "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+    ];
+    match fg {
+        30..=37 => Some(NORMAL[(fg - 30) as usize]),
+        90..=97 => Some(BRIGHT[(fg - 90) as usize]),
+        _ => None,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for chr in text.chars() {
+        match chr {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(chr),
+        }
+    }
+    escaped
+}
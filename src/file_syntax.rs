@@ -1,5 +1,6 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fmt;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -8,6 +9,10 @@ pub enum FileType {
     C,
     Rust,
     Ruby,
+    Makefile,
+    Dockerfile,
+    Toml,
+    GitIgnore,
 }
 
 impl fmt::Display for FileType {
@@ -18,6 +23,10 @@ impl fmt::Display for FileType {
             C => write!(f, "C"),
             Rust => write!(f, "Rust"),
             Ruby => write!(f, "Ruby"),
+            Makefile => write!(f, "Makefile"),
+            Dockerfile => write!(f, "Dockerfile"),
+            Toml => write!(f, "TOML"),
+            GitIgnore => write!(f, "gitignore"),
         }
     }
 }
@@ -26,6 +35,8 @@ bitflags! {
     pub struct SyntaxFlags: u16 {
       const HL_NUMBER = 1 << 0;
       const HL_STRING = 1 << 1;
+      const HL_PREPROCESSOR = 1 << 2;
+      const HL_SEMANTIC = 1 << 3;
     }
 }
 
@@ -33,9 +44,12 @@ bitflags! {
 pub struct FileSyntax {
     pub ftype: FileType,
     pub extensions: &'static [&'static str],
+    pub filenames: &'static [&'static str],
     pub singleline_comment_start: &'static str,
     pub multiline_comment_start: &'static str,
     pub multiline_comment_end: &'static str,
+    /// Starts of the comments documenting the code, e.g. `///` and `/**`
+    pub doc_comment_starts: &'static [&'static str],
     pub keywords: &'static [&'static str],
     pub flags: SyntaxFlags,
 }
@@ -45,17 +59,42 @@ impl FileSyntax {
         FileSyntax {
             ftype: FileType::Undefined,
             extensions: &[],
+            filenames: &[],
             singleline_comment_start: "#",
             multiline_comment_start: "",
             multiline_comment_end: "",
+            doc_comment_starts: &[],
             keywords: &[],
             flags: SyntaxFlags::empty(),
         }
     }
+
+    /// Whether a comment starting `text` is a doc comment. A start followed
+    /// by its own last character is not, e.g. `////` or `/***`, nor `/**/`.
+    pub fn is_doc_comment(&self, text: &str) -> bool {
+        self.doc_comment_starts.iter().any(|start| {
+            let next = match text.strip_prefix(start) {
+                Some(rest) => rest.chars().next(),
+                None => return false,
+            };
+            next != start.chars().next_back() && !(start.ends_with('*') && next == Some('/'))
+        })
+    }
+
+    /// The syntax of a file type given by name, e.g. `rust` or `none`.
+    pub fn by_name(name: &str) -> Option<FileSyntax> {
+        if name == "none" || name == "--" {
+            return Some(FileSyntax::new());
+        }
+        SYNTAX_DB
+            .values()
+            .find(|syntax| syntax.ftype.to_string().eq_ignore_ascii_case(name))
+            .copied()
+    }
 }
-const C_EXTENSIONS: [&'static str; 3] = ["c", "cpp", "h"];
+const C_EXTENSIONS: [&str; 3] = ["c", "cpp", "h"];
 
-const C_KEYWORDS: [&'static str; 23] = [
+const C_KEYWORDS: [&str; 23] = [
     "switch",
     "if",
     "while",
@@ -81,18 +120,18 @@ const C_KEYWORDS: [&'static str; 23] = [
     "void|",
 ];
 
-const RUST_EXTENSIONS: [&'static str; 1] = ["rs"];
+const RUST_EXTENSIONS: [&str; 1] = ["rs"];
 
-const RUST_KEYWORDS: [&'static str; 37] = [
+const RUST_KEYWORDS: [&str; 37] = [
     "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false|", "fn", "for",
     "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref|", "return",
     "self|", "Self|", "static", "struct", "super", "trait", "true|", "type", "unsafe", "use",
     "where", "while", "async", "await",
 ];
 
-const RUBY_EXTENSIONS: [&'static str; 1] = ["rb"];
+const RUBY_EXTENSIONS: [&str; 1] = ["rb"];
 
-const RUBY_KEYWORDS: [&'static str; 41] = [
+const RUBY_KEYWORDS: [&str; 41] = [
     "__ENCODING__|",
     "__LINE__|",
     "__FILE__|",
@@ -136,7 +175,57 @@ const RUBY_KEYWORDS: [&'static str; 41] = [
     "yield ",
 ];
 
-pub static SYNTAX_DB: Lazy<HashMap<&std::ffi::OsStr, FileSyntax>> = Lazy::new(|| {
+const MAKEFILE_EXTENSIONS: [&str; 1] = ["mk"];
+
+const MAKEFILE_FILENAMES: [&str; 3] = ["Makefile", "makefile", "GNUmakefile"];
+
+const MAKEFILE_KEYWORDS: [&str; 12] = [
+    "ifeq", "ifneq", "ifdef", "ifndef", "else", "endif", "include", "define", "endef", "export",
+    "override", "vpath",
+];
+
+const DOCKERFILE_EXTENSIONS: [&str; 1] = ["dockerfile"];
+
+const DOCKERFILE_FILENAMES: [&str; 2] = ["Dockerfile", "Containerfile"];
+
+const DOCKERFILE_KEYWORDS: [&str; 18] = [
+    "FROM",
+    "AS|",
+    "RUN",
+    "CMD",
+    "LABEL",
+    "EXPOSE",
+    "ENV",
+    "ADD",
+    "COPY",
+    "ENTRYPOINT",
+    "VOLUME",
+    "USER",
+    "WORKDIR",
+    "ARG",
+    "ONBUILD",
+    "STOPSIGNAL",
+    "HEALTHCHECK",
+    "SHELL",
+];
+
+const TOML_EXTENSIONS: [&str; 1] = ["toml"];
+
+const TOML_FILENAMES: [&str; 1] = ["Cargo.lock"];
+
+const TOML_KEYWORDS: [&str; 2] = ["true|", "false|"];
+
+const GITIGNORE_FILENAMES: [&str; 3] = [".gitignore", ".dockerignore", ".ignore"];
+
+/// Lookup key of `SYNTAX_DB`. A syntax is registered once for each of its
+/// extensions and once for each of its exact filenames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxKey<'a> {
+    Extension(&'a OsStr),
+    FileName(&'a OsStr),
+}
+
+pub static SYNTAX_DB: Lazy<HashMap<SyntaxKey<'static>, FileSyntax>> = Lazy::new(|| {
     use FileType::*;
     let mut result = HashMap::new();
 
@@ -144,34 +233,93 @@ pub static SYNTAX_DB: Lazy<HashMap<&std::ffi::OsStr, FileSyntax>> = Lazy::new(||
         FileSyntax {
             ftype: C,
             extensions: &C_EXTENSIONS,
+            filenames: &[],
             singleline_comment_start: "//",
             multiline_comment_start: "/*",
             multiline_comment_end: "*/",
+            doc_comment_starts: &["///", "//!", "/**", "/*!"],
             keywords: &C_KEYWORDS,
-            flags: SyntaxFlags::HL_NUMBER | SyntaxFlags::HL_STRING,
+            flags: SyntaxFlags::HL_NUMBER
+                | SyntaxFlags::HL_STRING
+                | SyntaxFlags::HL_PREPROCESSOR
+                | SyntaxFlags::HL_SEMANTIC,
         },
         FileSyntax {
             ftype: Rust,
             extensions: &RUST_EXTENSIONS,
+            filenames: &[],
             singleline_comment_start: "//",
             multiline_comment_start: "/*",
             multiline_comment_end: "*/",
+            doc_comment_starts: &["///", "//!", "/**", "/*!"],
             keywords: &RUST_KEYWORDS,
-            flags: SyntaxFlags::HL_NUMBER | SyntaxFlags::HL_STRING,
+            flags: SyntaxFlags::HL_NUMBER
+                | SyntaxFlags::HL_STRING
+                | SyntaxFlags::HL_PREPROCESSOR
+                | SyntaxFlags::HL_SEMANTIC,
         },
         FileSyntax {
             ftype: Ruby,
             extensions: &RUBY_EXTENSIONS,
+            filenames: &[],
             singleline_comment_start: "#",
             multiline_comment_start: "=begin",
             multiline_comment_end: "=end",
+            doc_comment_starts: &["##", "#:"],
             keywords: &RUBY_KEYWORDS,
+            flags: SyntaxFlags::HL_NUMBER | SyntaxFlags::HL_STRING | SyntaxFlags::HL_SEMANTIC,
+        },
+        FileSyntax {
+            ftype: Makefile,
+            extensions: &MAKEFILE_EXTENSIONS,
+            filenames: &MAKEFILE_FILENAMES,
+            singleline_comment_start: "#",
+            multiline_comment_start: "",
+            multiline_comment_end: "",
+            doc_comment_starts: &[],
+            keywords: &MAKEFILE_KEYWORDS,
+            flags: SyntaxFlags::HL_NUMBER,
+        },
+        FileSyntax {
+            ftype: Dockerfile,
+            extensions: &DOCKERFILE_EXTENSIONS,
+            filenames: &DOCKERFILE_FILENAMES,
+            singleline_comment_start: "#",
+            multiline_comment_start: "",
+            multiline_comment_end: "",
+            doc_comment_starts: &[],
+            keywords: &DOCKERFILE_KEYWORDS,
             flags: SyntaxFlags::HL_NUMBER | SyntaxFlags::HL_STRING,
         },
+        FileSyntax {
+            ftype: Toml,
+            extensions: &TOML_EXTENSIONS,
+            filenames: &TOML_FILENAMES,
+            singleline_comment_start: "#",
+            multiline_comment_start: "",
+            multiline_comment_end: "",
+            doc_comment_starts: &[],
+            keywords: &TOML_KEYWORDS,
+            flags: SyntaxFlags::HL_NUMBER | SyntaxFlags::HL_STRING,
+        },
+        FileSyntax {
+            ftype: GitIgnore,
+            extensions: &[],
+            filenames: &GITIGNORE_FILENAMES,
+            singleline_comment_start: "#",
+            multiline_comment_start: "",
+            multiline_comment_end: "",
+            doc_comment_starts: &[],
+            keywords: &[],
+            flags: SyntaxFlags::empty(),
+        },
     ];
     for s in syntaxes {
         for ext in s.extensions.iter() {
-            result.insert(std::ffi::OsStr::new(ext.clone()), s);
+            result.insert(SyntaxKey::Extension(OsStr::new(ext)), s);
+        }
+        for name in s.filenames.iter() {
+            result.insert(SyntaxKey::FileName(OsStr::new(name)), s);
         }
     }
     result
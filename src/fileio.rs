@@ -0,0 +1,267 @@
+use crate::buffer::LineEnding;
+use crate::remote::{self, RemotePath};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process::Child;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Lines a loading worker sends at once.
+const BATCH_LINES: usize = 4096;
+
+/// Batches of lines a loading worker reads ahead of the editor.
+const READ_AHEAD: usize = 4;
+
+/// Bytes a saving worker writes at once, between progress updates.
+const WRITE_CHUNK: usize = 64 * 1024;
+
+/// What a worker is doing with the file of a buffer.
+pub enum IoKind {
+    Loading,
+    /// Writing to `path`; autosaves report themselves differently
+    Saving {
+        path: PathBuf,
+        autosave: bool,
+    },
+    /// Copying a remote file into memory, then reading it
+    Fetching,
+    /// Copying the contents to the remote file of the buffer
+    Uploading,
+}
+
+pub enum IoEvent {
+    /// The next lines of the file, without their line endings, and the endings
+    Lines(Vec<String>, Vec<LineEnding>),
+    /// The file is not UTF-8 text; these are all its bytes
+    Binary(Vec<u8>),
+    Done,
+    Failed(io::Error),
+}
+
+/// A file read or written by a thread of its own, so that the editor keeps
+/// responding to keys meanwhile, e.g. on a slow network file system. The
+/// buffer can't be edited until the worker is done.
+pub struct FileIo {
+    pub kind: IoKind,
+    /// Size of the file, or of the contents written
+    total: u64,
+    /// Bytes read or written so far, counted by the worker
+    done: Arc<AtomicU64>,
+    events: Receiver<IoEvent>,
+}
+
+impl FileIo {
+    /// Reads `file` line by line.
+    pub fn load(file: File) -> io::Result<FileIo> {
+        let total = file.metadata()?.len();
+        let done = Arc::new(AtomicU64::new(0));
+        let (sender, events) = mpsc::sync_channel(READ_AHEAD);
+        let counter = done.clone();
+        thread::spawn(move || {
+            let event = match read_lines(file, &counter, &sender) {
+                Ok(event) => event,
+                Err(e) => IoEvent::Failed(e),
+            };
+            let _ = sender.send(event);
+        });
+        Ok(FileIo {
+            kind: IoKind::Loading,
+            total,
+            done,
+            events,
+        })
+    }
+
+    /// Writes `contents` to `file`, which is already created.
+    pub fn save(file: File, contents: Vec<u8>, path: PathBuf, autosave: bool) -> FileIo {
+        FileIo::write(file, None, contents, path, autosave)
+    }
+
+    /// Writes `contents` to `file`, created at `temp` in the directory of
+    /// `path`, then renames it over `path`, e.g. to replace a symbolic link
+    /// without a moment in which there is no file at `path`.
+    pub fn replace(file: File, temp: PathBuf, contents: Vec<u8>, path: PathBuf) -> FileIo {
+        FileIo::write(file, Some(temp), contents, path, false)
+    }
+
+    fn write(
+        mut file: File,
+        temp: Option<PathBuf>,
+        contents: Vec<u8>,
+        path: PathBuf,
+        autosave: bool,
+    ) -> FileIo {
+        let done = Arc::new(AtomicU64::new(0));
+        let (sender, events) = mpsc::channel();
+        let counter = done.clone();
+        let total = contents.len() as u64;
+        let target = path.clone();
+        thread::spawn(move || {
+            let result = contents.chunks(WRITE_CHUNK).try_for_each(|chunk| {
+                file.write_all(chunk)?;
+                counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                Ok(())
+            });
+            let mut result = result.and_then(|()| file.flush());
+            if let Some(temp) = &temp {
+                result = result.and_then(|()| fs::rename(temp, &target));
+                if result.is_err() {
+                    let _ = fs::remove_file(temp);
+                }
+            }
+            let event = match result {
+                Ok(()) => IoEvent::Done,
+                Err(e) => IoEvent::Failed(e),
+            };
+            let _ = sender.send(event);
+        });
+        FileIo {
+            kind: IoKind::Saving { path, autosave },
+            total,
+            done,
+            events,
+        }
+    }
+
+    /// Copies `remote` into memory with scp, then reads it line by line
+    /// like `load`. The size of the file isn't known beforehand, so the
+    /// progress is counted in bytes.
+    pub fn fetch(remote: &RemotePath) -> io::Result<FileIo> {
+        let file = remote::memory_file()?;
+        let child = remote.start_fetch(&file)?;
+        let done = Arc::new(AtomicU64::new(0));
+        let (sender, events) = mpsc::sync_channel(READ_AHEAD);
+        let counter = done.clone();
+        thread::spawn(move || {
+            let event = match fetch_lines(file, child, &counter, &sender) {
+                Ok(event) => event,
+                Err(e) => IoEvent::Failed(e),
+            };
+            let _ = sender.send(event);
+        });
+        Ok(FileIo {
+            kind: IoKind::Fetching,
+            total: 0,
+            done,
+            events,
+        })
+    }
+
+    /// Writes `contents` to `remote` with scp, from a copy in memory.
+    pub fn upload(contents: &[u8], remote: &RemotePath) -> io::Result<FileIo> {
+        let mut file = remote::memory_file()?;
+        file.write_all(contents)?;
+        let child = remote.start_upload(&file)?;
+        let (sender, events) = mpsc::channel();
+        thread::spawn(move || {
+            let event = match remote::wait(child, || {}) {
+                Ok(()) => IoEvent::Done,
+                Err(e) => IoEvent::Failed(e),
+            };
+            drop(file);
+            let _ = sender.send(event);
+        });
+        Ok(FileIo {
+            kind: IoKind::Uploading,
+            total: contents.len() as u64,
+            done: Arc::new(AtomicU64::new(0)),
+            events,
+        })
+    }
+
+    /// Bytes read or written so far.
+    pub fn done(&self) -> u64 {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// How far along the worker is, in percent.
+    pub fn progress(&self) -> u64 {
+        let done = self.done.load(Ordering::Relaxed);
+        (done * 100).checked_div(self.total).unwrap_or(100).min(100)
+    }
+
+    /// The next thing the worker sent, waiting up to `wait` for it.
+    pub fn next_event(&self, wait: Duration) -> Option<IoEvent> {
+        if wait.is_zero() {
+            return self.events.try_recv().ok();
+        }
+        self.events.recv_timeout(wait).ok()
+    }
+}
+
+/// Waits for scp to copy a remote file into `file`, counting its bytes,
+/// then sends its lines like `read_lines`.
+fn fetch_lines(
+    mut file: File,
+    child: Child,
+    counter: &AtomicU64,
+    sender: &SyncSender<IoEvent>,
+) -> io::Result<IoEvent> {
+    remote::wait(child, || {
+        let len = file.metadata().map_or(0, |metadata| metadata.len());
+        counter.store(len, Ordering::Relaxed);
+    })?;
+    file.seek(SeekFrom::Start(0))?;
+    read_lines(file, &AtomicU64::new(0), sender)
+}
+
+/// Sends the lines of `file` in batches, or all its bytes if it turns out
+/// not to be text. Returns the event telling how it ended.
+fn read_lines(
+    file: File,
+    counter: &AtomicU64,
+    sender: &SyncSender<IoEvent>,
+) -> io::Result<IoEvent> {
+    let mut reader = BufReader::new(file);
+    let mut lines = vec![];
+    let mut endings = vec![];
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        counter.fetch_add(read as u64, Ordering::Relaxed);
+        let mut ending = LineEnding::Missing;
+        if line.ends_with(b"\n") {
+            line.pop();
+            ending = LineEnding::Lf;
+            if line.ends_with(b"\r") {
+                line.pop();
+                ending = LineEnding::CrLf;
+            }
+        }
+        match String::from_utf8(std::mem::take(&mut line)) {
+            Ok(text) => {
+                lines.push(text);
+                endings.push(ending);
+            }
+            Err(_) => {
+                let mut file = reader.into_inner();
+                file.seek(SeekFrom::Start(0))?;
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes)?;
+                let _ = sender.send(IoEvent::Binary(bytes));
+                return Ok(IoEvent::Done);
+            }
+        }
+        if lines.len() >= BATCH_LINES
+            && sender
+                .send(IoEvent::Lines(
+                    std::mem::take(&mut lines),
+                    std::mem::take(&mut endings),
+                ))
+                .is_err()
+        {
+            // The buffer was closed
+            return Ok(IoEvent::Done);
+        }
+    }
+    let _ = sender.send(IoEvent::Lines(lines, endings));
+    Ok(IoEvent::Done)
+}
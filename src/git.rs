@@ -0,0 +1,295 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Runs git in `dir` with `input` on stdin and returns its output, or its
+/// error message when it fails.
+fn git(dir: &Path, args: &[&str], input: Option<&str>) -> Result<String, String> {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Can't run git: {}", e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| format!("Can't run git: {}", e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Can't run git: {}", e))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        let message = String::from_utf8_lossy(&output.stderr);
+        Err(message.lines().next().unwrap_or("git failed").to_string())
+    }
+}
+
+/// The top directory of the work tree the file `path` is in.
+fn top_level(path: &Path) -> Result<PathBuf, String> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let top = git(dir, &["rev-parse", "--show-toplevel"], None)?;
+    Ok(PathBuf::from(top.trim_end()))
+}
+
+/// A changed range of a file, from `git diff` without context lines.
+pub struct Hunk {
+    /// First line and length of the range in the work tree, from 1
+    pub new_start: usize,
+    pub new_len: usize,
+    /// The `@@` line and the changed lines
+    text: String,
+}
+
+impl Hunk {
+    /// Parses `@@ -old_start,old_len +new_start,new_len @@`.
+    fn parse_header(line: &str) -> Option<Hunk> {
+        let mut ranges = line.strip_prefix("@@ ")?.split(' ');
+        let range = |text: &str| -> Option<(usize, usize)> {
+            match text.split_once(',') {
+                Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+                None => Some((text.parse().ok()?, 1)),
+            }
+        };
+        range(ranges.next()?.strip_prefix('-')?)?;
+        let (new_start, new_len) = range(ranges.next()?.strip_prefix('+')?)?;
+        Some(Hunk {
+            new_start,
+            new_len,
+            text: format!("{}\n", line),
+        })
+    }
+
+    /// Whether the hunk covers `row` of the work tree file, counted from 0.
+    /// Removed lines belong to the line before them.
+    pub fn contains(&self, row: usize) -> bool {
+        if self.new_len == 0 {
+            row + 1 == self.new_start.max(1)
+        } else {
+            row + 1 >= self.new_start && row + 1 < self.new_start + self.new_len
+        }
+    }
+}
+
+/// The differences between the index and the work tree copy of a file.
+pub struct FileDiff {
+    top: PathBuf,
+    /// The `diff --git`, `---` and `+++` lines the hunks follow
+    header: String,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FileDiff {
+    pub fn read(path: &Path) -> Result<FileDiff, String> {
+        let path = path.canonicalize().map_err(|e| e.to_string())?;
+        let top = top_level(&path)?;
+        let path = path.to_string_lossy();
+        let output = git(
+            &top,
+            &["diff", "--no-color", "--no-ext-diff", "-U0", "--", &path],
+            None,
+        )?;
+        let mut header = String::new();
+        let mut hunks: Vec<Hunk> = vec![];
+        for line in output.lines() {
+            if let Some(hunk) = Hunk::parse_header(line) {
+                hunks.push(hunk);
+            } else if let Some(hunk) = hunks.last_mut() {
+                hunk.text.push_str(line);
+                hunk.text.push('\n');
+            } else {
+                header.push_str(line);
+                header.push('\n');
+            }
+        }
+        Ok(FileDiff { top, header, hunks })
+    }
+
+    pub fn hunk_at(&self, row: usize) -> Option<&Hunk> {
+        self.hunks.iter().find(|hunk| hunk.contains(row))
+    }
+
+    /// Copies `hunk` of the work tree file to the index.
+    pub fn stage(&self, hunk: &Hunk) -> Result<(), String> {
+        self.apply(hunk, &["--cached"])
+    }
+
+    /// Undoes `hunk` in the work tree file, restoring the lines of the index.
+    pub fn revert(&self, hunk: &Hunk) -> Result<(), String> {
+        self.apply(hunk, &["--reverse"])
+    }
+
+    fn apply(&self, hunk: &Hunk, options: &[&str]) -> Result<(), String> {
+        let patch = format!("{}{}", self.header, hunk.text);
+        let mut args = vec!["apply", "--unidiff-zero"];
+        args.extend(options);
+        args.push("-");
+        git(&self.top, &args, Some(&patch)).map(|_| ())
+    }
+}
+
+/// A commit which changed a file.
+pub struct Revision {
+    pub hash: String,
+    /// Date, author and subject
+    pub summary: String,
+    /// Path of the file in the commit, relative to the top of the work tree
+    path: String,
+}
+
+/// The commits which changed the file `path`, newest first, following renames.
+pub fn file_log(path: &Path) -> Result<Vec<Revision>, String> {
+    let path = path.canonicalize().map_err(|e| e.to_string())?;
+    let top = top_level(&path)?;
+    let output = git(
+        &top,
+        &[
+            "log",
+            "--follow",
+            "--date=short",
+            "--format=%x00%H%x09%ad %an: %s",
+            "--name-only",
+            "--",
+            &path.to_string_lossy(),
+        ],
+        None,
+    )?;
+    let mut revisions: Vec<Revision> = vec![];
+    for line in output.lines() {
+        if let Some(header) = line.strip_prefix('\0') {
+            let (hash, summary) = header.split_once('\t').unwrap_or((header, ""));
+            revisions.push(Revision {
+                hash: hash.to_string(),
+                summary: summary.to_string(),
+                path: String::new(),
+            });
+        } else if let Some(revision) = revisions.last_mut() {
+            if revision.path.is_empty() && !line.is_empty() {
+                revision.path = line.to_string();
+            }
+        }
+    }
+    Ok(revisions)
+}
+
+impl Revision {
+    /// The content of the file as of this commit.
+    pub fn content(&self, path: &Path) -> Result<String, String> {
+        let path = path.canonicalize().map_err(|e| e.to_string())?;
+        let top = top_level(&path)?;
+        let object = format!("{}:{}", self.hash, self.path);
+        git(&top, &["show", &object], None)
+    }
+}
+
+/// Who last changed a line, from `git blame`.
+#[derive(Debug, Clone)]
+pub struct BlameLine {
+    pub hash: String,
+    pub author: String,
+    /// Seconds since the epoch
+    pub time: i64,
+}
+
+impl BlameLine {
+    /// Lines changed in the work tree are blamed on a commit of zeros.
+    pub fn is_committed(&self) -> bool {
+        self.hash.bytes().any(|b| b != b'0')
+    }
+
+    /// `hash author date`, padded to `BLAME_WIDTH` columns.
+    pub fn annotation(&self) -> String {
+        if !self.is_committed() {
+            return format!("{:<1$}", "Not committed yet", BLAME_WIDTH);
+        }
+        let author: String = self.author.chars().take(12).collect();
+        format!(
+            "{} {:<12} {} ",
+            &self.hash[..7.min(self.hash.len())],
+            author,
+            format_date(self.time)
+        )
+    }
+}
+
+/// Width of a blame annotation in the gutter.
+pub const BLAME_WIDTH: usize = 32;
+
+/// The last commit of every line of the file `path`, as committed.
+pub fn blame(path: &Path) -> Result<Vec<BlameLine>, String> {
+    let path = path.canonicalize().map_err(|e| e.to_string())?;
+    let top = top_level(&path)?;
+    let output = git(
+        &top,
+        &["blame", "--porcelain", "--", &path.to_string_lossy()],
+        None,
+    )?;
+    let mut commits: std::collections::HashMap<String, (String, i64)> = Default::default();
+    let mut lines = vec![];
+    let mut hash = String::new();
+    for line in output.lines() {
+        if line.starts_with('\t') {
+            let (author, time) = commits.get(&hash).cloned().unwrap_or_default();
+            lines.push(BlameLine {
+                hash: hash.clone(),
+                author,
+                time,
+            });
+        } else if let Some(author) = line.strip_prefix("author ") {
+            commits.entry(hash.clone()).or_default().0 = author.to_string();
+        } else if let Some(time) = line.strip_prefix("author-time ") {
+            commits.entry(hash.clone()).or_default().1 = time.parse().unwrap_or(0);
+        } else {
+            // `<hash> <original line> <final line> [<lines in group>]` starts a line
+            let word = line.split(' ').next().unwrap_or("");
+            if word.len() == 40 && word.bytes().all(|b| b.is_ascii_hexdigit()) {
+                hash = word.to_string();
+            }
+        }
+    }
+    Ok(lines)
+}
+
+/// Hash, author, date and message of a commit, for showing it in full.
+pub fn commit_message(path: &Path, hash: &str) -> Result<String, String> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    git(
+        dir,
+        &[
+            "show",
+            "--no-patch",
+            "--format=commit %H%nAuthor: %an <%ae>%nDate:   %ad%n%n%B",
+            hash,
+        ],
+        None,
+    )
+}
+
+/// `YYYY-MM-DD` of a time in seconds since the epoch, in UTC.
+fn format_date(time: i64) -> String {
+    // Days to civil date, after Howard Hinnant's algorithm
+    let z = time.div_euclid(86_400) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+/// Directories searched for `#include <x.h>` after those of `goto.include_path`.
+const SYSTEM_INCLUDE_PATH: [&str; 2] = ["/usr/local/include", "/usr/include"];
+
+/// The file a line of C or Rust refers to: the header of an `#include`,
+/// the file of a `mod foo;` declaration or the module of a `use crate::…`
+/// path, found the way the compiler would. `file` is the file the line is
+/// in, and `include_path` the directories searched for headers.
+pub fn target(
+    line: &str,
+    file: &Path,
+    include_path: &[PathBuf],
+) -> Option<Result<PathBuf, String>> {
+    let line = line.trim();
+    if let Some(header) = line.strip_prefix('#').map(str::trim_start) {
+        let name = header.strip_prefix("include")?.trim();
+        return Some(include_target(name, file, include_path));
+    }
+    let line = strip_visibility(line);
+    if let Some(name) = line.strip_prefix("mod ") {
+        let name = name.trim().strip_suffix(';')?.trim();
+        let dir = module_dir(file)?;
+        return Some(
+            module_file(&dir.join(name)).ok_or_else(|| format!("No file for module {}", name)),
+        );
+    }
+    let path = line.strip_prefix("use ")?;
+    let path = path.split(['{', ';', ' ']).next()?;
+    Some(use_target(path.trim_end_matches("::*"), file))
+}
+
+/// Strips `pub`, `pub(crate)` and the like from the start of an item.
+fn strip_visibility(line: &str) -> &str {
+    match line.strip_prefix("pub") {
+        Some(rest) if rest.starts_with('(') => rest
+            .split_once(')')
+            .map_or(line, |(_, rest)| rest.trim_start()),
+        Some(rest) if rest.starts_with(' ') => rest.trim_start(),
+        _ => line,
+    }
+}
+
+fn include_target(name: &str, file: &Path, include_path: &[PathBuf]) -> Result<PathBuf, String> {
+    let (name, local) = if let Some(name) = name.strip_prefix('"') {
+        (name.split('"').next().unwrap_or(name), true)
+    } else if let Some(name) = name.strip_prefix('<') {
+        (name.split('>').next().unwrap_or(name), false)
+    } else {
+        return Err(format!("Can't follow #include {}", name));
+    };
+    let dir = file.parent().filter(|_| local);
+    dir.into_iter()
+        .chain(include_path.iter().map(PathBuf::as_path))
+        .chain(SYSTEM_INCLUDE_PATH.iter().map(Path::new))
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| format!("{} not found in the include path", name))
+}
+
+/// The directory of the files of the submodules of the module in `file`.
+fn module_dir(file: &Path) -> Option<PathBuf> {
+    let dir = file.parent()?;
+    match file.file_name()?.to_str()? {
+        "main.rs" | "lib.rs" | "mod.rs" => Some(dir.to_path_buf()),
+        _ => Some(dir.join(file.file_stem()?)),
+    }
+}
+
+/// The file of the module whose submodules are in `dir`: `dir.rs`,
+/// `dir/mod.rs`, or for the crate root `lib.rs` or `main.rs` in `dir`.
+fn module_file(dir: &Path) -> Option<PathBuf> {
+    let root = dir
+        .parent()
+        .is_some_and(|crate_dir| crate_dir.join("Cargo.toml").is_file());
+    let mut candidates = vec![dir.with_extension("rs"), dir.join("mod.rs")];
+    if root {
+        candidates.extend([dir.join("lib.rs"), dir.join("main.rs")]);
+    }
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+/// The `src` directory of the crate `file` belongs to.
+fn crate_dir(file: &Path) -> Option<PathBuf> {
+    file.ancestors()
+        .skip(1)
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .map(|dir| dir.join("src"))
+}
+
+/// The file of the deepest module of `path` in this crate; the rest of
+/// the path names items in it.
+fn use_target(path: &str, file: &Path) -> Result<PathBuf, String> {
+    let not_found = || format!("{} is not in this crate", path);
+    let mut segments = path.split("::").filter(|segment| !segment.is_empty());
+    let mut dir = match segments.next() {
+        Some("crate") => crate_dir(file).ok_or_else(not_found)?,
+        Some("self") => module_dir(file).ok_or_else(not_found)?,
+        Some("super") => {
+            let mut dir = module_dir(file).ok_or_else(not_found)?;
+            dir.pop();
+            while let Some("super") = segments.clone().next() {
+                segments.next();
+                dir.pop();
+            }
+            dir
+        }
+        Some(name) => module_dir(file).ok_or_else(not_found)?.join(name),
+        None => return Err(not_found()),
+    };
+    let mut found = module_file(&dir).ok_or_else(not_found)?;
+    for segment in segments {
+        dir.push(segment);
+        match module_file(&dir) {
+            Some(file) => found = file,
+            None => break,
+        }
+    }
+    Ok(found)
+}
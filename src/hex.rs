@@ -0,0 +1,99 @@
+pub const BYTES_PER_ROW: usize = 16;
+
+/// Width of the offset column and the gap after it.
+const OFFSET_WIDTH: usize = 10;
+
+/// A buffer's content as raw bytes, edited by overwriting hex digits.
+pub struct HexView {
+    pub bytes: Vec<u8>,
+    /// Offset of the byte under the cursor
+    pub cursor: usize,
+    /// Whether the cursor is on the second (low) hex digit of the byte
+    pub low_nibble: bool,
+    pub row_offset: usize,
+}
+
+impl HexView {
+    pub fn new(bytes: Vec<u8>) -> HexView {
+        HexView {
+            bytes,
+            cursor: 0,
+            low_nibble: false,
+            row_offset: 0,
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.bytes.len().div_ceil(BYTES_PER_ROW)
+    }
+
+    /// Moves the cursor by `delta` bytes, staying within the content.
+    pub fn move_by(&mut self, delta: isize) {
+        let last = self.bytes.len().saturating_sub(1) as isize;
+        self.cursor = (self.cursor as isize + delta).max(0).min(last) as usize;
+        self.low_nibble = false;
+    }
+
+    /// Overwrites the hex digit under the cursor and advances to the next digit.
+    pub fn input(&mut self, c: char) -> Result<(), String> {
+        let digit = c
+            .to_digit(16)
+            .ok_or_else(|| format!("Not a hex digit: {}", c))? as u8;
+        let byte = match self.bytes.get_mut(self.cursor) {
+            Some(byte) => byte,
+            None => return Err("Buffer is empty".to_string()),
+        };
+        if self.low_nibble {
+            *byte = (*byte & 0xf0) | digit;
+            self.move_by(1);
+        } else {
+            *byte = (*byte & 0x0f) | (digit << 4);
+            self.low_nibble = true;
+        }
+        Ok(())
+    }
+
+    /// `offset  hex bytes  |printable chars|` for one row of 16 bytes.
+    pub fn render_row(&self, row: usize) -> String {
+        let start = row * BYTES_PER_ROW;
+        let end = (start + BYTES_PER_ROW).min(self.bytes.len());
+        let chunk = &self.bytes[start..end];
+        let mut line = format!("{:08x}  ", start);
+        for index in 0..BYTES_PER_ROW {
+            match chunk.get(index) {
+                Some(byte) => line.push_str(&format!("{:02x} ", byte)),
+                None => line.push_str("   "),
+            }
+        }
+        line.push(' ');
+        line.push('|');
+        for byte in chunk {
+            line.push(if byte.is_ascii_graphic() || *byte == b' ' {
+                char::from(*byte)
+            } else {
+                '.'
+            });
+        }
+        line.push('|');
+        line
+    }
+
+    /// Screen row (before scrolling) and column of the cursor.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        let column = self.cursor % BYTES_PER_ROW;
+        (
+            self.cursor / BYTES_PER_ROW,
+            OFFSET_WIDTH + column * 3 + self.low_nibble as usize,
+        )
+    }
+
+    pub fn scroll(&mut self, screen_rows: usize) {
+        let row = self.cursor / BYTES_PER_ROW;
+        if row < self.row_offset {
+            self.row_offset = row;
+        }
+        if row >= self.row_offset + screen_rows {
+            self.row_offset = row + 1 - screen_rows;
+        }
+    }
+}
@@ -1,51 +1,185 @@
-use crate::file_syntax::{FileSyntax, FileType, SyntaxFlags, SYNTAX_DB};
+use crate::file_syntax::{FileSyntax, FileType, SyntaxFlags, SyntaxKey, SYNTAX_DB};
+use crate::text_buffer::{GapBuffer, Lines};
+#[cfg(feature = "treesitter")]
+use crate::ts_highlight::TreeSitterHighlighter;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::Instant;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum HighlightColor {
     Normal,
     Number,
     String,
     Comment,
     MultilineComment,
+    /// Comments documenting the code, e.g. `///` in Rust
+    DocComment,
+    /// Markup in doc comments: `@param` tags, code in backticks, links and
+    /// Markdown headings
+    DocTag,
     Keyword1,
     Keyword2,
     Match,
+    Type,
+    Function,
+    Field,
+    Constant,
+    Preprocessor,
 }
 
+impl HighlightColor {
+    /// Name used for this kind in the `[colors]` section of the config.
+    pub fn from_name(name: &str) -> Option<HighlightColor> {
+        use HighlightColor::*;
+        Some(match name {
+            "normal" => Normal,
+            "number" => Number,
+            "string" => String,
+            "comment" => Comment,
+            "multiline_comment" => MultilineComment,
+            "doc_comment" => DocComment,
+            "doc_tag" => DocTag,
+            "keyword1" => Keyword1,
+            "keyword2" => Keyword2,
+            "match" => Match,
+            "type" => Type,
+            "function" => Function,
+            "field" => Field,
+            "constant" => Constant,
+            "preprocessor" => Preprocessor,
+            _ => return None,
+        })
+    }
+}
+
+/// Lines longer than this many bytes, e.g. in minified files, are only
+/// highlighted around the part on screen.
+pub const LONG_LINE: usize = 10_000;
+/// Number of characters highlighted at once in a long line.
+const LONG_LINE_WINDOW: usize = 2_000;
+
 pub struct Highlight {
     pub syntax: FileSyntax,
-    pub highlights: Vec<Vec<HighlightColor>>,
-    pub in_comment: Vec<bool>,
+    pub highlights: GapBuffer<Vec<HighlightColor>>,
+    /// The color of the block comment a row ends in, if it does: a
+    /// multiline or a doc comment
+    pub in_comment: GapBuffer<Option<HighlightColor>>,
+    /// Character index where `highlights` of a row starts; only long lines
+    /// start elsewhere than at 0.
+    starts: GapBuffer<usize>,
+    /// First row whose colors may be out of date: rows below the screen
+    /// affected by an edit, e.g. one opening a comment, are highlighted
+    /// again only when the editor is idle
+    pub stale_from: Option<usize>,
+    /// When set, rows are colored from a tree-sitter syntax tree instead of
+    /// the keyword based highlighter below.
+    #[cfg(feature = "treesitter")]
+    tree_sitter: Option<TreeSitterHighlighter>,
 }
 
 fn get_syntax(path: PathBuf) -> FileSyntax {
-    match SYNTAX_DB.get(path.extension().unwrap_or(std::ffi::OsStr::new(""))) {
+    // Exact filenames (Makefile, Dockerfile, ...) take precedence over extensions
+    let by_filename = path
+        .file_name()
+        .and_then(|name| SYNTAX_DB.get(&SyntaxKey::FileName(name)));
+    match by_filename.or_else(|| {
+        path.extension()
+            .and_then(|ext| SYNTAX_DB.get(&SyntaxKey::Extension(ext)))
+    }) {
         Some(syntax) => *syntax,
         None => FileSyntax::new(),
     }
 }
 
 impl Highlight {
-    pub fn new(s: &Vec<String>, path: PathBuf) -> Self {
-        let syntax = get_syntax(path);
+    pub fn new(s: &(impl Lines + ?Sized), path: PathBuf) -> Self {
+        Highlight::with_syntax(s, get_syntax(path))
+    }
+
+    pub fn with_syntax(s: &(impl Lines + ?Sized), syntax: FileSyntax) -> Self {
         let mut h = Highlight {
             syntax,
-            highlights: vec![],
-            in_comment: vec![],
+            highlights: GapBuffer::default(),
+            in_comment: GapBuffer::default(),
+            starts: vec![0; s.line_count()].into(),
+            stale_from: None,
+            #[cfg(feature = "treesitter")]
+            tree_sitter: TreeSitterHighlighter::for_syntax(syntax),
         };
-        for (index, line) in s.into_iter().enumerate() {
-            h.highlights.push(vec![]);
-            h.in_comment.push(false);
-            match h.line_to_highlight_color(&line, index) {
-                (row, _) => h.highlights[index] = row,
+        #[cfg(feature = "treesitter")]
+        {
+            if let Some(ts) = &mut h.tree_sitter {
+                h.highlights = ts.parse(s).into();
+                h.in_comment = vec![None; s.line_count()].into();
+                return h;
             }
         }
+        for (index, line) in s.all_lines().enumerate() {
+            h.highlights.push(vec![]);
+            h.in_comment.push(None);
+            let (row, _) = h.highlight_line(line, index);
+            h.highlights[index] = row;
+        }
         h
     }
 
-    pub fn update_row(&mut self, row_index: usize, line: &String) -> Option<usize> {
-        match self.line_to_highlight_color(line, row_index) {
+    /// Highlights a line, or for a long line only the window starting at `starts`.
+    fn highlight_line(
+        &mut self,
+        line: &str,
+        row_index: usize,
+    ) -> (Vec<HighlightColor>, Option<usize>) {
+        if line.len() <= LONG_LINE {
+            return self.line_to_highlight_color(line, row_index);
+        }
+        let from = line
+            .char_indices()
+            .nth(self.starts[row_index])
+            .map_or(line.len(), |(index, _)| index);
+        let rest = &line[from..];
+        let to = rest
+            .char_indices()
+            .nth(LONG_LINE_WINDOW)
+            .map_or(rest.len(), |(index, _)| index);
+        // The window doesn't tell how the line ends, so the comment state stays
+        let in_comment = self.in_comment[row_index];
+        let (row, _) = self.line_to_highlight_color(&rest[..to], row_index);
+        self.in_comment[row_index] = in_comment;
+        (row, None)
+    }
+
+    /// Moves the highlighted window of a long line so that it covers the
+    /// characters `from..to` about to be drawn.
+    pub fn ensure_visible(&mut self, row_index: usize, line: &str, from: usize, to: usize) {
+        #[cfg(feature = "treesitter")]
+        {
+            if self.tree_sitter.is_some() {
+                return;
+            }
+        }
+        if line.len() <= LONG_LINE || row_index >= self.highlights.len() {
+            return;
+        }
+        let start = self.starts[row_index];
+        let len = self.highlights[row_index].len();
+        if start <= from && (to <= start + len || len < LONG_LINE_WINDOW) {
+            return;
+        }
+        self.starts[row_index] = from.saturating_sub(LONG_LINE_WINDOW / 4);
+        self.highlights[row_index] = self.highlight_line(line, row_index).0;
+    }
+
+    pub fn update_row(&mut self, row_index: usize, line: &str) -> Option<usize> {
+        #[cfg(feature = "treesitter")]
+        {
+            if let Some(ts) = &mut self.tree_sitter {
+                let rows = ts.update_row(row_index, line);
+                self.apply_rows(rows);
+                return None;
+            }
+        }
+        match self.highlight_line(line, row_index) {
             (row, Some(need_to_update_index)) => {
                 self.highlights[row_index] = row;
                 Some(need_to_update_index)
@@ -57,64 +191,160 @@ impl Highlight {
         }
     }
 
+    /// Notes that the rows from `row_index` on may need highlighting again.
+    pub fn mark_stale(&mut self, row_index: usize) {
+        self.stale_from = Some(self.stale_from.map_or(row_index, |row| row.min(row_index)));
+    }
+
+    /// Highlights the stale rows before `end` again, stopping early at
+    /// `deadline`.
+    pub fn resync(&mut self, lines: &(impl Lines + ?Sized), end: usize, deadline: Option<Instant>) {
+        let mut row = match self.stale_from {
+            Some(row) => row,
+            None => return,
+        };
+        let len = lines.line_count().min(self.highlights.len());
+        while row < end.min(len) && deadline.is_none_or(|deadline| Instant::now() < deadline) {
+            self.update_row(row, lines.line(row));
+            row += 1;
+        }
+        self.stale_from = if row < len { Some(row) } else { None };
+    }
+
     pub fn match_row(&mut self, row_index: usize, from: usize, to: usize) {
-        let current_highlight = &mut self.highlights[row_index];
-        for col_index in from..to {
-            current_highlight[col_index] = HighlightColor::Match;
+        // Rows of a file still loading aren't highlighted yet
+        if row_index >= self.highlights.len() {
+            return;
+        }
+        let start = self.starts[row_index];
+        let (from, to) = (from.saturating_sub(start), to.saturating_sub(start));
+        for color in self.highlights[row_index].iter_mut().take(to).skip(from) {
+            *color = HighlightColor::Match;
         }
     }
 
-    pub fn insert_row(&mut self, row_index: usize, line: &String) -> Option<usize> {
-        self.highlights.insert(row_index, vec![]);
-        self.in_comment.push(false);
-        match self.line_to_highlight_color(line, row_index) {
-            (row, Some(need_to_update_index)) => {
-                self.highlights[row_index] = row;
-                Some(need_to_update_index)
+    /// Adds rows for `lines` put before the row `at`, highlighting them.
+    /// Returns the row after them if it needs to be highlighted again.
+    pub fn insert_rows(&mut self, at: usize, lines: &[&str]) -> Option<usize> {
+        #[cfg(feature = "treesitter")]
+        {
+            if self.tree_sitter.is_some() {
+                self.insert_tree_rows(at, lines);
+                return None;
             }
-            (row, None) => {
-                self.highlights[row_index] = row;
-                None
+        }
+        let count = lines.len();
+        if let Some(stale) = &mut self.stale_from {
+            if *stale >= at {
+                *stale += count;
             }
         }
+        self.highlights
+            .insert_rows(at, std::iter::repeat_n(vec![], count));
+        self.in_comment
+            .insert_rows(at, std::iter::repeat_n(None, count));
+        self.starts.insert_rows(at, std::iter::repeat_n(0, count));
+        let mut next = None;
+        for (index, line) in lines.iter().enumerate() {
+            let (row, need_to_update_index) = self.highlight_line(line, at + index);
+            self.highlights[at + index] = row;
+            next = need_to_update_index;
+        }
+        next
     }
 
-    pub fn remove_row(&mut self, row_index: usize) {
-        self.highlights.remove(row_index);
-        self.in_comment.remove(row_index);
+    pub fn remove_rows(&mut self, rows: Range<usize>) {
+        #[cfg(feature = "treesitter")]
+        {
+            if self.tree_sitter.is_some() {
+                self.remove_tree_rows(rows);
+                return;
+            }
+        }
+        if let Some(stale) = &mut self.stale_from {
+            if *stale >= rows.end {
+                *stale -= rows.len();
+            } else if *stale > rows.start {
+                *stale = rows.start;
+            }
+        }
+        self.highlights.remove_rows(rows.clone());
+        self.in_comment.remove_rows(rows.clone());
+        self.starts.remove_rows(rows);
+    }
+
+    #[cfg(feature = "treesitter")]
+    fn insert_tree_rows(&mut self, at: usize, lines: &[&str]) {
+        let count = lines.len();
+        if let Some(stale) = &mut self.stale_from {
+            if *stale >= at {
+                *stale += count;
+            }
+        }
+        self.highlights
+            .insert_rows(at, std::iter::repeat_n(vec![], count));
+        self.in_comment
+            .insert_rows(at, std::iter::repeat_n(None, count));
+        self.starts.insert_rows(at, std::iter::repeat_n(0, count));
+        if let Some(ts) = &mut self.tree_sitter {
+            let rows = ts.insert_rows(at, lines);
+            self.apply_rows(rows);
+        }
     }
 
-    pub fn color(&self, row_index: usize, col_index: usize) -> u8 {
-        match self.highlights.get(row_index) {
-            Some(row) => match row.get(col_index) {
-                Some(HighlightColor::Normal) => 37,
-                Some(HighlightColor::Number) => 31,
-                Some(HighlightColor::String) => 35,
-                Some(HighlightColor::Comment) => 36,
-                Some(HighlightColor::MultilineComment) => 36,
-                Some(HighlightColor::Keyword1) => 33,
-                Some(HighlightColor::Keyword2) => 32,
-                Some(HighlightColor::Match) => 34,
-                None => 39,
-            },
-            None => 39,
+    #[cfg(feature = "treesitter")]
+    fn remove_tree_rows(&mut self, rows: Range<usize>) {
+        if let Some(stale) = &mut self.stale_from {
+            if *stale >= rows.end {
+                *stale -= rows.len();
+            } else if *stale > rows.start {
+                *stale = rows.start;
+            }
+        }
+        self.highlights.remove_rows(rows.clone());
+        self.in_comment.remove_rows(rows.clone());
+        self.starts.remove_rows(rows.clone());
+        if let Some(ts) = &mut self.tree_sitter {
+            let rows = ts.remove_rows(rows);
+            self.apply_rows(rows);
         }
     }
 
+    #[cfg(feature = "treesitter")]
+    fn apply_rows(&mut self, rows: Vec<(usize, Vec<HighlightColor>)>) {
+        for (row_index, row) in rows {
+            if row_index < self.highlights.len() {
+                self.highlights[row_index] = row;
+            }
+        }
+    }
+
+    pub fn kind(&self, row_index: usize, col_index: usize) -> Option<HighlightColor> {
+        let start = self.starts.get(row_index).copied().unwrap_or(0);
+        self.highlights
+            .get(row_index)?
+            .get(col_index.checked_sub(start)?)
+            .copied()
+    }
+
     fn line_to_highlight_color(
         &mut self,
-        line: &String,
+        line: &str,
         row_index: usize,
     ) -> (Vec<HighlightColor>, Option<usize>) {
         let mut highlight_row = vec![];
         let mut prev_sep = true;
         let mut in_string: Option<char> = None;
-        let mut in_comment = row_index > 0 && self.in_comment[row_index - 1];
+        let mut in_comment = match row_index {
+            0 => None,
+            _ => self.in_comment[row_index - 1],
+        };
         let mut skip = 0;
+        let mut after_type_keyword = false;
         let scs = self.syntax.singleline_comment_start;
         let mcs = self.syntax.multiline_comment_start;
         let mce = self.syntax.multiline_comment_end;
-        for (ci, chr) in line.chars().enumerate() {
+        for (ci, chr) in line.char_indices() {
             if self.syntax.ftype == FileType::Undefined {
                 highlight_row.push(HighlightColor::Normal);
                 continue;
@@ -123,57 +353,73 @@ impl Highlight {
                 skip -= 1;
                 continue;
             }
-            let prev_hl = if ci == 0 {
-                HighlightColor::Normal
-            } else {
-                highlight_row[ci - 1]
-            };
+            let prev_hl = *highlight_row.last().unwrap_or(&HighlightColor::Normal);
 
             // Single line comment
-            if scs.len() > 0
+            if !scs.is_empty()
                 && in_string.is_none()
-                && !in_comment
-                && line.len() > scs.len()
-                && ci < line.len() - scs.len()
+                && in_comment.is_none()
+                && line[ci..].starts_with(scs)
             {
-                if &line[ci..ci + scs.len()] == scs {
-                    for _ in 0..line.len() - ci {
-                        highlight_row.push(HighlightColor::Comment);
-                    }
-                    break;
+                let color = match self.syntax.is_doc_comment(&line[ci..]) {
+                    true => HighlightColor::DocComment,
+                    false => HighlightColor::Comment,
+                };
+                for _ in line[ci..].chars() {
+                    highlight_row.push(color);
                 }
+                break;
             }
 
             // Multiline comment
-            if mcs.len() > 0 && mce.len() > 0 && in_string.is_none() {
-                if in_comment {
-                    highlight_row.push(HighlightColor::MultilineComment);
-                    if let Some(chars) = &line.get(ci..ci + mce.len()) {
-                        if chars == &mce {
+            if !mcs.is_empty() && !mce.is_empty() && in_string.is_none() {
+                if let Some(color) = in_comment {
+                    highlight_row.push(color);
+                    if let Some(chars) = line.get(ci..ci + mce.len()) {
+                        if chars == mce {
                             for _ in 1..mce.len() {
-                                highlight_row.push(HighlightColor::MultilineComment);
+                                highlight_row.push(color);
                             }
                             skip = mce.len() - 2;
-                            in_comment = false;
+                            in_comment = None;
                             prev_sep = true;
                             continue;
                         }
                     }
                     continue;
                 } else {
-                    if let Some(chars) = &line.get(ci..ci + mcs.len()) {
-                        if chars == &mcs {
+                    if let Some(chars) = line.get(ci..ci + mcs.len()) {
+                        if chars == mcs {
+                            let color = match self.syntax.is_doc_comment(&line[ci..]) {
+                                true => HighlightColor::DocComment,
+                                false => HighlightColor::MultilineComment,
+                            };
                             for _ in 0..mcs.len() {
-                                highlight_row.push(HighlightColor::MultilineComment);
+                                highlight_row.push(color);
                             }
                             skip = mcs.len() - 1;
-                            in_comment = true;
+                            in_comment = Some(color);
                             continue;
                         }
                     }
                 }
             }
 
+            // Preprocessor directives (#define) and attributes (#[derive(..)])
+            if (self.syntax.flags & SyntaxFlags::HL_PREPROCESSOR).bits() != 0
+                && in_string.is_none()
+                && chr == '#'
+                && line[..ci].trim().is_empty()
+            {
+                let len = preprocessor_len(&line[ci..]);
+                for _ in 0..len {
+                    highlight_row.push(HighlightColor::Preprocessor);
+                }
+                skip = len - 1;
+                prev_sep = true;
+                continue;
+            }
+
             // String
             if (self.syntax.flags & SyntaxFlags::HL_STRING).bits() != 0 {
                 match in_string {
@@ -201,14 +447,13 @@ impl Highlight {
             }
 
             // Number
-            if (self.syntax.flags & SyntaxFlags::HL_NUMBER).bits() != 0 {
-                if (chr.is_digit(10) && (prev_sep || prev_hl == HighlightColor::Number))
-                    || (chr == '.' && prev_hl == HighlightColor::Number)
-                {
-                    highlight_row.push(HighlightColor::Number);
-                    prev_sep = false;
-                    continue;
-                }
+            if (self.syntax.flags & SyntaxFlags::HL_NUMBER).bits() != 0
+                && ((chr.is_ascii_digit() && (prev_sep || prev_hl == HighlightColor::Number))
+                    || (chr == '.' && prev_hl == HighlightColor::Number))
+            {
+                highlight_row.push(HighlightColor::Number);
+                prev_sep = false;
+                continue;
             }
 
             // Keyword
@@ -216,16 +461,18 @@ impl Highlight {
                 for keyword in self.syntax.keywords {
                     let mut is_kw2 = false;
                     let mut kw = *keyword;
-                    if keyword.ends_with("|") {
+                    if keyword.ends_with('|') {
                         kw = &keyword[0..keyword.len() - 1];
                         is_kw2 = true;
                     }
                     if line[ci..].len() < kw.len() {
                         continue;
                     }
-                    if &line[ci..ci + kw.len()] == kw
-                        && (line[ci..].len() == kw.len()
-                            || is_separator(line.chars().nth(ci + kw.len()).unwrap()))
+                    if line[ci..].starts_with(kw)
+                        && line[ci + kw.len()..]
+                            .chars()
+                            .next()
+                            .is_none_or(is_separator)
                     {
                         for _ in 0..kw.len() {
                             if is_kw2 {
@@ -235,6 +482,7 @@ impl Highlight {
                             }
                         }
                         skip = kw.len() - 1;
+                        after_type_keyword = TYPE_KEYWORDS.contains(&kw);
                         break;
                     }
                 }
@@ -243,11 +491,43 @@ impl Highlight {
                     continue;
                 }
             }
+
+            // Identifiers: function calls, type names, fields and constants
+            if (self.syntax.flags & SyntaxFlags::HL_SEMANTIC).bits() != 0
+                && (chr.is_alphabetic() || chr == '_')
+                && !line[..ci]
+                    .chars()
+                    .next_back()
+                    .is_some_and(is_identifier_char)
+            {
+                let rest = &line[ci..];
+                let word = &rest[..rest.find(|c| !is_identifier_char(c)).unwrap_or(rest.len())];
+                let color = if rest[word.len()..].trim_start().starts_with('(') {
+                    HighlightColor::Function
+                } else if after_type_keyword && chr.is_uppercase() {
+                    HighlightColor::Type
+                } else if line[..ci].ends_with('.') {
+                    HighlightColor::Field
+                } else if is_constant(word) {
+                    HighlightColor::Constant
+                } else {
+                    HighlightColor::Normal
+                };
+                let len = word.chars().count();
+                for _ in 0..len {
+                    highlight_row.push(color);
+                }
+                skip = len - 1;
+                after_type_keyword = false;
+                prev_sep = false;
+                continue;
+            }
             highlight_row.push(HighlightColor::Normal);
             prev_sep = is_separator(chr);
         }
 
-        let current_in_comment = self.in_comment[row_index].clone();
+        highlight_doc_tags(line, &mut highlight_row, self.syntax.doc_comment_starts);
+        let current_in_comment = self.in_comment[row_index];
         if in_comment != current_in_comment {
             self.in_comment[row_index] = in_comment;
             (highlight_row, Some(row_index + 1))
@@ -257,6 +537,117 @@ impl Highlight {
     }
 }
 
+/// Colors the markup in the doc comments of `line` as `DocTag`: `@param`
+/// and such tags, code in backticks, links in brackets and Markdown
+/// headings. `colors` has one entry per character of the line.
+pub fn highlight_doc_tags(line: &str, colors: &mut [HighlightColor], starts: &[&str]) {
+    let chars: Vec<char> = line.chars().collect();
+    let len = colors.len().min(chars.len());
+    let mut from = 0;
+    while from < len {
+        if colors[from] != HighlightColor::DocComment {
+            from += 1;
+            continue;
+        }
+        let to = colors[from..]
+            .iter()
+            .position(|color| *color != HighlightColor::DocComment)
+            .map_or(len, |run| (from + run).min(len));
+        let text: String = chars[from..to].iter().collect();
+        // After the start of the comment, or the star of a line of a block
+        let leader = match starts.iter().find(|start| text.starts_with(**start)) {
+            Some(start) => start.chars().count(),
+            None => chars[from..to]
+                .iter()
+                .take_while(|c| matches!(c, ' ' | '\t' | '*'))
+                .count(),
+        };
+        let body = from + leader;
+        let body = body
+            + chars[body..to]
+                .iter()
+                .take_while(|c| c.is_whitespace())
+                .count();
+        let mut i = body;
+        // A heading, or the fence of a code block, is marked whole
+        if chars[body..to].starts_with(&['#']) || chars[body..to].starts_with(&['`'; 3]) {
+            colors[body..to].fill(HighlightColor::DocTag);
+            i = to;
+        }
+        while i < to {
+            let end = match chars[i] {
+                '@' if i == body || chars[i - 1].is_whitespace() => {
+                    let len = chars[i + 1..to]
+                        .iter()
+                        .take_while(|c| is_identifier_char(**c))
+                        .count();
+                    Some(i + 1 + len).filter(|_| len > 0)
+                }
+                '`' => chars[i + 1..to]
+                    .iter()
+                    .position(|c| *c == '`')
+                    .map(|len| i + len + 2),
+                '[' => chars[i + 1..to]
+                    .iter()
+                    .position(|c| *c == ']')
+                    .map(|len| i + len + 2),
+                _ => None,
+            };
+            match end {
+                Some(end) => {
+                    colors[i..end].fill(HighlightColor::DocTag);
+                    i = end;
+                }
+                None => i += 1,
+            }
+        }
+        from = to;
+    }
+}
+
+/// Keywords after which a capitalized identifier names a type.
+const TYPE_KEYWORDS: [&str; 7] = [
+    "struct", "enum", "union", "trait", "type", "class", "module",
+];
+
+fn is_identifier_char(chr: char) -> bool {
+    chr.is_alphanumeric() || chr == '_'
+}
+
+/// ALL_CAPS identifiers such as `MAX_SIZE` are treated as constants.
+fn is_constant(word: &str) -> bool {
+    word.chars().count() > 1
+        && word.chars().any(|c| c.is_uppercase())
+        && !word.chars().any(|c| c.is_lowercase())
+}
+
+/// Number of chars covered by the directive or attribute starting at `rest`:
+/// a whole bracketed `#[..]` / `#![..]` attribute, or `#` plus its directive name.
+fn preprocessor_len(rest: &str) -> usize {
+    if rest.starts_with("#[") || rest.starts_with("#![") {
+        let mut depth = 0;
+        for (i, chr) in rest.chars().enumerate() {
+            match chr {
+                '[' => depth += 1,
+                ']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return i + 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        return rest.chars().count();
+    }
+    let spaces = rest[1..].chars().take_while(|c| c.is_whitespace()).count();
+    let name = rest[1 + spaces..]
+        .chars()
+        .take_while(|c| c.is_alphabetic())
+        .count();
+    1 + spaces + name
+}
+
 fn is_separator(chr: char) -> bool {
-    return chr.is_whitespace() || chr == '\0' || ",.()+-/*=~%<>[];".contains(chr);
+    chr.is_whitespace() || chr == '\0' || ",.()+-/*=~%<>[];".contains(chr)
 }
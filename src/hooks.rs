@@ -0,0 +1,119 @@
+use crate::config::Config;
+use std::collections::HashMap;
+
+/// An editor event features can attach handlers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hook {
+    /// A file was opened into a buffer
+    BufferOpen,
+    /// The buffer is about to be written; handlers may still change it
+    BeforeSave,
+    AfterSave,
+    /// The file type, hex mode or read-only flag of the buffer changed
+    ModeChange,
+    /// No key was pressed for `editor.idle_delay` milliseconds
+    Idle,
+    /// The terminal of the editor lost the focus
+    FocusOut,
+    /// Another buffer or the terminal pane is about to get the keys
+    BufferLeave,
+}
+
+const HOOKS: [Hook; 7] = [
+    Hook::BufferOpen,
+    Hook::BeforeSave,
+    Hook::AfterSave,
+    Hook::ModeChange,
+    Hook::Idle,
+    Hook::FocusOut,
+    Hook::BufferLeave,
+];
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::BufferOpen => "buffer-open",
+            Hook::BeforeSave => "before-save",
+            Hook::AfterSave => "after-save",
+            Hook::ModeChange => "mode-change",
+            Hook::Idle => "idle",
+            Hook::FocusOut => "focus-out",
+            Hook::BufferLeave => "buffer-leave",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Hook> {
+        HOOKS.iter().copied().find(|hook| hook.name() == name)
+    }
+}
+
+/// Features of the editor which run from a hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// Removes spaces and tabs at the end of every line
+    StripTrailingWhitespace,
+    /// Pipes the buffer through the command of its file type in the
+    /// `[formatters]` section, e.g. `python = "black -q -"`, or rustfmt
+    /// for Rust
+    Format,
+    /// Saves a modified file without asking anything. Buffers without a
+    /// file of their own, remote, encrypted or read-only ones are left as
+    /// they are.
+    Autosave,
+}
+
+impl Builtin {
+    fn parse(name: &str) -> Option<Builtin> {
+        match name {
+            "strip-trailing-whitespace" => Some(Builtin::StripTrailingWhitespace),
+            "format" => Some(Builtin::Format),
+            "autosave" => Some(Builtin::Autosave),
+            _ => None,
+        }
+    }
+}
+
+/// Something run when a hook fires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handler {
+    Builtin(Builtin),
+    /// The plugin at this index in `Plugins::list`
+    Plugin(usize),
+    /// A function of the init script
+    #[cfg(feature = "scripting")]
+    Script(String),
+}
+
+/// The handlers of every hook, in the order they run: the built-in
+/// features of the `[hooks]` section of the config, e.g.
+/// `before-save = "strip-trailing-whitespace format"` and
+/// `idle = "autosave"`, then those of plugins and scripts. With
+/// `focus-out = "autosave"` and `buffer-leave = "autosave"` a buffer is
+/// saved when switching to another window or buffer.
+#[derive(Default)]
+pub struct Hooks {
+    handlers: HashMap<Hook, Vec<Handler>>,
+}
+
+impl Hooks {
+    pub fn from_config(config: &Config) -> Result<Hooks, String> {
+        let mut hooks = Hooks::default();
+        for (name, builtins) in config.section("hooks") {
+            let hook = Hook::parse(name).ok_or_else(|| format!("hooks: unknown hook {}", name))?;
+            for builtin in builtins.split_whitespace() {
+                let builtin = Builtin::parse(builtin)
+                    .ok_or_else(|| format!("hooks: unknown feature {}", builtin))?;
+                hooks.add(hook, Handler::Builtin(builtin));
+            }
+        }
+        Ok(hooks)
+    }
+
+    pub fn add(&mut self, hook: Hook, handler: Handler) {
+        self.handlers.entry(hook).or_default().push(handler);
+    }
+
+    pub fn handlers(&self, hook: Hook) -> Vec<Handler> {
+        self.handlers.get(&hook).cloned().unwrap_or_default()
+    }
+}
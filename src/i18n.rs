@@ -0,0 +1,221 @@
+use crate::config::Config;
+use std::sync::RwLock;
+
+/// Messages of the editor in Japanese, by their English text. `{}` stands
+/// for a part of the message which is kept as it is, e.g. a file name.
+const JAPANESE: [(&str, &str); 49] = [
+    (
+        "HELP: Ctrl-X Ctrl-S = save | Ctrl-Q = quit | Ctrl-S = search",
+        "ヘルプ: Ctrl-X Ctrl-S = 保存 | Ctrl-Q = 終了 | Ctrl-S = 検索",
+    ),
+    (
+        "Search {} (cancel: ESC, forward: C-s, backward: C-r, only matching lines: C-l)",
+        "検索: {} (キャンセル: ESC, 次: C-s, 前: C-r, 一致する行のみ: C-l)",
+    ),
+    (
+        "Open file {} (ESC to cancel)",
+        "ファイルを開く: {} (ESCでキャンセル)",
+    ),
+    (
+        "Save as {} (ESC to cancel)",
+        "名前を付けて保存: {} (ESCでキャンセル)",
+    ),
+    (
+        "Export to {} (ESC to cancel)",
+        "エクスポート先: {} (ESCでキャンセル)",
+    ),
+    (
+        "Rename to {} (ESC to cancel)",
+        "新しい名前: {} (ESCでキャンセル)",
+    ),
+    (
+        "New file {} (ESC to cancel)",
+        "新規ファイル: {} (ESCでキャンセル)",
+    ),
+    ("Occur {} (ESC to cancel)", "行を抽出: {} (ESCでキャンセル)"),
+    ("Replace: {} (ESC to cancel)", "置換: {} (ESCでキャンセル)"),
+    (
+        "Replace {} with: {} (ESC to cancel)",
+        "{} を置換する文字列: {} (ESCでキャンセル)",
+    ),
+    (
+        "Insert character (hex or name): {}",
+        "文字を挿入 (16進数または名前): {}",
+    ),
+    ("Save changes to {}?", "{} の変更を保存しますか?"),
+    (
+        "{} already exists. Overwrite it?",
+        "{} は既に存在します。上書きしますか?",
+    ),
+    ("Delete {}?", "{} を削除しますか?"),
+    (
+        "{} bytes written to disk",
+        "{} バイトをディスクに書き込みました",
+    ),
+    ("Save aborted", "保存を中止しました"),
+    ("Quit cancelled", "終了を取り消しました"),
+    ("Aborted", "中止しました"),
+    ("Rename aborted", "名前の変更を中止しました"),
+    ("Renamed to {}", "{} に名前を変更しました"),
+    ("Deleted {}", "{} を削除しました"),
+    ("Replaced {} occurrences", "{} 箇所を置換しました"),
+    ("Command Not Found", "コマンドが見つかりません"),
+    ("Unknown command: {}", "不明なコマンド: {}"),
+    ("Unknown option: {}", "不明なオプション: {}"),
+    ("Buffer has no file", "バッファにファイルがありません"),
+    ("Buffer is read-only", "バッファは読み取り専用です"),
+    ("Buffer is loading", "バッファを読み込み中です"),
+    ("Buffer is saving", "バッファを保存中です"),
+    ("Can't open {}: {}", "{} を開けません: {}"),
+    ("Can't read {}: {}", "{} を読めません: {}"),
+    ("Can't write {}: {}", "{} に書き込めません: {}"),
+    ("No such file: {}", "ファイルがありません: {}"),
+    (
+        "Warning: {} changed on disk since it was read",
+        "警告: {} は読み込んだ後にディスク上で変更されました",
+    ),
+    ("Config error: {}", "設定のエラー: {}"),
+    ("Config reloaded", "設定を再読み込みしました"),
+    ("Theme {}", "テーマ {}"),
+    ("No recent files", "最近使ったファイルはありません"),
+    (
+        "No keyboard macro defined",
+        "キーボードマクロが定義されていません",
+    ),
+    (
+        "No file name at point",
+        "カーソル位置にファイル名がありません",
+    ),
+    (
+        "q closes the preview, Enter opens the file",
+        "q でプレビューを閉じ、Enter でファイルを開きます",
+    ),
+    (
+        "Window too small; C-q quits",
+        "ウィンドウが小さすぎます。C-q で終了します",
+    ),
+    ("New file...", "新規ファイル..."),
+    ("Open file...", "ファイルを開く..."),
+    ("Recent files", "最近使ったファイル"),
+    (
+        "C-x C-s save | C-x C-r recent files",
+        "C-x C-s 保存 | C-x C-r 最近使ったファイル",
+    ),
+    (
+        "C-s search | M-% replace | C-_ undo",
+        "C-s 検索 | M-% 置換 | C-_ 元に戻す",
+    ),
+    (
+        "M-x run a command | C-q quit",
+        "M-x コマンドを実行 | C-q 終了",
+    ),
+    (
+        "Up/Down choose, Enter open, any other key to start editing",
+        "上下で選択、Enter で開く、他のキーで編集を開始",
+    ),
+];
+
+/// Translations of the messages of the editor into the language in use.
+/// Messages without one are shown in English.
+struct Catalog {
+    entries: Vec<(String, String)>,
+}
+
+static CATALOG: RwLock<Catalog> = RwLock::new(Catalog {
+    entries: Vec::new(),
+});
+
+/// Chooses the language of messages: `editor.language` in the config, or
+/// with `auto`, the default, the one `$LC_ALL`, `$LC_MESSAGES` or `$LANG`
+/// names. Besides English and the built-in Japanese, a language `xx` is
+/// read from `messages/xx.toml` next to the config file, whose lines
+/// look like `"Save aborted" = "..."`; for Japanese it adds to the
+/// built-in messages.
+pub fn set_language(config: &Config) -> Result<(), String> {
+    let language = match config.get("editor.language") {
+        Some("auto") | None => ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .map(|value| value.split(['_', '.']).next().unwrap_or("").to_string())
+            .unwrap_or_default(),
+        Some(language) => language.to_string(),
+    };
+    let mut entries: Vec<(String, String)> = vec![];
+    if language == "ja" {
+        entries.extend(
+            JAPANESE
+                .iter()
+                .map(|(english, japanese)| (english.to_string(), japanese.to_string())),
+        );
+    }
+    let path = Config::path().map(|path| {
+        path.with_file_name("messages")
+            .join(format!("{}.toml", language))
+    });
+    if let Some(path) = path.filter(|path| !language.is_empty() && path.is_file()) {
+        let messages = Config::load_from(&path)?;
+        let mut extra: Vec<(String, String)> = messages
+            .values()
+            .map(|(english, translation)| (english.to_string(), translation.to_string()))
+            .collect();
+        // Entries of the file come first, so that they win
+        extra.append(&mut entries);
+        entries = extra;
+    }
+    if let Ok(mut catalog) = CATALOG.write() {
+        catalog.entries = entries;
+    }
+    Ok(())
+}
+
+/// `text` in the language in use.
+pub fn tr(text: &str) -> String {
+    let catalog = match CATALOG.read() {
+        Ok(catalog) => catalog,
+        Err(_) => return text.to_string(),
+    };
+    catalog
+        .entries
+        .iter()
+        .find_map(|(english, translation)| {
+            let parts = matches(english, text)?;
+            let mut pieces = translation.split("{}");
+            let mut translated = pieces.next().unwrap_or("").to_string();
+            let mut parts = parts.into_iter();
+            for piece in pieces {
+                translated.push_str(parts.next().unwrap_or(""));
+                translated.push_str(piece);
+            }
+            Some(translated)
+        })
+        .unwrap_or_else(|| text.to_string())
+}
+
+/// The parts of `text` which the `{}` of `template` stand for, if it
+/// matches.
+fn matches<'a>(template: &str, text: &'a str) -> Option<Vec<&'a str>> {
+    let mut pieces = template.split("{}");
+    let first = pieces.next().unwrap_or("");
+    let mut rest = text.strip_prefix(first)?;
+    let pieces: Vec<&str> = pieces.collect();
+    let mut parts = vec![];
+    for (index, piece) in pieces.iter().enumerate() {
+        if index + 1 == pieces.len() {
+            // The last piece ends the text
+            parts.push(rest.strip_suffix(piece)?);
+            rest = "";
+        } else if piece.is_empty() {
+            return None;
+        } else {
+            let at = rest.find(piece)?;
+            parts.push(&rest[..at]);
+            rest = &rest[at + piece.len()..];
+        }
+    }
+    if rest.is_empty() {
+        Some(parts)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,40 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How long idle tasks run at a time before the editor looks for keys again.
+pub const IDLE_SLICE: Duration = Duration::from_millis(20);
+
+/// Work put off until no key is pressed for a while.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleTask {
+    /// Highlights the stale rows of the buffer with this id, see
+    /// `Highlight::stale_from`
+    Highlight(usize),
+    /// Reads `git blame` again for the buffer with this id, if it shows it
+    Blame(usize),
+    /// Writes the pending edits of the buffer with this id to its journal
+    Journal(usize),
+}
+
+/// The idle tasks in the order they run, each queued at most once.
+#[derive(Default)]
+pub struct IdleTasks {
+    queue: VecDeque<IdleTask>,
+}
+
+impl IdleTasks {
+    pub fn schedule(&mut self, task: IdleTask) {
+        if !self.queue.contains(&task) {
+            self.queue.push_back(task);
+        }
+    }
+
+    pub fn next(&mut self) -> Option<IdleTask> {
+        self.queue.pop_front()
+    }
+
+    /// Puts back a task which ran out of time, to go on with it first.
+    pub fn resume(&mut self, task: IdleTask) {
+        self.queue.push_front(task);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::file_syntax::FileType;
+use crate::text_buffer::Lines;
+
+/// Ruby keywords which open a block closed by `end`, when they start a line.
+const RUBY_OPENERS: [&str; 11] = [
+    "def", "class", "module", "if", "unless", "while", "until", "case", "begin", "for", "do",
+];
+
+/// Ruby keywords which go at the level of the `if`, `case` or `begin`
+/// they belong to, like `end`.
+const RUBY_MIDDLES: [&str; 5] = ["else", "elsif", "when", "rescue", "ensure"];
+
+/// The indentation of line `row` when it starts with `start`, e.g. a `}`
+/// or Ruby `end` just typed: that of the line opening the construct it
+/// closes. None when `start` closes nothing in a file of type `ftype`.
+pub fn closing_indent(
+    lines: &(impl Lines + ?Sized),
+    row: usize,
+    start: &str,
+    ftype: FileType,
+) -> Option<String> {
+    let opener = match (start, ftype) {
+        ("}", FileType::C | FileType::Rust | FileType::Ruby) => '{',
+        (")", FileType::C | FileType::Rust | FileType::Ruby) => '(',
+        ("]", FileType::C | FileType::Rust | FileType::Ruby | FileType::Toml) => '[',
+        (word, FileType::Ruby) if word == "end" || RUBY_MIDDLES.contains(&word) => {
+            return ruby_opener(lines, row).map(|line| indent_of(line).to_string())
+        }
+        _ => return None,
+    };
+    let closer = start.chars().next()?;
+    let mut depth = 0;
+    for line in lines.lines_in(0..row).rev() {
+        for c in line.chars().rev() {
+            if c == closer {
+                depth += 1;
+            } else if c == opener && depth > 0 {
+                depth -= 1;
+            } else if c == opener {
+                return Some(indent_of(line).to_string());
+            }
+        }
+    }
+    None
+}
+
+/// The line of the Ruby block the line `row` is in.
+fn ruby_opener(lines: &(impl Lines + ?Sized), row: usize) -> Option<&str> {
+    let mut depth = 0;
+    for line in lines.lines_in(0..row).rev() {
+        let trimmed = line.trim();
+        let first = trimmed
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or("");
+        let opens = RUBY_OPENERS.contains(&first)
+            || trimmed.ends_with(" do")
+            || (trimmed.contains(" do |") && trimmed.ends_with('|'));
+        // A block on one line, e.g. `def x; end`, is closed already
+        let closed = trimmed.ends_with(" end") || trimmed.ends_with(";end");
+        if first == "end" {
+            depth += 1;
+        } else if !opens || closed {
+            continue;
+        } else if depth > 0 {
+            depth -= 1;
+        } else {
+            return Some(line);
+        }
+    }
+    None
+}
+
+/// The spaces and tabs `line` starts with.
+pub fn indent_of(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
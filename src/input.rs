@@ -1,7 +1,18 @@
+use crate::command_log::CommandLog;
+use crate::cursor::RESET_CURSOR_STYLE;
+use crate::macros::{KeyBinding, Keys, Macros};
+use crate::message::MessageLevel;
+use crate::theme::Background;
 use crate::window::Window;
+use std::collections::VecDeque;
 use std::io::{self, stdin, Read, Write};
 use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
 
+/// How long the terminal may take to tell its background color.
+const BACKGROUND_QUERY_TIMEOUT: Duration = Duration::from_millis(300);
+
+#[derive(Debug, Clone)]
 pub enum CursorMoveDirection {
     Left,
     Right,
@@ -12,20 +23,147 @@ pub enum CursorMoveDirection {
     LineTop,
     LineBottom,
 }
+#[derive(Debug, Clone)]
 pub enum InputType {
     CursorMove(CursorMoveDirection),
     Char(u8),
+    /// A non-ASCII character, read as its UTF-8 bytes
+    Text(char),
     Del,
     Backspace,
     NoOp,
     ControlS,
     ControlR,
     ControlX,
+    ControlL,
+    ControlSpace,
+    Meta(u8),
+    /// The terminal window got or lost the focus (`CSI I` / `CSI O`)
+    FocusIn,
+    FocusOut,
+    Mouse(MouseEvent),
+    /// Text pasted by the terminal between `CSI 200~` and `CSI 201~`
+    Paste(String),
+}
+
+impl InputType {
+    /// The bytes a terminal sends for the key, for passing it on to a program.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        use CursorMoveDirection::*;
+        use InputType::*;
+        let sequence: &[u8] = match self {
+            CursorMove(Up) => b"\x1b[A",
+            CursorMove(Down) => b"\x1b[B",
+            CursorMove(Right) => b"\x1b[C",
+            CursorMove(Left) => b"\x1b[D",
+            CursorMove(LineTop) => b"\x1b[H",
+            CursorMove(LineBottom) => b"\x1b[F",
+            CursorMove(PageUp) => b"\x1b[5~",
+            CursorMove(PageDown) => b"\x1b[6~",
+            Char(c) => return vec![*c],
+            Text(c) => return c.to_string().into_bytes(),
+            Del => b"\x1b[3~",
+            Backspace => &[BACKSPACE],
+            ControlS => &[CTRL_S],
+            ControlR => &[CTRL_R],
+            ControlX => &[CTRL_X],
+            ControlL => &[CTRL_L],
+            ControlSpace => &[CTRL_SPACE],
+            Meta(c) => return vec![b'\x1b', *c],
+            Paste(text) => return text.clone().into_bytes(),
+            NoOp | FocusIn | FocusOut | Mouse(_) => b"",
+        };
+        sequence.to_vec()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+    /// Motion with no button held
+    None,
+}
+
+/// A mouse report in SGR format (`CSI < Cb ; Cx ; Cy M` or `m`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub button: MouseButton,
+    /// False for a button release
+    pub pressed: bool,
+    /// The pointer moved while the button was held
+    pub dragging: bool,
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+    /// Screen column and row, both from 0
+    pub column: usize,
+    pub row: usize,
+}
+
+impl MouseEvent {
+    /// Parses the part after `CSI <`, e.g. `0;12;5M`.
+    fn parse(report: &str) -> Option<MouseEvent> {
+        let pressed = report.ends_with('M');
+        let mut fields = report.strip_suffix(['M', 'm'])?.split(';');
+        let code: u32 = fields.next()?.parse().ok()?;
+        let column: usize = fields.next()?.parse().ok()?;
+        let row: usize = fields.next()?.parse().ok()?;
+        let button = match (code & 64 != 0, code & 3) {
+            (false, 0) => MouseButton::Left,
+            (false, 1) => MouseButton::Middle,
+            (false, 2) => MouseButton::Right,
+            (false, _) => MouseButton::None,
+            (true, 0) => MouseButton::WheelUp,
+            (true, 1) => MouseButton::WheelDown,
+            (true, 2) => MouseButton::WheelLeft,
+            (true, _) => MouseButton::WheelRight,
+        };
+        Some(MouseEvent {
+            button,
+            pressed,
+            dragging: code & 32 != 0,
+            shift: code & 4 != 0,
+            alt: code & 8 != 0,
+            ctrl: code & 16 != 0,
+            column: column.saturating_sub(1),
+            row: row.saturating_sub(1),
+        })
+    }
 }
 
 pub struct RawMode {
     pub stdin: io::Stdin,
-    pub orig: termios::Termios,
+    /// The terminal settings to restore, unless replaying a script
+    pub orig: Option<termios::Termios>,
+    pub macros: Macros,
+    /// Input of a macro being replayed, read before the terminal
+    pending: VecDeque<Vec<u8>>,
+    /// How many replays are running inside each other
+    replay_depth: usize,
+    /// Set when a replayed command quit the editor
+    stop_requested: bool,
+    /// Digits typed after C-u, the count for the next command
+    pub prefix_arg: Option<String>,
+    /// When a key was last read from the terminal
+    last_input: Instant,
+    /// The bytes of the key read last
+    last_key: Vec<u8>,
+    /// Whether the key read last came from the terminal
+    typed: bool,
+    /// The commands typed in the session
+    pub log: CommandLog,
+    /// Set while secrets are typed, e.g. a passphrase or the text of an
+    /// encrypted file, which are then neither logged nor recorded into a
+    /// macro
+    pub private: bool,
+    /// Whether the keys come from a script instead of the terminal
+    scripted: bool,
 }
 
 pub const CTRL_Q: u8 = b'q' & 0x1f;
@@ -35,16 +173,33 @@ pub const CTRL_F: u8 = b'f' & 0x1f;
 pub const CTRL_P: u8 = b'p' & 0x1f;
 pub const CTRL_A: u8 = b'a' & 0x1f;
 pub const CTRL_E: u8 = b'e' & 0x1f;
+pub const CTRL_G: u8 = b'g' & 0x1f;
 pub const CTRL_H: u8 = b'h' & 0x1f;
+pub const CTRL_K: u8 = b'k' & 0x1f;
 pub const CTRL_L: u8 = b'l' & 0x1f;
+pub const CTRL_U: u8 = b'u' & 0x1f;
 pub const CTRL_S: u8 = b's' & 0x1f;
+pub const CTRL_W: u8 = b'w' & 0x1f;
+pub const CTRL_Y: u8 = b'y' & 0x1f;
 pub const CTRL_X: u8 = b'x' & 0x1f;
 pub const CTRL_R: u8 = b'r' & 0x1f;
+pub const CTRL_SPACE: u8 = 0;
+/// C-_, also sent for C-/
+pub const CTRL_UNDERSCORE: u8 = 0x1f;
 pub const BACKSPACE: u8 = 127;
 
+/// Reports presses, releases and drags in SGR format.
+pub const ENABLE_MOUSE: &str = "\x1b[?1002h\x1b[?1006h";
+pub const DISABLE_MOUSE: &str = "\x1b[?1006l\x1b[?1002l";
+
+/// Replays inside replays, beyond which a macro is taken to call itself.
+const MAX_REPLAY_DEPTH: usize = 16;
+/// Most replays of a macro repeated up to the end of the file.
+const MAX_REPEAT: usize = 1_000_000;
+
 pub enum LoopStatus {
-    CONTINUE,
-    STOP,
+    Continue,
+    Stop,
 }
 
 impl RawMode {
@@ -63,18 +218,203 @@ impl RawMode {
         termios.c_lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
         termios.c_cc[VMIN] = 0;
         termios.c_cc[VTIME] = 1;
-        termios::tcsetattr(stdin_fd, TCSAFLUSH, &mut termios)?;
-        Ok(RawMode { stdin, orig })
+        termios::tcsetattr(stdin_fd, TCSAFLUSH, &termios)?;
+        // Report focus changes and mark pasted text
+        print!("\x1b[?1004h\x1b[?2004h");
+        io::stdout().flush()?;
+        let mut raw = RawMode::replaying(vec![]);
+        raw.orig = Some(orig);
+        raw.scripted = false;
+        Ok(raw)
+    }
+
+    /// Reads `keys`, e.g. of a script, instead of the terminal, which
+    /// is left as it is.
+    pub fn replaying(keys: Keys) -> RawMode {
+        RawMode {
+            stdin: stdin(),
+            orig: None,
+            macros: Macros::default(),
+            scripted: true,
+            pending: keys.into(),
+            replay_depth: 0,
+            stop_requested: false,
+            last_input: Instant::now(),
+            last_key: vec![],
+            typed: false,
+            log: CommandLog::default(),
+            private: false,
+            prefix_arg: None,
+        }
+    }
+
+    /// Whether every key of the script was read.
+    pub fn script_ended(&self) -> bool {
+        self.scripted && self.pending.is_empty()
+    }
+
+    /// Runs `f` with the terminal settings the editor was started with,
+    /// e.g. for a child process which reads from the terminal.
+    pub fn with_cooked_mode<T>(&mut self, f: impl FnOnce() -> T) -> io::Result<T> {
+        let orig = match &self.orig {
+            Some(orig) => orig,
+            None => return Ok(f()),
+        };
+        let fd = self.stdin.as_raw_fd();
+        let raw = termios::Termios::from_fd(fd)?;
+        termios::tcsetattr(fd, termios::TCSAFLUSH, orig)?;
+        let result = f();
+        termios::tcsetattr(fd, termios::TCSAFLUSH, &raw)?;
+        Ok(result)
+    }
+
+    /// Reads from the macro being replayed, or else from the terminal,
+    /// recording what the terminal sent while a macro is recorded, unless
+    /// it is private.
+    fn read_input(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(chunk) = self.pending.front_mut() {
+            let read = chunk.len().min(buf.len());
+            buf[..read].copy_from_slice(&chunk[..read]);
+            chunk.drain(..read);
+            if chunk.is_empty() {
+                self.pending.pop_front();
+            }
+            self.last_key.extend_from_slice(&buf[..read]);
+            return Ok(read);
+        }
+        if self.scripted {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The script ended in the middle of a command",
+            ));
+        }
+        let read = self.stdin.read(buf)?;
+        if read > 0 && !self.private {
+            self.macros.record(&buf[..read]);
+            self.log.record(&buf[..read]);
+        }
+        if read > 0 {
+            self.typed = true;
+            self.last_input = Instant::now();
+            self.last_key.extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+
+    /// The bytes the key `readkey` returned last was made of.
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    /// Asks the terminal for its background color with OSC 11. A device
+    /// attributes query follows, which every terminal answers, so that one
+    /// not knowing OSC 11 isn't waited for until the timeout.
+    pub fn query_background(&mut self) -> io::Result<Option<Background>> {
+        print!("\x1b]11;?\x1b\\\x1b[c");
+        io::stdout().flush()?;
+        let deadline = Instant::now() + BACKGROUND_QUERY_TIMEOUT;
+        let mut answer = vec![];
+        let mut byte = [0; 1];
+        while Instant::now() < deadline {
+            if self.stdin.read(&mut byte)? == 0 {
+                continue;
+            }
+            answer.push(byte[0]);
+            if byte[0] == b'c' && answer.windows(3).any(|w| w == b"\x1b[?") {
+                break;
+            }
+        }
+        Ok(Background::from_osc11(&String::from_utf8_lossy(&answer)))
+    }
+
+    /// How long no key was pressed.
+    pub fn idle_time(&self) -> Duration {
+        self.last_input.elapsed()
+    }
+
+    /// The count given with C-u: 4 without digits, 1 without C-u.
+    pub fn take_count(&mut self) -> usize {
+        match self.prefix_arg.take() {
+            None => 1,
+            Some(digits) if digits.is_empty() => 4,
+            Some(digits) => digits.parse().unwrap_or(1),
+        }
+    }
+
+    /// Runs `keys` as if typed, `count` times. A count of 0 repeats them
+    /// until the cursor stops moving forward, e.g. to apply a macro to
+    /// every line up to the end of the file.
+    pub fn replay(&mut self, window: &mut Window, keys: &Keys, count: usize) -> io::Result<()> {
+        if self.replay_depth >= MAX_REPLAY_DEPTH {
+            window.push_message(MessageLevel::Error, "Keyboard macro calls itself");
+            return Ok(());
+        }
+        self.replay_depth += 1;
+        // Input of an outer replay stays queued behind this one
+        let outer = self.pending.len();
+        let mut done = 0;
+        while count == 0 || done < count {
+            let before = (window.buffer.cy, window.buffer.cx);
+            for chunk in keys.iter().rev() {
+                self.pending.push_front(chunk.clone());
+            }
+            while self.pending.len() > outer {
+                window.refresh_screen()?;
+                if let LoopStatus::Stop = self.process_keypress(window)? {
+                    self.stop_requested = true;
+                }
+                if self.stop_requested {
+                    self.pending.truncate(outer);
+                }
+            }
+            done += 1;
+            let after = (window.buffer.cy, window.buffer.cx);
+            let last_row = window.buffer.content_buffer.len().saturating_sub(1);
+            if self.stop_requested
+                || (count == 0 && (after <= before || before.0 >= last_row || done >= MAX_REPEAT))
+            {
+                break;
+            }
+        }
+        self.replay_depth -= 1;
+        Ok(())
     }
 
     pub fn readkey(&mut self) -> io::Result<InputType> {
+        self.typed = false;
+        let key = self.read_key()?;
+        if self.typed && !self.private {
+            self.log.key(&key);
+        }
+        Ok(key)
+    }
+
+    fn read_key(&mut self) -> io::Result<InputType> {
         use CursorMoveDirection::*;
         use InputType::*;
         let mut seq: [u8; 4] = [0; 4];
-        if self.stdin.read(&mut seq)? > 0 {
+        self.last_key.clear();
+        let read = self.read_input(&mut seq)?;
+        if read > 0 {
+            if seq[0] >= 0xc0 {
+                if let Some(c) = std::str::from_utf8(&seq[..read])
+                    .ok()
+                    .and_then(|text| text.chars().next())
+                {
+                    return Ok(Text(c));
+                }
+            }
             if seq[0] == b'\x1b' {
+                if seq[1] == b'[' && seq[2] == b'<' {
+                    return self.read_mouse(&seq[3..read]);
+                }
+                if seq[1] == b'[' && seq[2].is_ascii_digit() && seq[3].is_ascii_digit() {
+                    return match self.read_number_key(&seq[2..read])?.as_str() {
+                        "200" => self.read_paste(),
+                        _ => Ok(Char(b'\x1b')),
+                    };
+                }
                 if seq[1] == b'[' {
-                    println!("seq: {}, {}", seq[2], seq[3]);
                     if seq[2] >= b'0' && seq[2] <= b'9' && seq[3] == b'~' {
                         return match seq[2] {
                             b'1' => Ok(CursorMove(LineTop)),    // Homeキー
@@ -94,6 +434,8 @@ impl RawMode {
                             b'D' => Ok(CursorMove(Left)),       // ←キー
                             b'H' => Ok(CursorMove(LineTop)),    // Homeキー
                             b'F' => Ok(CursorMove(LineBottom)), // Endキー
+                            b'I' => Ok(FocusIn),
+                            b'O' => Ok(FocusOut),
                             _ => Ok(Char(b'\x1b')),
                         };
                     }
@@ -103,6 +445,9 @@ impl RawMode {
                         b'F' => Ok(CursorMove(LineBottom)), // Endキー
                         _ => Ok(Char(b'\x1b')),
                     };
+                } else if seq[1] != 0 {
+                    // Alt+key is sent as ESC followed by the key
+                    return Ok(Meta(seq[1]));
                 }
                 return Ok(Char(b'\x1b'));
             } else {
@@ -116,7 +461,8 @@ impl RawMode {
                     CTRL_E => Ok(CursorMove(LineBottom)),
                     BACKSPACE => Ok(Backspace),
                     CTRL_H => Ok(Backspace),
-                    CTRL_L => unimplemented!(),
+                    CTRL_L => Ok(ControlL),
+                    CTRL_SPACE => Ok(ControlSpace),
                     CTRL_S => Ok(ControlS),
                     CTRL_R => Ok(ControlR),
                     c => Ok(Char(c)),
@@ -126,13 +472,137 @@ impl RawMode {
         Ok(NoOp)
     }
 
+    /// Reads the rest of a key sent as `CSI number ~`, of which `start` was
+    /// already read, and returns the number.
+    fn read_number_key(&mut self, start: &[u8]) -> io::Result<String> {
+        let mut number = start.to_vec();
+        let mut byte = [0; 1];
+        while !number.ends_with(b"~") && number.len() < 8 {
+            if self.read_input(&mut byte)? == 0 {
+                break;
+            }
+            number.push(byte[0]);
+        }
+        Ok(String::from_utf8_lossy(number.strip_suffix(b"~").unwrap_or(&number)).into_owned())
+    }
+
+    /// Reads bracketed paste content up to the closing `CSI 201~`.
+    fn read_paste(&mut self) -> io::Result<InputType> {
+        const END: &[u8] = b"\x1b[201~";
+        let mut text = vec![];
+        let mut chunk = [0; 4096];
+        let mut idle = 0;
+        while !text.ends_with(END) {
+            let read = self.read_input(&mut chunk)?;
+            if read == 0 {
+                // Give up when the terminal stops sending without closing the paste
+                idle += 1;
+                if idle > 20 {
+                    break;
+                }
+                continue;
+            }
+            idle = 0;
+            text.extend_from_slice(&chunk[..read]);
+        }
+        if text.ends_with(END) {
+            text.truncate(text.len() - END.len());
+        }
+        Ok(InputType::Paste(
+            String::from_utf8_lossy(&text).into_owned(),
+        ))
+    }
+
+    /// Asks the terminal for the primary selection with OSC 52. Returns None
+    /// when the terminal doesn't answer, as many don't allow reading it.
+    pub fn query_primary_selection(&mut self) -> io::Result<Option<String>> {
+        use base64::Engine;
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b]52;p;?\x07")?;
+        stdout.flush()?;
+        let mut reply = vec![];
+        let mut byte = [0; 1];
+        let mut idle = 0;
+        while !reply.ends_with(b"\x07") && !reply.ends_with(b"\x1b\\") {
+            if self.stdin.read(&mut byte)? == 0 {
+                idle += 1;
+                if idle > 3 {
+                    return Ok(None);
+                }
+                continue;
+            }
+            reply.push(byte[0]);
+        }
+        let reply = String::from_utf8_lossy(&reply);
+        let data = reply
+            .trim_end_matches(['\x07', '\x1b', '\\'])
+            .rsplit(';')
+            .next()
+            .unwrap_or("");
+        Ok(base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .ok()
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    /// Reads the rest of an SGR mouse report, of which `start` was already read.
+    fn read_mouse(&mut self, start: &[u8]) -> io::Result<InputType> {
+        let mut report = start.to_vec();
+        let mut byte = [0; 1];
+        while !report.ends_with(b"M") && !report.ends_with(b"m") && report.len() < 32 {
+            if self.read_input(&mut byte)? == 0 {
+                return Ok(InputType::NoOp);
+            }
+            report.push(byte[0]);
+        }
+        Ok(std::str::from_utf8(&report)
+            .ok()
+            .and_then(MouseEvent::parse)
+            .map_or(InputType::NoOp, InputType::Mouse))
+    }
+
     pub fn process_keypress(&mut self, window: &mut Window) -> io::Result<LoopStatus> {
-        use CursorMoveDirection::*;
         use InputType::*;
+        self.macros.command_started();
+        self.log.command_started();
+        self.private = window.buffer.encrypted.is_some();
         let input_type = self.readkey()?;
+        if window.too_small() && !matches!(input_type, Char(CTRL_Q)) {
+            return Ok(LoopStatus::Continue);
+        }
+        if window.terminal_focused {
+            if let NoOp = input_type {
+                return Ok(LoopStatus::Continue);
+            }
+            window.terminal_key(self, input_type)?;
+            return Ok(LoopStatus::Continue);
+        }
+        if window.preview.is_some() {
+            if let NoOp = input_type {
+                return Ok(LoopStatus::Continue);
+            }
+            window.preview_key(self, input_type)?;
+            return Ok(LoopStatus::Continue);
+        }
+        if window.start_screen_key(self, &input_type)? {
+            return Ok(LoopStatus::Continue);
+        }
+        if let (Some(digits), Char(c @ b'0'..=b'9')) = (&mut self.prefix_arg, &input_type) {
+            digits.push(char::from(*c));
+            window.editor_set_status_mssage(format!("C-u {}", digits));
+            return Ok(LoopStatus::Continue);
+        }
+        if !matches!(input_type, NoOp | FocusIn | FocusOut) {
+            window.kill_ring.next_key();
+        }
         match input_type {
             Char(b'\x1b') => {
-                return Ok(LoopStatus::CONTINUE);
+                return Ok(LoopStatus::Continue);
+            }
+            Char(CTRL_U) => {
+                self.prefix_arg = Some(String::new());
+                window.editor_set_status_mssage("C-u");
+                return Ok(LoopStatus::Continue);
             }
             ControlX => {
                 window.set_control_x(self)?;
@@ -141,7 +611,28 @@ impl RawMode {
                 window.break_line();
             }
             Char(CTRL_Q) => {
-                return window.quit();
+                return window.quit(self);
+            }
+            Char(CTRL_K) => {
+                window.kill_line();
+            }
+            Char(CTRL_W) => {
+                window.cut_region();
+            }
+            Meta(b'w') => {
+                window.copy_region();
+            }
+            Char(CTRL_Y) => {
+                window.yank();
+            }
+            Meta(b'y') => {
+                window.yank_pop();
+            }
+            Char(CTRL_UNDERSCORE) => {
+                window.undo();
+            }
+            Meta(b'_') => {
+                window.redo();
             }
             Backspace => {
                 window.delete_char();
@@ -150,8 +641,7 @@ impl RawMode {
                 window.move_cursor(d);
             }
             Del => {
-                window.move_cursor(Right);
-                window.delete_char();
+                window.delete_char_forward();
             }
             ControlS => {
                 window.editor_find(self, true)?;
@@ -159,21 +649,84 @@ impl RawMode {
             ControlR => {
                 window.editor_find(self, false)?;
             }
+            Meta(b's') => {
+                window.set_meta_s(self)?;
+            }
+            Meta(b'g') => {
+                window.set_meta_g(self)?;
+            }
+            Meta(b'%') => {
+                window.replace(self)?;
+            }
+            Meta(b'x') => {
+                window.execute_command(self)?;
+            }
+            Meta(b'/') => {
+                window.complete(self)?;
+            }
+            Meta(b'h') => {
+                window.hover();
+            }
+            Meta(c) => {
+                window.run_binding(self, KeyBinding::Meta(c))?;
+            }
+            ControlSpace => {
+                window.set_mark();
+            }
+            ControlL => {
+                // The screen is redrawn on every iteration anyway
+            }
+            Char(b'\t') if window.buffer.mark.is_some() => {
+                window.indent_region(false);
+            }
             Char(c) => {
                 window.insert_char(char::from(c));
                 io::stdout().flush()?;
             }
+            Text(c) => {
+                window.insert_char(c);
+            }
+            FocusIn => {
+                window.focus_in(self)?;
+                return Ok(LoopStatus::Continue);
+            }
+            FocusOut => {
+                window.focus_out();
+                return Ok(LoopStatus::Continue);
+            }
+            Mouse(event) if event.button == MouseButton::Middle && event.pressed => {
+                window.paste_primary(self, event)?;
+            }
+            Mouse(event) => {
+                window.mouse(event);
+            }
+            Paste(text) => {
+                window.kill_ring.push(&text);
+                window.paste(&text);
+            }
             NoOp => {
-                return Ok(LoopStatus::CONTINUE);
+                return Ok(LoopStatus::Continue);
             }
         }
-        window.quit_confirming = false;
-        Ok(LoopStatus::CONTINUE)
+        self.prefix_arg = None;
+        if self.stop_requested && self.replay_depth == 0 {
+            return Ok(LoopStatus::Stop);
+        }
+        Ok(LoopStatus::Continue)
     }
 }
 
 impl Drop for RawMode {
     fn drop(&mut self) {
-        termios::tcsetattr(self.stdin.as_raw_fd(), termios::TCSAFLUSH, &self.orig).unwrap();
+        let orig = match &self.orig {
+            Some(orig) => orig,
+            None => return,
+        };
+        print!(
+            "\x1b[?2004l\x1b[?1004l{}{}",
+            DISABLE_MOUSE, RESET_CURSOR_STYLE
+        );
+        let _ = io::stdout().flush();
+        termios::tcsetattr(self.stdin.as_raw_fd(), termios::TCSAFLUSH, orig).unwrap();
     }
 }
@@ -0,0 +1,171 @@
+use crate::state::state_dir;
+use crate::undo::Edit;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER: &str = "kilo_rust journal";
+
+/// The edits made to a file buffer since it was last saved, appended to a
+/// file in the state directory whenever the editor is idle. After a crash
+/// they are replayed on top of the saved file, which is cheap even for big
+/// buffers since only the edits are written.
+///
+/// The journal starts with the modification time of the file it applies
+/// to, then has one edit per line: `+` or `-`, the row and byte column,
+/// and the inserted or deleted text with `\` and line breaks escaped.
+#[derive(Default)]
+pub struct Journal {
+    /// Edits not written yet
+    pending: Vec<Edit>,
+    /// Whether the journal file was started since the last save
+    started: bool,
+    /// Set when the buffer changed in a way edits don't describe, e.g. in
+    /// hex mode; nothing is recorded until the next save
+    stopped: bool,
+}
+
+impl Journal {
+    /// Where the journal of `file` is kept: its path with `/` turned into
+    /// `!` and `!` doubled, like Emacs names auto-save files.
+    pub fn path(file: &Path) -> Option<PathBuf> {
+        let name = file
+            .display()
+            .to_string()
+            .replace('!', "!!")
+            .replace('/', "!");
+        Some(state_dir()?.join("journal").join(name))
+    }
+
+    /// Notes an edit, returning whether it is to be written.
+    pub fn record(&mut self, edit: Edit) -> bool {
+        if !self.stopped {
+            self.pending.push(edit);
+        }
+        !self.stopped
+    }
+
+    /// Appends the pending edits to the journal of `file`, starting it for
+    /// the version of the file last modified at `mtime` if needed.
+    pub fn flush(&mut self, file: &Path, mtime: Option<SystemTime>) -> io::Result<()> {
+        let path = match Journal::path(file) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        if !self.started {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            text.push_str(&format!("{}\t{}\n", HEADER, mtime_nanos(mtime)));
+        }
+        for edit in &self.pending {
+            let (sign, (row, col), edited) = match edit {
+                Edit::Insert { at, text } => ('+', at, text),
+                Edit::Delete { at, text } => ('-', at, text),
+            };
+            text.push_str(&format!("{}\t{}\t{}\t{}\n", sign, row, col, escape(edited)));
+        }
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(self.started)
+            .truncate(!self.started)
+            .open(&path)?;
+        journal.write_all(text.as_bytes())?;
+        journal.sync_data()?;
+        self.started = true;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Forgets the edits, e.g. once they are saved, and removes the journal of `file`.
+    pub fn discard(&mut self, file: &Path) {
+        self.pending.clear();
+        self.started = false;
+        self.stopped = false;
+        if let Some(path) = Journal::path(file) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Removes the journal of `file` and records nothing until `discard`.
+    pub fn stop(&mut self, file: &Path) {
+        self.discard(file);
+        self.stopped = true;
+    }
+
+    /// The edits left in the journal of `file` by an earlier session, if
+    /// any. A journal for another version of the file is an error.
+    pub fn recover(file: &Path) -> Result<Option<Vec<Edit>>, String> {
+        let text = match Journal::path(file).map(fs::read_to_string) {
+            Some(Ok(text)) => text,
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("Can't read the journal: {}", e))
+            }
+            _ => return Ok(None),
+        };
+        let mut lines = text.lines();
+        let mtime = fs::metadata(file).and_then(|m| m.modified()).ok();
+        match lines.next().and_then(|line| line.split_once('\t')) {
+            Some((HEADER, nanos)) if nanos == mtime_nanos(mtime) => {}
+            Some((HEADER, _)) => {
+                return Err("The file changed since the journal was written".to_string())
+            }
+            _ => return Err("Not a journal".to_string()),
+        }
+        let edits = lines
+            .map(|line| parse_edit(line).ok_or_else(|| format!("Bad journal entry: {}", line)))
+            .collect::<Result<Vec<Edit>, String>>()?;
+        Ok(Some(edits).filter(|edits| !edits.is_empty()))
+    }
+}
+
+fn mtime_nanos(mtime: Option<SystemTime>) -> String {
+    match mtime.and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok()) {
+        Some(since) => since.as_nanos().to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn parse_edit(line: &str) -> Option<Edit> {
+    let mut fields = line.splitn(4, '\t');
+    let sign = fields.next()?;
+    let at = (fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+    let text = unescape(fields.next()?);
+    match sign {
+        "+" => Some(Edit::Insert { at, text }),
+        "-" => Some(Edit::Delete { at, text }),
+        _ => None,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                result.push('\n');
+                chars.next();
+            }
+            ('\\', Some('r')) => {
+                result.push('\r');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                result.push('\\');
+                chars.next();
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
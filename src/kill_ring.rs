@@ -0,0 +1,82 @@
+use crate::undo::Position;
+use std::collections::VecDeque;
+
+/// Entries kept in the kill ring; older ones are dropped.
+const KILL_RING_SIZE: usize = 60;
+
+/// What a key did with the kill ring, which the next key may build on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillAction {
+    /// Killed text, to which text killed next is added
+    Killed,
+    /// Pasted the entry `index` from `start` to `end`, which M-y replaces
+    /// with the entry after it
+    Yanked {
+        start: Position,
+        end: Position,
+        index: usize,
+    },
+}
+
+/// Text copied or pasted during the session, newest first, so that an
+/// older snippet can be inserted again after something else was copied.
+/// Pasting the same text again moves it to the front rather than adding
+/// it twice.
+#[derive(Default)]
+pub struct KillRing {
+    entries: VecDeque<String>,
+    /// What the key before the current one did
+    last: Option<KillAction>,
+    /// What the current key did
+    current: Option<KillAction>,
+}
+
+impl KillRing {
+    pub fn push(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.entries.retain(|entry| entry != text);
+        self.entries.push_front(text.to_string());
+        self.entries.truncate(KILL_RING_SIZE);
+    }
+
+    /// Adds killed text, to the newest entry if the key before killed
+    /// text too, so that a few C-k in a row are yanked back at once.
+    pub fn kill(&mut self, text: &str) {
+        match (self.last, self.entries.front_mut()) {
+            (Some(KillAction::Killed), Some(front)) => front.push_str(text),
+            _ => self.push(text),
+        }
+        self.current = Some(KillAction::Killed);
+    }
+
+    /// Called before each key, which starts with nothing done.
+    pub fn next_key(&mut self) {
+        self.last = self.current.take();
+    }
+
+    pub fn last_action(&self) -> Option<KillAction> {
+        self.last
+    }
+
+    pub fn set_action(&mut self, action: KillAction) {
+        self.current = Some(action);
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,170 @@
+use once_cell::sync::Lazy;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// Lock files created by this process, removed again on exit or panic.
+static HELD_LOCKS: Lazy<Mutex<Vec<PathBuf>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Who holds the lock of a file, as written in the lock file (`user@host.pid`,
+/// which Emacs follows with `:boot`).
+#[derive(Debug, PartialEq, Eq)]
+pub struct LockOwner {
+    pub user: String,
+    pub host: String,
+    pub pid: u32,
+}
+
+impl LockOwner {
+    fn current() -> LockOwner {
+        LockOwner {
+            user: std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()),
+            host: hostname(),
+            pid: std::process::id(),
+        }
+    }
+
+    fn parse(text: &str) -> Option<LockOwner> {
+        let text = text.trim();
+        let text = text.split_once(':').map_or(text, |(owner, _boot)| owner);
+        let (user, rest) = text.split_once('@')?;
+        let (host, pid) = rest.rsplit_once('.')?;
+        Some(LockOwner {
+            user: user.to_string(),
+            host: host.to_string(),
+            pid: pid.parse().ok()?,
+        })
+    }
+
+    fn to_lock_text(&self) -> String {
+        format!("{}@{}.{}", self.user, self.host, self.pid)
+    }
+
+    fn is_current(&self) -> bool {
+        *self == LockOwner::current()
+    }
+
+    /// A lock left behind by a process of this host which no longer runs.
+    fn is_stale(&self) -> bool {
+        if self.host != hostname() {
+            return false;
+        }
+        let alive = unsafe { libc::kill(self.pid as libc::pid_t, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        !alive
+    }
+}
+
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}@{} (pid {})", self.user, self.host, self.pid)
+    }
+}
+
+/// The lock file of `path`: `.#name` in the same directory, as Emacs does.
+fn lock_path(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_string_lossy();
+    Some(path.with_file_name(format!(".#{}", name)))
+}
+
+/// The text of the lock file `lock`: the target of the dangling symlink
+/// Emacs makes, or the contents of the file this editor writes.
+fn read_lock(lock: &Path) -> Option<String> {
+    match fs::read_link(lock) {
+        Ok(target) => Some(target.to_string_lossy().into_owned()),
+        Err(_) => fs::read_to_string(lock).ok(),
+    }
+}
+
+/// The other live process holding the lock of `path`, if any.
+pub fn owner(path: &Path) -> Option<LockOwner> {
+    let text = read_lock(&lock_path(path)?)?;
+    let owner = LockOwner::parse(&text)?;
+    if owner.is_current() || owner.is_stale() {
+        None
+    } else {
+        Some(owner)
+    }
+}
+
+/// Locks `path` for this process. Fails with the owner if another process holds the lock.
+///
+/// The lock file is created with `create_new`, so of two processes
+/// locking at once only one gets it. A stale lock is removed and tried
+/// again; a lock which cannot be read is never removed, as it may be being
+/// written or belong to another program.
+pub fn acquire(path: &Path) -> Result<(), String> {
+    let lock = match lock_path(path) {
+        Some(lock) => lock,
+        None => return Ok(()),
+    };
+    let mut tries = 2;
+    loop {
+        let created = OpenOptions::new().write(true).create_new(true).open(&lock);
+        match created {
+            Ok(mut file) => {
+                file.write_all(LockOwner::current().to_lock_text().as_bytes())
+                    .map_err(|e| e.to_string())?;
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let text = read_lock(&lock);
+                match text.as_deref().and_then(LockOwner::parse) {
+                    Some(owner) if owner.is_current() => break,
+                    Some(owner) if !owner.is_stale() => {
+                        return Err(format!("locked by {}", owner));
+                    }
+                    _ if tries == 0 => return Err("locked by another process".to_string()),
+                    // Being written by its owner right now, so wait for it once
+                    None if tries == 2 => thread::sleep(Duration::from_millis(10)),
+                    None => return Err("locked by another process".to_string()),
+                    // Stale, unless another process replaced it since it was read
+                    Some(_) => {
+                        if read_lock(&lock) == text {
+                            let _ = fs::remove_file(&lock);
+                        }
+                    }
+                }
+                tries -= 1;
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let mut held = HELD_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    if !held.contains(&lock) {
+        held.push(lock);
+    }
+    Ok(())
+}
+
+pub fn release(path: &Path) {
+    let lock = match lock_path(path) {
+        Some(lock) => lock,
+        None => return,
+    };
+    let mut held = HELD_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(index) = held.iter().position(|l| *l == lock) {
+        let _ = fs::remove_file(held.remove(index));
+    }
+}
+
+/// Removes every lock this process holds. Called on exit and from the panic hook.
+pub fn release_all() {
+    let mut held = HELD_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    for lock in held.drain(..) {
+        let _ = fs::remove_file(lock);
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
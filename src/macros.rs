@@ -0,0 +1,187 @@
+use crate::config::Config;
+use crate::state::state_dir;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// A keyboard macro: the input read while it was recorded, in the chunks
+/// it was read in, so that keys split the same way when it is replayed.
+pub type Keys = Vec<Vec<u8>>;
+
+/// A key a named macro can be bound to in the `[keys]` section of the
+/// config, e.g. `"M-m" = "fix-line"` or `"C-x m" = "fix-line"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyBinding {
+    Meta(u8),
+    ControlX(u8),
+}
+
+impl KeyBinding {
+    pub fn parse(key: &str) -> Option<KeyBinding> {
+        let (binding, rest): (fn(u8) -> KeyBinding, _) = if let Some(rest) = key.strip_prefix("M-")
+        {
+            (KeyBinding::Meta, rest)
+        } else {
+            (KeyBinding::ControlX, key.strip_prefix("C-x ")?)
+        };
+        match rest.as_bytes() {
+            [c] if c.is_ascii_graphic() => Some(binding(*c)),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyBinding::Meta(c) => write!(f, "M-{}", char::from(*c)),
+            KeyBinding::ControlX(c) => write!(f, "C-x {}", char::from(*c)),
+        }
+    }
+}
+
+/// The recorded and named keyboard macros.
+#[derive(Default)]
+pub struct Macros {
+    recording: Option<Keys>,
+    /// Length of the recording when the current command started
+    command_start: usize,
+    /// The macro recorded last, replayed with C-x e
+    pub last: Option<Keys>,
+    named: BTreeMap<String, Keys>,
+    bindings: HashMap<KeyBinding, String>,
+}
+
+impl Macros {
+    fn path() -> Option<PathBuf> {
+        Some(state_dir()?.join("macros"))
+    }
+
+    /// Reads the named macros saved in earlier sessions and the key bindings of the config.
+    pub fn load(config: &Config) -> Result<Macros, String> {
+        let text = Macros::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        let named = text
+            .lines()
+            .filter_map(|line| {
+                let (name, keys) = line.split_once('\t')?;
+                Some((name.to_string(), decode(keys)?))
+            })
+            .collect();
+        Ok(Macros {
+            named,
+            bindings: Macros::bindings_from_config(config)?,
+            ..Macros::default()
+        })
+    }
+
+    /// The macros bound to keys in the `[keys]` section of `config`.
+    pub fn bindings_from_config(config: &Config) -> Result<HashMap<KeyBinding, String>, String> {
+        let mut bindings = HashMap::new();
+        for (key, name) in config.section("keys") {
+            let binding =
+                KeyBinding::parse(key).ok_or_else(|| format!("keys: invalid key `{}`", key))?;
+            bindings.insert(binding, name.to_string());
+        }
+        Ok(bindings)
+    }
+
+    pub fn set_bindings(&mut self, bindings: HashMap<KeyBinding, String>) {
+        self.bindings = bindings;
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match Macros::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::File::create(path)?;
+        for (name, keys) in &self.named {
+            writeln!(file, "{}\t{}", name, encode(keys))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(vec![]);
+        self.command_start = 0;
+    }
+
+    /// Ends the recording, leaving out the command which ended it. Returns
+    /// false if nothing was recorded.
+    pub fn stop_recording(&mut self) -> bool {
+        match self.recording.take() {
+            Some(mut keys) => {
+                keys.truncate(self.command_start);
+                if keys.is_empty() {
+                    return false;
+                }
+                self.last = Some(keys);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the start of a command, which `stop_recording` cuts off.
+    pub fn command_started(&mut self) {
+        if let Some(keys) = &self.recording {
+            self.command_start = keys.len();
+        }
+    }
+
+    pub fn record(&mut self, chunk: &[u8]) {
+        if let Some(keys) = &mut self.recording {
+            keys.push(chunk.to_vec());
+        }
+    }
+
+    /// Saves the last recorded macro under `name` for M-x and key bindings.
+    pub fn name_last(&mut self, name: &str) -> Result<(), String> {
+        let keys = self.last.clone().ok_or("No keyboard macro defined")?;
+        self.named.insert(name.to_string(), keys);
+        self.save().map_err(|e| format!("Can't save macros: {}", e))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Keys> {
+        self.named.get(name)
+    }
+
+    /// The name of the macro bound to `key`.
+    pub fn bound_to(&self, key: KeyBinding) -> Option<&str> {
+        self.bindings.get(&key).map(|name| name.as_str())
+    }
+}
+
+/// Chunks as hex bytes, the chunks separated by spaces.
+fn encode(keys: &Keys) -> String {
+    keys.iter()
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn decode(text: &str) -> Option<Keys> {
+    text.split(' ')
+        .map(|chunk| {
+            (0..chunk.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(chunk.get(i..i + 2)?, 16).ok())
+                .collect()
+        })
+        .collect()
+}
@@ -1,31 +1,499 @@
 #[macro_use]
 extern crate bitflags;
 
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+mod abbrev;
+mod backup;
+mod batch;
+mod buffer;
+mod cargo_check;
+mod command_log;
+mod completion;
+mod config;
+mod crypt;
+mod cursor;
+mod describe;
+mod digraph;
+mod export;
 mod file_syntax;
+mod fileio;
+mod git;
+mod goto;
+mod hex;
 mod highlight;
+mod hooks;
+mod i18n;
+mod idle;
+mod indent;
 mod input;
+mod journal;
+mod kill_ring;
+mod lock;
+mod macros;
+mod message;
+mod peers;
+mod picker;
+mod plugin;
+mod preview;
+mod remote;
+mod runner;
+#[cfg(feature = "scripting")]
+mod script;
+mod start;
+mod state;
+#[cfg(feature = "sync")]
+mod sync;
+mod tags;
+mod terminal;
+mod text_buffer;
+mod theme;
+#[cfg(feature = "treesitter")]
+mod ts_highlight;
+mod undo;
 mod window;
+use crate::abbrev::Abbrevs;
+use crate::buffer::BufferOptions;
+use crate::config::Config;
+use crate::cursor::CursorStyle;
+use crate::digraph::Digraphs;
+use crate::hooks::Hooks;
 use crate::input::*;
+use crate::macros::Macros;
+use crate::message::MessageLevel;
+use crate::plugin::Plugins;
+use crate::remote::RemotePath;
+use crate::start::StartScreen;
+use crate::theme::Theme;
 use crate::window::*;
 
-fn main() -> io::Result<()> {
-    let mut raw = RawMode::new()?;
-    let mut window = Window::new(&mut raw.stdin)?;
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() >= 2 {
-        window.open_file(args[1].to_string())?;
+/// Columns and rows of the screen a script is replayed on, which decide
+/// e.g. how far PageDown moves.
+const SCRIPT_SCREEN_SIZE: (u16, u16) = (80, 24);
+
+/// Most bytes read from stdin or a named pipe, so that e.g. `yes | kilo_rust`
+/// doesn't fill the memory.
+const MAX_PIPED_BYTES: usize = 256 * 1024 * 1024;
+
+/// How long a named pipe given as the file may take to be written to the
+/// end before the editor refuses it, like other FIFOs.
+const PIPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings of a `--clean` start: the defaults, without anything restored
+/// from earlier sessions.
+const CLEAN_CONFIG: &str = "
+[editor]
+remember_cursor_position = false
+[start_screen]
+shown = false
+";
+
+/// What the editor is set up with before it starts: the config and what
+/// is read from it. A setting which is wrong in the config is left at its
+/// default, and the error told.
+struct Settings {
+    config: Config,
+    theme: Theme,
+    digraphs: Digraphs,
+    abbrevs: Abbrevs,
+    cursor_style: CursorStyle,
+    macros: Macros,
+    hooks: Hooks,
+    /// The last error found in the config
+    error: Option<String>,
+    warning: Option<String>,
+}
+
+impl Settings {
+    /// Reads the user config and the project config of `project_dir`.
+    fn load(project_dir: &Path) -> Settings {
+        let mut error = None;
+        let mut warning = None;
+        let config = match Config::load_for(project_dir) {
+            Ok((config, project_warning)) => {
+                warning = project_warning;
+                config
+            }
+            Err(e) => {
+                error = Some(e);
+                Config::default()
+            }
+        };
+        let theme = Theme::from_config(&config, None).unwrap_or_else(|e| {
+            error = Some(e);
+            Theme::default()
+        });
+        let digraphs = Digraphs::from_config(&config).unwrap_or_else(|e| {
+            error = Some(e);
+            Digraphs::default()
+        });
+        let abbrevs = Abbrevs::from_config(&config).unwrap_or_else(|e| {
+            error = Some(e);
+            Abbrevs::default()
+        });
+        let cursor_style = CursorStyle::from_config(&config).unwrap_or_else(|e| {
+            error = Some(e);
+            CursorStyle::default()
+        });
+        let macros = Macros::load(&config).unwrap_or_else(|e| {
+            error = Some(e);
+            Macros::default()
+        });
+        let hooks = Hooks::from_config(&config).unwrap_or_else(|e| {
+            error = Some(e);
+            Hooks::default()
+        });
+        if let Err(e) = i18n::set_language(&config) {
+            error = Some(e);
+        }
+        Settings {
+            config,
+            theme,
+            digraphs,
+            abbrevs,
+            cursor_style,
+            macros,
+            hooks,
+            error,
+            warning,
+        }
     }
 
-    window.editor_set_status_mssage("HELP: Ctrl-X Ctrl-S = save | Ctrl-Q = quit | Ctrl-S = search");
+    /// The defaults, for telling whether a problem comes from the config.
+    fn clean() -> Settings {
+        let config = Config::parse(CLEAN_CONFIG).unwrap_or_default();
+        let _ = i18n::set_language(&config);
+        Settings {
+            config,
+            theme: Theme::default(),
+            digraphs: Digraphs::default(),
+            abbrevs: Abbrevs::default(),
+            cursor_style: CursorStyle::default(),
+            macros: Macros::default(),
+            hooks: Hooks::default(),
+            error: None,
+            warning: None,
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut args: Vec<String> = std::env::args().collect();
+    let clean = take_clean_flag(&mut args);
+    let title = take_title(&mut args);
+    let script = take_script(&mut args).map(|path| {
+        let keys = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| command_log::parse_script(&text));
+        keys.unwrap_or_else(|e| {
+            eprintln!("Can't read the script {}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    let scripted = script.is_some();
+    let file = match args.get(1).map(String::as_str) {
+        Some("--cat" | "--html" | "--batch") => args.get(2),
+        _ => args.get(1),
+    };
+    let project_dir = project_dir(file);
+    let settings = if clean {
+        Settings::clean()
+    } else {
+        Settings::load(&project_dir)
+    };
+    let Settings {
+        config,
+        mut theme,
+        digraphs,
+        abbrevs,
+        cursor_style,
+        macros,
+        hooks,
+        error: config_error,
+        warning: config_warning,
+    } = settings;
+    if args.len() >= 3 && args[1] == "--batch" {
+        if let Some(e) = config_error {
+            eprintln!("Config error: {}", e);
+        }
+        if let Err(e) = batch::run(&args[2], &args[3..], &config) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.len() >= 3 && (args[1] == "--cat" || args[1] == "--html") {
+        let format = if args[1] == "--html" {
+            export::Format::Html
+        } else {
+            export::Format::Ansi
+        };
+        if let Some(e) = config_error {
+            eprintln!("Config error: {}", e);
+        }
+        if let Some(warning) = config_warning {
+            eprintln!("{}", warning);
+        }
+        let tab_width = BufferOptions::from_config(&config).tab_width;
+        return export::cat(&args[2], &theme, tab_width, format);
+    }
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        lock::release_all();
+        default_hook(info);
+    }));
+    let piped = read_piped_input(&mut args)?;
+    let mut raw = match script {
+        Some(keys) => {
+            discard_output()?;
+            RawMode::replaying(keys)
+        }
+        None => RawMode::new()?,
+    };
+    raw.macros = macros;
+    let background = if !scripted && Theme::needs_background(&config) {
+        raw.query_background()?
+    } else {
+        None
+    };
+    if let (Some(_), Ok(detected)) = (background, Theme::from_config(&config, background)) {
+        theme = detected;
+    }
+    let mut window = Window::new(
+        &mut raw.stdin,
+        scripted.then_some(SCRIPT_SCREEN_SIZE),
+        config,
+        theme,
+        digraphs,
+        abbrevs,
+        cursor_style,
+    )?;
+    window.background = background;
+    window.clean = clean;
+    window.messages.echo = scripted;
+    window.set_project_dir(project_dir);
+    if let Some(warning) = config_warning {
+        window.push_message(MessageLevel::Warn, warning);
+    }
+    match config_error {
+        Some(e) => window.push_message(MessageLevel::Error, format!("Config error: {}", e)),
+        None => window.editor_set_status_mssage(
+            "HELP: Ctrl-X Ctrl-S = save | Ctrl-Q = quit | Ctrl-S = search",
+        ),
+    }
+    if !clean && !scripted {
+        let (plugins, errors) = Plugins::start(&window.config);
+        window.plugins = plugins;
+        for e in errors {
+            window.push_message(MessageLevel::Error, e);
+        }
+        #[cfg(feature = "scripting")]
+        match script::Scripts::load() {
+            Ok(scripts) => window.scripts = scripts,
+            Err(e) => window.push_message(MessageLevel::Error, format!("Script error: {}", e)),
+        }
+    }
+    window.set_hooks(hooks);
+    if let Some(bytes) = piped {
+        window.open_piped(bytes);
+    } else if args.len() >= 2 {
+        if let Err(e) = window.open_file(args[1].to_string()) {
+            if e.kind() != io::ErrorKind::InvalidInput {
+                return Err(e);
+            }
+            window.editor_set_status_mssage("");
+            window.push_message(
+                MessageLevel::Error,
+                format!("Can't open {}: {}", args[1], e),
+            );
+        }
+    } else if title.is_none() && !scripted {
+        window.start_screen = StartScreen::new(&window.config);
+    }
+    if let (None, Some(title)) = (args.get(1), title) {
+        window.set_title(title);
+    }
 
     loop {
+        window.poll_terminal();
+        window.poll_job();
+        window.poll_cargo_check();
+        window.poll_file_io();
+        window.poll_plugins();
+        #[cfg(feature = "sync")]
+        window.poll_sync();
+        window.poll_idle(raw.idle_time());
+        window.poll_config(&mut raw.macros);
+        window.poll_encrypted(&mut raw)?;
         window.refresh_screen()?;
-        match raw.process_keypress(&mut window)? {
-            LoopStatus::CONTINUE => {}
-            LoopStatus::STOP => break,
+        if scripted {
+            window.wait_file_io();
+            if raw.script_ended() {
+                window.leave();
+                break;
+            }
+        }
+        match raw.process_keypress(&mut window) {
+            Ok(LoopStatus::Continue) => {}
+            Ok(LoopStatus::Stop) => break,
+            Err(e) if scripted => {
+                eprintln!("{}", e);
+                window.leave();
+                std::process::exit(1);
+            }
+            Err(e) => return Err(e),
         }
     }
+    if window.messages.errors > 0 && scripted {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// The directory whose project config applies: that of the file given on
+/// the command line, or the working directory.
+fn project_dir(file: Option<&String>) -> PathBuf {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let path = match file {
+        Some(file) if RemotePath::parse(file).is_none() => cwd.join(file),
+        _ => return cwd,
+    };
+    let dir = path.parent().unwrap_or(&cwd);
+    dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())
+}
+
+/// Removes `--title NAME` from the arguments, returning the name. It names
+/// the unnamed buffer, e.g. one of text read from a pipe, and is where the
+/// buffer is saved by default.
+fn take_title(args: &mut Vec<String>) -> Option<String> {
+    let index = args.iter().skip(1).position(|arg| arg == "--title")? + 1;
+    let title = (index + 1 < args.len()).then(|| args.remove(index + 1));
+    args.remove(index);
+    title
+}
+
+/// Removes `--script FILE` from the arguments, returning the file: keys
+/// saved with M-x save-command-log, which are replayed on the file given
+/// instead of reading the terminal, drawing nothing. Warnings and errors
+/// are written to stderr.
+fn take_script(args: &mut Vec<String>) -> Option<String> {
+    let index = args.iter().skip(1).position(|arg| arg == "--script")? + 1;
+    let script = (index + 1 < args.len()).then(|| args.remove(index + 1));
+    args.remove(index);
+    script
+}
+
+/// Sends what is drawn to /dev/null, for replaying a script.
+fn discard_output() -> io::Result<()> {
+    let null = File::options().write(true).open("/dev/null")?;
+    if unsafe { libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads the text piped to the editor: stdin when it isn't a terminal and
+/// no file is given, or when the file is `-`, or else a named pipe given as
+/// the file, e.g. by `kilo_rust <(git show HEAD:main.rs)`. The argument is
+/// removed, and when stdin was read, the terminal takes its place so that
+/// keys can be read from it. A named pipe which is too big or not written
+/// to the end within `PIPE_TIMEOUT` is left to be refused as a file.
+fn read_piped_input(args: &mut Vec<String>) -> io::Result<Option<Vec<u8>>> {
+    use std::os::unix::fs::FileTypeExt;
+    let mut bytes = vec![];
+    match args.get(1).map(String::as_str) {
+        Some("-") => {
+            args.remove(1);
+        }
+        None if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 => {}
+        Some(arg) if fs::metadata(arg).is_ok_and(|m| m.file_type().is_fifo()) => {
+            let bytes = read_fifo(Path::new(arg))?;
+            if bytes.is_some() {
+                args.remove(1);
+            }
+            return Ok(bytes);
+        }
+        _ => return Ok(None),
+    }
+    io::stdin()
+        .take(MAX_PIPED_BYTES as u64 + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() > MAX_PIPED_BYTES {
+        return Err(io::Error::other(format!(
+            "more than {} MiB piped in",
+            MAX_PIPED_BYTES >> 20
+        )));
+    }
+    let tty = File::open("/dev/tty")?;
+    if unsafe { libc::dup2(tty.as_raw_fd(), libc::STDIN_FILENO) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(bytes))
+}
+
+/// Reads the named pipe at `path` to the end, or None when that takes
+/// longer than `PIPE_TIMEOUT` or more than `MAX_PIPED_BYTES`. It is opened
+/// without blocking, so that a pipe nothing writes to doesn't hang the
+/// editor.
+fn read_fifo(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = File::options()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let deadline = Instant::now() + PIPE_TIMEOUT;
+    let mut bytes = vec![];
+    let mut chunk = [0; 64 * 1024];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(None);
+        }
+        let mut poll = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll, 1, left.as_millis() as libc::c_int) } <= 0 {
+            continue;
+        }
+        match file.read(&mut chunk) {
+            Ok(0) => return Ok(Some(bytes)),
+            Ok(read) if bytes.len() + read > MAX_PIPED_BYTES => return Ok(None),
+            Ok(read) => bytes.extend_from_slice(&chunk[..read]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Removes `--clean`, or its Vim spelling `-u NONE`, from the start of the
+/// arguments, returning whether it was there. The editor then starts with
+/// the default settings, reading no config, starting no plugins or scripts
+/// and restoring nothing from earlier sessions.
+fn take_clean_flag(args: &mut Vec<String>) -> bool {
+    let mut clean = false;
+    loop {
+        match (
+            args.get(1).map(String::as_str),
+            args.get(2).map(String::as_str),
+        ) {
+            (Some("--clean"), _) => {
+                args.remove(1);
+            }
+            (Some("-u"), Some("NONE")) => {
+                args.drain(1..3);
+            }
+            _ => return clean,
+        }
+        clean = true;
+    }
+}
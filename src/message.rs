@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+/// Severity of a message in the message bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+impl MessageLevel {
+    /// How long a message of this level stays visible.
+    fn timeout(self) -> Duration {
+        match self {
+            MessageLevel::Info => Duration::from_secs(3),
+            MessageLevel::Warn => Duration::from_secs(8),
+            MessageLevel::Error => Duration::from_secs(12),
+        }
+    }
+
+    fn sgr(self) -> &'static str {
+        match self {
+            MessageLevel::Info => "",
+            MessageLevel::Warn => "\x1b[33m",
+            MessageLevel::Error => "\x1b[1;31m",
+        }
+    }
+}
+
+struct StatusMessage {
+    text: String,
+    level: MessageLevel,
+    expires: Instant,
+}
+
+/// Messages shown in the message bar. A new info message replaces the
+/// previous one, while warnings and errors stay until their own timeout.
+#[derive(Default)]
+pub struct MessageQueue {
+    messages: Vec<StatusMessage>,
+    /// Whether warnings and errors are also written to stderr, as when
+    /// replaying a script, which draws nothing
+    pub echo: bool,
+    /// Errors pushed so far
+    pub errors: usize,
+}
+
+/// At most this many warnings and errors are kept at once.
+const MAX_MESSAGES: usize = 4;
+
+impl MessageQueue {
+    pub fn push(&mut self, level: MessageLevel, text: String) {
+        if level == MessageLevel::Error {
+            self.errors += 1;
+        }
+        if self.echo && level > MessageLevel::Info {
+            eprintln!("{}", text);
+        }
+        let now = Instant::now();
+        self.messages
+            .retain(|m| m.expires > now && (level != MessageLevel::Info || m.level != level));
+        if self.messages.len() >= MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push(StatusMessage {
+            text,
+            level,
+            expires: now + level.timeout(),
+        });
+    }
+
+    /// The live messages for a bar of `columns` drawn with the SGR
+    /// sequence `style`: the info message first, then warnings and errors,
+    /// newest first.
+    pub fn render(&self, columns: usize, style: &str) -> String {
+        let now = Instant::now();
+        let mut live: Vec<&StatusMessage> = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|m| m.expires > now && !m.text.is_empty())
+            .collect();
+        live.sort_by_key(|m| m.level != MessageLevel::Info);
+        let mut line = String::new();
+        let mut width = 0;
+        for message in live {
+            if width > 0 {
+                if width + 2 >= columns {
+                    break;
+                }
+                line.push_str("  ");
+                width += 2;
+            }
+            let mut text = String::new();
+            for c in message.text.chars() {
+                if width + cells(c) > columns {
+                    break;
+                }
+                width += cells(c);
+                text.push(c);
+            }
+            line.push_str(message.level.sgr());
+            line.push_str(&text);
+            if message.level != MessageLevel::Info {
+                line.push_str(style);
+            }
+        }
+        line
+    }
+}
+
+/// Cells `c` takes in the message bar: two for the wide characters of
+/// Chinese, Japanese and Korean, e.g. in translated messages.
+fn cells(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
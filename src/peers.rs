@@ -0,0 +1,46 @@
+use crate::buffer::Region;
+use crate::undo::Position;
+
+/// The cursor of someone else editing the buffer, e.g. in a pair-editing
+/// session, drawn as a block of its color, with what it selected
+/// underlined in that color. Plugins place them with `set_cursor`.
+pub struct PeerCursor {
+    pub name: String,
+    /// Row and byte column
+    pub cursor: Position,
+    /// The other end of the selection, if any
+    pub anchor: Option<Position>,
+    /// SGR foreground code of the color
+    pub color: u8,
+}
+
+impl PeerCursor {
+    pub fn region(&self) -> Option<Region> {
+        let anchor = self.anchor.filter(|anchor| *anchor != self.cursor)?;
+        Some(Region {
+            start: anchor.min(self.cursor),
+            end: anchor.max(self.cursor),
+        })
+    }
+
+    /// Keeps the cursor on its line when `count` lines are inserted at `row`.
+    pub fn shift_added(&mut self, row: usize, count: usize) {
+        for position in Some(&mut self.cursor).into_iter().chain(&mut self.anchor) {
+            if position.0 >= row {
+                position.0 += count;
+            }
+        }
+    }
+
+    /// Keeps the cursor on its line when the lines `from..to` are removed,
+    /// moving it to the start of the next one if it was on them.
+    pub fn shift_removed(&mut self, from: usize, to: usize) {
+        for position in Some(&mut self.cursor).into_iter().chain(&mut self.anchor) {
+            if position.0 >= to {
+                position.0 -= to - from;
+            } else if position.0 >= from {
+                *position = (from, 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+/// A list of items the user chooses from, narrowed by a fuzzy query.
+/// While a picker is open `Window` draws it over the text area.
+pub struct Picker {
+    pub title: String,
+    pub items: Vec<String>,
+    pub query: String,
+    /// Indexes into `items` of the entries matching `query`
+    pub filtered: Vec<usize>,
+    /// Index into `filtered` of the highlighted entry
+    pub selected: usize,
+}
+
+impl Picker {
+    pub fn new(title: &str, items: Vec<String>) -> Picker {
+        let filtered = (0..items.len()).collect();
+        Picker {
+            title: title.to_string(),
+            items,
+            query: String::new(),
+            filtered,
+            selected: 0,
+        }
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.query.push(c);
+        self.refilter();
+    }
+
+    pub fn pop(&mut self) {
+        self.query.pop();
+        self.refilter();
+    }
+
+    fn refilter(&mut self) {
+        let query = &self.query;
+        self.filtered = (0..self.items.len())
+            .filter(|i| fuzzy_match(query, &self.items[*i]))
+            .collect();
+        self.selected = 0;
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.filtered.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    /// Index into `items` of the highlighted entry
+    pub fn selected_item(&self) -> Option<usize> {
+        self.filtered.get(self.selected).copied()
+    }
+
+    /// The title, match count and query; the cursor is placed at its end.
+    pub fn title_line(&self) -> String {
+        format!(
+            "{} ({}/{}): {}",
+            self.title,
+            self.filtered.len(),
+            self.items.len(),
+            self.query
+        )
+    }
+
+    /// Lines to draw for a picker `height` rows high: the title line followed
+    /// by the entries, scrolled so that the selected one is visible. The
+    /// second value is the line index of the selected entry.
+    pub fn lines(&self, height: usize) -> (Vec<String>, Option<usize>) {
+        let (entries, selected) = self.entries(height.saturating_sub(1).max(1));
+        let mut lines = vec![self.title_line()];
+        lines.extend(entries);
+        (lines, selected.map(|index| index + 1))
+    }
+
+    /// At most `height` matching entries, scrolled so that the selected one
+    /// is visible, and the index of the selected one among them.
+    pub fn entries(&self, height: usize) -> (Vec<String>, Option<usize>) {
+        let first = (self.selected + 1).saturating_sub(height);
+        let entries = self
+            .filtered
+            .iter()
+            .skip(first)
+            .take(height)
+            .map(|index| self.items[*index].clone())
+            .collect();
+        let selected = if self.filtered.is_empty() {
+            None
+        } else {
+            Some(self.selected - first)
+        };
+        (entries, selected)
+    }
+}
+
+/// Whether the chars of `query` appear in order in `item`, ignoring case.
+pub fn fuzzy_match(query: &str, item: &str) -> bool {
+    let mut item_chars = item.chars().flat_map(|c| c.to_lowercase());
+    query
+        .chars()
+        .flat_map(|c| c.to_lowercase())
+        .all(|q| item_chars.any(|c| c == q))
+}
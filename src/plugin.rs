@@ -0,0 +1,254 @@
+use crate::config::Config;
+use crate::hooks::Hook;
+use crate::macros::KeyBinding;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the editor waits for the answer to a request.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// What a plugin asks the editor to do.
+pub enum PluginCommand {
+    /// Inserts text at the cursor
+    InsertText(String),
+    SetStatus(String),
+    /// Highlights the chars `start..end` of a line, all counted from 0
+    Highlight {
+        line: usize,
+        start: usize,
+        end: usize,
+    },
+    /// Shows the cursor of a peer at a line and char, with the other end
+    /// of its selection, if any, and its color
+    SetCursor {
+        name: String,
+        line: usize,
+        column: usize,
+        anchor: Option<(usize, usize)>,
+        color: String,
+    },
+    RemoveCursor(String),
+}
+
+impl PluginCommand {
+    /// The command of a notification from a plugin.
+    fn parse(message: &Value) -> Result<PluginCommand, String> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let text = || {
+            params["text"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("{}: missing text", method))
+        };
+        let number = |name: &str| {
+            params[name]
+                .as_u64()
+                .map(|n| n as usize)
+                .ok_or_else(|| format!("{}: missing {}", method, name))
+        };
+        let name = || {
+            params["name"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("{}: missing name", method))
+        };
+        match method {
+            "insert_text" => Ok(PluginCommand::InsertText(text()?)),
+            "set_status" => Ok(PluginCommand::SetStatus(text()?)),
+            "highlight" => Ok(PluginCommand::Highlight {
+                line: number("line")?,
+                start: number("start")?,
+                end: number("end")?,
+            }),
+            "set_cursor" => Ok(PluginCommand::SetCursor {
+                name: name()?,
+                line: number("line")?,
+                column: number("column")?,
+                anchor: match params.get("anchor_line") {
+                    Some(_) => Some((number("anchor_line")?, number("anchor_column")?)),
+                    None => None,
+                },
+                color: params["color"].as_str().unwrap_or("magenta").to_string(),
+            }),
+            "remove_cursor" => Ok(PluginCommand::RemoveCursor(name()?)),
+            _ => Err(format!("unknown method {}", method)),
+        }
+    }
+}
+
+/// An external program extending the editor, started for each entry of
+/// the `[plugins]` section of the config, e.g. `spell = "kilo-spell --en"`.
+///
+/// The editor and the plugin exchange JSON-RPC 2.0 messages, one per line,
+/// on the stdin and stdout of the plugin:
+/// - `initialize` is answered with
+///   `{"bindings": ["M-p", "C-x P"], "hooks": ["buffer-open", "before-save"]}`,
+///   the keys the plugin handles and the hooks it attaches to;
+/// - `key` requests are sent when such a key is pressed and `before-save`
+///   requests before a file is written, both with the `path`, `line`,
+///   `column` and `text` of the cursor line; the editor waits a second for
+///   the answer;
+/// - the other hooks are sent as notifications with the same parameters;
+/// - the plugin may send `insert_text` (`text`), `set_status` (`text`),
+///   `highlight` (`line`, `start`, `end`), `set_cursor` (`name`, `line`,
+///   `column`, optionally `anchor_line`, `anchor_column` and `color`) and
+///   `remove_cursor` (`name`) notifications at any time. Lines and columns
+///   count lines and chars from 0.
+pub struct Plugin {
+    pub name: String,
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<Value>,
+    bindings: Vec<KeyBinding>,
+    pub hooks: Vec<Hook>,
+    next_id: u64,
+}
+
+impl Plugin {
+    fn start(name: &str, command: &str) -> Result<Plugin, String> {
+        let error = |e: std::io::Error| format!("plugin {}: {}", name, e);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(error)?;
+        let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => return Err(format!("plugin {}: no pipes", name)),
+        };
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let message = match line {
+                    Ok(line) => serde_json::from_str(&line).unwrap_or(Value::Null),
+                    Err(_) => break,
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut plugin = Plugin {
+            name: name.to_string(),
+            child,
+            stdin,
+            messages,
+            bindings: vec![],
+            hooks: vec![],
+            next_id: 0,
+        };
+        let (result, _) = plugin.request("initialize", json!({}))?;
+        plugin.bindings = result["bindings"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|key| KeyBinding::parse(key.as_str()?))
+            .collect();
+        plugin.hooks = result["hooks"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|hook| Hook::parse(hook.as_str()?))
+            .collect();
+        Ok(plugin)
+    }
+
+    fn send(&mut self, message: Value) -> Result<(), String> {
+        writeln!(self.stdin, "{}", message)
+            .and_then(|()| self.stdin.flush())
+            .map_err(|e| format!("plugin {}: {}", self.name, e))
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) -> Result<(), String> {
+        self.send(json!({"jsonrpc": "2.0", "method": method, "params": params}))
+    }
+
+    /// Sends a request and waits for its result. Commands the plugin sends
+    /// in the meantime are returned with it.
+    pub fn request(
+        &mut self,
+        method: &str,
+        params: Value,
+    ) -> Result<(Value, Vec<Result<PluginCommand, String>>), String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut commands = vec![];
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let message = match self.messages.recv_timeout(timeout) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(format!("plugin {}: no answer to {}", self.name, method))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(format!("plugin {}: exited", self.name))
+                }
+            };
+            if message.get("method").is_some() {
+                commands.push(PluginCommand::parse(&message));
+            } else if message["id"].as_u64() == Some(id) {
+                if let Some(error) = message.get("error") {
+                    return Err(format!("plugin {}: {}", self.name, error["message"]));
+                }
+                return Ok((message["result"].clone(), commands));
+            }
+        }
+    }
+
+    /// Commands the plugin sent on its own since the last call.
+    pub fn take_commands(&mut self) -> Vec<Result<PluginCommand, String>> {
+        self.messages
+            .try_iter()
+            .filter(|message| message.get("method").is_some())
+            .map(|message| PluginCommand::parse(&message))
+            .collect()
+    }
+
+    pub fn binds(&self, key: KeyBinding) -> bool {
+        self.bindings.contains(&key)
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The running plugins.
+#[derive(Default)]
+pub struct Plugins {
+    pub list: Vec<Plugin>,
+}
+
+impl Plugins {
+    /// Starts the plugins of the config. Those which fail to start are left
+    /// out and their errors returned.
+    pub fn start(config: &Config) -> (Plugins, Vec<String>) {
+        let mut list = vec![];
+        let mut errors = vec![];
+        for (name, command) in config.section("plugins") {
+            match Plugin::start(name, command) {
+                Ok(plugin) => list.push(plugin),
+                Err(e) => errors.push(e),
+            }
+        }
+        (Plugins { list }, errors)
+    }
+
+    /// Index of the plugin handling `key`.
+    pub fn bound_to(&self, key: KeyBinding) -> Option<usize> {
+        self.list.iter().position(|plugin| plugin.binds(key))
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Bytes of a file read for a preview; the rest isn't shown.
+const PREVIEW_BYTES: u64 = 1024 * 1024;
+
+/// A file shown read-only in a pane below the text, e.g. the one a path
+/// under the cursor names, without opening a buffer for it.
+pub struct Preview {
+    pub path: PathBuf,
+    lines: Vec<String>,
+    /// The line pointed out, e.g. that of a grep result, counted from 0
+    pub line: Option<usize>,
+    /// First line shown
+    pub offset: usize,
+    /// Lines the pane shows
+    pub rows: usize,
+}
+
+impl Preview {
+    /// Reads the start of `path`, scrolled so that `line` is in sight.
+    pub fn open(path: PathBuf, line: Option<usize>, rows: usize) -> io::Result<Preview> {
+        let mut bytes = vec![];
+        File::open(&path)?
+            .take(PREVIEW_BYTES)
+            .read_to_end(&mut bytes)?;
+        let lines = String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(|line| {
+                line.chars()
+                    .map(|c| if c.is_control() && c != '\t' { '?' } else { c })
+                    .collect()
+            })
+            .collect();
+        let mut preview = Preview {
+            path,
+            lines,
+            line,
+            offset: 0,
+            rows,
+        };
+        if let Some(line) = line {
+            preview.scroll(line as isize - rows as isize / 3);
+        }
+        Ok(preview)
+    }
+
+    /// Moves the shown lines by `by`, down if positive.
+    pub fn scroll(&mut self, by: isize) {
+        let last = self.lines.len().saturating_sub(self.rows);
+        self.offset = (self.offset as isize + by).clamp(0, last as isize) as usize;
+    }
+
+    /// The lines in the pane, with whether each is the one pointed out.
+    pub fn visible(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(self.offset)
+            .take(self.rows)
+            .map(move |(index, line)| (line.as_str(), Some(index) == self.line))
+    }
+}
+
+/// The path-like string around byte `index` of `line`, and the line number
+/// following it as in the `path:line:` of grep results, e.g. the `x.h` of
+/// `#include "x.h"`.
+pub fn path_at(line: &str, index: usize) -> Option<(String, Option<usize>)> {
+    let is_path = |c: char| !c.is_whitespace() && !"\"'`<>()[]{},;|".contains(c);
+    let start = line[..index]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_path(*c))
+        .last()
+        .map_or(index, |(i, _)| i);
+    let end = line[index..]
+        .find(|c: char| !is_path(c))
+        .map_or(line.len(), |i| index + i);
+    let mut parts = line[start..end].split(':');
+    let path = parts.next().filter(|path| !path.is_empty())?;
+    let number = parts
+        .next()
+        .and_then(|number| number.parse::<usize>().ok())
+        .filter(|number| *number > 0);
+    Some((path.to_string(), number.map(|number| number - 1)))
+}
+
+/// The file `path` names: `~/` is the home directory, and a relative path
+/// is looked for in `dir`, the directory of the current file, then in the
+/// working directory.
+pub fn resolve(path: &str, dir: Option<&Path>) -> Option<PathBuf> {
+    if let Some(rest) = path.strip_prefix("~/") {
+        let home = PathBuf::from(std::env::var_os("HOME")?);
+        return Some(home.join(rest)).filter(|path| path.is_file());
+    }
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return Some(path.to_path_buf()).filter(|path| path.is_file());
+    }
+    dir.map(|dir| dir.join(path))
+        .into_iter()
+        .chain(Some(path.to_path_buf()))
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,110 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// How often a worker looks whether scp is done.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A file on another machine, named `[user@]host:path` as for scp. It is
+/// copied into memory when opened and back when saved, so nothing of it
+/// is kept on this machine. scp runs in batch mode, so the host has to
+/// let the user in without a password, e.g. through ssh-agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemotePath {
+    pub host: String,
+    pub path: String,
+}
+
+impl RemotePath {
+    /// The remote file `arg` names, unless it is a local path, e.g. an
+    /// existing file named `a:b` or `./a:b`.
+    pub fn parse(arg: &str) -> Option<RemotePath> {
+        let (host, path) = arg.split_once(':')?;
+        if host.is_empty() || path.is_empty() || host.contains('/') || Path::new(arg).exists() {
+            return None;
+        }
+        Some(RemotePath {
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}:{}", self.host, self.path)
+    }
+
+    /// Starts copying the remote file into `file`.
+    pub fn start_fetch(&self, file: &File) -> io::Result<Child> {
+        scp(file, &self.name(), &fd_path(file))
+    }
+
+    /// Starts copying `file` to the remote file.
+    pub fn start_upload(&self, file: &File) -> io::Result<Child> {
+        scp(file, &fd_path(file), &self.name())
+    }
+}
+
+/// An anonymous file in memory, which scp reads or writes through its
+/// `/dev/fd` path.
+pub fn memory_file() -> io::Result<File> {
+    let name = CString::new("kilo_rust").expect("no NUL in the name");
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Waits for scp to finish, calling `tick` meanwhile, e.g. to count the
+/// bytes copied so far. A failure is told by the last line scp printed.
+pub fn wait(mut child: Child, mut tick: impl FnMut()) -> io::Result<()> {
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        tick();
+        thread::sleep(POLL_INTERVAL);
+    };
+    tick();
+    if status.success() {
+        return Ok(());
+    }
+    let mut stderr = String::new();
+    if let Some(mut pipe) = child.stderr.take() {
+        let _ = pipe.read_to_string(&mut stderr);
+    }
+    let message = match stderr.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => line.trim().to_string(),
+        None => format!("scp failed: {}", status),
+    };
+    Err(io::Error::other(message))
+}
+
+fn fd_path(file: &File) -> String {
+    format!("/dev/fd/{}", file.as_raw_fd())
+}
+
+/// Runs scp from `from` to `to`, letting it open `file` by its `/dev/fd` path.
+fn scp(file: &File, from: &str, to: &str) -> io::Result<Child> {
+    let fd = file.as_raw_fd();
+    let mut command = Command::new("scp");
+    command
+        .args(["-q", "-B", "--", from, to])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    // Safety: only calls an async-signal-safe function between fork and exec
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    command.spawn()
+}
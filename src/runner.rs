@@ -0,0 +1,89 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A command running in the background, e.g. a build or a test suite,
+/// whose output is collected line by line while editing goes on.
+pub struct Job {
+    pub command: String,
+    /// Id of the buffer the output goes to
+    pub buffer: usize,
+    child: Child,
+    output: Receiver<String>,
+    started: Instant,
+}
+
+impl Job {
+    /// Runs `command` with `sh -c`, reading its stdout and stderr on threads
+    /// of their own. It gets a process group of its own, so that killing it
+    /// reaches the programs the shell started too.
+    pub fn start(command: &str, buffer: usize) -> io::Result<Job> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let (sender, output) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(stdout, sender.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_lines(stderr, sender);
+        }
+        Ok(Job {
+            command: command.to_string(),
+            buffer,
+            child,
+            output,
+            started: Instant::now(),
+        })
+    }
+
+    /// The lines written since the last call, and whether more may follow.
+    pub fn take_output(&mut self) -> (Vec<String>, bool) {
+        let mut lines = vec![];
+        loop {
+            match self.output.try_recv() {
+                Ok(line) => lines.push(line),
+                Err(TryRecvError::Empty) => return (lines, true),
+                Err(TryRecvError::Disconnected) => return (lines, false),
+            }
+        }
+    }
+
+    /// Waits for the command once its output is complete.
+    pub fn finish(&mut self) -> io::Result<(ExitStatus, Duration)> {
+        Ok((self.child.wait()?, self.started.elapsed()))
+    }
+
+    pub fn kill(&mut self) {
+        unsafe {
+            libc::killpg(self.child.id() as libc::pid_t, libc::SIGKILL);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+fn forward_lines(stream: impl Read + Send + 'static, sender: mpsc::Sender<String>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = vec![];
+        while let Ok(read) = reader.read_until(b'\n', &mut line) {
+            if read == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\n', '\r']).to_string();
+            if sender.send(text).is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+}
@@ -0,0 +1,240 @@
+use crate::config::Config;
+use crate::hooks::Hook;
+use crate::macros::KeyBinding;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Scope, AST, INT};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// What a script function asked the editor to do, in the order asked.
+pub enum ScriptAction {
+    /// Inserts text at the cursor
+    Insert(String),
+    /// Replaces the text of a line
+    SetLine(usize, String),
+    /// Moves the cursor to a row and char column
+    MoveTo(usize, usize),
+    Status(String),
+    /// Makes the buffer with this name the current one
+    SwitchTo(String),
+}
+
+/// The editor as a running script function sees it: the buffer as it was
+/// when the function was called, and the actions it asked for.
+#[derive(Default)]
+pub struct Snapshot {
+    pub lines: Vec<String>,
+    /// Row and char column of the cursor
+    pub cursor: (usize, usize),
+    pub path: String,
+    /// Names of the open buffers
+    pub buffers: Vec<String>,
+    actions: Vec<ScriptAction>,
+}
+
+impl Snapshot {
+    pub fn new(
+        lines: Vec<String>,
+        cursor: (usize, usize),
+        path: String,
+        buffers: Vec<String>,
+    ) -> Snapshot {
+        Snapshot {
+            lines,
+            cursor,
+            path,
+            buffers,
+            actions: vec![],
+        }
+    }
+}
+
+/// What the init script set up: names of its functions by command name,
+/// key and hook.
+#[derive(Default)]
+struct Registry {
+    commands: HashMap<String, String>,
+    bindings: HashMap<KeyBinding, String>,
+    hooks: Vec<(Hook, String)>,
+    errors: Vec<String>,
+}
+
+/// The init script, `~/.config/kilo_rust/init.rhai`, written in Rhai. Its
+/// top level runs once at startup and sets things up:
+/// - `command("name", "function")` adds an M-x command;
+/// - `bind("M-j", "function")` or `bind("C-x j", "function")` binds a key;
+/// - `on("before-save", "function")` attaches a function to a hook, see
+///   `Hook` for their names.
+///
+/// The functions get no arguments and may call `line(row)`, `line_count()`,
+/// `cursor_row()`, `cursor_col()`, `path()` and `buffers()` to read the
+/// buffer as it was when they were called, and `insert(text)`,
+/// `set_line(row, text)`, `goto(row, col)`, `status(text)` and
+/// `switch_to(name)` to change it, which happens once they return.
+pub struct Scripts {
+    engine: Engine,
+    ast: AST,
+    snapshot: Rc<RefCell<Snapshot>>,
+    registry: Registry,
+}
+
+impl Scripts {
+    pub fn path() -> Option<PathBuf> {
+        Some(Config::path()?.with_file_name("init.rhai"))
+    }
+
+    /// Compiles and runs the init script. A missing script yields None.
+    pub fn load() -> Result<Option<Scripts>, String> {
+        let path = match Scripts::path() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        let error = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let snapshot = Rc::new(RefCell::new(Snapshot::default()));
+        let registry = Rc::new(RefCell::new(Registry::default()));
+        let mut engine = Engine::new();
+        register_api(&mut engine, &snapshot, &registry);
+        let ast = engine.compile(&text).map_err(|e| error(&e))?;
+        engine.run_ast(&ast).map_err(|e| error(&e))?;
+        let registry = registry.replace(Registry::default());
+        if let Some(e) = registry.errors.first() {
+            return Err(error(e));
+        }
+        Ok(Some(Scripts {
+            engine,
+            ast,
+            snapshot,
+            registry,
+        }))
+    }
+
+    pub fn command(&self, name: &str) -> Option<&str> {
+        self.registry.commands.get(name).map(String::as_str)
+    }
+
+    pub fn bound_to(&self, key: KeyBinding) -> Option<&str> {
+        self.registry.bindings.get(&key).map(String::as_str)
+    }
+
+    /// The functions attached to hooks, in the order they were attached.
+    pub fn hooks(&self) -> &[(Hook, String)] {
+        &self.registry.hooks
+    }
+
+    /// Calls the script function `name` on `snapshot`, returning the actions
+    /// it asked for.
+    pub fn call(&mut self, name: &str, snapshot: Snapshot) -> Result<Vec<ScriptAction>, String> {
+        self.snapshot.replace(snapshot);
+        let options = CallFnOptions::new().eval_ast(false);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            name,
+            (),
+        );
+        let actions = std::mem::take(&mut self.snapshot.borrow_mut().actions);
+        result
+            .map(|_| actions)
+            .map_err(|e| format!("{}: {}", name, e))
+    }
+}
+
+fn register_api(
+    engine: &mut Engine,
+    snapshot: &Rc<RefCell<Snapshot>>,
+    registry: &Rc<RefCell<Registry>>,
+) {
+    let registry_of = |f: fn(&mut Registry, &str, &str)| {
+        let registry = registry.clone();
+        move |first: &str, function: &str| f(&mut registry.borrow_mut(), first, function)
+    };
+    engine.register_fn(
+        "command",
+        registry_of(|registry, name, function| {
+            registry
+                .commands
+                .insert(name.to_string(), function.to_string());
+        }),
+    );
+    engine.register_fn(
+        "bind",
+        registry_of(|registry, key, function| match KeyBinding::parse(key) {
+            Some(key) => {
+                registry.bindings.insert(key, function.to_string());
+            }
+            None => registry.errors.push(format!("bind: bad key {}", key)),
+        }),
+    );
+    engine.register_fn(
+        "on",
+        registry_of(|registry, hook, function| match Hook::parse(hook) {
+            Some(hook) => registry.hooks.push((hook, function.to_string())),
+            None => registry.errors.push(format!("on: unknown hook {}", hook)),
+        }),
+    );
+
+    let s = snapshot.clone();
+    engine.register_fn("line", move |row: INT| -> String {
+        let snapshot = s.borrow();
+        usize::try_from(row)
+            .ok()
+            .and_then(|row| snapshot.lines.get(row).cloned())
+            .unwrap_or_default()
+    });
+    let s = snapshot.clone();
+    engine.register_fn("line_count", move || s.borrow().lines.len() as INT);
+    let s = snapshot.clone();
+    engine.register_fn("cursor_row", move || s.borrow().cursor.0 as INT);
+    let s = snapshot.clone();
+    engine.register_fn("cursor_col", move || s.borrow().cursor.1 as INT);
+    let s = snapshot.clone();
+    engine.register_fn("path", move || s.borrow().path.clone());
+    let s = snapshot.clone();
+    engine.register_fn("buffers", move || -> Array {
+        s.borrow()
+            .buffers
+            .iter()
+            .cloned()
+            .map(Dynamic::from)
+            .collect()
+    });
+
+    let action_of = |f: fn(&str) -> ScriptAction| {
+        let s = snapshot.clone();
+        move |text: &str| s.borrow_mut().actions.push(f(text))
+    };
+    engine.register_fn(
+        "insert",
+        action_of(|text| ScriptAction::Insert(text.to_string())),
+    );
+    engine.register_fn(
+        "status",
+        action_of(|text| ScriptAction::Status(text.to_string())),
+    );
+    engine.register_fn(
+        "switch_to",
+        action_of(|name| ScriptAction::SwitchTo(name.to_string())),
+    );
+    let s = snapshot.clone();
+    engine.register_fn("set_line", move |row: INT, text: &str| {
+        let row = row.max(0) as usize;
+        s.borrow_mut()
+            .actions
+            .push(ScriptAction::SetLine(row, text.to_string()));
+    });
+    let s = snapshot.clone();
+    engine.register_fn("goto", move |row: INT, col: INT| {
+        let (row, col) = (row.max(0) as usize, col.max(0) as usize);
+        s.borrow_mut().actions.push(ScriptAction::MoveTo(row, col));
+    });
+}
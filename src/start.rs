@@ -0,0 +1,98 @@
+use crate::config::Config;
+use crate::i18n::tr;
+use crate::state::RecentFiles;
+use std::path::PathBuf;
+
+/// Recent files listed when `start_screen.recent` isn't set.
+const DEFAULT_RECENT: usize = 5;
+
+/// Keys worth knowing from the start, shown at the bottom of the screen.
+const HINTS: [&str; 4] = [
+    "C-x C-s save | C-x C-r recent files",
+    "C-s search | M-% replace | C-_ undo",
+    "M-x run a command | C-q quit",
+    "Up/Down choose, Enter open, any other key to start editing",
+];
+
+/// What an entry of the start screen does.
+pub enum StartItem {
+    /// Asks for the path of a new file
+    NewFile,
+    /// Asks for the path of an existing file
+    OpenFile,
+    Recent(PathBuf),
+}
+
+/// The menu shown over the empty buffer when the editor starts without a
+/// file, until a key other than those choosing an entry is pressed. The
+/// `[start_screen]` section of the config sets whether it is `shown` and
+/// how many `recent` files it lists.
+pub struct StartScreen {
+    items: Vec<StartItem>,
+    selected: usize,
+}
+
+impl StartScreen {
+    /// The start screen, or None if the config turns it off.
+    pub fn new(config: &Config) -> Option<StartScreen> {
+        if config.get_bool("start_screen.shown") == Some(false) {
+            return None;
+        }
+        let recent = config
+            .get("start_screen.recent")
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(DEFAULT_RECENT);
+        let mut items = vec![StartItem::NewFile, StartItem::OpenFile];
+        items.extend(
+            RecentFiles::load()
+                .paths
+                .into_iter()
+                .filter(|path| path.exists())
+                .take(recent)
+                .map(StartItem::Recent),
+        );
+        Some(StartScreen { items, selected: 0 })
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.items.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn selected_item(&self) -> &StartItem {
+        &self.items[self.selected]
+    }
+
+    /// The lines to draw, and which of them is the selected entry.
+    pub fn lines(&self) -> (Vec<String>, usize) {
+        let mut lines = vec![
+            format!("Kilo in Rust -- version {}", env!("CARGO_PKG_VERSION")),
+            String::new(),
+        ];
+        let mut selected = 0;
+        for (index, item) in self.items.iter().enumerate() {
+            if let StartItem::Recent(_) = item {
+                if !matches!(self.items[index - 1], StartItem::Recent(_)) {
+                    lines.push(String::new());
+                    lines.push(tr("Recent files"));
+                }
+            }
+            if index == self.selected {
+                selected = lines.len();
+            }
+            lines.push(match item {
+                StartItem::NewFile => format!("  {}", tr("New file...")),
+                StartItem::OpenFile => format!("  {}", tr("Open file...")),
+                StartItem::Recent(path) => format!("  {}", path.display()),
+            });
+        }
+        lines.push(String::new());
+        lines.extend(HINTS.iter().map(|hint| tr(hint)));
+        (lines, selected)
+    }
+}
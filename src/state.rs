@@ -0,0 +1,113 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory for the files kept between sessions (`$XDG_STATE_HOME/kilo_rust`).
+pub fn state_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(base.join("kilo_rust"))
+}
+
+const MAX_CURSOR_POSITIONS: usize = 200;
+
+/// Last cursor position of recently edited files, most recent first.
+pub struct CursorPositions {
+    entries: Vec<(PathBuf, usize, usize)>,
+}
+
+impl CursorPositions {
+    fn path() -> Option<PathBuf> {
+        Some(state_dir()?.join("positions"))
+    }
+
+    /// Reads the stored positions. A missing or unreadable file yields an empty list.
+    pub fn load() -> CursorPositions {
+        let text = CursorPositions::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        let entries = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let row = fields.next()?.parse().ok()?;
+                let col = fields.next()?.parse().ok()?;
+                Some((PathBuf::from(fields.next()?), row, col))
+            })
+            .collect();
+        CursorPositions { entries }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<(usize, usize)> {
+        self.entries
+            .iter()
+            .find(|(p, _, _)| p == path)
+            .map(|(_, row, col)| (*row, *col))
+    }
+
+    pub fn set(&mut self, path: &Path, row: usize, col: usize) {
+        self.entries.retain(|(p, _, _)| p != path);
+        self.entries.insert(0, (path.to_path_buf(), row, col));
+        self.entries.truncate(MAX_CURSOR_POSITIONS);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match CursorPositions::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::File::create(path)?;
+        for (p, row, col) in &self.entries {
+            writeln!(file, "{}\t{}\t{}", row, col, p.display())?;
+        }
+        Ok(())
+    }
+}
+
+const MAX_RECENT_FILES: usize = 50;
+
+/// Recently opened files, most recent first.
+pub struct RecentFiles {
+    pub paths: Vec<PathBuf>,
+}
+
+impl RecentFiles {
+    fn path() -> Option<PathBuf> {
+        Some(state_dir()?.join("recent"))
+    }
+
+    pub fn load() -> RecentFiles {
+        let text = RecentFiles::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        RecentFiles {
+            paths: text.lines().map(PathBuf::from).collect(),
+        }
+    }
+
+    pub fn add(&mut self, path: &Path) {
+        self.paths.retain(|p| p != path);
+        self.paths.insert(0, path.to_path_buf());
+        self.paths.truncate(MAX_RECENT_FILES);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match RecentFiles::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::File::create(path)?;
+        for p in &self.paths {
+            writeln!(file, "{}", p.display())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,620 @@
+use crate::text_buffer::Lines;
+use crate::undo::Position;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often a shared buffer's listener looks for someone joining.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long someone connecting has to send the token.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest first message taken for the token.
+const MAX_TOKEN_MESSAGE: usize = 256;
+
+/// Identifies a char of a shared buffer on both instances: the Lamport
+/// clock of the instance that inserted it when it did, and that instance.
+/// Later inserts have greater ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CharId {
+    clock: u64,
+    site: u64,
+}
+
+impl CharId {
+    fn to_json(self) -> Value {
+        json!([self.clock, self.site])
+    }
+
+    fn from_json(value: &Value) -> Option<CharId> {
+        Some(CharId {
+            clock: value.get(0)?.as_u64()?,
+            site: value.get(1)?.as_u64()?,
+        })
+    }
+}
+
+/// One change of a shared buffer, as sent to the other instance.
+pub enum Op {
+    /// Puts `ch` right after the char `after`, or at the start
+    Insert {
+        id: CharId,
+        after: Option<CharId>,
+        ch: char,
+    },
+    /// Removes a char, which is kept as a tombstone so that inserts after
+    /// it still find their place
+    Delete(CharId),
+}
+
+impl Op {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Op::Insert { id, after, ch } => json!({
+                "insert": id.to_json(),
+                "after": after.map(CharId::to_json),
+                "char": ch.to_string(),
+            }),
+            Op::Delete(id) => json!({ "delete": id.to_json() }),
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Option<Op> {
+        if let Some(id) = value.get("delete") {
+            return Some(Op::Delete(CharId::from_json(id)?));
+        }
+        let mut chars = value["char"].as_str()?.chars();
+        let ch = chars.next().filter(|_| chars.next().is_none())?;
+        let after = match &value["after"] {
+            Value::Null => None,
+            after => Some(CharId::from_json(after)?),
+        };
+        Some(Op::Insert {
+            id: CharId::from_json(&value["insert"])?,
+            after,
+            ch,
+        })
+    }
+}
+
+/// What ops of the other instance did to the text, by char index, to be
+/// made in order.
+pub enum Change {
+    Inserted(usize, String),
+    /// Removed this many chars
+    Deleted(usize, usize),
+}
+
+struct Element {
+    id: CharId,
+    deleted: bool,
+}
+
+/// The text of a shared buffer as a replicated growable array: the ids of
+/// every char ever inserted, in order, with those deleted kept as tombstones. Ops
+/// commute, so both instances end with the same text whatever order their
+/// concurrent edits arrive in. Lines are joined with `\n`.
+pub struct Replica {
+    site: u64,
+    clock: u64,
+    elements: Vec<Element>,
+    /// Position of each id in `elements`
+    positions: HashMap<CharId, usize>,
+}
+
+impl Replica {
+    /// `text` as it is when sharing starts, numbered the same way on both
+    /// instances as inserted by `origin`. This instance is `site`.
+    pub fn new(text: &str, origin: u64, site: u64) -> Replica {
+        let elements: Vec<Element> = text
+            .chars()
+            .enumerate()
+            .map(|(index, _)| Element {
+                id: CharId {
+                    clock: index as u64 + 1,
+                    site: origin,
+                },
+                deleted: false,
+            })
+            .collect();
+        let mut replica = Replica {
+            site,
+            clock: elements.len() as u64,
+            elements,
+            positions: HashMap::new(),
+        };
+        replica.index_positions(0);
+        replica
+    }
+
+    /// Inserts `text` at the char `index`, returning the ops to send.
+    pub fn insert(&mut self, index: usize, text: &str) -> Vec<Op> {
+        let start = self.physical(index);
+        let mut after = start.checked_sub(1).map(|p| self.elements[p].id);
+        let mut ops = vec![];
+        let mut inserted = vec![];
+        for ch in text.chars() {
+            self.clock += 1;
+            let id = CharId {
+                clock: self.clock,
+                site: self.site,
+            };
+            inserted.push(Element { id, deleted: false });
+            ops.push(Op::Insert { id, after, ch });
+            after = Some(id);
+        }
+        self.elements.splice(start..start, inserted);
+        self.index_positions(start);
+        ops
+    }
+
+    /// Deletes `count` chars from the char `index`, returning the ops to send.
+    pub fn delete(&mut self, index: usize, count: usize) -> Vec<Op> {
+        let positions: Vec<usize> = self.visible().skip(index).take(count).collect();
+        positions
+            .into_iter()
+            .map(|position| {
+                self.elements[position].deleted = true;
+                Op::Delete(self.elements[position].id)
+            })
+            .collect()
+    }
+
+    /// Applies ops of the other instance, returning what they changed.
+    /// Runs of chars inserted one after the other and of deletes are
+    /// applied at once, so that a paste costs one pass over the text.
+    pub fn apply(&mut self, ops: &[Op]) -> Vec<Change> {
+        let mut changes = vec![];
+        let mut rest = ops;
+        while let Some(op) = rest.first() {
+            let run = match op {
+                Op::Insert { .. } => {
+                    let mut previous = None;
+                    rest.iter()
+                        .take_while(|op| match op {
+                            Op::Insert { id, after, .. } => {
+                                let follows = previous.is_none() || *after == previous;
+                                previous = Some(*id);
+                                follows
+                            }
+                            Op::Delete(_) => false,
+                        })
+                        .count()
+                }
+                Op::Delete(_) => rest
+                    .iter()
+                    .take_while(|op| matches!(op, Op::Delete(_)))
+                    .count(),
+            };
+            let (ops, next) = rest.split_at(run);
+            match op {
+                Op::Insert { .. } => changes.extend(self.apply_inserts(ops)),
+                Op::Delete(_) => changes.extend(self.apply_deletes(ops)),
+            }
+            rest = next;
+        }
+        changes
+    }
+
+    /// Applies inserts each right after the one before.
+    fn apply_inserts(&mut self, ops: &[Op]) -> Option<Change> {
+        let mut inserted = vec![];
+        let mut text = String::new();
+        for op in ops {
+            if let Op::Insert { id, ch, .. } = *op {
+                self.clock = self.clock.max(id.clock);
+                inserted.push(Element { id, deleted: false });
+                text.push(ch);
+            }
+        }
+        let (first, after) = match ops.first()? {
+            Op::Insert { id, after, .. } => (*id, *after),
+            Op::Delete(_) => return None,
+        };
+        let mut position = match after {
+            Some(after) => self.find(after)? + 1,
+            None => 0,
+        };
+        // Concurrent inserts at the same place go latest first. The rest of
+        // the run has greater ids than the first, so goes right after it.
+        while self.elements.get(position).is_some_and(|e| e.id > first) {
+            position += 1;
+        }
+        self.elements.splice(position..position, inserted);
+        self.index_positions(position);
+        Some(Change::Inserted(self.visible_index(position), text))
+    }
+
+    /// Applies deletes, returning the ranges removed from the last to the
+    /// first, so that each index still holds when it is made.
+    fn apply_deletes(&mut self, ops: &[Op]) -> Vec<Change> {
+        let mut positions: Vec<usize> = ops
+            .iter()
+            .filter_map(|op| match *op {
+                Op::Delete(id) => self.find(id),
+                Op::Insert { .. } => None,
+            })
+            .filter(|&position| !self.elements[position].deleted)
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        let mut indexes = Vec::with_capacity(positions.len());
+        let mut visible = 0;
+        let mut next = 0;
+        for &position in &positions {
+            visible += self.elements[next..position]
+                .iter()
+                .filter(|e| !e.deleted)
+                .count();
+            indexes.push(visible);
+            next = position;
+        }
+        for &position in &positions {
+            self.elements[position].deleted = true;
+        }
+        let mut changes: Vec<Change> = vec![];
+        for index in indexes.into_iter().rev() {
+            match changes.last_mut() {
+                Some(Change::Deleted(start, count)) if *start == index + 1 => {
+                    *start = index;
+                    *count += 1;
+                }
+                _ => changes.push(Change::Deleted(index, 1)),
+            }
+        }
+        changes
+    }
+
+    /// Positions of the chars not deleted.
+    fn visible(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.elements.len()).filter(move |&position| !self.elements[position].deleted)
+    }
+
+    /// Position of the char `index`, or the end.
+    fn physical(&self, index: usize) -> usize {
+        self.visible().nth(index).unwrap_or(self.elements.len())
+    }
+
+    /// Index of the char at `position` among those not deleted.
+    fn visible_index(&self, position: usize) -> usize {
+        self.elements[..position]
+            .iter()
+            .filter(|e| !e.deleted)
+            .count()
+    }
+
+    fn find(&self, id: CharId) -> Option<usize> {
+        self.positions.get(&id).copied()
+    }
+
+    /// Notes the positions of the elements from `start` on, which moved.
+    fn index_positions(&mut self, start: usize) {
+        for (position, element) in self.elements.iter().enumerate().skip(start) {
+            self.positions.insert(element.id, position);
+        }
+    }
+}
+
+/// Index of the char at `position` in `lines` joined with `\n`.
+pub fn index_of(lines: &(impl Lines + ?Sized), (row, col): Position) -> usize {
+    let before: usize = lines
+        .all_lines()
+        .take(row)
+        .map(|line| line.chars().count() + 1)
+        .sum();
+    match lines.get_line(row) {
+        Some(line) => before + line[..col].chars().count(),
+        None => before.saturating_sub(1),
+    }
+}
+
+/// Position of the char `index` in `lines` joined with `\n`, or the end.
+pub fn position_of(lines: &(impl Lines + ?Sized), mut index: usize) -> Position {
+    for (row, line) in lines.all_lines().enumerate() {
+        let chars = line.chars().count();
+        if index <= chars {
+            let col = line
+                .char_indices()
+                .nth(index)
+                .map_or(line.len(), |(i, _)| i);
+            return (row, col);
+        }
+        index -= chars + 1;
+    }
+    match lines.line_count().checked_sub(1) {
+        Some(last) => (last, lines.line(last).len()),
+        None => (0, 0),
+    }
+}
+
+/// Where the char `index` is after `removed` chars at `at` were replaced
+/// by `added` ones. A char right at `at` stays before those added.
+pub fn shift_index(index: usize, at: usize, removed: usize, added: usize) -> usize {
+    if index <= at {
+        return index;
+    }
+    index.saturating_sub(removed).max(at) + added
+}
+
+pub enum SyncEvent {
+    /// Another instance joined the buffer this one shares
+    Joined(TcpStream, String),
+    Message(Value),
+    /// The connection ended, and why
+    Closed(String),
+}
+
+/// A buffer edited by two instances of the editor at once, one sharing it
+/// with M-x sync-host and the other joining with M-x sync-join. Edits are
+/// sent over TCP as ops of a `Replica`, one JSON object per line, and
+/// each side shows the other's cursor. The joining side starts from the
+/// text of the sharing side. Its first message is the token the sharing
+/// side made up, without which the connection is dropped.
+pub struct Session {
+    /// The buffer shared
+    pub buffer: usize,
+    /// This instance in the ids of the chars it inserts
+    pub site: u64,
+    /// Address of the other instance, once connected
+    pub peer: Option<String>,
+    /// Whether this instance joined, so takes the text of the other
+    pub joining: bool,
+    /// The text, once both sides have the same
+    pub replica: Option<Replica>,
+    /// Index of the char the other instance's cursor is at
+    pub peer_cursor: Option<usize>,
+    /// Index of the cursor last sent
+    pub sent_cursor: Option<usize>,
+    stream: Option<TcpStream>,
+    events: Receiver<SyncEvent>,
+    /// Tells the listener to stop waiting for someone to join
+    stopped: Arc<AtomicBool>,
+}
+
+impl Session {
+    /// Shares `buffer`, waiting at `address` for another instance to join
+    /// with `token`. Connections sending anything else are dropped.
+    pub fn host(address: &str, token: String, buffer: usize) -> io::Result<Session> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let (sender, events) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        thread::spawn(move || {
+            let (stream, peer) = loop {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                match listener.accept() {
+                    Ok((stream, peer)) if sent_token(&stream).as_deref() == Some(&token) => {
+                        break (stream, peer)
+                    }
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_INTERVAL)
+                    }
+                    Err(e) => {
+                        let _ = sender.send(SyncEvent::Closed(e.to_string()));
+                        return;
+                    }
+                }
+            };
+            let reader = stream
+                .set_nonblocking(false)
+                .and_then(|()| stream.try_clone());
+            match reader {
+                Ok(reader) => {
+                    let _ = sender.send(SyncEvent::Joined(stream, peer.to_string()));
+                    read_messages(reader, sender);
+                }
+                Err(e) => {
+                    let _ = sender.send(SyncEvent::Closed(e.to_string()));
+                }
+            }
+        });
+        Ok(Session::new(buffer, None, events, stopped))
+    }
+
+    /// Joins the buffer another instance shares at `address` with `token`,
+    /// into `buffer`.
+    pub fn join(address: &str, token: &str, buffer: usize) -> io::Result<Session> {
+        let mut stream = TcpStream::connect(address)?;
+        writeln!(stream, "{}", json!({ "token": token }))?;
+        let reader = stream.try_clone()?;
+        let (sender, events) = mpsc::channel();
+        thread::spawn(move || read_messages(reader, sender));
+        let mut session = Session::new(buffer, Some(stream), events, Default::default());
+        session.peer = Some(address.to_string());
+        session.joining = true;
+        Ok(session)
+    }
+
+    fn new(
+        buffer: usize,
+        stream: Option<TcpStream>,
+        events: Receiver<SyncEvent>,
+        stopped: Arc<AtomicBool>,
+    ) -> Session {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Session {
+            buffer,
+            site: now.as_nanos() as u64 ^ ((std::process::id() as u64) << 32),
+            peer: None,
+            joining: false,
+            replica: None,
+            peer_cursor: None,
+            sent_cursor: None,
+            stream,
+            events,
+            stopped,
+        }
+    }
+
+    /// Starts sending to the instance that joined.
+    pub fn accept(&mut self, stream: TcpStream, peer: String) {
+        self.stream = Some(stream);
+        self.peer = Some(peer);
+    }
+
+    pub fn next_event(&self) -> Option<SyncEvent> {
+        self.events.try_recv().ok()
+    }
+
+    pub fn send(&mut self, message: Value) -> Result<(), String> {
+        match &mut self.stream {
+            Some(stream) => writeln!(stream, "{}", message).map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn send_ops(&mut self, ops: &[Op]) -> Result<(), String> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let ops: Vec<Value> = ops.iter().map(Op::to_json).collect();
+        self.send(json!({ "ops": ops }))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(stream) = &self.stream {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A token for M-x sync-host: 16 random bytes, in hex.
+pub fn new_token() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The token in the first message of someone who connected, read a byte
+/// at a time so that nothing after it is taken from the messages.
+fn sent_token(mut stream: &TcpStream) -> Option<String> {
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(TOKEN_TIMEOUT)).ok()?;
+    let mut line = vec![];
+    let mut byte = [0; 1];
+    while line.len() < MAX_TOKEN_MESSAGE {
+        stream.read_exact(&mut byte).ok()?;
+        if byte[0] == b'\n' {
+            stream.set_read_timeout(None).ok()?;
+            let message: Value = serde_json::from_slice(&line).ok()?;
+            return Some(message["token"].as_str()?.to_string());
+        }
+        line.push(byte[0]);
+    }
+    None
+}
+
+/// Sends what the other instance writes, one JSON object per line, until
+/// the connection ends.
+fn read_messages(stream: TcpStream, sender: Sender<SyncEvent>) {
+    for line in BufReader::new(stream).lines() {
+        let event = match line.map(|line| serde_json::from_str(&line)) {
+            Ok(Ok(message)) => SyncEvent::Message(message),
+            Ok(Err(e)) => SyncEvent::Closed(format!("invalid message: {}", e)),
+            Err(e) => SyncEvent::Closed(e.to_string()),
+        };
+        let closed = matches!(event, SyncEvent::Closed(_));
+        if sender.send(event).is_err() || closed {
+            return;
+        }
+    }
+    let _ = sender.send(SyncEvent::Closed("the other side left".to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `text` with `changes` made to it, in order.
+    fn changed(text: &str, changes: &[Change]) -> String {
+        let mut chars: Vec<char> = text.chars().collect();
+        for change in changes {
+            match change {
+                Change::Inserted(index, text) => {
+                    chars.splice(index..index, text.chars());
+                }
+                Change::Deleted(index, count) => {
+                    chars.drain(*index..index + count);
+                }
+            }
+        }
+        chars.into_iter().collect()
+    }
+
+    #[test]
+    fn concurrent_inserts_at_the_same_place_end_the_same_on_both_sides() {
+        let mut a = Replica::new("ab", 1, 1);
+        let mut b = Replica::new("ab", 1, 2);
+        let from_a = a.insert(1, "123");
+        let from_b = b.insert(1, "xyz");
+        // Each side gets the ops of the other one at a time, interleaved
+        // with more typing of its own
+        let (mut text_a, mut text_b) = ("a123b".to_string(), "axyzb".to_string());
+        let more_a = a.insert(4, "4");
+        text_a.insert(4, '4');
+        for op in &from_b {
+            text_a = changed(&text_a, &a.apply(std::slice::from_ref(op)));
+        }
+        for op in from_a.iter().chain(&more_a) {
+            text_b = changed(&text_b, &b.apply(std::slice::from_ref(op)));
+        }
+        assert_eq!(text_a, text_b);
+        // The run of each side stays together
+        assert!(text_a == "axyz1234b" || text_a == "a1234xyzb", "{}", text_a);
+    }
+
+    #[test]
+    fn a_delete_and_an_insert_inside_it_both_hold() {
+        let mut a = Replica::new("hello world", 1, 1);
+        let mut b = Replica::new("hello world", 1, 2);
+        let deleted = a.delete(2, 6);
+        let inserted = b.insert(5, "!");
+        let text_a = changed("herld", &a.apply(&inserted));
+        let text_b = changed("hello! world", &b.apply(&deleted));
+        assert_eq!(text_a, "he!rld");
+        assert_eq!(text_b, text_a);
+    }
+
+    #[test]
+    fn a_paste_arrives_as_one_change() {
+        let mut a = Replica::new("ab", 1, 1);
+        let mut b = Replica::new("ab", 1, 2);
+        let changes = b.apply(&a.insert(1, "pasted\ntext"));
+        assert!(matches!(
+            changes.as_slice(),
+            [Change::Inserted(1, text)] if text == "pasted\ntext"
+        ));
+        let changes = b.apply(&a.delete(0, 5));
+        assert!(matches!(changes.as_slice(), [Change::Deleted(0, 5)]));
+        assert_eq!(b.visible().count(), 8);
+    }
+
+    #[test]
+    fn char_indexes_and_positions_round_trip() {
+        let lines: Vec<String> = vec!["aé".into(), "".into(), "xyz".into()];
+        assert_eq!(index_of(&lines, (0, 3)), 2);
+        assert_eq!(index_of(&lines, (2, 1)), 5);
+        assert_eq!(position_of(&lines, 5), (2, 1));
+        assert_eq!(position_of(&lines, 1), (0, 1));
+        assert_eq!(position_of(&lines, 100), (2, 3));
+        assert_eq!(shift_index(5, 2, 1, 3), 7);
+        assert_eq!(shift_index(2, 2, 1, 3), 2);
+    }
+}
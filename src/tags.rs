@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A definition listed in a ctags `tags` file.
+pub struct Tag {
+    /// The file of the definition, relative paths resolved against the tags file
+    pub file: PathBuf,
+    /// How to find the line: a line number or a search pattern
+    address: String,
+}
+
+/// The nearest `tags` file in `dir` or one of its parents.
+pub fn find_tags_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join("tags"))
+        .find(|path| path.is_file())
+}
+
+/// The definitions of `name` in `tags_file`.
+pub fn lookup(tags_file: &Path, name: &str) -> Vec<Tag> {
+    let text = fs::read_to_string(tags_file).unwrap_or_default();
+    let base = tags_file.parent().unwrap_or_else(|| Path::new(""));
+    text.lines()
+        .filter(|line| !line.starts_with("!_TAG_"))
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            if fields.next()? != name {
+                return None;
+            }
+            let file = base.join(fields.next()?);
+            let rest = fields.next()?;
+            let address = rest.split(";\"").next().unwrap_or(rest).to_string();
+            Some(Tag { file, address })
+        })
+        .collect()
+}
+
+impl Tag {
+    /// Row of the definition in `lines`.
+    fn find_row(&self, lines: &[&str]) -> Option<usize> {
+        if let Ok(number) = self.address.trim().parse::<usize>() {
+            return number.checked_sub(1).filter(|row| *row < lines.len());
+        }
+        let pattern = self
+            .address
+            .strip_prefix('/')
+            .and_then(|p| p.strip_suffix('/'))
+            .or_else(|| self.address.strip_prefix('?')?.strip_suffix('?'))?;
+        let pattern = pattern.replace("\\/", "/").replace("\\\\", "\\");
+        let (anchored_start, pattern) = match pattern.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, pattern.as_str()),
+        };
+        let (anchored_end, pattern) = match pattern.strip_suffix('$') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        lines
+            .iter()
+            .position(|line| match (anchored_start, anchored_end) {
+                (true, true) => *line == pattern,
+                (true, false) => line.starts_with(pattern),
+                (false, true) => line.ends_with(pattern),
+                (false, false) => line.contains(pattern),
+            })
+    }
+
+    /// The comment lines right above the definition followed by its first
+    /// line, e.g. a doc comment and a function signature.
+    pub fn documentation(&self) -> Option<Vec<String>> {
+        let text = fs::read_to_string(&self.file).ok()?;
+        let lines: Vec<&str> = text.lines().collect();
+        let row = self.find_row(&lines)?;
+        let mut doc = vec![];
+        for line in lines[..row].iter().rev().map(|line| line.trim()) {
+            // Attributes may stand between a doc comment and the definition
+            if line.starts_with("#[") {
+                continue;
+            }
+            if !is_comment(line) {
+                break;
+            }
+            doc.push(line.to_string());
+        }
+        doc.reverse();
+        doc.push(lines[row].trim().to_string());
+        Some(doc)
+    }
+}
+
+fn is_comment(line: &str) -> bool {
+    line.starts_with("//")
+        || line.starts_with("/*")
+        || line.starts_with('*')
+        || line == "#"
+        || line.starts_with("# ")
+}
@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+
+/// A shell running on a pseudo terminal, with the screen it draws kept as
+/// lines of characters. Only the escape sequences common for a dumb
+/// terminal are understood: cursor movement and erasing; colors are dropped.
+pub struct Terminal {
+    master: File,
+    child: Child,
+    /// The visible screen, `rows` lines of `columns` characters
+    screen: Vec<Vec<char>>,
+    pub rows: usize,
+    pub columns: usize,
+    /// Cursor row and column on the screen
+    pub cursor: (usize, usize),
+    /// Bytes of an escape sequence or a UTF-8 character read only in part
+    pending: Vec<u8>,
+}
+
+impl Terminal {
+    /// Starts `shell` on a new pseudo terminal of the given size.
+    pub fn spawn(shell: &str, rows: usize, columns: usize) -> io::Result<Terminal> {
+        let size = libc::winsize {
+            ws_row: rows as u16,
+            ws_col: columns as u16,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let (mut master_fd, mut slave_fd) = (0, 0);
+        let result = unsafe {
+            libc::openpty(
+                &mut master_fd,
+                &mut slave_fd,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(master_fd) };
+        let slave = unsafe { File::from_raw_fd(slave_fd) };
+        unsafe {
+            let flags = libc::fcntl(master_fd, libc::F_GETFL);
+            libc::fcntl(master_fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        let mut command = Command::new(shell);
+        command
+            .env("TERM", "dumb")
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        unsafe {
+            // The shell gets its own session with the pty as controlling terminal
+            command.pre_exec(|| {
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        Ok(Terminal {
+            master,
+            child,
+            screen: vec![vec![' '; columns]; rows],
+            rows,
+            columns,
+            cursor: (0, 0),
+            pending: vec![],
+        })
+    }
+
+    /// Reads what the shell wrote since the last call. Returns false once
+    /// the shell has exited.
+    pub fn poll(&mut self) -> bool {
+        let mut buf = [0; 4096];
+        loop {
+            match self.master.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => {
+                    let mut bytes = std::mem::take(&mut self.pending);
+                    bytes.extend_from_slice(&buf[..read]);
+                    self.feed(&bytes);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // WouldBlock when nothing is left; EIO once the shell is gone
+                Err(_) => break,
+            }
+        }
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Sends keys to the shell.
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.master.write_all(bytes)
+    }
+
+    pub fn line(&self, row: usize) -> String {
+        self.screen
+            .get(row)
+            .map(|line| line.iter().collect())
+            .unwrap_or_default()
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        let mut index = 0;
+        while index < bytes.len() {
+            let consumed = match bytes[index] {
+                0x1b => self.escape(&bytes[index..]),
+                b'\r' => {
+                    self.cursor.1 = 0;
+                    Some(1)
+                }
+                b'\n' => {
+                    self.line_feed();
+                    Some(1)
+                }
+                0x08 => {
+                    self.cursor.1 = self.cursor.1.saturating_sub(1);
+                    Some(1)
+                }
+                b'\t' => {
+                    self.cursor.1 = ((self.cursor.1 / 8 + 1) * 8).min(self.columns - 1);
+                    Some(1)
+                }
+                byte if byte < 0x20 || byte == 0x7f => Some(1),
+                _ => self.text(&bytes[index..]),
+            };
+            match consumed {
+                Some(consumed) => index += consumed,
+                None => {
+                    // Wait for the rest of the sequence
+                    self.pending = bytes[index..].to_vec();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Puts the character at the start of `bytes` on the screen. Returns
+    /// the bytes used, or None if the character is incomplete.
+    fn text(&mut self, bytes: &[u8]) -> Option<usize> {
+        let len = match bytes[0] {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        if bytes.len() < len {
+            return None;
+        }
+        let c = std::str::from_utf8(&bytes[..len])
+            .ok()
+            .and_then(|text| text.chars().next())
+            .unwrap_or('?');
+        if self.cursor.1 >= self.columns {
+            self.cursor.1 = 0;
+            self.line_feed();
+        }
+        let (row, column) = self.cursor;
+        self.screen[row][column] = c;
+        self.cursor.1 += 1;
+        Some(len)
+    }
+
+    fn line_feed(&mut self) {
+        if self.cursor.0 + 1 < self.rows {
+            self.cursor.0 += 1;
+        } else {
+            self.screen.remove(0);
+            self.screen.push(vec![' '; self.columns]);
+        }
+    }
+
+    /// Handles the escape sequence at the start of `bytes`. Returns the
+    /// bytes used, or None if the sequence is incomplete.
+    fn escape(&mut self, bytes: &[u8]) -> Option<usize> {
+        match bytes.get(1)? {
+            b'[' => {
+                let end = bytes[2..].iter().position(|b| (0x40..=0x7e).contains(b))? + 2;
+                let params: Vec<usize> = std::str::from_utf8(&bytes[2..end])
+                    .unwrap_or("")
+                    .trim_start_matches('?')
+                    .split(';')
+                    .map(|param| param.parse().unwrap_or(0))
+                    .collect();
+                self.csi(bytes[end], &params);
+                Some(end + 1)
+            }
+            b']' => {
+                // An OSC string, e.g. a window title, ends with BEL or ST
+                let end = bytes
+                    .windows(2)
+                    .position(|w| w[0] == 0x07 || w == b"\x1b\\");
+                match end {
+                    Some(end) if bytes[end] == 0x07 => Some(end + 1),
+                    Some(end) => Some(end + 2),
+                    None if bytes.last() == Some(&0x07) => Some(bytes.len()),
+                    None => None,
+                }
+            }
+            _ => Some(2),
+        }
+    }
+
+    fn csi(&mut self, command: u8, params: &[usize]) {
+        let count = params.first().copied().unwrap_or(0).max(1);
+        let (row, column) = self.cursor;
+        match command {
+            b'A' => self.cursor.0 = row.saturating_sub(count),
+            b'B' => self.cursor.0 = (row + count).min(self.rows - 1),
+            b'C' => self.cursor.1 = (column + count).min(self.columns - 1),
+            b'D' => self.cursor.1 = column.saturating_sub(count),
+            b'G' => self.cursor.1 = (count - 1).min(self.columns - 1),
+            b'H' | b'f' => {
+                let column = params.get(1).copied().unwrap_or(0).max(1);
+                self.cursor = (
+                    (count - 1).min(self.rows - 1),
+                    (column - 1).min(self.columns - 1),
+                );
+            }
+            b'J' => {
+                let (from, to) = match params.first().copied().unwrap_or(0) {
+                    0 => ((row, column), (self.rows, 0)),
+                    1 => ((0, 0), (row, column + 1)),
+                    _ => ((0, 0), (self.rows, 0)),
+                };
+                self.erase(from, to);
+            }
+            b'K' => {
+                let (from, to) = match params.first().copied().unwrap_or(0) {
+                    0 => (column, self.columns),
+                    1 => (0, column + 1),
+                    _ => (0, self.columns),
+                };
+                self.erase((row, from), (row, to));
+            }
+            _ => {}
+        }
+    }
+
+    /// Blanks the screen from `from` up to, but not including, `to`.
+    fn erase(&mut self, from: (usize, usize), to: (usize, usize)) {
+        for (row, line) in self.screen.iter_mut().enumerate() {
+            for (column, cell) in line.iter_mut().enumerate() {
+                let at = (row, column);
+                if from <= at && at < to {
+                    *cell = ' ';
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::kill(self.child.id() as libc::pid_t, libc::SIGHUP);
+        }
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,322 @@
+use std::iter::{self, Chain, FromIterator};
+use std::mem;
+use std::ops::{Index, IndexMut, Range};
+use std::slice;
+
+/// Fewest slots a gap buffer adds when its gap is used up.
+const MIN_GAP: usize = 64;
+
+/// The lines of a buffer, without their line endings.
+pub type TextBuffer = GapBuffer<String>;
+
+/// Rows kept in a vector with a gap of unused slots where rows were last
+/// added or removed. Adding or removing rows moves the gap there first,
+/// which costs the distance from the last edit rather than the rows after
+/// it, so that typing Enter or deleting lines one after the other near
+/// the same place doesn't move the rest of the buffer every time.
+///
+/// Rows are read and edited in place by their index, like in a slice.
+pub struct GapBuffer<T> {
+    /// The rows, with unused slots in `gap`
+    slots: Vec<T>,
+    gap: Range<usize>,
+}
+
+impl<T: Default> GapBuffer<T> {
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.gap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Index in `slots` of the row `row`.
+    fn slot(&self, row: usize) -> usize {
+        if row < self.gap.start {
+            row
+        } else {
+            row + self.gap.len()
+        }
+    }
+
+    pub fn get(&self, row: usize) -> Option<&T> {
+        (row < self.len()).then(|| &self.slots[self.slot(row)])
+    }
+
+    pub fn get_mut(&mut self, row: usize) -> Option<&mut T> {
+        if row >= self.len() {
+            return None;
+        }
+        let slot = self.slot(row);
+        Some(&mut self.slots[slot])
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        self.get(self.len().checked_sub(1)?)
+    }
+
+    pub fn iter(&self) -> Chain<slice::Iter<'_, T>, slice::Iter<'_, T>> {
+        let (before, after) = self.slots.split_at(self.gap.start);
+        before.iter().chain(after[self.gap.len()..].iter())
+    }
+
+    pub fn iter_mut(&mut self) -> Chain<slice::IterMut<'_, T>, slice::IterMut<'_, T>> {
+        let (before, after) = self.slots.split_at_mut(self.gap.start);
+        before.iter_mut().chain(after[self.gap.len()..].iter_mut())
+    }
+
+    pub fn push(&mut self, row: T) {
+        self.insert(self.len(), row);
+    }
+
+    pub fn insert(&mut self, at: usize, row: T) {
+        self.insert_rows(at, iter::once(row));
+    }
+
+    /// Puts `rows` before the row `at`, or at the end for the row count.
+    pub fn insert_rows<I: IntoIterator<Item = T>>(&mut self, at: usize, rows: I) {
+        self.move_gap(at);
+        for row in rows {
+            if self.gap.is_empty() {
+                self.grow_gap();
+            }
+            self.slots[self.gap.start] = row;
+            self.gap.start += 1;
+        }
+    }
+
+    /// Takes out the rows `rows`.
+    pub fn remove_rows(&mut self, rows: Range<usize>) -> Vec<T> {
+        self.move_gap(rows.end);
+        let removed = self.slots[rows.clone()].iter_mut().map(mem::take).collect();
+        self.gap.start = rows.start;
+        removed
+    }
+
+    /// Adds copies of `row` at the end or takes rows off it, until there are `len`.
+    pub fn resize(&mut self, len: usize, row: T)
+    where
+        T: Clone,
+    {
+        let count = self.len();
+        if len < count {
+            self.remove_rows(len..count);
+        } else {
+            self.insert_rows(count, iter::repeat_n(row, len - count));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.gap = 0..0;
+    }
+
+    /// Moves the gap to start at the row `row`, a slot at a time.
+    fn move_gap(&mut self, row: usize) {
+        let Range { start, end } = self.gap;
+        if row < start {
+            for slot in (row..start).rev() {
+                self.slots.swap(slot, slot + end - start);
+            }
+        } else {
+            for slot in start..row {
+                self.slots.swap(slot, slot + end - start);
+            }
+        }
+        self.gap = row..row + end - start;
+    }
+
+    /// Adds slots to the empty gap, in proportion to the rows so that
+    /// adding many rows one at a time takes linear time.
+    fn grow_gap(&mut self) {
+        let added = (self.slots.len() / 8).max(MIN_GAP);
+        let at = self.gap.start;
+        self.slots
+            .splice(at..at, iter::repeat_with(T::default).take(added));
+        self.gap.end += added;
+    }
+}
+
+impl<T: Default> Default for GapBuffer<T> {
+    fn default() -> GapBuffer<T> {
+        GapBuffer::from(vec![])
+    }
+}
+
+impl<T: Default + Clone> Clone for GapBuffer<T> {
+    fn clone(&self) -> GapBuffer<T> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<T: Default + std::fmt::Debug> std::fmt::Debug for GapBuffer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Default + PartialEq> PartialEq for GapBuffer<T> {
+    fn eq(&self, other: &GapBuffer<T>) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Default + Eq> Eq for GapBuffer<T> {}
+
+impl<T> From<Vec<T>> for GapBuffer<T> {
+    fn from(rows: Vec<T>) -> GapBuffer<T> {
+        let len = rows.len();
+        GapBuffer {
+            slots: rows,
+            gap: len..len,
+        }
+    }
+}
+
+impl<T> FromIterator<T> for GapBuffer<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(rows: I) -> GapBuffer<T> {
+        GapBuffer::from(rows.into_iter().collect::<Vec<T>>())
+    }
+}
+
+impl<T: Default> Extend<T> for GapBuffer<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, rows: I) {
+        self.insert_rows(self.len(), rows);
+    }
+}
+
+impl<T: Default> Index<usize> for GapBuffer<T> {
+    type Output = T;
+
+    fn index(&self, row: usize) -> &T {
+        assert!(row < self.len(), "row {} out of {}", row, self.len());
+        &self.slots[self.slot(row)]
+    }
+}
+
+impl<T: Default> IndexMut<usize> for GapBuffer<T> {
+    fn index_mut(&mut self, row: usize) -> &mut T {
+        assert!(row < self.len(), "row {} out of {}", row, self.len());
+        let slot = self.slot(row);
+        &mut self.slots[slot]
+    }
+}
+
+/// Numbered lines of text to read, which a `TextBuffer` and a vector of
+/// lines both are, so that e.g. highlighting, completion and search take
+/// either.
+pub trait Lines {
+    fn line_count(&self) -> usize;
+
+    /// The line `row`, which must be below `line_count`.
+    fn line(&self, row: usize) -> &str;
+
+    fn get_line(&self, row: usize) -> Option<&str> {
+        (row < self.line_count()).then(|| self.line(row))
+    }
+
+    /// The lines `rows`, which also go backwards with `rev`.
+    fn lines_in(&self, rows: Range<usize>) -> impl DoubleEndedIterator<Item = &str> {
+        rows.map(move |row| self.line(row))
+    }
+
+    fn all_lines(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.lines_in(0..self.line_count())
+    }
+}
+
+impl Lines for TextBuffer {
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+
+    fn line(&self, row: usize) -> &str {
+        &self[row]
+    }
+}
+
+impl Lines for Vec<String> {
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+
+    fn line(&self, row: usize) -> &str {
+        &self[row]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(buffer: &GapBuffer<usize>) -> Vec<usize> {
+        buffer.iter().copied().collect()
+    }
+
+    #[test]
+    fn edits_in_different_places_keep_the_order() {
+        let mut buffer: GapBuffer<usize> = (0..10).collect();
+        buffer.insert_rows(3, [30, 31]);
+        buffer.insert_rows(11, [90]);
+        buffer.insert(0, 100);
+        assert_eq!(
+            rows(&buffer),
+            [100, 0, 1, 2, 30, 31, 3, 4, 5, 6, 7, 8, 90, 9]
+        );
+        assert_eq!(buffer.remove_rows(4..7), [30, 31, 3]);
+        assert_eq!(buffer.remove_rows(0..1), [100]);
+        buffer.push(10);
+        assert_eq!(rows(&buffer), [0, 1, 2, 4, 5, 6, 7, 8, 90, 9, 10]);
+        assert_eq!(buffer.len(), 11);
+        assert_eq!((buffer[3], buffer[8], buffer.last()), (4, 90, Some(&10)));
+        buffer[8] = 80;
+        assert_eq!(buffer.get(8), Some(&80));
+        assert_eq!(buffer.get(11), None);
+    }
+
+    #[test]
+    fn adding_many_rows_one_at_a_time_grows_the_gap() {
+        let mut buffer = GapBuffer::default();
+        for row in 0..1000 {
+            buffer.insert(row / 2, row);
+        }
+        let mut expected: Vec<usize> = vec![];
+        for row in 0..1000 {
+            expected.insert(row / 2, row);
+        }
+        assert_eq!(rows(&buffer), expected);
+        assert_eq!(buffer, expected.into_iter().collect());
+    }
+
+    #[test]
+    fn lines_read_the_same_as_a_vector() {
+        let lines: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        let mut buffer: TextBuffer = lines.clone().into();
+        buffer.remove_rows(1..2);
+        buffer.insert(1, "b".to_string());
+        assert_eq!(buffer.line_count(), lines.line_count());
+        assert!(buffer.all_lines().eq(lines.all_lines()));
+        assert!(buffer.lines_in(1..3).rev().eq(["c", "b"]));
+        assert_eq!(buffer.get_line(4), None);
+    }
+
+    #[test]
+    fn resizing_and_editing_in_place_see_past_the_gap() {
+        let mut buffer: GapBuffer<usize> = (0..6).collect();
+        buffer.insert_rows(2, [20]);
+        buffer.resize(9, 7);
+        assert_eq!(rows(&buffer), [0, 1, 20, 2, 3, 4, 5, 7, 7]);
+        for row in buffer.iter_mut() {
+            *row += 1;
+        }
+        *buffer.get_mut(3).unwrap() = 30;
+        assert_eq!(buffer.get_mut(9), None);
+        buffer.resize(4, 0);
+        assert_eq!(rows(&buffer), [1, 2, 21, 30]);
+    }
+}
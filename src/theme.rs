@@ -0,0 +1,379 @@
+use crate::config::Config;
+use crate::highlight::HighlightColor;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Name of the built-in theme for dark terminal backgrounds.
+const DEFAULT_THEME: &str = "default";
+
+/// Name of the built-in theme for light terminal backgrounds.
+const LIGHT_THEME: &str = "light";
+
+/// How a piece of text is drawn: SGR foreground color codes for the text
+/// and its background, plus attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    pub fg: u8,
+    /// The foreground code of the background color, `DEFAULT_FG` for none
+    pub bg: u8,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    pub const fn fg(fg: u8) -> Style {
+        Style {
+            fg,
+            bg: DEFAULT_FG,
+            bold: false,
+            italic: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+
+    /// Escape sequence switching to this style, resetting any previous attributes.
+    pub fn sgr(&self) -> String {
+        let mut s = String::from("\x1b[0");
+        if self.bold {
+            s.push_str(";1");
+        }
+        if self.italic {
+            s.push_str(";3");
+        }
+        if self.underline {
+            s.push_str(";4");
+        }
+        if self.reverse {
+            s.push_str(";7");
+        }
+        s.push_str(&format!(";{}", self.fg));
+        if self.bg != DEFAULT_FG {
+            s.push_str(&format!(";{}", u16::from(self.bg) + 10));
+        }
+        s.push('m');
+        s
+    }
+
+    /// Parses a space separated spec such as `"bright-blue bold"`, `"33
+    /// underline"` or `"black on-cyan"`.
+    pub fn parse(spec: &str) -> Result<Style, String> {
+        let mut style = Style::fg(DEFAULT_FG);
+        for word in spec.split_whitespace() {
+            match word {
+                "bold" => style.bold = true,
+                "italic" => style.italic = true,
+                "underline" => style.underline = true,
+                "reverse" => style.reverse = true,
+                _ if word.starts_with("on-") => match color_code(&word[3..]) {
+                    Some(code) => style.bg = code,
+                    None => return Err(format!("unknown background color `{}`", word)),
+                },
+                _ => match color_code(word) {
+                    Some(code) => style.fg = code,
+                    None => return Err(format!("unknown color or attribute `{}`", word)),
+                },
+            }
+        }
+        Ok(style)
+    }
+}
+
+pub const DEFAULT_FG: u8 = 39;
+
+fn color_code(name: &str) -> Option<u8> {
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    if let Some(index) = NAMES.iter().position(|n| *n == name) {
+        return Some(30 + index as u8);
+    }
+    if let Some(bright) = name.strip_prefix("bright-") {
+        return NAMES
+            .iter()
+            .position(|n| *n == bright)
+            .map(|index| 90 + index as u8);
+    }
+    if name == "default" {
+        return Some(DEFAULT_FG);
+    }
+    // Only the codes of the eight colors and their bright forms, as others
+    // are attributes or would overflow as a background
+    name.parse()
+        .ok()
+        .filter(|code| matches!(code, 30..=37 | 90..=97))
+}
+
+/// Whether the terminal draws dark text on a light background or the
+/// other way around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Background {
+    Light,
+    Dark,
+}
+
+impl Background {
+    /// The background told by the answer of a terminal to the OSC 11
+    /// query, e.g. `ESC ] 11 ; rgb:ffff/ffff/dddd BEL`.
+    pub fn from_osc11(answer: &str) -> Option<Background> {
+        let rgb = answer.split("rgb:").nth(1)?;
+        let mut channels = rgb.splitn(3, '/').map(|channel| {
+            let digits: String = channel
+                .chars()
+                .take_while(|c| c.is_ascii_hexdigit())
+                .collect();
+            let max = 16f64.powi(digits.len() as i32) - 1.0;
+            u32::from_str_radix(&digits, 16)
+                .ok()
+                .map(|value| value as f64 / max)
+        });
+        let (r, g, b) = (channels.next()??, channels.next()??, channels.next()??);
+        if 0.299 * r + 0.587 * g + 0.114 * b > 0.5 {
+            Some(Background::Light)
+        } else {
+            Some(Background::Dark)
+        }
+    }
+}
+
+/// Maps every highlight kind to the style it is drawn with.
+///
+/// Besides the built-in `default` and `light` themes, every
+/// `themes/NAME.toml` file next to the config file is a theme, with a
+/// `[colors]` section like that of the config. The `[theme]` section of
+/// the config chooses one by `name`, or else the theme set as `light` or
+/// `dark` for the `background`, which is asked from the terminal unless
+/// set to `"light"` or `"dark"`. Besides highlight kinds, `[colors]` may
+/// style the status and message bars and the indentation guides.
+pub struct Theme {
+    pub name: String,
+    styles: HashMap<HighlightColor, Style>,
+    /// Style of the file name and flags on the left of the status bar, set
+    /// as `status_bar` in `[colors]`
+    pub status_bar: Style,
+    /// Styles of the space in the middle of the status bar and of the file
+    /// type and position on its right, `status_bar_fill` and
+    /// `status_bar_right`, if they differ from `status_bar`
+    status_bar_fill: Option<Style>,
+    status_bar_right: Option<Style>,
+    pub message_bar: Style,
+    /// Style and character of the indentation guides, `indent_guide` in
+    /// `[colors]` and in `[theme]`
+    pub indent_guide: Style,
+    pub indent_guide_char: char,
+    /// Drawn after the left part of the status bar and before its right
+    /// part, e.g. the powerline arrows, set as `separator` and
+    /// `right_separator` in `[theme]`
+    pub separator: String,
+    pub right_separator: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        use HighlightColor::*;
+        let styles = [
+            (Normal, 37),
+            (Number, 31),
+            (String, 35),
+            (Comment, 36),
+            (MultilineComment, 36),
+            (DocComment, 32),
+            (DocTag, 93),
+            (Keyword1, 33),
+            (Keyword2, 32),
+            (Match, 34),
+            (Type, 92),
+            (Function, 94),
+            (Field, 96),
+            (Constant, 91),
+            (Preprocessor, 95),
+        ]
+        .iter()
+        .map(|(kind, fg)| (*kind, Style::fg(*fg)))
+        .collect();
+        Theme {
+            name: DEFAULT_THEME.to_string(),
+            styles,
+            ..Theme::bars()
+        }
+    }
+}
+
+impl Theme {
+    /// The theme the config chooses for a terminal with the `detected`
+    /// background, with the `[colors]` section of the config applied on top.
+    pub fn from_config(config: &Config, detected: Option<Background>) -> Result<Theme, String> {
+        if let Some(name) = config.get("theme.name") {
+            return Theme::named(name, config);
+        }
+        let background = match config.get("theme.background") {
+            Some("light") => Some(Background::Light),
+            Some("dark") => Some(Background::Dark),
+            Some("auto") | None => detected,
+            Some(other) => {
+                return Err(format!(
+                    "theme.background: expected light, dark or auto, got {}",
+                    other
+                ))
+            }
+        };
+        let name = match background {
+            Some(Background::Light) => config.get("theme.light").unwrap_or(LIGHT_THEME),
+            Some(Background::Dark) | None => config.get("theme.dark").unwrap_or(DEFAULT_THEME),
+        };
+        Theme::named(name, config)
+    }
+
+    /// Whether the background of the terminal is needed to choose the theme.
+    pub fn needs_background(config: &Config) -> bool {
+        config.get("theme.name").is_none()
+            && matches!(config.get("theme.background"), Some("auto") | None)
+    }
+
+    /// The theme drawing dark text on a light background.
+    fn light() -> Theme {
+        use HighlightColor::*;
+        let styles = [
+            (Normal, DEFAULT_FG),
+            (Number, 31),
+            (String, 32),
+            (Comment, 90),
+            (MultilineComment, 90),
+            (DocComment, 32),
+            (DocTag, 33),
+            (Keyword1, 34),
+            (Keyword2, 35),
+            (Match, 94),
+            (Type, 36),
+            (Function, 94),
+            (Field, 35),
+            (Constant, 31),
+            (Preprocessor, 35),
+        ]
+        .iter()
+        .map(|(kind, fg)| (*kind, Style::fg(*fg)))
+        .collect();
+        Theme {
+            name: LIGHT_THEME.to_string(),
+            styles,
+            ..Theme::bars()
+        }
+    }
+
+    /// A theme without highlight styles and with the bars of the default
+    /// theme: the status bar in reverse video, without separators.
+    fn bars() -> Theme {
+        Theme {
+            name: String::new(),
+            styles: HashMap::new(),
+            status_bar: Style {
+                reverse: true,
+                ..Style::fg(DEFAULT_FG)
+            },
+            status_bar_fill: None,
+            status_bar_right: None,
+            message_bar: Style::fg(DEFAULT_FG),
+            indent_guide: Style::fg(90),
+            indent_guide_char: '│',
+            separator: String::new(),
+            right_separator: String::new(),
+        }
+    }
+
+    pub fn status_bar_fill(&self) -> Style {
+        self.status_bar_fill.unwrap_or(self.status_bar)
+    }
+
+    pub fn status_bar_right(&self) -> Style {
+        self.status_bar_right.unwrap_or(self.status_bar)
+    }
+
+    /// The installed theme `name` with the `[colors]` section of the
+    /// config applied on top.
+    pub fn named(name: &str, config: &Config) -> Result<Theme, String> {
+        let mut theme = Theme::default();
+        if name == LIGHT_THEME {
+            theme = Theme::light();
+        } else if name != DEFAULT_THEME {
+            let path = Theme::dir()
+                .map(|dir| dir.join(format!("{}.toml", name)))
+                .filter(|path| path.is_file())
+                .ok_or_else(|| format!("No such theme: {}", name))?;
+            theme.apply_colors(&Config::load_from(&path)?)?;
+            theme.name = name.to_string();
+        }
+        theme.apply_colors(config)?;
+        Ok(theme)
+    }
+
+    /// Names of the installed themes, the built-in ones first.
+    pub fn installed() -> Vec<String> {
+        let mut names: Vec<String> = Theme::dir()
+            .and_then(|dir| fs::read_dir(dir).ok())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "toml" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .filter(|name| name != DEFAULT_THEME && name != LIGHT_THEME)
+            .collect();
+        names.sort();
+        names.splice(0..0, [DEFAULT_THEME.to_string(), LIGHT_THEME.to_string()]);
+        names
+    }
+
+    fn dir() -> Option<PathBuf> {
+        Some(Config::path()?.with_file_name("themes"))
+    }
+
+    fn apply_colors(&mut self, config: &Config) -> Result<(), String> {
+        for (name, spec) in config.section("colors") {
+            let style = Style::parse(spec).map_err(|e| format!("colors.{}: {}", name, e))?;
+            match name {
+                "status_bar" => self.status_bar = style,
+                "status_bar_fill" => self.status_bar_fill = Some(style),
+                "status_bar_right" => self.status_bar_right = Some(style),
+                "message_bar" => self.message_bar = style,
+                "indent_guide" => self.indent_guide = style,
+                _ => {
+                    let kind = HighlightColor::from_name(name)
+                        .ok_or_else(|| format!("colors: unknown highlight kind `{}`", name))?;
+                    self.styles.insert(kind, style);
+                }
+            }
+        }
+        if let Some(separator) = config.get("theme.separator") {
+            self.separator = separator.to_string();
+        }
+        if let Some(separator) = config.get("theme.right_separator") {
+            self.right_separator = separator.to_string();
+        }
+        if let Some(guide) = config.get("theme.indent_guide") {
+            let mut chars = guide.chars();
+            self.indent_guide_char = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => {
+                    return Err(format!(
+                        "theme.indent_guide: expected one character, got `{}`",
+                        guide
+                    ))
+                }
+            };
+        }
+        Ok(())
+    }
+
+    pub fn style(&self, kind: HighlightColor) -> Style {
+        self.styles
+            .get(&kind)
+            .copied()
+            .unwrap_or(Style::fg(DEFAULT_FG))
+    }
+}
@@ -0,0 +1,281 @@
+use crate::file_syntax::{FileSyntax, FileType};
+use crate::highlight::{highlight_doc_tags, HighlightColor};
+use crate::text_buffer::Lines;
+use std::ops::Range;
+use tree_sitter::{InputEdit, Node, Parser, Point, Tree};
+
+/// Highlighting backend which keeps a tree-sitter syntax tree of the buffer
+/// and reparses it incrementally on every row edit.
+pub struct TreeSitterHighlighter {
+    parser: Parser,
+    tree: Option<Tree>,
+    /// The text parsed, every row followed by `\n`
+    source: String,
+    /// Byte offset in `source` of every row, then of the end
+    offsets: Vec<usize>,
+    /// For telling doc comments from others
+    syntax: FileSyntax,
+}
+
+impl TreeSitterHighlighter {
+    pub fn for_syntax(syntax: FileSyntax) -> Option<Self> {
+        let language: tree_sitter::Language = match syntax.ftype {
+            FileType::Rust => tree_sitter_rust::LANGUAGE.into(),
+            FileType::C => tree_sitter_c::LANGUAGE.into(),
+            _ => return None,
+        };
+        let mut parser = Parser::new();
+        parser.set_language(&language).ok()?;
+        Some(TreeSitterHighlighter {
+            parser,
+            tree: None,
+            source: String::new(),
+            offsets: vec![0],
+            syntax,
+        })
+    }
+
+    /// Parses the whole buffer from scratch and returns the colors of every row.
+    pub fn parse(&mut self, lines: &(impl Lines + ?Sized)) -> Vec<Vec<HighlightColor>> {
+        self.source.clear();
+        self.offsets = vec![0];
+        for line in lines.all_lines() {
+            self.source.push_str(line);
+            self.source.push('\n');
+            self.offsets.push(self.source.len());
+        }
+        self.tree = self.parser.parse(&self.source, None);
+        self.colorize(0..self.row_count())
+    }
+
+    /// Replaces the row `at` and returns the rows whose colors changed.
+    pub fn update_row(&mut self, at: usize, line: &str) -> Vec<(usize, Vec<HighlightColor>)> {
+        let start = self.offsets[at];
+        let old_len = self.line(at).len();
+        self.splice(at..at, start..start + old_len, line);
+        self.reparse(
+            InputEdit {
+                start_byte: start,
+                old_end_byte: start + old_len,
+                new_end_byte: start + line.len(),
+                start_position: Point::new(at, 0),
+                old_end_position: Point::new(at, old_len),
+                new_end_position: Point::new(at, line.len()),
+            },
+            at..at + 1,
+        )
+    }
+
+    /// Puts `lines` before the row `at` and returns the rows whose colors changed.
+    pub fn insert_rows(&mut self, at: usize, lines: &[&str]) -> Vec<(usize, Vec<HighlightColor>)> {
+        let start = self.offsets[at];
+        let mut text = String::new();
+        for line in lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+        self.splice(at..at, start..start, &text);
+        self.reparse(
+            InputEdit {
+                start_byte: start,
+                old_end_byte: start,
+                new_end_byte: start + text.len(),
+                start_position: Point::new(at, 0),
+                old_end_position: Point::new(at, 0),
+                new_end_position: Point::new(at + lines.len(), 0),
+            },
+            at..at + lines.len(),
+        )
+    }
+
+    /// Takes out the rows `rows` and returns the rows whose colors changed.
+    pub fn remove_rows(&mut self, rows: Range<usize>) -> Vec<(usize, Vec<HighlightColor>)> {
+        let start = self.offsets[rows.start];
+        let end = self.offsets[rows.end];
+        self.splice(rows.clone(), start..end, "");
+        self.reparse(
+            InputEdit {
+                start_byte: start,
+                old_end_byte: end,
+                new_end_byte: start,
+                start_position: Point::new(rows.start, 0),
+                old_end_position: Point::new(rows.end, 0),
+                new_end_position: Point::new(rows.start, 0),
+            },
+            rows.start..rows.start,
+        )
+    }
+
+    fn row_count(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// The row `row`, without its `\n`.
+    fn line(&self, row: usize) -> &str {
+        &self.source[self.offsets[row]..self.offsets[row + 1] - 1]
+    }
+
+    /// Replaces the bytes `bytes` of the source, which start at the row
+    /// `rows.start` and take out the rows `rows` whole, with `text`, keeping
+    /// the offsets of the rows in step.
+    fn splice(&mut self, rows: Range<usize>, bytes: Range<usize>, text: &str) {
+        let start = bytes.start;
+        let removed = bytes.len();
+        self.source.replace_range(bytes, text);
+        let added = text.match_indices('\n').map(|(index, _)| start + index + 1);
+        let shifted: Vec<usize> = added
+            .chain(
+                self.offsets[rows.end + 1..]
+                    .iter()
+                    .map(|offset| offset - removed + text.len()),
+            )
+            .collect();
+        self.offsets.truncate(rows.start + 1);
+        self.offsets.extend(shifted);
+    }
+
+    fn reparse(
+        &mut self,
+        edit: InputEdit,
+        edited_rows: Range<usize>,
+    ) -> Vec<(usize, Vec<HighlightColor>)> {
+        let mut rows: Vec<usize> = edited_rows.collect();
+        match self.tree.take() {
+            Some(mut old_tree) => {
+                old_tree.edit(&edit);
+                self.tree = self.parser.parse(&self.source, Some(&old_tree));
+                if let Some(tree) = &self.tree {
+                    for range in old_tree.changed_ranges(tree) {
+                        rows.extend(range.start_point.row..=range.end_point.row);
+                    }
+                }
+            }
+            None => {
+                self.tree = self.parser.parse(&self.source, None);
+                rows.extend(0..self.row_count());
+            }
+        }
+        rows.sort_unstable();
+        rows.dedup();
+        rows.retain(|row| *row < self.row_count());
+        match (rows.first(), rows.last()) {
+            (Some(&first), Some(&last)) => {
+                let colors = self.colorize(first..last + 1);
+                rows.into_iter()
+                    .map(|row| (row, colors[row - first].clone()))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn colorize(&self, rows: Range<usize>) -> Vec<Vec<HighlightColor>> {
+        let mut colors: Vec<Vec<HighlightColor>> = rows
+            .clone()
+            .map(|row| vec![HighlightColor::Normal; self.line(row).chars().count()])
+            .collect();
+        if let Some(tree) = &self.tree {
+            self.paint_node(tree.root_node(), &rows, &mut colors);
+        }
+        for (row, colors) in rows.zip(&mut colors) {
+            highlight_doc_tags(self.line(row), colors, self.syntax.doc_comment_starts);
+        }
+        colors
+    }
+
+    fn paint_node(&self, node: Node, rows: &Range<usize>, colors: &mut [Vec<HighlightColor>]) {
+        if node.end_position().row < rows.start || node.start_position().row >= rows.end {
+            return;
+        }
+        if let Some(mut color) = node_color(&node) {
+            let start = node.start_position();
+            let text = (start.row < self.row_count())
+                .then(|| self.line(start.row))
+                .and_then(|line| line.get(start.column..));
+            if color == HighlightColor::Comment
+                && text.is_some_and(|text| self.syntax.is_doc_comment(text))
+            {
+                color = HighlightColor::DocComment;
+            }
+            self.paint_range(
+                node.start_position(),
+                node.end_position(),
+                color,
+                rows,
+                colors,
+            );
+            return;
+        }
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            self.paint_node(child, rows, colors);
+        }
+    }
+
+    fn paint_range(
+        &self,
+        start: Point,
+        end: Point,
+        color: HighlightColor,
+        rows: &Range<usize>,
+        colors: &mut [Vec<HighlightColor>],
+    ) {
+        let first = start.row.max(rows.start);
+        let last = end.row.min(rows.end - 1);
+        for row in first..=last {
+            let line = self.line(row);
+            let from = if row == start.row { start.column } else { 0 };
+            let to = if row == end.row {
+                end.column
+            } else {
+                line.len()
+            };
+            let from = line.get(..from).map_or(0, |s| s.chars().count());
+            let to = line
+                .get(..to.min(line.len()))
+                .map_or(0, |s| s.chars().count());
+            for c in colors[row - rows.start].iter_mut().take(to).skip(from) {
+                *c = color;
+            }
+        }
+    }
+}
+
+fn node_color(node: &Node) -> Option<HighlightColor> {
+    let kind = node.kind();
+    match kind {
+        "line_comment" | "block_comment" | "comment" => return Some(HighlightColor::Comment),
+        "string_literal" | "raw_string_literal" | "char_literal" | "system_lib_string" => {
+            return Some(HighlightColor::String)
+        }
+        "integer_literal" | "float_literal" | "number_literal" => {
+            return Some(HighlightColor::Number)
+        }
+        "boolean_literal" | "true" | "false" | "null" | "self" => {
+            return Some(HighlightColor::Keyword2)
+        }
+        "type_identifier" | "primitive_type" => return Some(HighlightColor::Type),
+        "field_identifier" | "shorthand_field_identifier" => return Some(HighlightColor::Field),
+        _ => {}
+    }
+    if kind == "identifier" {
+        let parent = node.parent()?;
+        let is_function = match parent.kind() {
+            "function_item" | "function_declarator" | "function_signature_item" => true,
+            "call_expression" | "macro_invocation" => parent
+                .child_by_field_name("function")
+                .or_else(|| parent.child_by_field_name("macro"))
+                .is_some_and(|f| f.id() == node.id()),
+            _ => false,
+        };
+        return if is_function {
+            Some(HighlightColor::Function)
+        } else {
+            None
+        };
+    }
+    if !node.is_named() && kind.chars().all(|c| c.is_ascii_alphabetic() || c == '_') {
+        return Some(HighlightColor::Keyword1);
+    }
+    None
+}
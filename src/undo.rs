@@ -0,0 +1,548 @@
+use crate::config::Config;
+use std::time::{Duration, Instant};
+
+/// A position in a buffer as (row, byte column).
+pub type Position = (usize, usize);
+
+/// One change of the text. `text` may span several lines joined with `\n`.
+#[derive(Debug, Clone)]
+pub enum Edit {
+    Insert { at: Position, text: String },
+    Delete { at: Position, text: String },
+}
+
+impl Edit {
+    /// Where the edited text ends, for an insertion the position after it.
+    pub fn end(&self) -> Position {
+        match self {
+            Edit::Insert { at, text } | Edit::Delete { at, text } => text_end(*at, text),
+        }
+    }
+}
+
+/// The position after `text` put at `at`.
+fn text_end(at: Position, text: &str) -> Position {
+    match text.rfind('\n') {
+        Some(newline) => (at.0 + text.matches('\n').count(), text.len() - newline - 1),
+        None => (at.0, at.1 + text.len()),
+    }
+}
+
+/// Where `position` is after `text` is inserted at `at`. A position right
+/// at `at` stays before the text.
+pub fn shift_inserted(position: Position, at: Position, text: &str) -> Position {
+    if position <= at {
+        return position;
+    }
+    let end = text_end(at, text);
+    if position.0 == at.0 {
+        (end.0, end.1 + position.1 - at.1)
+    } else {
+        (position.0 + end.0 - at.0, position.1)
+    }
+}
+
+/// Where `position` is after the text from `start` to `end` is deleted.
+/// A position inside it goes to `start`.
+pub fn shift_deleted(position: Position, start: Position, end: Position) -> Position {
+    if position <= start {
+        position
+    } else if position < end {
+        start
+    } else if position.0 == end.0 {
+        (start.0, start.1 + position.1 - end.1)
+    } else {
+        (position.0 - (end.0 - start.0), position.1)
+    }
+}
+
+/// Edits undone and redone together, with the cursor before and after them.
+#[derive(Debug, Clone)]
+pub struct UndoGroup {
+    pub edits: Vec<Edit>,
+    pub cursor_before: Position,
+    pub cursor_after: Position,
+}
+
+/// How consecutively typed characters are merged into one undo step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coalescing {
+    /// Every character is its own step
+    Char,
+    /// A word and the separators typed after it form one step
+    Word,
+    /// Everything typed without a pause longer than the duration is one step
+    Time(Duration),
+}
+
+impl Coalescing {
+    /// Reads `undo.group` (`char`, `word` or `time`) and `undo.group_ms`.
+    pub fn from_config(config: &Config) -> Coalescing {
+        match config.get("undo.group") {
+            Some("char") => Coalescing::Char,
+            Some("time") => Coalescing::Time(Duration::from_millis(
+                config
+                    .get("undo.group_ms")
+                    .and_then(|ms| ms.parse().ok())
+                    .unwrap_or(1000),
+            )),
+            _ => Coalescing::Word,
+        }
+    }
+}
+
+/// Kind of a recorded edit, deciding whether it may join the previous step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditKind {
+    /// A typed character
+    Typing(char),
+    /// A character removed with backspace or delete
+    Deleting(char),
+    /// Anything else, always a step of its own unless inside an explicit group
+    Other,
+}
+
+/// A state of the buffer in the undo tree, reached by applying `group` to its parent.
+struct UndoNode {
+    group: UndoGroup,
+    parent: usize,
+    children: Vec<usize>,
+    /// The child redo moves to: the one created or visited last
+    last_child: Option<usize>,
+    created: Instant,
+}
+
+/// One line of the undo tree as listed by `UndoHistory::tree`.
+pub struct TreeEntry {
+    /// Node number, 0 for the state before any edit
+    pub node: usize,
+    pub depth: usize,
+    /// Whether the node is one of several alternatives after the same state
+    pub starts_branch: bool,
+    pub is_current: bool,
+    pub edits: usize,
+    /// The first edit, for telling the entries apart
+    pub first_edit: Option<Edit>,
+    pub created: Instant,
+}
+
+/// Undo history kept as a tree: edits after an undo start a new branch
+/// instead of discarding the undone steps, so every earlier state stays
+/// reachable.
+pub struct UndoHistory {
+    /// Node 0 is the state before the first edit
+    nodes: Vec<UndoNode>,
+    current: usize,
+    /// The oldest state still reachable, node 0 unless edits of someone
+    /// else made the steps before it impossible to undo
+    root: usize,
+    coalescing: Coalescing,
+    /// Nesting depth of `begin_group`; while positive every edit joins the open group
+    group_depth: usize,
+    /// Whether the next edit starts a new step even inside an explicit group
+    group_started: bool,
+    /// Kind and time of the last recorded edit, while it may still be extended
+    last: Option<(EditKind, Instant)>,
+}
+
+impl UndoHistory {
+    pub fn new(coalescing: Coalescing) -> UndoHistory {
+        UndoHistory {
+            nodes: vec![UndoNode {
+                group: UndoGroup {
+                    edits: vec![],
+                    cursor_before: (0, 0),
+                    cursor_after: (0, 0),
+                },
+                parent: 0,
+                children: vec![],
+                last_child: None,
+                created: Instant::now(),
+            }],
+            current: 0,
+            root: 0,
+            coalescing,
+            group_depth: 0,
+            group_started: false,
+            last: None,
+        }
+    }
+
+    /// Makes the edits until the matching `end_group` a single undo step,
+    /// e.g. for a paste or a replace-all.
+    pub fn begin_group(&mut self) {
+        if self.group_depth == 0 {
+            self.group_started = true;
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        self.group_depth = self.group_depth.saturating_sub(1);
+        self.last = None;
+    }
+
+    /// Ends coalescing, so that the next edit starts a new undo step.
+    pub fn break_coalescing(&mut self) {
+        self.last = None;
+    }
+
+    pub fn record(&mut self, edit: Edit, kind: EditKind, before: Position, after: Position) {
+        let now = Instant::now();
+        let joins = if self.group_depth > 0 {
+            !std::mem::replace(&mut self.group_started, false)
+        } else {
+            self.joins_previous(kind, before, now)
+        };
+        // A step which already has branches below it is never extended
+        if joins && self.current != self.root && self.nodes[self.current].children.is_empty() {
+            let group = &mut self.nodes[self.current].group;
+            group.edits.push(edit);
+            group.cursor_after = after;
+        } else {
+            let node = self.nodes.len();
+            self.nodes.push(UndoNode {
+                group: UndoGroup {
+                    edits: vec![edit],
+                    cursor_before: before,
+                    cursor_after: after,
+                },
+                parent: self.current,
+                children: vec![],
+                last_child: None,
+                created: now,
+            });
+            let parent = &mut self.nodes[self.current];
+            parent.children.push(node);
+            parent.last_child = Some(node);
+            self.current = node;
+        }
+        self.last = Some((kind, now));
+    }
+
+    fn joins_previous(&self, kind: EditKind, before: Position, now: Instant) -> bool {
+        let (last_kind, last_time) = match self.last {
+            Some(last) => last,
+            None => return false,
+        };
+        if self.nodes[self.current].group.cursor_after != before {
+            return false;
+        }
+        match (last_kind, kind) {
+            (EditKind::Typing(previous), EditKind::Typing(c))
+            | (EditKind::Deleting(previous), EditKind::Deleting(c)) => match self.coalescing {
+                Coalescing::Char => false,
+                // A new word starts a new step; separators stay with the word before
+                Coalescing::Word => !is_word_char(c) || is_word_char(previous),
+                Coalescing::Time(window) => now.duration_since(last_time) <= window,
+            },
+            _ => false,
+        }
+    }
+
+    /// Moves the positions of every step over `edit`, made to the text by
+    /// someone else. Steps whose text it overlaps can't be undone or redone
+    /// any more: the newest such step leading to the current state becomes
+    /// the root, and other branches lose them and the steps after them.
+    #[cfg(feature = "sync")]
+    pub fn transform(&mut self, edit: &Edit) {
+        self.last = None;
+        let mut overlapped = vec![];
+        for (node, entry) in self.nodes.iter_mut().enumerate().skip(1) {
+            let group = &mut entry.group;
+            if group.edits.iter().any(|step| overlaps(step, edit)) {
+                overlapped.push(node);
+            }
+            for step in &mut group.edits {
+                let (Edit::Insert { at, .. } | Edit::Delete { at, .. }) = step;
+                *at = shift_over(*at, edit);
+            }
+            group.cursor_before = shift_over(group.cursor_before, edit);
+            group.cursor_after = shift_over(group.cursor_after, edit);
+        }
+        let path = self.ancestors(self.current);
+        if let Some(node) = path.iter().find(|node| overlapped.contains(node)) {
+            self.root = *node;
+        }
+        for node in overlapped {
+            if path.contains(&node) {
+                continue;
+            }
+            let parent = self.nodes[node].parent;
+            let parent = &mut self.nodes[parent];
+            parent.children.retain(|child| *child != node);
+            if parent.last_child == Some(node) {
+                parent.last_child = parent.children.last().copied();
+            }
+        }
+    }
+
+    /// Takes the current step for undoing it, moving to its parent state.
+    pub fn undo(&mut self) -> Option<UndoGroup> {
+        self.last = None;
+        if self.current == self.root {
+            return None;
+        }
+        let node = &self.nodes[self.current];
+        let group = node.group.clone();
+        let parent = node.parent;
+        self.nodes[parent].last_child = Some(self.current);
+        self.current = parent;
+        Some(group)
+    }
+
+    /// Takes the step into the most recent branch below the current state for redoing it.
+    pub fn redo(&mut self) -> Option<UndoGroup> {
+        let child = self.nodes[self.current].last_child?;
+        self.redo_into(child)
+    }
+
+    /// Takes the step into `child`, which must be a child of the current state.
+    fn redo_into(&mut self, child: usize) -> Option<UndoGroup> {
+        self.last = None;
+        if self.nodes.get(child)?.parent != self.current || child == self.root {
+            return None;
+        }
+        self.nodes[self.current].last_child = Some(child);
+        self.current = child;
+        Some(self.nodes[child].group.clone())
+    }
+
+    /// The steps leading from the current state to `target`: the groups to
+    /// undo up to the common ancestor followed by the groups to redo from it.
+    /// Moves the current state to `target`.
+    pub fn go_to(&mut self, target: usize) -> Option<(Vec<UndoGroup>, Vec<UndoGroup>)> {
+        if target >= self.nodes.len() {
+            return None;
+        }
+        let ancestors = self.ancestors(target);
+        if ancestors.last() != Some(&self.root) {
+            return None;
+        }
+        let mut undone = vec![];
+        while !ancestors.contains(&self.current) {
+            undone.extend(self.undo());
+        }
+        let mut redone = vec![];
+        let from = ancestors.iter().position(|node| *node == self.current)?;
+        for node in ancestors[..from].iter().rev() {
+            redone.extend(self.redo_into(*node));
+        }
+        Some((undone, redone))
+    }
+
+    /// `node` and the nodes above it, up to and including the root, or
+    /// node 0 for a node no longer reachable.
+    fn ancestors(&self, mut node: usize) -> Vec<usize> {
+        let mut ancestors = vec![node];
+        while node != self.root && node != 0 {
+            node = self.nodes[node].parent;
+            ancestors.push(node);
+        }
+        ancestors
+    }
+
+    /// Every state in depth-first order, older branches first.
+    pub fn tree(&self) -> Vec<TreeEntry> {
+        let mut entries = vec![];
+        let mut pending = vec![(self.root, 0, false)];
+        while let Some((node, depth, starts_branch)) = pending.pop() {
+            let entry = &self.nodes[node];
+            // Only the steps after the root can be undone
+            let edits = if node == self.root {
+                &[][..]
+            } else {
+                &entry.group.edits[..]
+            };
+            entries.push(TreeEntry {
+                node,
+                depth,
+                starts_branch,
+                is_current: node == self.current,
+                edits: edits.len(),
+                first_edit: edits.first().cloned(),
+                created: entry.created,
+            });
+            // A single child continues at the same depth, like a line of history
+            let forks = entry.children.len() > 1;
+            let child_depth = if forks { depth + 1 } else { depth };
+            for child in entry.children.iter().rev() {
+                pending.push((*child, child_depth, forks));
+            }
+        }
+        entries
+    }
+}
+
+/// Where `position` is after `edit`.
+#[cfg(feature = "sync")]
+fn shift_over(position: Position, edit: &Edit) -> Position {
+    match edit {
+        Edit::Insert { at, text } => shift_inserted(position, *at, text),
+        Edit::Delete { at, .. } => shift_deleted(position, *at, edit.end()),
+    }
+}
+
+/// Whether `edit` changes the text `step` put in, or takes out text
+/// around the place `step` took text out of.
+#[cfg(feature = "sync")]
+fn overlaps(step: &Edit, edit: &Edit) -> bool {
+    match (step, edit) {
+        (Edit::Insert { at: start, .. }, Edit::Insert { at, .. }) => start < at && *at < step.end(),
+        (Edit::Insert { at: start, .. }, Edit::Delete { at, .. }) => {
+            at < &step.end() && start < &edit.end()
+        }
+        (Edit::Delete { at: place, .. }, Edit::Delete { at, .. }) => {
+            at < place && place < &edit.end()
+        }
+        (Edit::Delete { .. }, Edit::Insert { .. }) => false,
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(at: Position, text: &str) -> Edit {
+        Edit::Insert {
+            at,
+            text: text.to_string(),
+        }
+    }
+
+    /// A history of one step per edit, each typed at the end of the one before.
+    fn history(edits: &[Edit]) -> UndoHistory {
+        let mut undo = UndoHistory::new(Coalescing::Char);
+        for edit in edits {
+            undo.break_coalescing();
+            undo.record(edit.clone(), EditKind::Other, edit.end(), edit.end());
+        }
+        undo
+    }
+
+    /// Records `text` typed a char at a time from the start of the line.
+    fn type_text(undo: &mut UndoHistory, text: &str) {
+        for (col, c) in text.char_indices() {
+            let edit = insert((0, col), &c.to_string());
+            undo.record(edit, EditKind::Typing(c), (0, col), (0, col + 1));
+        }
+    }
+
+    /// The text each step put in, from the newest.
+    fn undo_all(undo: &mut UndoHistory) -> Vec<String> {
+        std::iter::from_fn(|| undo.undo())
+            .map(|group| {
+                group
+                    .edits
+                    .iter()
+                    .map(|edit| match edit {
+                        Edit::Insert { text, .. } | Edit::Delete { text, .. } => text.as_str(),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn typing_is_grouped_by_word() {
+        let mut undo = UndoHistory::new(Coalescing::Word);
+        type_text(&mut undo, "ab cd, ef");
+        assert_eq!(undo_all(&mut undo), ["ef", "cd, ", "ab "]);
+        let mut undo = UndoHistory::new(Coalescing::Char);
+        type_text(&mut undo, "ab");
+        assert_eq!(undo_all(&mut undo), ["b", "a"]);
+    }
+
+    #[test]
+    fn moving_the_cursor_or_a_group_starts_a_new_step() {
+        let mut undo = UndoHistory::new(Coalescing::Time(Duration::from_secs(60)));
+        type_text(&mut undo, "ab");
+        // Typed somewhere else
+        undo.record(insert((3, 0), "x"), EditKind::Typing('x'), (3, 0), (3, 1));
+        undo.begin_group();
+        undo.record(insert((0, 0), "1"), EditKind::Other, (0, 0), (0, 1));
+        undo.record(insert((5, 0), "2"), EditKind::Other, (5, 0), (5, 1));
+        undo.end_group();
+        assert_eq!(undo_all(&mut undo), ["12", "x", "ab"]);
+    }
+
+    #[test]
+    fn edits_after_an_undo_start_a_branch() {
+        let mut undo = history(&[insert((0, 0), "a"), insert((0, 1), "b")]);
+        undo.undo();
+        undo.break_coalescing();
+        undo.record(insert((0, 1), "c"), EditKind::Other, (0, 1), (0, 2));
+        let tree: Vec<(usize, usize, bool, bool)> = undo
+            .tree()
+            .iter()
+            .map(|entry| {
+                (
+                    entry.node,
+                    entry.depth,
+                    entry.starts_branch,
+                    entry.is_current,
+                )
+            })
+            .collect();
+        assert_eq!(
+            tree,
+            [
+                (0, 0, false, false),
+                (1, 0, false, false),
+                (2, 1, true, false),
+                (3, 1, true, true)
+            ]
+        );
+        // Back to the undone branch: undo c, redo b
+        let (undone, redone) = undo.go_to(2).unwrap();
+        assert!(matches!(&undone[0].edits[..], [Edit::Insert { text, .. }] if text == "c"));
+        assert!(matches!(&redone[0].edits[..], [Edit::Insert { text, .. }] if text == "b"));
+        // Redo follows the branch visited last
+        undo.undo();
+        assert!(
+            matches!(&undo.redo().unwrap().edits[..], [Edit::Insert { text, .. }] if text == "b")
+        );
+        assert!(undo.go_to(9).is_none());
+    }
+
+    #[test]
+    fn positions_shift_over_inserts_and_deletes() {
+        assert_eq!(shift_inserted((0, 5), (0, 2), "ab"), (0, 7));
+        assert_eq!(shift_inserted((0, 5), (0, 2), "a\nbc"), (1, 5));
+        assert_eq!(shift_inserted((2, 1), (0, 2), "a\nb"), (3, 1));
+        assert_eq!(shift_inserted((0, 2), (0, 2), "ab"), (0, 2));
+        assert_eq!(shift_deleted((0, 5), (0, 1), (0, 3)), (0, 3));
+        assert_eq!(shift_deleted((0, 2), (0, 1), (0, 3)), (0, 1));
+        assert_eq!(shift_deleted((2, 4), (0, 1), (2, 2)), (0, 3));
+        assert_eq!(shift_deleted((3, 4), (0, 1), (2, 2)), (1, 4));
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn steps_move_over_edits_of_someone_else() {
+        let mut undo = history(&[insert((1, 0), "abc")]);
+        undo.transform(&insert((0, 0), "x\n"));
+        let group = undo.undo().unwrap();
+        assert!(matches!(
+            &group.edits[..],
+            [Edit::Insert { at: (2, 0), .. }]
+        ));
+        assert_eq!(group.cursor_after, (2, 3));
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn steps_overlapped_by_someone_else_can_no_longer_be_undone() {
+        let mut undo = history(&[insert((0, 0), "abc"), insert((0, 3), "def")]);
+        // Into the text of the first step, which stays done
+        undo.transform(&insert((0, 1), "x"));
+        assert!(undo.undo().is_some());
+        assert!(undo.undo().is_none());
+        assert!(undo.redo().is_some());
+        assert_eq!(undo.tree().len(), 2);
+    }
+}
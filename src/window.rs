@@ -1,11 +1,84 @@
+#[cfg(feature = "scripting")]
+use crate::script::{ScriptAction, Scripts, Snapshot};
+#[cfg(feature = "sync")]
+use crate::sync::{self, Change, Op, Replica, Session, SyncEvent};
 use crate::{
-    highlight::Highlight,
-    input::{CursorMoveDirection, LoopStatus, RawMode},
+    abbrev::{self, Abbrevs},
+    backup::backup_file,
+    buffer::{
+        char_cells, cluster_cells, split_lines, Buffer, BufferOptions, Cluster, LineChange,
+        LineEnding, Region, RenderRow, BOM,
+    },
+    cargo_check::{self, CargoCheck, Level},
+    completion::{self, POPUP_HEIGHT},
+    config::Config,
+    crypt::{Cipher, Encrypted},
+    cursor::CursorStyle,
+    describe,
+    digraph::Digraphs,
+    export,
+    file_syntax::{FileSyntax, FileType},
+    fileio::{FileIo, IoEvent, IoKind},
+    git::{self, BlameLine, FileDiff, BLAME_WIDTH},
+    goto,
+    hex::{HexView, BYTES_PER_ROW},
+    highlight::{Highlight, HighlightColor, LONG_LINE},
+    hooks::{Builtin, Handler, Hook, Hooks},
+    i18n,
+    idle::{IdleTask, IdleTasks, IDLE_SLICE},
+    indent,
+    input::{
+        CursorMoveDirection, InputType, LoopStatus, MouseButton, MouseEvent, RawMode, CTRL_G,
+        CTRL_W, CTRL_X, ENABLE_MOUSE,
+    },
+    journal::Journal,
+    kill_ring::{KillAction, KillRing},
+    lock,
+    macros::{KeyBinding, Macros},
+    message::{MessageLevel, MessageQueue},
+    peers::PeerCursor,
+    picker::Picker,
+    plugin::{PluginCommand, Plugins},
+    preview::{self, Preview},
+    remote::RemotePath,
+    runner::Job,
+    start::{StartItem, StartScreen},
+    state::{CursorPositions, RecentFiles},
+    tags,
+    terminal::Terminal,
+    text_buffer::{Lines, TextBuffer},
+    theme::{Background, Style, Theme, DEFAULT_FG},
+    undo::{shift_deleted, shift_inserted, Edit, EditKind, Position, UndoGroup, UndoHistory},
 };
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// What saving does with a file opened through a symbolic link (`save.symlinks`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symlinks {
+    /// Edit the target file; the buffer is bound to the resolved path
+    Follow,
+    /// Keep the link path for the buffer and write through the link
+    Keep,
+    /// Replace the link with a regular file when saving
+    Break,
+}
+
+/// Answer to a question asked with `Window::confirm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    /// Yes for this and every following question of the same kind
+    All,
+    /// Stop asking, leaving the rest as it is
+    Quit,
+}
 
 #[derive(PartialEq, Eq)]
 pub enum SearchDirection {
@@ -14,60 +87,177 @@ pub enum SearchDirection {
 }
 
 pub struct Window {
-    pub cx: usize, // 文字列上でのカーソル位置
     pub rx: usize, // 実際にレンダリングされたカーソル位置
-    pub cy: usize,
     pub rows: usize,
     pub columns: usize,
-    pub row_offset: usize,
-    pub col_offset: usize,
     pub stdout: io::Stdout,
     pub text_buffer: String,
-    pub content_buffer: Vec<String>,
-    pub render_buffer: Vec<String>,
-    pub filename: Option<PathBuf>,
-    pub status_message: String,
-    pub message_time: Instant,
-    pub dirty: bool,
-    pub quit_confirming: bool,
+    /// The buffer being displayed and edited
+    pub buffer: Buffer,
+    /// Other open buffers, most recently used first
+    pub buffers: Vec<Buffer>,
+    pub next_buffer_id: usize,
+    pub messages: MessageQueue,
     pub search_last_match: Option<usize>,
     pub search_direction: SearchDirection,
-    pub highlight: Highlight,
+    /// File rows shown on screen when the view is filtered (e.g. only the
+    /// lines matching the search query). `row_offset` then indexes this list.
+    pub view_filter: Option<Vec<usize>>,
+    /// Extra information shown after the prompt text, e.g. the number of matches.
+    pub prompt_info: String,
+    /// Picker drawn instead of the text while the user chooses an item
+    pub picker: Option<Picker>,
+    /// Completions shown in a popup at the cursor, narrowed as the word is typed
+    pub completion: Option<Picker>,
+    /// Documentation shown in a popup until the cursor leaves the position
+    pub hover: Option<(Position, Vec<String>)>,
+    /// Menu shown over the text at startup without a file
+    pub start_screen: Option<StartScreen>,
+    /// Shell shown in a pane below the text
+    pub terminal: Option<Terminal>,
+    /// Whether keys go to the terminal pane instead of the buffer
+    pub terminal_focused: bool,
+    /// File shown read-only in a pane below the text, which gets the keys
+    pub preview: Option<Preview>,
+    /// Text copied or pasted so far, to insert again from a picker
+    pub kill_ring: KillRing,
+    /// Command running in the background with its output in `*Output*`
+    pub job: Option<Job>,
+    /// The command last run, for running it again
+    pub last_job: Option<String>,
+    /// `cargo check` running for the diagnostics of the package
+    pub cargo_check: Option<CargoCheck>,
+    /// External programs handling events and keys, see `Plugin`
+    pub plugins: Plugins,
+    /// What runs when editor events happen
+    pub hooks: Hooks,
+    /// Whether the idle hook ran since the last key press
+    idle_hook_ran: bool,
+    /// Work done while no key is pressed
+    pub idle_tasks: IdleTasks,
+    /// Commands, key bindings and hooks of the init script
+    #[cfg(feature = "scripting")]
+    pub scripts: Option<Scripts>,
+    /// The buffer edited together with another instance, see `Session`
+    #[cfg(feature = "sync")]
+    pub sync: Option<Session>,
+    /// Region the running search is restricted to
+    pub search_scope: Option<Region>,
+    pub cursor_style: CursorStyle,
+    /// Whether the terminal has the focus, as told by focus events
+    pub focused: bool,
+    /// Time, screen cell and count of the last click, for double and triple clicks
+    last_click: Option<(Instant, usize, usize, usize)>,
+    /// Where the left button was pressed, while it may still be dragged
+    drag_anchor: Option<Position>,
+    /// The cursor style last sent to the terminal
+    shown_cursor_style: Option<CursorStyle>,
+    pub theme: Theme,
+    /// The background color of the terminal, if it told it
+    pub background: Option<Background>,
+    pub digraphs: Digraphs,
+    pub abbrevs: Abbrevs,
+    pub config: Config,
+    /// Modification time of the config file when it was last read
+    config_mtime: Option<SystemTime>,
+    /// Where the project config is looked for, see `Config::load_for`
+    project_dir: PathBuf,
+    /// Started with `--clean`: no config file is read, even when it changes
+    pub clean: bool,
+    /// When the config file was last looked at for changes
+    config_checked: Instant,
 }
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const KILO_TAB_STOP: usize = 8;
-const DISPLAY_STATUS_MESSAGE_DURATION: u64 = 3;
-const DEFAULT_COLOR: u8 = 39;
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Lines of documentation the hover popup shows at most.
+const HOVER_HEIGHT: usize = 10;
+/// Longest time between the clicks of a double or triple click.
+const MULTI_CLICK_INTERVAL: Duration = Duration::from_millis(400);
+
+/// How long reading or writing a file may keep the screen from being
+/// drawn before the rest is done in the background.
+const IO_WAIT: Duration = Duration::from_millis(100);
+
+/// Time spent taking in loaded lines between two looks at the keyboard.
+const IO_SLICE: Duration = Duration::from_millis(50);
+
+/// The smallest window the editor draws itself in: columns, and rows of text.
+const MIN_COLUMNS: usize = 20;
+const MIN_ROWS: usize = 3;
+
+/// How often the config file is looked at for changes.
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_STYLE: Style = Style::fg(DEFAULT_FG);
 
 impl Window {
-    pub fn new(mut stdin: &mut io::Stdin) -> Result<Window, io::Error> {
+    /// The editor on a screen of `size` columns and rows, or of the size
+    /// of the terminal if not given.
+    pub fn new(
+        stdin: &mut io::Stdin,
+        size: Option<(u16, u16)>,
+        config: Config,
+        theme: Theme,
+        digraphs: Digraphs,
+        abbrevs: Abbrevs,
+        cursor_style: CursorStyle,
+    ) -> Result<Window, io::Error> {
         let mut stdout = io::stdout();
-        match get_window_size(&mut stdin, &mut stdout) {
+        if config.get_bool("editor.mouse").unwrap_or(true) {
+            write!(stdout, "{}", ENABLE_MOUSE)?;
+        }
+        let size = match size {
+            Some(size) => Ok(Some(size)),
+            None => get_window_size(stdin, &mut stdout),
+        };
+        match size {
             Ok(Some((columns, rows))) => Ok(Window {
-                cx: 0,
                 rx: 0,
-                cy: 0,
                 columns: columns as usize,
-                rows: (rows as usize) - 2,
-                row_offset: 0,
-                col_offset: 0,
+                rows: (rows as usize).saturating_sub(2),
                 stdout,
                 text_buffer: String::new(),
-                content_buffer: vec![],
-                render_buffer: vec![],
-                filename: None,
-                status_message: String::new(),
-                message_time: Instant::now(),
-                dirty: false,
-                quit_confirming: false,
+                buffer: Buffer::new(0, BufferOptions::from_config(&config)),
+                buffers: vec![],
+                next_buffer_id: 1,
+                messages: MessageQueue::default(),
                 search_last_match: None,
                 search_direction: SearchDirection::Forward,
-                highlight: Highlight {
-                    syntax: crate::file_syntax::FileSyntax::new(),
-                    highlights: vec![],
-                    in_comment: vec![],
-                },
+                view_filter: None,
+                prompt_info: String::new(),
+                picker: None,
+                completion: None,
+                hover: None,
+                start_screen: None,
+                terminal: None,
+                terminal_focused: false,
+                preview: None,
+                kill_ring: KillRing::default(),
+                job: None,
+                last_job: None,
+                cargo_check: None,
+                plugins: Plugins::default(),
+                hooks: Hooks::default(),
+                idle_hook_ran: false,
+                idle_tasks: IdleTasks::default(),
+                #[cfg(feature = "scripting")]
+                scripts: None,
+                #[cfg(feature = "sync")]
+                sync: None,
+                search_scope: None,
+                cursor_style,
+                focused: true,
+                last_click: None,
+                drag_anchor: None,
+                shown_cursor_style: None,
+                theme,
+                background: None,
+                digraphs,
+                abbrevs,
+                config,
+                config_mtime: None,
+                project_dir: PathBuf::new(),
+                clean: false,
+                config_checked: Instant::now(),
             }),
             Ok(_) => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -78,485 +268,5925 @@ impl Window {
     }
 
     fn editor_draw_status_bar(&mut self) {
-        let filename = if let Some(path) = &self.filename {
-            match path.file_name() {
-                Some(osstr) => osstr.to_str().unwrap_or("[NO NAME]").to_string(),
-                None => "[NO NAME]".to_string(),
-            }
+        let mut filename = self.buffer.display_name();
+        if let Some(target) = &self.buffer.link_target {
+            filename.push_str(&format!(" -> {}", target.display()));
+        }
+        let dirty_symbol = if self.buffer.dirty { "*" } else { "" };
+        let read_only_symbol = if self.buffer.read_only { " [RO]" } else { "" };
+        let writable_symbol = if self.buffer.disk_writable {
+            ""
+        } else {
+            " [not writable]"
+        };
+        let narrow_symbol = if self.buffer.narrow.is_some() {
+            " [Narrow]"
         } else {
-            "[NO NAME]".to_string()
-        };
-        let dirty_symbol = if self.dirty { "*" } else { "" };
-        let status_left = format!("{}{}", filename, dirty_symbol);
-        let file_type = self.highlight.syntax.ftype;
-        let status_right = format!(
-            "{} | {}/{}",
-            file_type,
-            self.cy + 1,
-            self.content_buffer.len()
+            ""
+        };
+        let io_progress = match &self.buffer.io {
+            Some(io) => match io.kind {
+                IoKind::Loading => format!(" [loading {}%]", io.progress()),
+                IoKind::Saving { .. } => format!(" [saving {}%]", io.progress()),
+                IoKind::Fetching => format!(" [fetching {} KB]", io.done() / 1024),
+                IoKind::Uploading => " [uploading]".to_string(),
+            },
+            None => String::new(),
+        };
+        let status_left = format!(
+            "{}{}{}{}{}{}",
+            filename, dirty_symbol, read_only_symbol, writable_symbol, narrow_symbol, io_progress
         );
-        self.text_buffer.push_str(&format!(
-            "\x1b[7m{}{}{}\x1b[m\r\n",
-            status_left,
-            (0..(self.columns - (status_left.len() + status_right.len())))
-                .map(|_| " ")
-                .collect::<String>(),
-            status_right
-        ));
+        let file_type = self.buffer.highlight.syntax.ftype;
+        let bom = match self.buffer.content_buffer.first() {
+            Some(line) if line.starts_with(BOM) => " BOM",
+            _ => "",
+        };
+        let status_right = match &self.buffer.hex {
+            Some(hex) => format!("Hex | 0x{:x}/0x{:x}", hex.cursor, hex.bytes.len()),
+            None => format!(
+                "{} | {}{} | {}/{}",
+                file_type,
+                self.buffer.eol.name(),
+                bom,
+                self.buffer.cy + 1,
+                self.buffer.content_buffer.len()
+            ),
+        };
+        let theme = &self.theme;
+        let (left, fill, right) = (
+            theme.status_bar,
+            theme.status_bar_fill(),
+            theme.status_bar_right(),
+        );
+        // Every char takes one cell, as in the rows of the text
+        let cells = |text: &str| text.chars().count();
+        let right_width = cells(&status_right).min(self.columns);
+        let left_width =
+            cells(&status_left).min(self.columns.saturating_sub(
+                right_width + cells(&theme.separator) + cells(&theme.right_separator),
+            ));
+        let mut parts = vec![
+            (left, status_left.chars().take(left_width).collect()),
+            (separator_style(left, fill), theme.separator.clone()),
+            (fill, String::new()),
+            (separator_style(fill, right), theme.right_separator.clone()),
+            (right, status_right.chars().take(right_width).collect()),
+        ];
+        let used: usize = parts.iter().map(|(_, text)| cells(text)).sum();
+        parts[2].1 = " ".repeat(self.columns.saturating_sub(used));
+        // Dimmed while another window has the focus
+        let dim = if self.focused { "" } else { "\x1b[2m" };
+        let mut text = String::new();
+        let mut width = 0;
+        for (style, part) in parts {
+            let part: String = part.chars().take(self.columns - width).collect();
+            width += cells(&part);
+            text.push_str(&format!("{}{}{}", style.sgr(), dim, part));
+        }
+        text.push_str("\x1b[m\r\n");
+        self.text_buffer.push_str(&text);
     }
 
     fn editor_draw_message_bar(&mut self) {
-        self.text_buffer.push_str("\x1b[K");
-        if Instant::now() - self.message_time < Duration::from_secs(DISPLAY_STATUS_MESSAGE_DURATION)
-        {
-            self.text_buffer.push_str(&self.status_message);
-        }
+        let style = self.theme.message_bar.sgr();
+        let line = self.messages.render(self.columns, &style);
+        self.text_buffer
+            .push_str(&format!("{}\x1b[K{}\x1b[m", style, line));
     }
 
     pub fn editor_set_status_mssage<T: ToString>(&mut self, message: T) {
-        self.status_message = message.to_string();
-        self.message_time = Instant::now();
+        self.push_message(MessageLevel::Info, message);
+    }
+
+    pub fn push_message<T: ToString>(&mut self, level: MessageLevel, message: T) {
+        self.messages.push(level, i18n::tr(&message.to_string()));
+    }
+
+    /// Flags the buffer as modified, locking its file on the first change.
+    fn set_dirty(&mut self) {
+        if !self.buffer.dirty {
+            if let Some(path) = &self.buffer.filename {
+                if let Err(e) = lock::acquire(path) {
+                    let message = format!("Warning: {} is {}", self.buffer.display_name(), e);
+                    self.push_message(MessageLevel::Warn, message);
+                }
+            }
+        }
+        self.buffer.dirty = true;
+    }
+
+    /// Refuses edits to read-only buffers and to buffers whose file is
+    /// being read or written, telling the user why.
+    fn check_writable(&mut self) -> bool {
+        let reason = match &self.buffer.io {
+            Some(io) => match io.kind {
+                IoKind::Loading | IoKind::Fetching => "Buffer is loading",
+                IoKind::Saving { .. } | IoKind::Uploading => "Buffer is saving",
+            },
+            None if self.buffer.read_only => "Buffer is read-only",
+            None => return true,
+        };
+        self.editor_set_status_mssage(reason);
+        false
     }
 
     pub fn insert_char(&mut self, c: char) {
+        if !self.check_writable() {
+            return;
+        }
+        if let Some(hex) = &mut self.buffer.hex {
+            match hex.input(c) {
+                Ok(()) => {
+                    if let Some(path) = &self.buffer.filename {
+                        self.buffer.journal.stop(path);
+                    }
+                    self.set_dirty();
+                }
+                Err(e) => self.editor_set_status_mssage(e),
+            }
+            return;
+        }
+        let expanded = !abbrev::is_word_char(c) && self.expand_abbrev();
+        self.insert_typed(c);
+        if expanded {
+            self.buffer.undo.end_group();
+        }
+        if self.buffer.options.auto_indent {
+            self.electric_dedent();
+        }
+    }
+
+    /// Moves a line starting with what was just typed, e.g. a `}` or Ruby
+    /// `end`, to the indentation of the construct it closes, as an undo
+    /// step of its own.
+    fn electric_dedent(&mut self) {
+        let (row, col) = (self.buffer.cy, self.buffer.cx);
+        let line = match self.buffer.content_buffer.get(row) {
+            Some(line) => &line[..col],
+            None => return,
+        };
+        let indent = indent::indent_of(line);
+        let ftype = self.buffer.highlight.syntax.ftype;
+        let lines = &self.buffer.content_buffer;
+        let target = match indent::closing_indent(lines, row, &line[indent.len()..], ftype) {
+            Some(target) if target != indent => target,
+            _ => return,
+        };
+        let indent_len = indent.len();
+        if indent.starts_with(&target) {
+            self.delete_text((row, target.len()), (row, indent_len), EditKind::Other);
+        } else {
+            self.buffer.undo.begin_group();
+            self.delete_text((row, 0), (row, indent_len), EditKind::Other);
+            self.insert_text((row, 0), &target, EditKind::Other);
+            self.buffer.undo.end_group();
+        }
+        self.buffer.cx = col - indent_len + target.len();
+    }
+
+    /// Replaces the abbreviation before the cursor by its expansion. When it
+    /// does, an undo group is left open for the separator typed after it,
+    /// so that a single undo restores the abbreviation.
+    fn expand_abbrev(&mut self) -> bool {
+        if !self.buffer.options.abbrevs || self.abbrevs.is_empty() {
+            return false;
+        }
+        let (row, end) = (self.buffer.cy, self.buffer.cx);
+        let line = match self.buffer.content_buffer.get(row) {
+            Some(line) => line,
+            None => return false,
+        };
+        let start = word_start(line, end);
+        let expansion = match self
+            .abbrevs
+            .get(self.buffer.highlight.syntax.ftype, &line[start..end])
+        {
+            Some(expansion) if start < end => expansion.to_string(),
+            _ => return false,
+        };
+        self.buffer.undo.begin_group();
+        self.delete_text((row, start), (row, end), EditKind::Other);
+        self.insert_text((row, start), &expansion, EditKind::Other);
+        true
+    }
+
+    /// Inserts a typed character at the cursor, as spaces for a tab if tabs are expanded.
+    fn insert_typed(&mut self, c: char) {
         use std::cmp::min;
-        if self.cy == self.content_buffer.len() {
-            self.content_buffer.push(String::new());
-            self.editor_insert_row(0);
+        if c == '\t' && self.buffer.options.expand_tab {
+            let rx = match self.buffer.content_buffer.get(self.buffer.cy) {
+                Some(line) => self.cx_to_rx(line, self.buffer.cx),
+                None => 0,
+            };
+            for _ in 0..char_cells('\t', rx, self.buffer.options.tab_width) {
+                self.insert_typed(' ');
+            }
+            return;
         }
-        let at = min(self.cx, self.content_buffer[self.cy].len());
-        self.content_buffer[self.cy].insert(at, c);
-        self.editor_update_row(self.cy);
-        self.cx += 1;
-        self.dirty = true;
+        let at = match self.buffer.content_buffer.get(self.buffer.cy) {
+            Some(line) => min(self.buffer.cx, line.len()),
+            None => 0,
+        };
+        self.insert_text((self.buffer.cy, at), &c.to_string(), EditKind::Typing(c));
+        self.set_dirty();
     }
 
     pub fn delete_char(&mut self) {
-        if self.cy == self.rows {
+        if !self.check_writable() {
+            return;
+        }
+        if let Some(hex) = &mut self.buffer.hex {
+            // Bytes are only overwritten in hex mode, never removed
+            hex.move_by(-1);
+            return;
+        }
+        // Nothing to delete on the empty row past the last line, so the
+        // cursor just goes back to the end of the text
+        if self.buffer.cy >= self.buffer.content_buffer.len() {
+            if let Some(last) = self.buffer.content_buffer.len().checked_sub(1) {
+                self.buffer.cy = last;
+                self.buffer.cx = self.buffer.content_buffer[last].len();
+            }
+            return;
+        }
+        // Nothing before the first line, or the first narrowed line
+        let first_row = self.buffer.narrow.as_ref().map_or(0, |narrow| narrow.start);
+        if self.buffer.cx == 0 && self.buffer.cy <= first_row {
             return;
         }
-        if self.cx == 0 && self.cy == 0 {
+        let end = (self.buffer.cy, self.buffer.cx);
+        if self.buffer.cx > 0 {
+            let line = &self.buffer.content_buffer[self.buffer.cy];
+            let previous = previous_grapheme_boundary(line, self.buffer.cx);
+            let c = line[previous..].chars().next().unwrap_or(' ');
+            self.delete_text((self.buffer.cy, previous), end, EditKind::Deleting(c));
+        } else {
+            let previous_len = self.buffer.content_buffer[self.buffer.cy - 1].len();
+            self.delete_text(
+                (self.buffer.cy - 1, previous_len),
+                end,
+                EditKind::Deleting('\n'),
+            );
+        }
+        self.set_dirty();
+    }
+
+    /// Deletes the character under the cursor, or joins the next line at
+    /// the end of a line. The cursor stays where it is.
+    pub fn delete_char_forward(&mut self) {
+        if !self.check_writable() || self.buffer.hex.is_some() {
             return;
         }
-        if self.cx > 0 {
-            self.content_buffer[self.cy].remove(self.cx - 1);
-            self.cx -= 1;
-            self.editor_update_row(self.cy);
+        let (row, col) = (self.buffer.cy, self.buffer.cx);
+        let line = match self.buffer.content_buffer.get(row) {
+            Some(line) => line,
+            None => return,
+        };
+        if col < line.len() {
+            let c = line[col..].chars().next().unwrap_or(' ');
+            let next = next_grapheme_boundary(line, col);
+            self.delete_text((row, col), (row, next), EditKind::Deleting(c));
         } else {
-            self.cx = self.content_buffer[self.cy - 1].len();
-            let line = &self.content_buffer[self.cy].clone();
-            self.content_buffer[self.cy - 1].push_str(&line);
-            self.editor_update_row(self.cy - 1);
-            self.content_buffer.remove(self.cy);
-            self.render_buffer.remove(self.cy);
-            self.highlight.remove_row(self.cy);
-            self.cy -= 1;
+            // Nothing after the last line, or the last narrowed line
+            let end_row = self
+                .buffer
+                .narrow
+                .as_ref()
+                .map_or(self.buffer.content_buffer.len(), |narrow| narrow.end);
+            if row + 1 >= end_row {
+                return;
+            }
+            self.delete_text((row, line.len()), (row + 1, 0), EditKind::Deleting('\n'));
         }
-        self.dirty = true;
+        self.set_dirty();
     }
 
     pub fn break_line(&mut self) {
-        let line = &self.content_buffer[self.cy].clone();
-        let remain = &line[..self.cx];
-        let rest = &line[self.cx..line.len()];
-        self.content_buffer[self.cy] = remain.to_string();
-        self.content_buffer.insert(self.cy + 1, rest.to_string());
-        self.editor_insert_row(self.cy + 1);
-        self.editor_update_row(self.cy);
-        self.cy += 1;
-        self.cx = 0;
-        self.dirty = true;
+        if self.buffer.occur_source.is_some() {
+            self.occur_goto();
+            return;
+        }
+        if self.buffer.undo_tree_source.is_some() {
+            self.undo_tree_goto();
+            return;
+        }
+        if let Some(source) = self.buffer.revision_source {
+            // Flips back to the working copy at the same line for comparing
+            let row = self.buffer.cy;
+            if self.switch_to_buffer(source) {
+                self.buffer.cy = row.min(self.buffer.content_buffer.len());
+                self.buffer.cx = 0;
+            } else {
+                self.editor_set_status_mssage("The buffer of the working copy is gone");
+            }
+            return;
+        }
+        if !self.check_writable() || self.buffer.hex.is_some() {
+            return;
+        }
+        let expanded = self.expand_abbrev();
+        let (row, col) = (self.buffer.cy, self.buffer.cx);
+        let (text, cursor) = self.line_break(row, col);
+        self.insert_text((row, col), &text, EditKind::Typing('\n'));
+        if let Some(cursor) = cursor {
+            (self.buffer.cy, self.buffer.cx) = cursor;
+        }
+        if expanded {
+            self.buffer.undo.end_group();
+        }
+        self.set_dirty();
     }
 
-    pub fn refresh_screen(&mut self) -> io::Result<()> {
-        self.editor_scroll();
-        self.text_buffer.push_str("\x1b[?25l\x1b[H");
-        self.editor_draw_rows()?;
-        self.editor_draw_status_bar();
-        self.editor_draw_message_bar();
-        self.text_buffer.push_str(&format!(
-            "\x1b[{};{}H",
-            (self.cy - self.row_offset) + 1,
-            (self.rx - self.col_offset) + 1
-        ));
-        self.text_buffer.push_str("\x1b[?25h");
-        write!(self.stdout, "{}", self.text_buffer)?;
-        self.stdout.flush()?;
-        self.text_buffer.clear();
-        Ok(())
+    /// The text Enter inserts at `col` of `row`, and where the cursor goes
+    /// if not after it. With `auto_indent` the new line is indented like
+    /// the broken one; between a bracket and its closing one, e.g. in
+    /// `{}`, the closing bracket goes to a line of its own and the cursor
+    /// to an empty line in between, indented one level more.
+    fn line_break(&self, row: usize, col: usize) -> (String, Option<Position>) {
+        if let Some(leader) = self.comment_leader(row, col) {
+            return (format!("\n{}", leader), None);
+        }
+        let line = match self.buffer.content_buffer.get(row) {
+            Some(line) if self.buffer.options.auto_indent => line,
+            _ => return ("\n".to_string(), None),
+        };
+        let before = &line[..col];
+        let indent = indent::indent_of(before);
+        let pair = (before.chars().next_back(), line[col..].chars().next());
+        if let (Some('{'), Some('}')) | (Some('('), Some(')')) | (Some('['), Some(']')) = pair {
+            let inner = format!("{}{}", indent, self.buffer.options.indent_unit());
+            let text = format!("\n{}\n{}", inner, indent);
+            return (text, Some((row + 1, inner.len())));
+        }
+        (format!("\n{}", indent), None)
     }
 
-    fn editor_draw_rows(&mut self) -> io::Result<()> {
-        use std::cmp::min;
-        for y in 0..self.rows {
-            let filerow = y + self.row_offset;
-            if self.filename.is_none() && filerow >= self.content_buffer.len() {
-                if self.content_buffer.len() == 0 && y == self.rows / 3 {
-                    let welcome = format!("Kilo in Rust -- version {}", VERSION);
-                    let mut padding = (self.columns - welcome.len()) / 2;
-                    if padding > 0 {
-                        self.text_buffer.push_str("~");
-                        padding -= 1;
-                    }
-                    for _ in 0..padding {
-                        self.text_buffer.push_str(" ");
-                    }
-                    self.text_buffer
-                        .push_str(&welcome[..min(welcome.len(), self.columns)])
-                } else {
-                    self.text_buffer.push_str("~");
-                }
-            } else {
-                if let Some(line) = &self.render_buffer.get(filerow) {
-                    let line_min = if line.len() > 0 && self.col_offset < line.len() {
-                        self.col_offset
-                    } else {
-                        0
-                    };
-                    let line_max = if self.col_offset < line.len() {
-                        min(line.len(), self.columns + self.col_offset)
-                    } else {
-                        0
-                    };
-                    self.text_buffer.push_str("\x1b[39m");
-                    let mut last_color = DEFAULT_COLOR;
-                    for (ci, chr) in line[line_min..line_max].chars().enumerate() {
-                        if chr.is_control() {
-                            let mut bytes = [0; 2];
-                            chr.encode_utf8(&mut bytes);
-                            // Ctrl-A to Ctrl-Z
-                            let converted_chr = if bytes[0] <= 26 {
-                                char::from(bytes[0] + b'@')
-                            } else {
-                                '?'
-                            };
-                            self.text_buffer
-                                .push_str(&format!("\x1b[7m{}\x1b[m", converted_chr));
-                            if last_color != DEFAULT_COLOR {
-                                self.text_buffer.push_str(&format!("\x1b[{}m", last_color));
-                            }
-                        } else {
-                            let color = self.highlight.color(filerow, ci + line_min);
-                            if last_color != color {
-                                self.text_buffer.push_str(&format!("\x1b[{}m", color));
-                                last_color = color;
-                            }
-                            self.text_buffer.push(chr);
-                        }
-                    }
-                    self.text_buffer.push_str("\x1b[39m");
-                } else {
-                    self.text_buffer.push_str("~");
-                }
+    /// The start of the line after `row` when it is broken at `col` in a
+    /// comment with `continue_comments`: the indentation and the comment
+    /// leader, e.g. `// ` after a line comment, `/// ` after a Rust doc
+    /// comment or ` * ` in a block comment. Only for comments starting
+    /// the line, not those after code.
+    fn comment_leader(&self, row: usize, col: usize) -> Option<String> {
+        let syntax = &self.buffer.highlight.syntax;
+        if !self.buffer.options.continue_comments || syntax.ftype == FileType::Undefined {
+            return None;
+        }
+        let line = self.buffer.content_buffer.get(row)?;
+        let indent = indent::indent_of(line);
+        let text = &line[indent.len()..];
+        let line_start = syntax.singleline_comment_start;
+        let block_start = syntax.multiline_comment_start;
+        let stars = block_start.ends_with('*');
+        // The indentation and the leader of the next line, and where the
+        // leader of this one ends
+        let (indent, leader, end) = if !line_start.is_empty() && text.starts_with(line_start) {
+            // Doubled or with a `!`, e.g. `///`, `//!` or `##`
+            let more: usize = text[line_start.len()..]
+                .chars()
+                .take_while(|c| line_start.contains(*c) || *c == '!')
+                .map(char::len_utf8)
+                .sum();
+            let leader = &text[..line_start.len() + more];
+            (indent.to_string(), leader, indent.len() + leader.len())
+        } else if stars && text.starts_with(block_start) {
+            let more = text[block_start.len()..].len()
+                - text[block_start.len()..].trim_start_matches('*').len();
+            // The stars of the next lines go under the first one of `/*`
+            (
+                format!("{} ", indent),
+                "*",
+                indent.len() + block_start.len() + more,
+            )
+        } else if stars && text.starts_with('*') && !text.starts_with("*/") {
+            let at = line[..indent.len()].chars().count();
+            use HighlightColor::*;
+            match self.buffer.highlight.kind(row, at) {
+                Some(MultilineComment | DocComment | Comment) => {}
+                _ => return None,
             }
-            self.text_buffer.push_str("\x1b[K");
-            self.text_buffer.push_str("\r\n");
+            (indent.to_string(), "*", indent.len() + 1)
+        } else {
+            return None;
+        };
+        if col < end || (leader == "*" && line[end..col].contains(syntax.multiline_comment_end)) {
+            return None;
         }
-        Ok(())
+        let space = match indent::indent_of(&line[end..col]) {
+            "" if line[end..col].is_empty() => " ",
+            space => space,
+        };
+        Some(format!("{}{}{}", indent, leader, space))
     }
 
-    pub fn move_cursor(&mut self, direction: CursorMoveDirection) {
-        use std::cmp::min;
-        use CursorMoveDirection::*;
-        match direction {
-            Down => {
-                if self.content_buffer.len() > self.cy {
-                    self.cy += 1;
-                }
+    /// Inserts `text`, which may span several lines, at `at` and moves the
+    /// cursor after it. The edit is recorded for undo.
+    fn insert_text(&mut self, at: Position, text: &str, kind: EditKind) {
+        let before = (self.buffer.cy, self.buffer.cx);
+        let end = self.apply_insert(at, text);
+        (self.buffer.cy, self.buffer.cx) = end;
+        let edit = Edit::Insert {
+            at,
+            text: text.to_string(),
+        };
+        self.buffer.undo.record(edit, kind, before, end);
+    }
+
+    /// Removes the text between `start` and `end`, moves the cursor to
+    /// `start` and returns the removed text. The edit is recorded for undo.
+    fn delete_text(&mut self, start: Position, end: Position, kind: EditKind) -> String {
+        let before = (self.buffer.cy, self.buffer.cx);
+        let text = self.apply_delete(start, end);
+        (self.buffer.cy, self.buffer.cx) = start;
+        let edit = Edit::Delete {
+            at: start,
+            text: text.clone(),
+        };
+        self.buffer.undo.record(edit, kind, before, start);
+        text
+    }
+
+    /// Notes an edit of a file buffer in its journal, to be written when idle.
+    fn journal_edit(&mut self, edit: Edit) {
+        if self.buffer.filename.is_some()
+            && self.buffer.encrypted.is_none()
+            && self.buffer.journal.record(edit)
+        {
+            self.idle_tasks.schedule(IdleTask::Journal(self.buffer.id));
+        }
+    }
+
+    fn apply_insert(&mut self, at: Position, text: &str) -> Position {
+        let edit = Edit::Insert {
+            at,
+            text: text.to_string(),
+        };
+        #[cfg(feature = "sync")]
+        self.share_edit(&edit);
+        self.journal_edit(edit);
+        let (row, col) = at;
+        if row == self.buffer.content_buffer.len() {
+            self.buffer.content_buffer.push(String::new());
+            self.buffer.mark_added(row, 1);
+            self.editor_insert_rows(row, 1);
+        }
+        self.mark_inserted(at, text);
+        let tail = self.buffer.content_buffer[row].split_off(col);
+        let mut lines = text.split('\n');
+        self.buffer.content_buffer[row].push_str(lines.next().unwrap_or(""));
+        let rest: Vec<&str> = lines.collect();
+        if rest.is_empty() {
+            let end = (row, self.buffer.content_buffer[row].len());
+            self.buffer.content_buffer[row].push_str(&tail);
+            self.editor_update_row(row);
+            return end;
+        }
+        self.editor_update_row(row);
+        let end = (row + rest.len(), rest[rest.len() - 1].len());
+        let mut lines: Vec<String> = rest.iter().map(|line| line.to_string()).collect();
+        lines[rest.len() - 1].push_str(&tail);
+        self.buffer.content_buffer.insert_rows(row + 1, lines);
+        self.editor_insert_rows(row + 1, rest.len());
+        end
+    }
+
+    fn apply_delete(&mut self, start: Position, end: Position) -> String {
+        let ((start_row, start_col), (end_row, end_col)) = (start, end);
+        self.mark_deleted(start, end);
+        if start_row == end_row {
+            let removed: String = self.buffer.content_buffer[start_row]
+                .drain(start_col..end_col)
+                .collect();
+            self.editor_update_row(start_row);
+            let edit = Edit::Delete {
+                at: start,
+                text: removed.clone(),
+            };
+            #[cfg(feature = "sync")]
+            self.share_edit(&edit);
+            self.journal_edit(edit);
+            return removed;
+        }
+        let tail = self.buffer.content_buffer[end_row][end_col..].to_string();
+        let mut removed = self.buffer.content_buffer[start_row].split_off(start_col);
+        for row in start_row + 1..=end_row {
+            removed.push('\n');
+            let line = &self.buffer.content_buffer[row];
+            removed.push_str(if row == end_row {
+                &line[..end_col]
+            } else {
+                line
+            });
+        }
+        let rows = start_row + 1..end_row + 1;
+        self.buffer.content_buffer.remove_rows(rows.clone());
+        self.buffer.render_buffer.remove_rows(rows.clone());
+        self.buffer.highlight.remove_rows(rows);
+        self.buffer.content_buffer[start_row].push_str(&tail);
+        self.editor_update_row(start_row);
+        let edit = Edit::Delete {
+            at: start,
+            text: removed.clone(),
+        };
+        #[cfg(feature = "sync")]
+        self.share_edit(&edit);
+        self.journal_edit(edit);
+        removed
+    }
+
+    /// Updates the change marks and the region mark for `text` about to be
+    /// inserted at `at`.
+    fn mark_inserted(&mut self, at: Position, text: &str) {
+        self.buffer.mark = self.buffer.mark.map(|mark| shift_inserted(mark, at, text));
+        let (row, col) = at;
+        let new_lines = text.matches('\n').count();
+        if new_lines == 0 {
+            self.buffer.mark_changed(row);
+        } else if col == 0 && text.ends_with('\n') {
+            // Whole lines put above the row, which itself stays as it is
+            self.buffer.mark_added(row, new_lines);
+        } else {
+            if !(col == self.buffer.content_buffer[row].len() && text.starts_with('\n')) {
+                self.buffer.mark_changed(row);
             }
-            Up => {
-                if 0 < self.cy {
-                    self.cy -= 1;
-                }
+            self.buffer.mark_added(row + 1, new_lines);
+        }
+    }
+
+    /// Updates the change marks and the region mark for the text between
+    /// `start` and `end` about to be deleted.
+    fn mark_deleted(&mut self, start: Position, end: Position) {
+        self.buffer.mark = self.buffer.mark.map(|mark| shift_deleted(mark, start, end));
+        let ((start_row, start_col), (end_row, end_col)) = (start, end);
+        if start_row == end_row {
+            if start_col != end_col {
+                self.buffer.mark_changed(start_row);
             }
-            Right => {
-                if let Some(line) = self.content_buffer.get(self.cy) {
-                    if self.cx < line.len() {
-                        self.cx += 1;
-                    } else if self.cx == line.len() {
-                        self.cy += 1;
-                        self.cx = 0;
-                    }
-                }
+        } else if start_col == 0 && end_col == 0 {
+            // Whole lines removed; the row after them moves up unchanged
+            self.buffer.unmark_removed(start_row, end_row);
+        } else {
+            let start_len = self.buffer.content_buffer[start_row].len();
+            let end_len = self
+                .buffer
+                .content_buffer
+                .get(end_row)
+                .map_or(0, |line| line.len());
+            self.buffer.unmark_removed(start_row + 1, end_row + 1);
+            if !(start_col == start_len && end_col == end_len) {
+                self.buffer.mark_changed(start_row);
             }
-            Left => {
-                if self.cx > 0 {
-                    self.cx -= 1;
-                } else if self.cy > 0 {
-                    self.cy -= 1;
-                    let line_length = match self.content_buffer.get(self.cy) {
-                        Some(line) => line.len(),
-                        _ => 0,
-                    };
-                    self.cx = line_length;
-                }
+        }
+    }
+
+    /// Reverts the latest undo step.
+    pub fn undo(&mut self) {
+        if !self.check_writable() {
+            return;
+        }
+        if self.buffer.hex.is_some() {
+            self.editor_set_status_mssage("Undo is not available in hex mode");
+            return;
+        }
+        let group = match self.buffer.undo.undo() {
+            Some(group) => group,
+            None => {
+                self.editor_set_status_mssage("Nothing to undo");
+                return;
             }
-            PageUp => {
-                self.cy = self.row_offset;
-                for _ in 0..self.rows {
-                    self.move_cursor(Up);
-                }
+        };
+        self.revert_group(&group);
+        self.set_dirty();
+        self.editor_set_status_mssage("Undo");
+    }
+
+    /// Applies the latest undone step again.
+    pub fn redo(&mut self) {
+        if !self.check_writable() {
+            return;
+        }
+        if self.buffer.hex.is_some() {
+            self.editor_set_status_mssage("Redo is not available in hex mode");
+            return;
+        }
+        let group = match self.buffer.undo.redo() {
+            Some(group) => group,
+            None => {
+                self.editor_set_status_mssage("Nothing to redo");
+                return;
             }
-            PageDown => {
-                self.cy = self.row_offset + self.rows - 1;
-                if self.cy > self.content_buffer.len() {
-                    self.cy = self.content_buffer.len();
-                }
-                for _ in 0..self.rows {
-                    self.move_cursor(Down);
+        };
+        self.apply_group(&group);
+        self.set_dirty();
+        self.editor_set_status_mssage("Redo");
+    }
+
+    fn revert_group(&mut self, group: &UndoGroup) {
+        for edit in group.edits.iter().rev() {
+            match edit {
+                Edit::Insert { at, .. } => {
+                    self.apply_delete(*at, edit.end());
                 }
-            }
-            LineTop => self.cx = 0,
-            LineBottom => {
-                if let Some(line) = self.content_buffer.get(self.cy) {
-                    self.cx = min(self.columns + self.col_offset - 1, line.len());
-                } else {
-                    self.cx = 0;
+                Edit::Delete { at, text } => {
+                    self.apply_insert(*at, text);
                 }
             }
-        };
-        let line_length = match self.content_buffer.get(self.cy) {
-            Some(line) => line.len(),
-            _ => 0,
-        };
-        self.cx = min(self.cx, line_length);
+        }
+        (self.buffer.cy, self.buffer.cx) = group.cursor_before;
     }
 
-    fn cx_to_rx(&self, line: &String) -> usize {
-        let mut rx = 0;
-        for (char_index, char) in line.chars().enumerate() {
-            if self.cx == char_index {
-                break;
-            }
-            if char == '\t' {
-                rx += (KILO_TAB_STOP - 1) - (rx % KILO_TAB_STOP);
+    fn apply_group(&mut self, group: &UndoGroup) {
+        for edit in &group.edits {
+            match edit {
+                Edit::Insert { at, text } => {
+                    self.apply_insert(*at, text);
+                }
+                Edit::Delete { at, .. } => {
+                    self.apply_delete(*at, edit.end());
+                }
             }
-            rx += 1
         }
-        rx
+        (self.buffer.cy, self.buffer.cx) = group.cursor_after;
     }
 
-    fn rx_to_cx(&self, rx: usize, line: &String) -> usize {
-        let mut cur_rx = 0;
-        for (cx, rc) in line.chars().enumerate() {
-            if rc == '\t' {
-                cur_rx += (KILO_TAB_STOP - 1) - (cur_rx % KILO_TAB_STOP);
-            }
-            cur_rx += 1;
-            if cur_rx > rx {
-                return cx;
+    /// C-x U: lists every state of the undo history in a read-only
+    /// `*Undo Tree*` buffer. Enter on a listed state restores it.
+    pub fn undo_tree(&mut self) {
+        if self.buffer.undo_tree_source.is_some() || self.buffer.occur_source.is_some() {
+            return;
+        }
+        let now = Instant::now();
+        let mut lines = vec![format!(
+            "Undo tree of {} (Enter: go to state, * current, + branch)",
+            self.buffer.display_name()
+        )];
+        let mut cy = 1;
+        for entry in self.buffer.undo.tree() {
+            if entry.is_current {
+                cy = lines.len();
             }
+            let glyph = if entry.is_current {
+                '*'
+            } else if entry.starts_branch {
+                '+'
+            } else {
+                'o'
+            };
+            let description = match &entry.first_edit {
+                None => "original".to_string(),
+                Some(edit) => {
+                    let (sign, text) = match edit {
+                        Edit::Insert { text, .. } => ('+', text),
+                        Edit::Delete { text, .. } => ('-', text),
+                    };
+                    let preview: String = text.escape_debug().take(30).collect();
+                    let more = if entry.edits > 1 {
+                        format!(" (+{} edits)", entry.edits - 1)
+                    } else {
+                        String::new()
+                    };
+                    format!("{}\"{}\"{}", sign, preview, more)
+                }
+            };
+            lines.push(format!(
+                "{:>4}: {}{} {}  {}s ago",
+                entry.node,
+                "  ".repeat(entry.depth),
+                glyph,
+                description,
+                now.duration_since(entry.created).as_secs()
+            ));
         }
-        return line.len();
+
+        self.buffers.retain(|b| b.undo_tree_source.is_none());
+        let mut tree = self.new_buffer();
+        tree.name = Some("*Undo Tree*".to_string());
+        tree.read_only = true;
+        tree.undo_tree_source = Some(self.buffer.id);
+        tree.cy = cy;
+        tree.render_buffer = lines.iter().map(|l| self.render_row(l)).collect();
+        tree.highlight = Highlight::new(&lines, PathBuf::new());
+        tree.content_buffer = lines.into();
+        self.add_buffer(tree);
     }
 
-    pub fn editor_scroll(&mut self) {
-        self.rx = 0;
-        if self.cy < self.content_buffer.len() {
-            self.rx = self.cx_to_rx(&self.content_buffer[self.cy]);
-        }
-        if self.cy < self.row_offset {
-            self.row_offset = self.cy;
+    /// Restores the source buffer to the state under the cursor of the undo tree buffer.
+    fn undo_tree_goto(&mut self) {
+        let node = self
+            .buffer
+            .content_buffer
+            .get(self.buffer.cy)
+            .filter(|_| self.buffer.cy > 0)
+            .and_then(|line| line.split(':').next())
+            .and_then(|number| number.trim().parse::<usize>().ok());
+        let (source, node) = match (self.buffer.undo_tree_source, node) {
+            (Some(source), Some(node)) => (source, node),
+            _ => return,
+        };
+        if !self.switch_to_buffer(source) {
+            self.editor_set_status_mssage("The buffer of this undo tree is gone");
+            return;
         }
-        if self.cy >= self.row_offset + self.rows {
-            self.row_offset = self.cy - self.rows + 1;
+        self.buffers.retain(|b| b.undo_tree_source.is_none());
+        if !self.check_writable() {
+            return;
         }
-        if self.rx < self.col_offset {
-            self.col_offset = self.rx
+        let (undone, redone) = match self.buffer.undo.go_to(node) {
+            Some(steps) => steps,
+            None => return,
+        };
+        for group in &undone {
+            self.revert_group(group);
         }
-        if self.rx >= self.col_offset + self.columns {
-            self.col_offset = self.rx - self.columns + 1
+        for group in &redone {
+            self.apply_group(group);
         }
+        self.set_dirty();
+        self.editor_set_status_mssage(format!(
+            "Went to state {} ({} steps undone, {} redone)",
+            node,
+            undone.len(),
+            redone.len()
+        ));
     }
 
-    pub fn open_file(&mut self, filename: String) -> io::Result<()> {
-        use crate::highlight::*;
-        use std::fs::canonicalize;
-        use std::path::Path;
-        let canonicalized_path = canonicalize(Path::new(&filename))?;
-        self.filename = Some(canonicalized_path.clone());
-        for line in BufReader::new(File::open(filename)?).lines() {
-            let line = line?;
-            self.render_buffer.push(self.to_render_line(&line));
-            self.content_buffer.push(line);
-        }
-        self.highlight = Highlight::new(&self.content_buffer, canonicalized_path);
+    pub fn refresh_screen(&mut self) -> io::Result<()> {
+        if self.too_small() {
+            return self.editor_draw_too_small();
+        }
+        self.editor_scroll();
+        let screen_end = match (&self.view_filter, &self.buffer.narrow) {
+            (Some(_), _) => usize::MAX,
+            (None, Some(narrow)) => narrow.start + self.buffer.row_offset + self.rows,
+            (None, None) => self.buffer.row_offset + self.rows,
+        };
+        self.buffer
+            .highlight
+            .resync(&self.buffer.content_buffer, screen_end, None);
+        self.text_buffer.push_str("\x1b[?25l\x1b[H");
+        if self.picker.is_some() {
+            self.editor_draw_picker();
+        } else if self.buffer.hex.is_some() {
+            self.editor_draw_hex_rows();
+        } else {
+            self.editor_draw_rows()?;
+        }
+        self.editor_draw_terminal();
+        self.editor_draw_preview();
+        self.editor_draw_status_bar();
+        self.editor_draw_message_bar();
+        self.editor_draw_hover();
+        self.editor_draw_completion();
+        self.editor_draw_start_screen();
+        let (cursor_row, cursor_col) = match (&self.picker, &self.buffer.hex) {
+            (Some(picker), _) => (1, picker.title_line().chars().count() + 1),
+            (None, _) if self.terminal_focused && self.terminal.is_some() => {
+                let (row, col) = self.terminal.as_ref().map_or((0, 0), |t| t.cursor);
+                (self.rows + row + 2, col + 1)
+            }
+            (None, Some(hex)) => {
+                let (row, col) = hex.cursor_position();
+                (row - hex.row_offset + 1, col + 1)
+            }
+            (None, None) => {
+                let (row, col) = self.cursor_screen_position();
+                (row + 1, col + 1)
+            }
+        };
+        self.text_buffer.push_str(&format!(
+            "\x1b[{};{}H",
+            cursor_row,
+            cursor_col.min(self.columns)
+        ));
+        if self.shown_cursor_style != Some(self.cursor_style) {
+            self.text_buffer.push_str(&self.cursor_style.sequence());
+            self.shown_cursor_style = Some(self.cursor_style);
+        }
+        self.text_buffer.push_str("\x1b[?25h");
+        write!(self.stdout, "{}", self.text_buffer)?;
+        self.stdout.flush()?;
+        self.text_buffer.clear();
         Ok(())
     }
 
-    fn editor_prompt(
+    /// Whether the window is too small to draw the editor in, in which case
+    /// keys other than C-q are ignored until it grows.
+    pub fn too_small(&self) -> bool {
+        self.columns < MIN_COLUMNS || self.rows < MIN_ROWS
+    }
+
+    /// Draws only a note that the window is too small, after looking
+    /// whether it grew meanwhile.
+    fn editor_draw_too_small(&mut self) -> io::Result<()> {
+        if let Some((columns, rows)) = terminal_size() {
+            self.columns = columns as usize;
+            self.rows = (rows as usize).saturating_sub(2);
+            if !self.too_small() {
+                return self.refresh_screen();
+            }
+        }
+        let note: String = i18n::tr("Window too small; C-q quits")
+            .chars()
+            .take(self.columns)
+            .collect();
+        write!(self.stdout, "\x1b[2J\x1b[H{}", note)?;
+        self.stdout.flush()
+    }
+
+    /// Draws the terminal pane: a title line, then the screen of the shell.
+    fn editor_draw_terminal(&mut self) {
+        let terminal = match &self.terminal {
+            Some(terminal) => terminal,
+            None => return,
+        };
+        let title = if self.terminal_focused {
+            " *terminal*  C-x o: back to the text  C-x k: kill"
+        } else {
+            " *terminal*  C-x o: switch to the terminal"
+        };
+        let title: String = format!("{:<1$}", title, self.columns)
+            .chars()
+            .take(self.columns)
+            .collect();
+        let mut text = format!("\x1b[7m{}\x1b[m\r\n", title);
+        for row in 0..terminal.rows {
+            let line: String = terminal.line(row).chars().take(self.columns).collect();
+            text.push_str(line.trim_end());
+            text.push_str("\x1b[K\r\n");
+        }
+        self.text_buffer.push_str(&text);
+    }
+
+    /// Draws the preview pane: a title line, then the lines in sight.
+    fn editor_draw_preview(&mut self) {
+        let preview = match &self.preview {
+            Some(preview) => preview,
+            None => return,
+        };
+        let title = match preview.line {
+            Some(line) => format!(" *preview* {}:{}", preview.path.display(), line + 1),
+            None => format!(" *preview* {}", preview.path.display()),
+        };
+        let title: String = format!("{}  q: close  Enter: open", title)
+            .chars()
+            .chain(std::iter::repeat(' '))
+            .take(self.columns)
+            .collect();
+        let mut text = format!("\x1b[7m{}\x1b[m\r\n", title);
+        let mut drawn = 0;
+        for (line, pointed) in preview.visible() {
+            let line: String = self
+                .to_render_line(line)
+                .chars()
+                .take(self.columns)
+                .collect();
+            if pointed {
+                text.push_str(&format!("\x1b[7m{}\x1b[m", line));
+            } else {
+                text.push_str(&line);
+            }
+            text.push_str("\x1b[K\r\n");
+            drawn += 1;
+        }
+        for _ in drawn..preview.rows {
+            text.push_str("~\x1b[K\r\n");
+        }
+        self.text_buffer.push_str(&text);
+    }
+
+    /// C-x f: shows the file named by the path under the cursor, e.g. in an
+    /// include or a grep result, read-only in a pane below the text. A
+    /// relative path is resolved against the directory of the current file.
+    pub fn preview_file_at_point(&mut self) -> Result<String, String> {
+        let line = self
+            .buffer
+            .content_buffer
+            .get(self.buffer.cy)
+            .map_or("", |line| line.as_str());
+        let (path, number) = preview::path_at(line, self.buffer.cx.min(line.len()))
+            .ok_or_else(|| "No file name at point".to_string())?;
+        let dir = self.buffer.filename.as_ref().and_then(|file| file.parent());
+        let file = preview::resolve(&path, dir).ok_or_else(|| format!("No such file: {}", path))?;
+        self.close_preview();
+        let height = (self.rows / 2).max(3);
+        if self.rows < height + 4 {
+            return Err("The window is too small for a preview".to_string());
+        }
+        let preview = Preview::open(file, number, height)
+            .map_err(|e| format!("Can't read {}: {}", path, e))?;
+        self.rows -= height + 1;
+        self.preview = Some(preview);
+        Ok("q closes the preview, Enter opens the file".to_string())
+    }
+
+    /// C-x g: opens the file the current line refers to, i.e. the header
+    /// of an `#include`, the file of a `mod foo;` or the module of a `use
+    /// crate::…`, or else the file named at point. Headers are looked for in
+    /// the directories of `goto.include_path`, separated by `:`.
+    pub fn goto_file(&mut self) -> Result<String, String> {
+        let line = self
+            .buffer
+            .content_buffer
+            .get(self.buffer.cy)
+            .map_or("", |line| line.as_str());
+        let include_path: Vec<PathBuf> = self
+            .config
+            .get("goto.include_path")
+            .map_or(vec![], |dirs| dirs.split(':').map(PathBuf::from).collect());
+        let target = match &self.buffer.filename {
+            Some(file) => goto::target(line, file, &include_path),
+            None => None,
+        };
+        let (file, number) = match target {
+            Some(target) => (target?, None),
+            None => {
+                let (path, number) = preview::path_at(line, self.buffer.cx.min(line.len()))
+                    .ok_or_else(|| "No file name at point".to_string())?;
+                let dir = self.buffer.filename.as_ref().and_then(|file| file.parent());
+                let file = preview::resolve(&path, dir)
+                    .ok_or_else(|| format!("No such file: {}", path))?;
+                (file, number)
+            }
+        };
+        let name = file.display().to_string();
+        self.visit_file(name.clone())
+            .map_err(|e| format!("Can't open {}: {}", name, e))?;
+        if let (Some(number), None) = (number, &self.buffer.io) {
+            self.buffer.cy = number.min(self.buffer.content_buffer.len());
+            self.buffer.cx = 0;
+        }
+        Ok(name)
+    }
+
+    /// C-x ] and C-x [: opens the file after or before the current one in
+    /// its directory, in the order of their names, e.g. to step through a
+    /// series of logs.
+    pub fn step_file(&mut self, forward: bool) -> Result<String, String> {
+        let file = self
+            .buffer
+            .filename
+            .clone()
+            .ok_or_else(|| "Buffer has no file".to_string())?;
+        let next = sibling_file(&file, forward)?;
+        let name = next.display().to_string();
+        self.visit_file(name.clone())
+            .map_err(|e| format!("Can't open {}: {}", name, e))?;
+        Ok(name)
+    }
+
+    /// Gives the rows of the preview pane back to the text.
+    fn close_preview(&mut self) {
+        if let Some(preview) = self.preview.take() {
+            self.rows += preview.rows + 1;
+        }
+    }
+
+    /// Handles a key while the preview pane is shown: q or ESC closes it,
+    /// the arrows and page keys scroll it and Enter opens the file.
+    pub fn preview_key(&mut self, input: &mut RawMode, key: InputType) -> io::Result<()> {
+        use crate::input::InputType::*;
+        use CursorMoveDirection::*;
+        let preview = match &mut self.preview {
+            Some(preview) => preview,
+            None => return Ok(()),
+        };
+        let page = preview.rows as isize;
+        match key {
+            Char(b'q') | Char(b'\x1b') | Char(CTRL_G) => self.close_preview(),
+            CursorMove(Up) | Char(b'k') => preview.scroll(-1),
+            CursorMove(Down) | Char(b'j') => preview.scroll(1),
+            CursorMove(PageUp) | Char(b'b') => preview.scroll(-page),
+            CursorMove(PageDown) | Char(b' ') => preview.scroll(page),
+            Char(b'\r') => {
+                let (path, line) = (preview.path.display().to_string(), preview.line);
+                self.close_preview();
+                self.find_file(input, path)?;
+                if let (Some(line), None) = (line, &self.buffer.io) {
+                    self.buffer.cy = line.min(self.buffer.content_buffer.len());
+                    self.buffer.cx = 0;
+                }
+            }
+            FocusIn => return self.focus_in(input),
+            FocusOut => self.focus_out(),
+            _ => self.editor_set_status_mssage("q closes the preview, Enter opens the file"),
+        }
+        Ok(())
+    }
+
+    /// Opens a shell in a pane below the text, or moves the focus to it.
+    pub fn open_terminal(&mut self) {
+        if self.terminal.is_some() {
+            self.run_hook(Hook::BufferLeave);
+            self.terminal_focused = true;
+            return;
+        }
+        let height = (self.rows / 3).max(3);
+        if self.rows < height + 4 {
+            self.editor_set_status_mssage("The window is too small for a terminal");
+            return;
+        }
+        let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+        match Terminal::spawn(&shell, height, self.columns) {
+            Ok(terminal) => {
+                self.run_hook(Hook::BufferLeave);
+                self.rows -= height + 1;
+                self.terminal = Some(terminal);
+                self.terminal_focused = true;
+            }
+            Err(e) => {
+                self.push_message(MessageLevel::Error, format!("Can't start {}: {}", shell, e))
+            }
+        }
+    }
+
+    /// Kills the shell and gives its rows back to the text.
+    pub fn close_terminal(&mut self) {
+        if let Some(terminal) = self.terminal.take() {
+            self.rows += terminal.rows + 1;
+        }
+        self.terminal_focused = false;
+    }
+
+    /// Takes in the output of the shell, closing the pane when it has exited.
+    pub fn poll_terminal(&mut self) {
+        if let Some(terminal) = &mut self.terminal {
+            if !terminal.poll() {
+                self.close_terminal();
+                self.editor_set_status_mssage("Shell exited");
+            }
+        }
+    }
+
+    /// Sends a key to the focused terminal. C-x starts the commands of the
+    /// pane: C-x o returns to the text, C-x k kills the shell and C-x C-x
+    /// sends C-x itself.
+    pub fn terminal_key(&mut self, input: &mut RawMode, key: InputType) -> io::Result<()> {
+        use crate::input::InputType::*;
+        let bytes = match key {
+            ControlX => loop {
+                match input.readkey()? {
+                    NoOp => self.poll_terminal(),
+                    Char(b'o') => {
+                        self.terminal_focused = false;
+                        return Ok(());
+                    }
+                    Char(b'k') => {
+                        self.close_terminal();
+                        return Ok(());
+                    }
+                    ControlX => break vec![CTRL_X],
+                    _ => return Ok(()),
+                }
+            },
+            FocusIn => return self.focus_in(input),
+            FocusOut => {
+                self.focus_out();
+                return Ok(());
+            }
+            Mouse(_) => return Ok(()),
+            key => key.to_bytes(),
+        };
+        if let Some(terminal) = &mut self.terminal {
+            if let Err(e) = terminal.write(&bytes) {
+                self.push_message(MessageLevel::Error, format!("Terminal: {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    fn editor_draw_hex_rows(&mut self) {
+        let hex = match &self.buffer.hex {
+            Some(hex) => hex,
+            None => return,
+        };
+        let mut text = String::new();
+        for y in 0..self.rows {
+            let row = y + hex.row_offset;
+            if row < hex.rows() {
+                let line = hex.render_row(row);
+                text.extend(line.chars().take(self.columns));
+            } else {
+                text.push('~');
+            }
+            text.push_str("\x1b[K\r\n");
+        }
+        self.text_buffer.push_str(&text);
+    }
+
+    /// The terminal window got the focus: picks up changes other programs
+    /// made to the file in the meantime.
+    pub fn focus_in(&mut self, input: &mut RawMode) -> io::Result<()> {
+        self.focused = true;
+        // A chmod doesn't change the modification time
+        self.buffer.disk_writable = disk_writable(&self.buffer.filename);
+        let mtime = disk_mtime(&self.buffer.filename);
+        if mtime.is_none() || mtime == self.buffer.disk_mtime {
+            return Ok(());
+        }
+        let name = self.buffer.display_name();
+        if self.buffer.hex.is_some() || self.buffer.encrypted.is_some() {
+            self.push_message(
+                MessageLevel::Warn,
+                format!("Warning: {} changed on disk since it was read", name),
+            );
+            return Ok(());
+        }
+        if self.buffer.dirty {
+            let question = format!("{} changed on disk. Reload and discard your changes?", name);
+            if self.confirm(input, &question, false)? != Answer::Yes {
+                // Don't ask again until the file changes once more
+                self.buffer.disk_mtime = mtime;
+                self.push_message(
+                    MessageLevel::Warn,
+                    format!("Warning: {} changed on disk since it was read", name),
+                );
+                return Ok(());
+            }
+        }
+        let text = match self.buffer.filename.as_ref().map(std::fs::read_to_string) {
+            Some(Ok(text)) => text,
+            _ => return Ok(()),
+        };
+        self.load_text(&text);
+        self.buffer.disk_mtime = mtime;
+        self.buffer.dirty = false;
+        if let Some(path) = &self.buffer.filename {
+            lock::release(path);
+        }
+        self.editor_set_status_mssage(format!("Reloaded {}: changed on disk", name));
+        Ok(())
+    }
+
+    pub fn focus_out(&mut self) {
+        self.focused = false;
+        self.run_hook(Hook::FocusOut);
+    }
+
+    /// Switches the buffer between text and hex mode. Entering hex mode
+    /// reads the exact bytes of an unmodified file from disk.
+    pub fn toggle_hex(&mut self) {
+        if self.buffer.encrypted.is_some() {
+            self.editor_set_status_mssage("Encrypted files aren't shown in hex mode");
+            return;
+        }
+        match self.buffer.hex.take() {
+            None => {
+                let on_disk = match &self.buffer.filename {
+                    Some(path) if !self.buffer.dirty => std::fs::read(path).ok(),
+                    _ => None,
+                };
+                let bytes = on_disk.unwrap_or_else(|| {
+                    self.buffer
+                        .content_buffer
+                        .iter()
+                        .flat_map(|line| line.bytes().chain(Some(b'\n')))
+                        .collect()
+                });
+                self.buffer.hex = Some(HexView::new(bytes));
+                self.editor_set_status_mssage("Hex mode: type hex digits to overwrite bytes");
+            }
+            Some(hex) => match String::from_utf8(hex.bytes) {
+                Ok(text) => {
+                    self.load_text(&text);
+                    self.editor_set_status_mssage("Text mode");
+                }
+                Err(e) => {
+                    self.buffer.hex = Some(HexView::new(e.into_bytes()));
+                    self.editor_set_status_mssage("Not valid UTF-8; staying in hex mode");
+                }
+            },
+        }
+        self.run_hook(Hook::ModeChange);
+    }
+
+    /// Shows the bytes read from a pipe in the unnamed buffer, in hex mode
+    /// if they aren't UTF-8 text.
+    pub fn open_piped(&mut self, bytes: Vec<u8>) {
+        match String::from_utf8(bytes) {
+            Ok(text) => {
+                self.load_text(&text);
+                self.check_long_lines();
+            }
+            Err(e) => self.buffer.hex = Some(HexView::new(e.into_bytes())),
+        }
+    }
+
+    /// Names the unnamed buffer `title`, e.g. for text read from a pipe,
+    /// which is also the path it is saved to by default and tells its
+    /// file type.
+    pub fn set_title(&mut self, title: String) {
+        let path = PathBuf::from(&title);
+        self.buffer.highlight = Highlight::new(&self.buffer.content_buffer, path.clone());
+        self.buffer.name = Some(title);
+        self.buffer.save_path = Some(path);
+    }
+
+    /// Replaces the lines of the buffer with `text`, keeping its syntax.
+    /// The undo history starts over.
+    fn load_text(&mut self, text: &str) {
+        self.buffer.undo = UndoHistory::new(self.buffer.options.undo_coalescing);
+        self.buffer.changes.clear();
+        self.buffer.blame = None;
+        self.buffer.narrow = None;
+        let (lines, endings) = split_lines(text);
+        (self.buffer.content_buffer, self.buffer.endings) = (lines.into(), endings.into());
+        self.buffer.detect_eol();
+        self.buffer.render_buffer = self
+            .buffer
+            .content_buffer
+            .iter()
+            .map(|line| self.render_row(line))
+            .collect();
+        self.buffer.highlight =
+            Highlight::with_syntax(&self.buffer.content_buffer, self.buffer.highlight.syntax);
+        self.buffer.cy = self.buffer.cy.min(self.buffer.content_buffer.len());
+        self.buffer.cx = 0;
+    }
+
+    fn editor_draw_picker(&mut self) {
+        let (lines, selected) = match &self.picker {
+            Some(picker) => picker.lines(self.rows),
+            None => return,
+        };
+        for y in 0..self.rows {
+            if let Some(line) = lines.get(y) {
+                let shown: String = line.chars().take(self.columns).collect();
+                if Some(y) == selected {
+                    self.text_buffer
+                        .push_str(&format!("\x1b[7m{}\x1b[m", shown));
+                } else {
+                    self.text_buffer.push_str(&shown);
+                }
+            }
+            self.text_buffer.push_str("\x1b[K\r\n");
+        }
+    }
+
+    /// Draws the completion popup with the entries aligned to the word being completed.
+    fn editor_draw_completion(&mut self) {
+        let (entries, selected, typed) = match &self.completion {
+            Some(completion) => {
+                let (entries, selected) = completion.entries(POPUP_HEIGHT);
+                (entries, selected, completion.query.chars().count())
+            }
+            None => return,
+        };
+        self.editor_draw_popup(&entries, selected, typed + 1, false);
+    }
+
+    /// Draws the hover popup while the cursor stays where it was opened.
+    fn editor_draw_hover(&mut self) {
+        let lines = match &self.hover {
+            Some((at, lines)) if *at == (self.buffer.cy, self.buffer.cx) => lines.clone(),
+            Some(_) => {
+                self.hover = None;
+                return;
+            }
+            None => return,
+        };
+        self.editor_draw_popup(&lines, None, 0, true);
+    }
+
+    /// Draws `lines` in a box over the text, starting `back` columns left of
+    /// the cursor, above the cursor line if `above` is set and below it
+    /// otherwise, or on the other side when there is no room.
+    fn editor_draw_popup(
         &mut self,
-        input: &mut RawMode,
-        format: &str,
-        callback: Option<fn(&mut Self, &str, u8)>,
-    ) -> io::Result<Option<String>> {
+        lines: &[String],
+        selected: Option<usize>,
+        back: usize,
+        above: bool,
+    ) {
+        let (row, col) = self.cursor_screen_position();
+        let height = lines.len().min(self.rows.saturating_sub(1));
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count() + 2)
+            .max()
+            .unwrap_or(0)
+            .min(self.columns);
+        let left = col.saturating_sub(back).min(self.columns - width);
+        let fits_below = row + 1 + height <= self.rows;
+        let top = if (above && row >= height) || !fits_below {
+            row.saturating_sub(height)
+        } else {
+            row + 1
+        };
+        self.editor_draw_box(lines, selected, top, left);
+    }
+
+    /// Draws the start screen in a box in the middle of the text area.
+    fn editor_draw_start_screen(&mut self) {
+        let (lines, selected) = match &self.start_screen {
+            Some(start) => start.lines(),
+            None => return,
+        };
+        let height = lines.len().min(self.rows);
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count() + 2)
+            .max()
+            .unwrap_or(0)
+            .min(self.columns);
+        let top = (self.rows - height) / 2;
+        let left = (self.columns - width) / 2;
+        self.editor_draw_box(&lines, Some(selected), top, left);
+    }
+
+    /// Draws `lines` over the text in a box with its top left corner at
+    /// the screen row `top` and column `left`, highlighting the line
+    /// `selected`.
+    fn editor_draw_box(
+        &mut self,
+        lines: &[String],
+        selected: Option<usize>,
+        top: usize,
+        left: usize,
+    ) {
+        let height = lines.len().min(self.rows.saturating_sub(top));
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count() + 2)
+            .max()
+            .unwrap_or(0)
+            .min(self.columns - left);
+        for (index, line) in lines.iter().take(height).enumerate() {
+            let shown: String = format!(" {:<1$}", line, width - 1)
+                .chars()
+                .take(width)
+                .collect();
+            let style = if Some(index) == selected {
+                "\x1b[7m"
+            } else {
+                "\x1b[100m"
+            };
+            self.text_buffer.push_str(&format!(
+                "\x1b[{};{}H{}{}\x1b[m",
+                top + index + 1,
+                left + 1,
+                style,
+                shown
+            ));
+        }
+    }
+
+    /// Shows the documentation comment and signature of the definition of
+    /// the word under the cursor, found through a ctags `tags` file, in a
+    /// popup which closes when the cursor moves.
+    pub fn hover(&mut self) {
+        let word = match self.buffer.content_buffer.get(self.buffer.cy) {
+            Some(line) => {
+                let index = self.buffer.cx.min(line.len());
+                let end = line[index..]
+                    .find(|c: char| !abbrev::is_word_char(c))
+                    .map_or(line.len(), |i| index + i);
+                line[word_start(line, index)..end].to_string()
+            }
+            None => String::new(),
+        };
+        if word.is_empty() {
+            self.editor_set_status_mssage("No word under the cursor");
+            return;
+        }
+        let start = match &self.buffer.filename {
+            Some(path) => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+        let tags_file = match tags::find_tags_file(&start) {
+            Some(tags_file) => tags_file,
+            None => {
+                self.editor_set_status_mssage("No tags file found");
+                return;
+            }
+        };
+        let lines: Vec<String> = tags::lookup(&tags_file, &word)
+            .iter()
+            .filter_map(tags::Tag::documentation)
+            .flat_map(|doc| doc.into_iter().take(HOVER_HEIGHT))
+            .take(HOVER_HEIGHT)
+            .collect();
+        if lines.is_empty() {
+            self.editor_set_status_mssage(format!("No definition of {}", word));
+            return;
+        }
+        self.hover = Some(((self.buffer.cy, self.buffer.cx), lines));
+    }
+
+    /// Completes the word before the cursor with a word of the buffer. When
+    /// several words match they are shown in a popup, narrowed as more of
+    /// the word is typed; Tab and the arrow keys choose and Enter accepts.
+    pub fn complete(&mut self, input: &mut RawMode) -> io::Result<()> {
         use crate::input::InputType::*;
-        let mut prompt_buffer = String::new();
+        if !self.check_writable() || self.buffer.hex.is_some() {
+            return Ok(());
+        }
+        let row = self.buffer.cy;
+        let (start, prefix) = match self.buffer.content_buffer.get(row) {
+            Some(line) => {
+                let start = word_start(line, self.buffer.cx);
+                (start, line[start..self.buffer.cx].to_string())
+            }
+            None => return Ok(()),
+        };
+        if prefix.is_empty() {
+            self.editor_set_status_mssage("No word to complete");
+            return Ok(());
+        }
+        let words = completion::word_candidates(&self.buffer.content_buffer, (row, start), &prefix);
+        match words.len() {
+            0 => {
+                self.editor_set_status_mssage("No completions");
+                return Ok(());
+            }
+            1 => {
+                self.complete_word(start, &words[0]);
+                return Ok(());
+            }
+            _ => {}
+        }
+        let mut completion = Picker::new("", words);
+        prefix.chars().for_each(|c| completion.push(c));
+        self.completion = Some(completion);
         loop {
-            self.editor_set_status_mssage(str::replace(format, "{}", &prompt_buffer));
             self.refresh_screen()?;
+            let key = input.readkey()?;
+            let completion = match &mut self.completion {
+                Some(completion) => completion,
+                None => break,
+            };
+            match key {
+                NoOp => continue,
+                Char(b'\r') => {
+                    if let Some(index) = completion.selected_item() {
+                        let word = completion.items[index].clone();
+                        self.complete_word(start, &word);
+                    }
+                    break;
+                }
+                Char(b'\t') | CursorMove(CursorMoveDirection::Down) => completion.select_next(),
+                CursorMove(CursorMoveDirection::Up) => completion.select_prev(),
+                Backspace => {
+                    completion.pop();
+                    self.delete_char();
+                }
+                Char(c) if abbrev::is_word_char(char::from(c)) => {
+                    completion.push(char::from(c));
+                    self.insert_char(char::from(c));
+                }
+                Text(c) if abbrev::is_word_char(c) => {
+                    completion.push(c);
+                    self.insert_char(c);
+                }
+                // A separator ends the word as typed
+                Char(c) if !c.is_ascii_control() => {
+                    self.completion = None;
+                    self.insert_char(char::from(c));
+                }
+                _ => break,
+            }
+            match &self.completion {
+                Some(completion)
+                    if !completion.query.is_empty() && !completion.filtered.is_empty() => {}
+                _ => break,
+            }
+        }
+        self.completion = None;
+        Ok(())
+    }
+
+    /// Replaces the word from `start` to the cursor with `word`, as one undo step.
+    fn complete_word(&mut self, start: usize, word: &str) {
+        let (row, end) = (self.buffer.cy, self.buffer.cx);
+        self.buffer.undo.begin_group();
+        self.delete_text((row, start), (row, end), EditKind::Other);
+        self.insert_text((row, start), word, EditKind::Other);
+        self.buffer.undo.end_group();
+        self.set_dirty();
+    }
+
+    /// Lets the user choose one of `items` in a picker. Returns the chosen
+    /// index, or None when cancelled with ESC.
+    pub fn pick(
+        &mut self,
+        input: &mut RawMode,
+        title: &str,
+        items: Vec<String>,
+    ) -> io::Result<Option<usize>> {
+        use crate::input::InputType::*;
+        self.picker = Some(Picker::new(title, items));
+        let result = loop {
+            self.refresh_screen()?;
+            let picker = match &mut self.picker {
+                Some(picker) => picker,
+                None => break None,
+            };
+            match input.readkey()? {
+                Char(b'\x1b') => break None,
+                Char(b'\r') => break picker.selected_item(),
+                Backspace | Del => picker.pop(),
+                CursorMove(CursorMoveDirection::Down) | ControlS => picker.select_next(),
+                CursorMove(CursorMoveDirection::Up) | ControlR => picker.select_prev(),
+                Char(c) if !c.is_ascii_control() => picker.push(char::from(c)),
+                Text(c) => picker.push(c),
+                _ => {}
+            }
+        };
+        self.picker = None;
+        Ok(result)
+    }
+
+    /// Reopens a file from the list of recently opened files.
+    pub fn open_recent(&mut self, input: &mut RawMode) -> io::Result<()> {
+        let recent = RecentFiles::load();
+        if recent.paths.is_empty() {
+            self.editor_set_status_mssage("No recent files");
+            return Ok(());
+        }
+        let items = recent
+            .paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        if let Some(index) = self.pick(input, "Recent files", items)? {
+            let path = recent.paths[index].display().to_string();
+            if let Err(e) = self.visit_file(path.clone()) {
+                self.push_message(MessageLevel::Error, format!("Can't open {}: {}", path, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the buffer is an untouched empty one, e.g. the one at startup,
+    /// which is reused for the next file rather than kept.
+    fn is_untouched(&self) -> bool {
+        self.buffer.filename.is_none()
+            && self.buffer.name.is_none()
+            && !self.buffer.dirty
+            && self.buffer.content_buffer.is_empty()
+    }
+
+    /// Displays the file in a buffer, reusing the buffer if the file is already open.
+    pub fn visit_file(&mut self, filename: String) -> io::Result<()> {
+        let remote = RemotePath::parse(&filename);
+        let path = match remote {
+            Some(_) => None,
+            None => Some(self.resolve_path(Path::new(&filename))?),
+        };
+        let is_visiting = |buffer: &Buffer| match &remote {
+            Some(remote) => buffer.remote.as_ref() == Some(remote),
+            None => buffer.filename == path,
+        };
+        if is_visiting(&self.buffer) {
+            return Ok(());
+        }
+        let open = self.buffers.iter().find(|b| is_visiting(b)).map(|b| b.id);
+        if let Some(id) = open {
+            self.switch_to_buffer(id);
+            return Ok(());
+        }
+        let reuse = self.is_untouched();
+        if !reuse {
+            let buffer = self.new_buffer();
+            self.add_buffer(buffer);
+        }
+        if let Err(e) = self.open_file(filename) {
+            if !reuse {
+                self.buffer = self.buffers.remove(0);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// File row at a view row, taking `view_filter` into account.
+    fn view_row_to_file_row(&self, view_row: usize) -> Option<usize> {
+        match (&self.view_filter, &self.buffer.narrow) {
+            (Some(rows), _) => rows.get(view_row).copied(),
+            (None, Some(narrow)) => Some(narrow.start + view_row).filter(|row| *row < narrow.end),
+            (None, None) => Some(view_row),
+        }
+    }
+
+    /// Number of view rows, taking `view_filter` and narrowing into account.
+    fn view_rows(&self) -> usize {
+        match (&self.view_filter, &self.buffer.narrow) {
+            (Some(rows), _) => rows.len(),
+            (None, Some(narrow)) => narrow.len(),
+            (None, None) => self.buffer.content_buffer.len(),
+        }
+    }
+
+    /// Keeps the cursor within the narrowed rows.
+    fn clamp_to_narrowing(&mut self) {
+        let narrow = match &self.buffer.narrow {
+            Some(narrow) => narrow.clone(),
+            None => return,
+        };
+        if self.buffer.cy < narrow.start {
+            (self.buffer.cy, self.buffer.cx) = (narrow.start, 0);
+        } else if self.buffer.cy >= narrow.end {
+            self.buffer.cy = narrow.end - 1;
+            self.buffer.cx = self.buffer.content_buffer[self.buffer.cy].len();
+        }
+    }
+
+    /// C-x n n: restricts display, search and editing to the lines of the
+    /// region, or the cursor line without one.
+    fn narrow_to_region(&mut self) {
+        let (first, last) = match self.buffer.region() {
+            // A region ending at the start of a line doesn't take it in
+            Some(region) if region.end.1 == 0 && region.end.0 > region.start.0 => {
+                (region.start.0, region.end.0 - 1)
+            }
+            Some(region) => (region.start.0, region.end.0),
+            None => (self.buffer.cy, self.buffer.cy),
+        };
+        let last = last.min(self.buffer.content_buffer.len().saturating_sub(1));
+        if first > last {
+            self.editor_set_status_mssage("No lines to narrow to");
+            return;
+        }
+        self.buffer.mark = None;
+        self.view_filter = None;
+        self.buffer.narrow = Some(first..last + 1);
+        self.buffer.row_offset = 0;
+        self.clamp_to_narrowing();
+        self.editor_set_status_mssage("Narrowed; C-x n w widens");
+    }
+
+    /// C-x n w: shows the whole buffer again after narrowing.
+    fn widen(&mut self) {
+        if self.buffer.narrow.take().is_some() {
+            self.buffer.row_offset = self.buffer.cy.saturating_sub(self.rows / 2);
+            self.editor_set_status_mssage("Widened");
+        }
+    }
+
+    /// Width of the line number column, including the space after the numbers.
+    fn gutter_width(&self) -> usize {
+        let numbers = if self.buffer.options.line_numbers {
+            self.buffer.content_buffer.len().max(1).to_string().len() + 1
+        } else {
+            0
+        };
+        let blame = if self.buffer.blame.is_some() {
+            BLAME_WIDTH
+        } else {
+            0
+        };
+        blame + numbers + self.change_marks_shown() as usize
+    }
+
+    /// Whether the gutter starts with a column marking changed lines.
+    fn change_marks_shown(&self) -> bool {
+        self.buffer.options.change_marks && !self.buffer.read_only
+    }
+
+    /// Screen columns left for the text after the line numbers.
+    fn text_columns(&self) -> usize {
+        self.columns.saturating_sub(self.gutter_width()).max(1)
+    }
+
+    /// Screen lines a file row takes up: more than one only for long lines when wrapping.
+    fn wrapped_height(&self, filerow: usize) -> usize {
+        match self.buffer.render_buffer.get(filerow) {
+            Some(line) if self.buffer.options.wrap => line.width() / self.text_columns() + 1,
+            _ => 1,
+        }
+    }
+
+    /// What each screen line of the text area shows: the file row and the
+    /// render column it starts at, or None past the end of the text.
+    fn screen_lines(&self) -> Vec<Option<(usize, usize)>> {
+        let mut lines = vec![];
+        let mut view_row = self.buffer.row_offset;
+        while lines.len() < self.rows {
+            match self.view_row_to_file_row(view_row) {
+                Some(filerow) if filerow < self.buffer.content_buffer.len() => {
+                    if self.buffer.options.wrap {
+                        let width = self.text_columns();
+                        for segment in 0..self.wrapped_height(filerow) {
+                            lines.push(Some((filerow, segment * width)));
+                        }
+                    } else {
+                        lines.push(Some((filerow, self.buffer.col_offset)));
+                    }
+                }
+                _ => lines.push(None),
+            }
+            view_row += 1;
+        }
+        lines.truncate(self.rows);
+        lines
+    }
+
+    fn editor_draw_rows(&mut self) -> io::Result<()> {
+        let gutter_width = self.gutter_width();
+        let width = self.text_columns();
+        for (y, screen_line) in self.screen_lines().into_iter().enumerate() {
+            let (filerow, start) = match screen_line {
+                Some(screen_line) => screen_line,
+                None if self.view_filter.is_some() => {
+                    self.text_buffer.push_str("\x1b[K\r\n");
+                    continue;
+                }
+                None if self.buffer.filename.is_none()
+                    && self.buffer.content_buffer.is_empty()
+                    && self.start_screen.is_none()
+                    && y == self.rows / 3 =>
+                {
+                    let welcome = format!("Kilo in Rust -- version {}", VERSION);
+                    let mut padding = self.columns.saturating_sub(welcome.len()) / 2;
+                    if padding > 0 {
+                        self.text_buffer.push('~');
+                        padding -= 1;
+                    }
+                    for _ in 0..padding {
+                        self.text_buffer.push(' ');
+                    }
+                    let welcome: String = welcome.chars().take(self.columns).collect();
+                    self.text_buffer.push_str(&welcome);
+                    self.text_buffer.push_str("\x1b[K\r\n");
+                    continue;
+                }
+                None => {
+                    self.text_buffer.push_str("~\x1b[K\r\n");
+                    continue;
+                }
+            };
+            let mut numbers_width = gutter_width;
+            if let Some(blame) = &self.buffer.blame {
+                numbers_width -= BLAME_WIDTH;
+                let annotation = match blame.get(filerow) {
+                    Some(Some(line)) if start == 0 || !self.buffer.options.wrap => {
+                        line.annotation()
+                    }
+                    _ => " ".repeat(BLAME_WIDTH),
+                };
+                self.text_buffer
+                    .push_str(&format!("\x1b[36m{}\x1b[m", annotation));
+            }
+            if self.change_marks_shown() {
+                numbers_width -= 1;
+                self.text_buffer
+                    .push_str(match self.buffer.line_change(filerow) {
+                        LineChange::Unchanged => " ",
+                        LineChange::Changed => "\x1b[33m~\x1b[m",
+                        LineChange::Added => "\x1b[32m+\x1b[m",
+                    });
+            }
+            if numbers_width > 0 {
+                let number = if start == 0 || !self.buffer.options.wrap {
+                    (filerow + 1).to_string()
+                } else {
+                    String::new()
+                };
+                self.text_buffer.push_str(&format!(
+                    "\x1b[2m{:>width$} \x1b[m",
+                    number,
+                    width = numbers_width - 1
+                ));
+            }
+            // Render columns covered by the region, drawn in inverse video
+            let region = self.buffer.region().and_then(|region| {
+                let content = &self.buffer.content_buffer[filerow];
+                let (from, to) = region.columns(filerow, content.len())?;
+                Some((self.cx_to_rx(content, from), self.cx_to_rx(content, to)))
+            });
+            // Render columns of the cursors of peers and of what they selected
+            let peers: Vec<(usize, usize, Style)> = self
+                .buffer
+                .peers
+                .iter()
+                .flat_map(|peer| {
+                    let content = &self.buffer.content_buffer[filerow];
+                    let rx = |col: usize| self.cx_to_rx(content, col.min(content.len()));
+                    let selected = peer.region().and_then(|region| {
+                        let (from, to) = region.columns(filerow, content.len())?;
+                        let style = Style {
+                            underline: true,
+                            ..Style::fg(peer.color)
+                        };
+                        Some((rx(from), rx(to), style))
+                    });
+                    let cursor = (peer.cursor.0 == filerow).then(|| {
+                        let at = rx(peer.cursor.1);
+                        let style = Style {
+                            bg: peer.color,
+                            ..Style::fg(30)
+                        };
+                        (at, at + 1, style)
+                    });
+                    selected.into_iter().chain(cursor)
+                })
+                .collect();
+            // Render columns of the diagnostics of cargo check, underlined
+            // in the color of their level
+            let diagnostics: Vec<(usize, usize, u8)> = self
+                .buffer
+                .diagnostics
+                .iter()
+                .filter_map(|diagnostic| {
+                    let content = &self.buffer.content_buffer[filerow];
+                    let (from, to) = diagnostic.region().columns(filerow, content.len())?;
+                    let rx = |col: usize| self.cx_to_rx(content, col);
+                    Some((rx(from), rx(to), diagnostic.level.color()))
+                })
+                .collect();
+            let content = &self.buffer.content_buffer[filerow];
+            let line = &self.buffer.render_buffer[filerow];
+            let clusters: Vec<Cluster> = line
+                .clusters(content, start)
+                .take_while(|cluster| cluster.column < start + width)
+                .collect();
+            if let (Some(first), Some(last)) = (clusters.first(), clusters.last()) {
+                let to = last.char_index + last.text.chars().count();
+                self.buffer
+                    .highlight
+                    .ensure_visible(filerow, content, first.char_index, to);
+            }
+            // Render columns of the leading whitespace, where guides are drawn
+            let indent = match self.buffer.options.indent_guides {
+                true => line
+                    .clusters(content, 0)
+                    .take_while(|cluster| cluster.text == " " || cluster.is_tab())
+                    .last()
+                    .map_or(0, |cluster| cluster.column + cluster.cells),
+                false => 0,
+            };
+            let tab_width = self.buffer.options.tab_width;
+            let mut text = String::new();
+            text.push_str("\x1b[m");
+            let mut last_style = DEFAULT_STYLE;
+            for cluster in &clusters {
+                let chr = cluster.text.chars().next().unwrap_or(' ');
+                if chr.is_control() && !cluster.is_tab() {
+                    let mut bytes = [0; 2];
+                    chr.encode_utf8(&mut bytes);
+                    // Ctrl-A to Ctrl-Z
+                    let converted_chr = if bytes[0] <= 26 {
+                        char::from(bytes[0] + b'@')
+                    } else {
+                        '?'
+                    };
+                    text.push_str(&format!("\x1b[7m{}\x1b[m", converted_chr));
+                    if last_style != DEFAULT_STYLE {
+                        text.push_str(&last_style.sgr());
+                    }
+                    continue;
+                }
+                // A wide character cut off at an edge of the screen is
+                // drawn as spaces, as is a tab
+                let cells =
+                    cluster.column.max(start)..(cluster.column + cluster.cells).min(start + width);
+                let clipped = cells.len() < cluster.cells;
+                for ci in cells {
+                    let guide = ci < indent && ci % tab_width == 0;
+                    let mut style = match self.buffer.highlight.kind(filerow, cluster.char_index) {
+                        _ if guide => self.theme.indent_guide,
+                        Some(kind) => self.theme.style(kind),
+                        None => DEFAULT_STYLE,
+                    };
+                    let diagnostic = diagnostics
+                        .iter()
+                        .find(|(from, to, _)| *from <= ci && ci < *to);
+                    if let Some((_, _, color)) = diagnostic {
+                        style.fg = *color;
+                        style.underline = true;
+                    }
+                    let peer = peers
+                        .iter()
+                        .rev()
+                        .find(|(from, to, _)| *from <= ci && ci < *to);
+                    if let Some((_, _, peer_style)) = peer {
+                        style = *peer_style;
+                    }
+                    if let Some((from, to)) = region {
+                        style.reverse = from <= ci && ci < to;
+                    }
+                    if last_style != style {
+                        text.push_str(&style.sgr());
+                        last_style = style;
+                    }
+                    if guide {
+                        text.push(self.theme.indent_guide_char);
+                    } else if clipped || cluster.is_tab() {
+                        text.push(' ');
+                    } else if ci == cluster.column {
+                        text.push_str(cluster.text);
+                    }
+                }
+            }
+            // The cursor of a peer at the end of the line
+            let end = line.width();
+            let cursor = peers
+                .iter()
+                .find(|(from, to, _)| (*from, *to) == (end, end + 1));
+            if let (Some((_, _, style)), true) = (cursor, (start..start + width).contains(&end)) {
+                text.push_str(&style.sgr());
+                text.push(' ');
+            }
+            text.push_str("\x1b[m");
+            self.text_buffer.push_str(&text);
+            self.text_buffer.push_str("\x1b[K");
+            self.text_buffer.push_str("\r\n");
+        }
+        Ok(())
+    }
+
+    pub fn move_cursor(&mut self, direction: CursorMoveDirection) {
+        use std::cmp::min;
+        use CursorMoveDirection::*;
+        self.buffer.undo.break_coalescing();
+        if let Some(hex) = &mut self.buffer.hex {
+            let row = BYTES_PER_ROW as isize;
+            let column = (hex.cursor % BYTES_PER_ROW) as isize;
+            hex.move_by(match direction {
+                Left => -1,
+                Right => 1,
+                Up => -row,
+                Down => row,
+                PageUp => -row * self.rows as isize,
+                PageDown => row * self.rows as isize,
+                LineTop => -column,
+                LineBottom => row - 1 - column,
+            });
+            return;
+        }
+        match direction {
+            Down | Up => {
+                // Keep to the column on screen, which differs from the
+                // byte index with tabs and wide characters
+                let rx = match self.buffer.content_buffer.get(self.buffer.cy) {
+                    Some(line) => self.cx_to_rx(line, self.buffer.cx),
+                    None => 0,
+                };
+                if matches!(direction, Down) && self.buffer.content_buffer.len() > self.buffer.cy {
+                    self.buffer.cy += 1;
+                } else if matches!(direction, Up) && 0 < self.buffer.cy {
+                    self.buffer.cy -= 1;
+                }
+                if let Some(line) = self.buffer.content_buffer.get(self.buffer.cy) {
+                    self.buffer.cx = self.rx_to_cx(line, rx);
+                }
+            }
+            Right => {
+                if let Some(line) = self.buffer.content_buffer.get(self.buffer.cy) {
+                    if self.buffer.cx < line.len() {
+                        self.buffer.cx = next_grapheme_boundary(line, self.buffer.cx);
+                    } else if self.buffer.cx == line.len() {
+                        self.buffer.cy += 1;
+                        self.buffer.cx = 0;
+                    }
+                }
+            }
+            Left => {
+                if self.buffer.cx > 0 {
+                    let line = &self.buffer.content_buffer[self.buffer.cy];
+                    self.buffer.cx = previous_grapheme_boundary(line, self.buffer.cx);
+                } else if self.buffer.cy > 0 {
+                    self.buffer.cy -= 1;
+                    let line_length = match self.buffer.content_buffer.get(self.buffer.cy) {
+                        Some(line) => line.len(),
+                        _ => 0,
+                    };
+                    self.buffer.cx = line_length;
+                }
+            }
+            PageUp => {
+                self.buffer.cy = self
+                    .view_row_to_file_row(self.buffer.row_offset)
+                    .unwrap_or(self.buffer.row_offset);
+                for _ in 0..self.rows {
+                    self.move_cursor(Up);
+                }
+            }
+            PageDown => {
+                self.buffer.cy = self
+                    .view_row_to_file_row(self.buffer.row_offset + self.rows - 1)
+                    .unwrap_or(self.buffer.row_offset + self.rows - 1);
+                if self.buffer.cy > self.buffer.content_buffer.len() {
+                    self.buffer.cy = self.buffer.content_buffer.len();
+                }
+                for _ in 0..self.rows {
+                    self.move_cursor(Down);
+                }
+            }
+            LineTop => self.buffer.cx = 0,
+            LineBottom => {
+                if let Some(line) = self.buffer.content_buffer.get(self.buffer.cy) {
+                    self.buffer.cx = if self.buffer.options.wrap {
+                        line.len()
+                    } else {
+                        self.rx_to_cx(line, self.text_columns() + self.buffer.col_offset - 1)
+                    };
+                } else {
+                    self.buffer.cx = 0;
+                }
+            }
+        };
+        self.clamp_to_narrowing();
+        let line_length = match self.buffer.content_buffer.get(self.buffer.cy) {
+            Some(line) => line.len(),
+            _ => 0,
+        };
+        self.buffer.cx = min(self.buffer.cx, line_length);
+        if let Some(line) = self.buffer.content_buffer.get(self.buffer.cy) {
+            self.buffer.cx = grapheme_start(line, self.buffer.cx);
+        }
+    }
+
+    /// Render column of the cluster at byte index `cx` in `line`.
+    fn cx_to_rx(&self, line: &str, cx: usize) -> usize {
+        let tab_width = self.buffer.options.tab_width;
+        let mut rx = 0;
+        for (byte_index, cluster) in line.grapheme_indices(true) {
+            if cx <= byte_index {
+                break;
+            }
+            rx += cluster_cells(cluster, rx, tab_width);
+        }
+        rx
+    }
+
+    /// Byte index in `line` of the cluster drawn at render column `rx`.
+    fn rx_to_cx(&self, line: &str, rx: usize) -> usize {
+        let tab_width = self.buffer.options.tab_width;
+        let mut current = 0;
+        for (byte_index, cluster) in line.grapheme_indices(true) {
+            current += cluster_cells(cluster, current, tab_width);
+            if current > rx {
+                return byte_index;
+            }
+        }
+        line.len()
+    }
+
+    /// The text position under a screen cell. Cells past the end of a line
+    /// or below the text map to the nearest position in the text.
+    fn screen_to_position(&self, column: usize, row: usize) -> Option<Position> {
+        if self.buffer.hex.is_some() || self.picker.is_some() {
+            return None;
+        }
+        if self.buffer.content_buffer.is_empty() {
+            return Some((0, 0));
+        }
+        let lines = self.screen_lines();
+        let (filerow, rx) = match lines.get(row.min(lines.len().saturating_sub(1)))? {
+            Some((filerow, start)) => {
+                (*filerow, start + column.saturating_sub(self.gutter_width()))
+            }
+            None => (lines.iter().flatten().last()?.0, usize::MAX),
+        };
+        let line = &self.buffer.content_buffer[filerow];
+        Some((filerow, self.rx_to_cx(line, rx)))
+    }
+
+    /// Left click moves the cursor and dragging selects a region; a double
+    /// click selects a word and a triple click the whole line.
+    pub fn mouse(&mut self, event: MouseEvent) {
+        let step = self
+            .config
+            .get("editor.wheel_step")
+            .and_then(|step| step.parse::<isize>().ok())
+            .unwrap_or(3);
+        match event.button {
+            // Reserved for zooming, which a terminal can't do by itself
+            MouseButton::WheelUp | MouseButton::WheelDown if event.ctrl => return,
+            MouseButton::WheelUp if event.shift => return self.scroll_columns(-step),
+            MouseButton::WheelDown if event.shift => return self.scroll_columns(step),
+            MouseButton::WheelUp => return self.scroll_lines(-step),
+            MouseButton::WheelDown => return self.scroll_lines(step),
+            MouseButton::WheelLeft => return self.scroll_columns(-step),
+            MouseButton::WheelRight => return self.scroll_columns(step),
+            MouseButton::Left => {}
+            _ => return,
+        }
+        let position = match self.screen_to_position(event.column, event.row) {
+            Some(position) => position,
+            None => return,
+        };
+        self.buffer.undo.break_coalescing();
+        if event.dragging {
+            if let Some(anchor) = self.drag_anchor {
+                if self.buffer.mark.is_none() && anchor != position {
+                    self.buffer.mark = Some(anchor);
+                }
+                (self.buffer.cy, self.buffer.cx) = position;
+            }
+            return;
+        }
+        if !event.pressed {
+            self.drag_anchor = None;
+            return;
+        }
+        let now = Instant::now();
+        let count = match self.last_click {
+            Some((time, column, row, count))
+                if now.duration_since(time) <= MULTI_CLICK_INTERVAL
+                    && (column, row) == (event.column, event.row) =>
+            {
+                count % 3 + 1
+            }
+            _ => 1,
+        };
+        self.last_click = Some((now, event.column, event.row, count));
+        let (row, col) = position;
+        let line = self
+            .buffer
+            .content_buffer
+            .get(row)
+            .map_or("", |line| line.as_str());
+        let (mark, cursor) = match count {
+            1 => {
+                self.drag_anchor = Some(position);
+                (None, position)
+            }
+            2 => {
+                let (start, end) = word_bounds(line, col);
+                (Some((row, start)), (row, end))
+            }
+            _ => {
+                let end = if row + 1 < self.buffer.content_buffer.len() {
+                    (row + 1, 0)
+                } else {
+                    (row, line.len())
+                };
+                (Some((row, 0)), end)
+            }
+        };
+        self.buffer.mark = mark;
+        (self.buffer.cy, self.buffer.cx) = cursor;
+    }
+
+    /// Scrolls the view by `lines`, moving the cursor along only as far as
+    /// needed to keep it on screen.
+    fn scroll_lines(&mut self, lines: isize) {
+        if self.buffer.hex.is_some() || self.picker.is_some() {
+            return;
+        }
+        let view_rows = self.view_rows();
+        if view_rows == 0 {
+            return;
+        }
+        let offset = self.buffer.row_offset as isize + lines;
+        self.buffer.row_offset = offset.clamp(0, view_rows as isize - 1) as usize;
+        let margin = self
+            .buffer
+            .options
+            .scroll_margin
+            .min(self.rows.saturating_sub(1) / 2);
+        let top = (self.buffer.row_offset + margin).min(view_rows - 1);
+        let bottom = (self.buffer.row_offset + self.rows)
+            .saturating_sub(margin + 1)
+            .clamp(top, view_rows - 1);
+        let view_row = self.cursor_view_row().clamp(top, bottom);
+        if let Some(filerow) = self.view_row_to_file_row(view_row) {
+            self.buffer.cy = filerow;
+            self.buffer.cx = self.rx_to_cx(&self.buffer.content_buffer[filerow], self.rx);
+        }
+    }
+
+    /// Scrolls long lines sideways by `columns`, keeping the cursor on screen.
+    fn scroll_columns(&mut self, columns: isize) {
+        if self.buffer.options.wrap || self.buffer.hex.is_some() || self.picker.is_some() {
+            return;
+        }
+        let line = match self.buffer.content_buffer.get(self.buffer.cy) {
+            Some(line) => line,
+            None => return,
+        };
+        let offset = self.buffer.col_offset as isize + columns;
+        self.buffer.col_offset = offset.max(0) as usize;
+        let width = self.text_columns();
+        let side_margin = self
+            .buffer
+            .options
+            .side_scroll_margin
+            .min(width.saturating_sub(1) / 2);
+        let rx = self.rx.clamp(
+            self.buffer.col_offset + side_margin,
+            (self.buffer.col_offset + width).saturating_sub(side_margin + 1),
+        );
+        self.buffer.cx = self.rx_to_cx(line, rx);
+    }
+
+    /// Inserts pasted text at the cursor as a single edit.
+    pub fn paste(&mut self, text: &str) {
+        if !self.check_writable() || self.buffer.hex.is_some() || text.is_empty() {
+            return;
+        }
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        self.buffer.undo.break_coalescing();
+        self.insert_text((self.buffer.cy, self.buffer.cx), &text, EditKind::Other);
+        self.buffer.undo.break_coalescing();
+        self.set_dirty();
+    }
+
+    /// Lets the user choose an entry of the kill ring in a picker and
+    /// pastes it at the cursor.
+    pub fn yank_from_ring(&mut self, input: &mut RawMode) -> io::Result<()> {
+        if self.kill_ring.is_empty() {
+            self.editor_set_status_mssage("The kill ring is empty");
+            return Ok(());
+        }
+        let entries: Vec<String> = self.kill_ring.entries().map(str::to_string).collect();
+        // One line per entry, with the line breaks shown
+        let items = entries
+            .iter()
+            .map(|text| text.lines().collect::<Vec<&str>>().join("⏎"))
+            .collect();
+        if let Some(index) = self.pick(input, "Kill ring", items)? {
+            self.kill_ring.push(&entries[index]);
+            self.paste(&entries[index]);
+        }
+        Ok(())
+    }
+
+    /// Middle click: pastes the primary selection at the clicked position,
+    /// when the terminal lets it be read with OSC 52.
+    pub fn paste_primary(&mut self, input: &mut RawMode, event: MouseEvent) -> io::Result<()> {
+        if !self.check_writable() {
+            return Ok(());
+        }
+        let position = match self.screen_to_position(event.column, event.row) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        match input.query_primary_selection()? {
+            Some(text) => {
+                (self.buffer.cy, self.buffer.cx) = position;
+                self.kill_ring.push(&text);
+                self.paste(&text);
+            }
+            None => self.editor_set_status_mssage(
+                "The terminal doesn't share the primary selection; paste with Shift+middle click",
+            ),
+        }
+        Ok(())
+    }
+
+    pub fn editor_scroll(&mut self) {
+        if let Some(hex) = &mut self.buffer.hex {
+            hex.scroll(self.rows);
+            return;
+        }
+        self.clamp_to_narrowing();
+        self.rx = 0;
+        if self.buffer.cy < self.buffer.content_buffer.len() {
+            self.rx = self.cx_to_rx(&self.buffer.content_buffer[self.buffer.cy], self.buffer.cx);
+        }
+        let view_row = self.cursor_view_row();
+        // Context lines kept above and below the cursor, but not past the end of the text
+        let margin = self
+            .buffer
+            .options
+            .scroll_margin
+            .min(self.rows.saturating_sub(1) / 2);
+        let view_rows = self.view_rows();
+        let below = margin.min(view_rows.saturating_sub(view_row + 1));
+        if view_row < self.buffer.row_offset + margin {
+            self.buffer.row_offset = view_row.saturating_sub(margin);
+        }
+        if self.buffer.options.wrap {
+            self.buffer.col_offset = 0;
+            while self.buffer.row_offset < view_row
+                && self.cursor_screen_position().0 + below >= self.rows
+            {
+                self.buffer.row_offset += 1;
+            }
+            return;
+        }
+        if view_row + below >= self.buffer.row_offset + self.rows {
+            self.buffer.row_offset = view_row + below + 1 - self.rows;
+        }
+        let width = self.text_columns();
+        let side_margin = self
+            .buffer
+            .options
+            .side_scroll_margin
+            .min(width.saturating_sub(1) / 2);
+        if self.rx < self.buffer.col_offset + side_margin {
+            self.buffer.col_offset = self.rx.saturating_sub(side_margin);
+        }
+        if self.rx + side_margin >= self.buffer.col_offset + width {
+            self.buffer.col_offset = self.rx + side_margin + 1 - width;
+        }
+    }
+
+    /// Row and column of the cursor within the text area, both from 0.
+    fn cursor_screen_position(&self) -> (usize, usize) {
+        let view_row = self.cursor_view_row();
+        if !self.buffer.options.wrap {
+            return (
+                view_row - self.buffer.row_offset,
+                self.rx - self.buffer.col_offset + self.gutter_width(),
+            );
+        }
+        let width = self.text_columns();
+        let rows_above: usize = (self.buffer.row_offset..view_row)
+            .filter_map(|v| self.view_row_to_file_row(v))
+            .map(|filerow| self.wrapped_height(filerow))
+            .sum();
+        (
+            rows_above + self.rx / width,
+            self.rx % width + self.gutter_width(),
+        )
+    }
+
+    /// Screen row (before scrolling) of the cursor line, taking `view_filter`
+    /// and narrowing into account.
+    fn cursor_view_row(&self) -> usize {
+        match (&self.view_filter, &self.buffer.narrow) {
+            (Some(rows), _) => match rows.binary_search(&self.buffer.cy) {
+                Ok(index) => index,
+                Err(index) => index.min(rows.len().saturating_sub(1)),
+            },
+            (None, Some(narrow)) => self.buffer.cy.saturating_sub(narrow.start),
+            (None, None) => self.buffer.cy,
+        }
+    }
+
+    pub fn open_file(&mut self, filename: String) -> io::Result<()> {
+        use crate::highlight::*;
+        if let Some(remote) = RemotePath::parse(&filename) {
+            return self.open_remote(remote);
+        }
+        let canonicalized_path = self.resolve_path(Path::new(&filename))?;
+        check_regular_file(&canonicalized_path)?;
+        self.buffer.filename = Some(canonicalized_path.clone());
+        self.buffer.link_target = std::fs::read_link(&canonicalized_path).ok();
+        if let Some(cipher) = Cipher::of(&canonicalized_path) {
+            // Decrypted by poll_encrypted, which can ask for the passphrase
+            self.buffer.encrypted = Some(Encrypted::new(cipher));
+            self.buffer.read_only = true;
+            return Ok(());
+        }
+        self.buffer.highlight = Highlight::new(&TextBuffer::default(), canonicalized_path);
+        self.buffer.io = Some(FileIo::load(File::open(&filename)?)?);
+        // Small files are read before the screen is drawn
+        self.poll_buffer_io(Instant::now() + IO_WAIT, true);
+        Ok(())
+    }
+
+    /// Opens `[user@]host:path`, copied into memory by scp in the
+    /// background. The buffer has no local file, so it isn't journaled,
+    /// locked, backed up or autosaved.
+    fn open_remote(&mut self, remote: RemotePath) -> io::Result<()> {
+        self.buffer.name = Some(remote.name());
+        self.buffer.highlight = Highlight::new(&TextBuffer::default(), PathBuf::from(&remote.path));
+        self.buffer.io = Some(FileIo::fetch(&remote)?);
+        self.buffer.remote = Some(remote);
+        Ok(())
+    }
+
+    /// Finishes opening a file once all of it was read.
+    fn finish_open(&mut self) {
+        let canonicalized_path = match (&self.buffer.filename, &self.buffer.remote) {
+            (Some(path), _) => path.clone(),
+            (None, Some(remote)) => PathBuf::from(&remote.path),
+            (None, None) => return,
+        };
+        if self.buffer.hex.is_none() {
+            // The syntax of `notes.md.gpg` is that of `notes.md`
+            let syntax_path = match &self.buffer.encrypted {
+                Some(_) => canonicalized_path.with_extension(""),
+                None => canonicalized_path.clone(),
+            };
+            self.buffer.highlight = Highlight::new(&self.buffer.content_buffer, syntax_path);
+            self.buffer.detect_eol();
+            self.check_long_lines();
+        }
+        if self.buffer.remote.is_some() {
+            self.run_hook(Hook::BufferOpen);
+            return;
+        }
+        self.buffer.disk_mtime = disk_mtime(&self.buffer.filename);
+        self.buffer.disk_writable = disk_writable(&self.buffer.filename);
+        record_recent_file(&canonicalized_path);
+        if let Some(owner) = lock::owner(&canonicalized_path) {
+            self.push_message(
+                MessageLevel::Warn,
+                format!(
+                    "Warning: {} is being edited by {}",
+                    self.buffer.display_name(),
+                    owner
+                ),
+            );
+        }
+        if self.remember_cursor_position() {
+            if let Some((row, col)) = CursorPositions::load().get(&canonicalized_path) {
+                self.buffer.cy = row.min(self.buffer.content_buffer.len());
+                self.buffer.cx = match self.buffer.content_buffer.get(self.buffer.cy) {
+                    Some(line) => grapheme_start(line, col.min(line.len())),
+                    None => 0,
+                };
+            }
+        }
+        match Journal::recover(&canonicalized_path) {
+            Ok(Some(_)) => self.push_message(
+                MessageLevel::Warn,
+                format!(
+                    "{} has unsaved changes from an earlier session; M-x recover replays them",
+                    self.buffer.display_name()
+                ),
+            ),
+            Ok(None) => {}
+            Err(e) => self.push_message(
+                MessageLevel::Warn,
+                format!("Journal of {}: {}", self.buffer.display_name(), e),
+            ),
+        }
+        self.run_hook(Hook::BufferOpen);
+    }
+
+    /// Tells why a file with lines longer than `LONG_LINE`, e.g. a minified
+    /// one, behaves differently: such lines are only highlighted around the
+    /// part on screen. Wraps them too, unless `editor.long_line_wrap` is
+    /// false, so that moving along them doesn't scroll the screen sideways.
+    fn check_long_lines(&mut self) {
+        let longest = self.buffer.content_buffer.iter().map(String::len).max();
+        let longest = match longest {
+            Some(longest) if longest > LONG_LINE => longest,
+            _ => return,
+        };
+        let name = self.buffer.display_name();
+        let message = if self.buffer.options.wrap {
+            format!(
+                "{} has lines of up to {} bytes, highlighted only where shown",
+                name, longest
+            )
+        } else if self
+            .config
+            .get_bool("editor.long_line_wrap")
+            .unwrap_or(true)
+        {
+            self.buffer.options.wrap = true;
+            format!(
+                "{} has lines of up to {} bytes: wrapped them, highlighted only where shown",
+                name, longest
+            )
+        } else {
+            format!("{} has lines of up to {} bytes, highlighted only where shown; M-x set wrap wraps them", name, longest)
+        };
+        self.push_message(MessageLevel::Info, message);
+    }
+
+    /// How `save.symlinks` says to treat files opened through a symbolic link.
+    fn symlinks(&self) -> Symlinks {
+        match self.config.get("save.symlinks") {
+            Some("keep") => Symlinks::Keep,
+            Some("break") => Symlinks::Break,
+            _ => Symlinks::Follow,
+        }
+    }
+
+    /// The absolute path a buffer for `path` is bound to. Symbolic links are
+    /// resolved unless `save.symlinks` keeps the link path.
+    fn resolve_path(&self, path: &Path) -> io::Result<PathBuf> {
+        use std::fs::canonicalize;
+        if self.symlinks() == Symlinks::Follow {
+            return canonicalize(path);
+        }
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => return canonicalize(path),
+        };
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let resolved = canonicalize(parent)?.join(name);
+        // Fail like canonicalize for files which don't exist
+        std::fs::symlink_metadata(&resolved)?;
+        Ok(resolved)
+    }
+
+    fn remember_cursor_position(&self) -> bool {
+        self.config
+            .get_bool("editor.remember_cursor_position")
+            .unwrap_or(true)
+    }
+
+    /// Stores the cursor position of every file buffer for the next session.
+    fn save_cursor_positions(&self) -> io::Result<()> {
+        if !self.remember_cursor_position() {
+            return Ok(());
+        }
+        let mut positions = CursorPositions::load();
+        for buffer in self.buffers.iter().rev().chain(Some(&self.buffer)) {
+            if let Some(path) = &buffer.filename {
+                positions.set(path, buffer.cy, buffer.cx);
+            }
+        }
+        positions.save()
+    }
+
+    fn editor_prompt(
+        &mut self,
+        input: &mut RawMode,
+        format: &str,
+        callback: Option<fn(&mut Self, &str, u8)>,
+    ) -> io::Result<Option<String>> {
+        use crate::input::InputType::*;
+        let mut prompt_buffer = String::new();
+        self.prompt_info.clear();
+        loop {
+            let shown = if self.prompt_info.is_empty() {
+                prompt_buffer.clone()
+            } else {
+                format!("{} [{}]", prompt_buffer, self.prompt_info)
+            };
+            self.editor_set_status_mssage(str::replace(format, "{}", &shown));
+            self.refresh_screen()?;
+
+            let input_type = input.readkey()?;
+            match input_type {
+                Char(b'\x1b') => {
+                    self.editor_set_status_mssage(String::new());
+                    self.prompt_info.clear();
+                    if let Some(cb) = callback {
+                        cb(self, &prompt_buffer, b'\x1b');
+                    }
+                    return Ok(None);
+                }
+                Char(b'\r') => {
+                    self.editor_set_status_mssage(String::new());
+                    self.prompt_info.clear();
+                    if let Some(cb) = callback {
+                        cb(self, &prompt_buffer, b'\r');
+                    }
+                    return Ok(Some(prompt_buffer));
+                }
+                Backspace | Del => {
+                    prompt_buffer.pop();
+                }
+                Char(c) => {
+                    prompt_buffer.push(char::from(c));
+                    if let Some(cb) = callback {
+                        cb(self, &prompt_buffer, c);
+                    }
+                }
+                Text(c) => {
+                    prompt_buffer.push(c);
+                    if let Some(cb) = callback {
+                        // Callbacks only look at control keys; pass the lead byte
+                        let mut bytes = [0; 4];
+                        c.encode_utf8(&mut bytes);
+                        cb(self, &prompt_buffer, bytes[0]);
+                    }
+                }
+                ControlS | CursorMove(CursorMoveDirection::Down) => {
+                    if let Some(cb) = callback {
+                        cb(self, &prompt_buffer, crate::input::CTRL_S);
+                    }
+                }
+                ControlR | CursorMove(CursorMoveDirection::Up) => {
+                    if let Some(cb) = callback {
+                        cb(self, &prompt_buffer, crate::input::CTRL_R);
+                    }
+                }
+                ControlL => {
+                    if let Some(cb) = callback {
+                        cb(self, &prompt_buffer, crate::input::CTRL_L);
+                    }
+                }
+                Paste(text) => {
+                    prompt_buffer.push_str(text.lines().next().unwrap_or(""));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn set_control_x(&mut self, input: &mut RawMode) -> io::Result<()> {
+        use crate::input::InputType::*;
+        self.editor_set_status_mssage("C-x -");
+        self.refresh_screen()?;
+
+        loop {
+            let input_type = input.readkey()?;
+            match input_type {
+                Char(b'\x1b') => {
+                    self.editor_set_status_mssage("C-x esc");
+                    return Ok(());
+                }
+                ControlS => {
+                    self.editor_set_status_mssage("C-x C-s");
+                    return self.save_file(input);
+                }
+                ControlR => {
+                    self.editor_set_status_mssage("C-x C-r");
+                    return self.open_recent(input);
+                }
+                Char(CTRL_W) => {
+                    self.editor_set_status_mssage("C-x C-w");
+                    return self.save_as(input);
+                }
+                Char(b'w') => {
+                    self.editor_set_status_mssage("C-x w");
+                    return self.export(input);
+                }
+                Char(b'p') => {
+                    self.editor_set_status_mssage("C-x p");
+                    return self.print(input);
+                }
+                Char(b'n') => {
+                    return self.set_control_x_n(input);
+                }
+                Char(b'b') => {
+                    match self.choose_buffer(input)? {
+                        Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+                    }
+                    return Ok(());
+                }
+                Char(b'k') => {
+                    match self.kill_buffer(input)? {
+                        Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+                    }
+                    return Ok(());
+                }
+                Char(b'u') => {
+                    self.undo();
+                    return Ok(());
+                }
+                Char(b'U') => {
+                    self.undo_tree();
+                    return Ok(());
+                }
+                Char(b'v') => {
+                    self.show_line_commit();
+                    return Ok(());
+                }
+                Char(b't') => {
+                    self.open_terminal();
+                    return Ok(());
+                }
+                Char(b'g') => {
+                    match self.goto_file() {
+                        Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+                    }
+                    return Ok(());
+                }
+                Char(c @ (b']' | b'[')) => {
+                    match self.step_file(c == b']') {
+                        Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+                    }
+                    return Ok(());
+                }
+                Char(b'f') => {
+                    match self.preview_file_at_point() {
+                        Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+                    }
+                    return Ok(());
+                }
+                Char(b'o') if self.terminal.is_some() => {
+                    self.terminal_focused = true;
+                    return Ok(());
+                }
+                Char(b'y') => {
+                    self.editor_set_status_mssage("C-x y");
+                    return self.yank_from_ring(input);
+                }
+                Char(b'?') => {
+                    return self.describe_key(input);
+                }
+                Char(b'=') => {
+                    let info = self.what_cursor_position();
+                    self.editor_set_status_mssage(info);
+                    return Ok(());
+                }
+                Char(b'\\') => {
+                    return self.compose(input);
+                }
+                Char(b'8') => {
+                    self.editor_set_status_mssage("C-x 8");
+                    return self.insert_unicode(input);
+                }
+                Char(b'(') => {
+                    input.macros.start_recording();
+                    self.editor_set_status_mssage("Defining keyboard macro...");
+                    return Ok(());
+                }
+                Char(b')') => {
+                    if !input.macros.is_recording() {
+                        self.editor_set_status_mssage("Not defining a keyboard macro");
+                    } else if input.macros.stop_recording() {
+                        self.editor_set_status_mssage("Keyboard macro defined");
+                    } else {
+                        self.editor_set_status_mssage("Keyboard macro is empty");
+                    }
+                    return Ok(());
+                }
+                Char(b'e') => {
+                    let count = input.take_count();
+                    match input.macros.last.clone() {
+                        Some(keys) => input.replay(self, &keys, count)?,
+                        None => self.editor_set_status_mssage("No keyboard macro defined"),
+                    }
+                    return Ok(());
+                }
+                NoOp => {}
+                Char(c) => {
+                    if !self.run_binding(input, KeyBinding::ControlX(c))? {
+                        self.editor_set_status_mssage("Command Not Found");
+                    }
+                    return Ok(());
+                }
+                _ => {
+                    self.editor_set_status_mssage("Command Not Found");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// C-x n: narrowing (n to narrow, w to widen) and scratch buffers (s).
+    fn set_control_x_n(&mut self, input: &mut RawMode) -> io::Result<()> {
+        use crate::input::InputType::*;
+        self.editor_set_status_mssage("C-x n -");
+        self.refresh_screen()?;
+        loop {
+            match input.readkey()? {
+                NoOp => continue,
+                Char(b'n') => self.narrow_to_region(),
+                Char(b'w') => self.widen(),
+                Char(b's') => self.scratch_buffer(),
+                _ => self.editor_set_status_mssage("Command Not Found"),
+            }
+            return Ok(());
+        }
+    }
+
+    /// Writes the buffer with its highlighting to a file, as HTML if the
+    /// name ends in `.html` and as ANSI colored text otherwise.
+    pub fn export(&mut self, input: &mut RawMode) -> io::Result<()> {
+        let path = match self.editor_prompt(input, "Export to {} (ESC to cancel)", None)? {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => return Ok(()),
+        };
+        let rendered = export::render(
+            &self.buffer.content_buffer,
+            &self.buffer.highlight,
+            &self.theme,
+            self.buffer.options.tab_width,
+            export::Format::for_path(&path),
+            &self.buffer.display_name(),
+        );
+        match std::fs::write(&path, rendered) {
+            Ok(()) => self.editor_set_status_mssage(format!("Exported to {}", path.display())),
+            Err(e) => self.push_message(
+                MessageLevel::Error,
+                format!("Can't export to {}: {}", path.display(), e),
+            ),
+        }
+        Ok(())
+    }
+
+    /// Sends the buffer, or the region if the mark is set, as plain text to
+    /// `print.command` (lpr by default), numbering the lines if
+    /// `print.line_numbers` is set.
+    pub fn print(&mut self, input: &mut RawMode) -> io::Result<()> {
+        use std::process::{Command, Stdio};
+        let lines: Vec<(usize, &str)> = match self.buffer.region() {
+            Some(region) => (region.start.0..=region.end.0)
+                .filter_map(|row| {
+                    let line = self.buffer.content_buffer.get(row)?;
+                    let (from, to) = region.columns(row, line.len())?;
+                    Some((row, &line[from..to]))
+                })
+                .collect(),
+            None => self
+                .buffer
+                .content_buffer
+                .iter()
+                .enumerate()
+                .map(|(row, line)| (row, line.as_str()))
+                .collect(),
+        };
+        let count = lines.len();
+        let number_width = self.buffer.content_buffer.len().to_string().len();
+        let line_numbers = self.config.get_bool("print.line_numbers").unwrap_or(false);
+        let mut text = String::new();
+        for (row, line) in &lines {
+            if line_numbers {
+                text.push_str(&format!("{:>width$}  ", row + 1, width = number_width));
+            }
+            text.push_str(&self.to_render_line(line));
+            text.push('\n');
+        }
+        let command = self
+            .config
+            .get("print.command")
+            .unwrap_or("lpr")
+            .to_string();
+        let question = format!("Print {} lines with {}?", count, command);
+        if self.confirm(input, &question, false)? != Answer::Yes {
+            self.editor_set_status_mssage("Print aborted");
+            return Ok(());
+        }
+        let mut words = command.split_whitespace();
+        let program = match words.next() {
+            Some(program) => program,
+            None => {
+                self.editor_set_status_mssage("print.command is empty");
+                return Ok(());
+            }
+        };
+        let result = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(text.as_bytes())?;
+                }
+                child.wait()
+            });
+        match result {
+            Ok(status) if status.success() => {
+                self.editor_set_status_mssage(format!("Sent {} lines to {}", count, command))
+            }
+            Ok(status) => self.push_message(
+                MessageLevel::Error,
+                format!("{} failed: {}", command, status),
+            ),
+            Err(e) => {
+                self.push_message(MessageLevel::Error, format!("Can't run {}: {}", command, e))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_meta_s(&mut self, input: &mut RawMode) -> io::Result<()> {
+        use crate::input::InputType::*;
+        self.editor_set_status_mssage("M-s -");
+        self.refresh_screen()?;
+
+        loop {
+            let input_type = input.readkey()?;
+            match input_type {
+                Char(b'\x1b') => {
+                    self.editor_set_status_mssage("M-s esc");
+                    return Ok(());
+                }
+                Char(b'o') => {
+                    self.editor_set_status_mssage("M-s o");
+                    return self.occur(input);
+                }
+                NoOp => {}
+                _ => {
+                    self.editor_set_status_mssage("Command Not Found");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// M-g: reads the key of a command going to a place, e.g. M-g n to
+    /// the next diagnostic of `cargo check`.
+    pub fn set_meta_g(&mut self, input: &mut RawMode) -> io::Result<()> {
+        use crate::input::InputType::*;
+        self.editor_set_status_mssage("M-g -");
+        self.refresh_screen()?;
+
+        loop {
+            let input_type = input.readkey()?;
+            let message = match input_type {
+                Char(b'\x1b') => Ok("M-g esc".to_string()),
+                Char(b'n') => self.step_diagnostic(true),
+                Char(b'p') => self.step_diagnostic(false),
+                NoOp => continue,
+                _ => Err("Command Not Found".to_string()),
+            };
+            match message {
+                Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+            }
+            return Ok(());
+        }
+    }
+
+    pub fn save_file(&mut self, input: &mut RawMode) -> io::Result<()> {
+        if !self.check_writable() {
+            return Ok(());
+        }
+        if let Some(remote) = self.buffer.remote.clone() {
+            self.upload_buffer(&remote);
+            return Ok(());
+        }
+        let filename = match self.buffer.filename.clone() {
+            Some(filename) => filename,
+            None => match self.prompt_save_path(input)? {
+                Some(filename) => filename,
+                None => return Ok(()),
+            },
+        };
+        self.write_buffer(input, filename)
+    }
+
+    /// C-x C-w: writes the buffer to another file, which the buffer visits from then on.
+    pub fn save_as(&mut self, input: &mut RawMode) -> io::Result<()> {
+        if !self.check_writable() {
+            return Ok(());
+        }
+        let filename = match self.prompt_save_path(input)? {
+            Some(filename) => filename,
+            None => return Ok(()),
+        };
+        match filename.to_str().and_then(RemotePath::parse) {
+            Some(remote) => {
+                self.buffer.filename = None;
+                self.buffer.name = Some(remote.name());
+                self.upload_buffer(&remote);
+                self.buffer.remote = Some(remote);
+                Ok(())
+            }
+            None => self.write_buffer(input, filename),
+        }
+    }
+
+    /// Copies the buffer to its remote file with scp in the background.
+    fn upload_buffer(&mut self, remote: &RemotePath) {
+        self.run_hook(Hook::BeforeSave);
+        match FileIo::upload(&self.buffer_bytes(), remote) {
+            Ok(io) => self.buffer.io = Some(io),
+            Err(e) => {
+                let message = format!("Can't upload {}: {}", remote.name(), e);
+                self.push_message(MessageLevel::Error, message);
+            }
+        }
+    }
+
+    /// Asks for the path to save to, confirming before an existing other file is overwritten.
+    fn prompt_save_path(&mut self, input: &mut RawMode) -> io::Result<Option<PathBuf>> {
+        let default = self.buffer.save_path.clone();
+        let prompt = match &default {
+            Some(path) => format!("Save as {{}} (Enter for {}, ESC to cancel)", path.display()),
+            None => "Save as {} (ESC to cancel)".to_string(),
+        };
+        let filename = match (self.editor_prompt(input, &prompt, None)?, default) {
+            (Some(f), _) if !f.is_empty() => PathBuf::from(f),
+            (Some(_), Some(default)) => default,
+            _ => {
+                self.editor_set_status_mssage("Save aborted");
+                return Ok(None);
+            }
+        };
+        let is_other_file = match self.resolve_path(&filename) {
+            Ok(path) => self.buffer.filename.as_ref() != Some(&path),
+            Err(_) => false,
+        };
+        if is_other_file {
+            let question = format!("{} already exists. Overwrite it?", filename.display());
+            if self.confirm(input, &question, false)? != Answer::Yes {
+                self.editor_set_status_mssage("Save aborted");
+                return Ok(None);
+            }
+        }
+        Ok(Some(filename))
+    }
+
+    fn write_buffer(&mut self, input: &mut RawMode, filename: PathBuf) -> io::Result<()> {
+        if let Some(Encrypted {
+            decrypted: false, ..
+        }) = self.buffer.encrypted
+        {
+            self.editor_set_status_mssage("Not decrypted; M-x decrypt first");
+            return Ok(());
+        }
+        self.run_hook(Hook::BeforeSave);
+        if let Some(dir) = filename.parent() {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                let question = format!("Directory {} does not exist. Create it?", dir.display());
+                if self.confirm(input, &question, false)? != Answer::Yes {
+                    self.editor_set_status_mssage("Save aborted");
+                    return Ok(());
+                }
+                if let Err(e) = std::fs::create_dir_all(dir) {
+                    self.push_message(
+                        MessageLevel::Error,
+                        format!("Can't create {}: {}", dir.display(), e),
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        if let Err(e) = backup_file(&self.config, &filename) {
+            self.push_message(MessageLevel::Warn, format!("Can't make backup: {}", e));
+            return Ok(());
+        }
+        let contents = match self.encrypt_for_save(input, &filename, self.buffer_bytes())? {
+            Some(contents) => contents,
+            None => return Ok(()),
+        };
+        if self.symlinks() == Symlinks::Break && self.buffer.link_target.is_some() {
+            // Replace the link with a regular file instead of writing to its
+            // target, keeping the mode of the target. The file is written
+            // next to the link and renamed over it, so that the link is
+            // only gone once the file is complete.
+            let permissions = std::fs::metadata(&filename).ok().map(|m| m.permissions());
+            let temp = replacement_path(&filename);
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp)?;
+            if let Some(permissions) = permissions {
+                file.set_permissions(permissions)?;
+            }
+            self.buffer.io = Some(FileIo::replace(file, temp, contents, filename));
+            self.poll_buffer_io(Instant::now() + IO_WAIT, true);
+            return Ok(());
+        }
+        match File::create(&filename) {
+            Ok(file) => {
+                self.buffer.io = Some(FileIo::save(file, contents, filename, false));
+                // Small files are written before the screen is drawn
+                self.poll_buffer_io(Instant::now() + IO_WAIT, true);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                if self.save_elevated(input, &filename, &contents)? {
+                    self.finish_save(&filename, contents.len())?;
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Finishes writing `len` bytes of the buffer to `filename`.
+    fn finish_save(&mut self, filename: &Path, len: usize) -> io::Result<()> {
+        self.editor_set_status_mssage(format!("{} bytes written to disk", len));
+        self.mark_saved();
+        // A remote file or a titled buffer saved as a local file
+        if self.buffer.remote.take().is_some() || self.buffer.save_path.take().is_some() {
+            self.buffer.name = None;
+        }
+        let canonicalized_path = self.resolve_path(filename)?;
+        self.buffer.link_target = std::fs::read_link(&canonicalized_path).ok();
+        self.bind_to_path(canonicalized_path);
+        self.buffer.disk_mtime = disk_mtime(&self.buffer.filename);
+        self.buffer.disk_writable = disk_writable(&self.buffer.filename);
+        if self.buffer.blame.is_some() {
+            self.idle_tasks.schedule(IdleTask::Blame(self.buffer.id));
+        }
+        self.run_hook(Hook::AfterSave);
+        Ok(())
+    }
+
+    /// M-x rename: moves the file of the buffer to `target`, asked for when
+    /// empty, and binds the buffer to the new path.
+    fn rename_file(
+        &mut self,
+        input: &mut RawMode,
+        target: &str,
+    ) -> io::Result<Result<String, String>> {
+        let path = match &self.buffer.filename {
+            Some(path) => path.clone(),
+            None => return Ok(Err("Buffer has no file".to_string())),
+        };
+        let target = if target.is_empty() {
+            match self.editor_prompt(input, "Rename to {} (ESC to cancel)", None)? {
+                Some(target) if !target.is_empty() => target,
+                _ => return Ok(Err("Rename aborted".to_string())),
+            }
+        } else {
+            target.to_string()
+        };
+        let target = PathBuf::from(target);
+        if target.exists() {
+            let question = format!("{} already exists. Overwrite it?", target.display());
+            if self.confirm(input, &question, false)? != Answer::Yes {
+                return Ok(Err("Rename aborted".to_string()));
+            }
+        }
+        if let Err(e) = std::fs::rename(&path, &target) {
+            return Ok(Err(format!("Can't rename {}: {}", path.display(), e)));
+        }
+        lock::release(&path);
+        // The journal of unsaved edits was kept for the old path
+        if self.buffer.dirty {
+            self.buffer.journal.stop(&path);
+        } else {
+            self.buffer.journal.discard(&path);
+        }
+        let target = self.resolve_path(&target)?;
+        self.bind_to_path(target.clone());
+        if self.buffer.dirty {
+            if let Err(e) = lock::acquire(&target) {
+                let message = format!("Warning: {} is {}", self.buffer.display_name(), e);
+                self.push_message(MessageLevel::Warn, message);
+            }
+        }
+        self.buffer.disk_mtime = disk_mtime(&self.buffer.filename);
+        self.buffer.disk_writable = disk_writable(&self.buffer.filename);
+        Ok(Ok(format!("Renamed to {}", target.display())))
+    }
+
+    /// M-x new: displays an empty buffer for the file `path`, asked for when
+    /// empty, creating missing directories after asking. The file itself is
+    /// written on the first save; an existing file is opened instead.
+    fn new_file(&mut self, input: &mut RawMode, path: &str) -> io::Result<Result<String, String>> {
+        let path = if path.is_empty() {
+            match self.editor_prompt(input, "New file {} (ESC to cancel)", None)? {
+                Some(path) if !path.is_empty() => path,
+                _ => return Ok(Err("Aborted".to_string())),
+            }
+        } else {
+            path.to_string()
+        };
+        if Path::new(&path).exists() {
+            return Ok(match self.visit_file(path.clone()) {
+                Ok(()) => Ok(format!("{} already exists; opened it", path)),
+                Err(e) => Err(format!("Can't open {}: {}", path, e)),
+            });
+        }
+        let path = PathBuf::from(path);
+        let name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            None => return Ok(Err(format!("{} is not a file name", path.display()))),
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if !dir.exists() {
+            let question = format!("Directory {} does not exist. Create it?", dir.display());
+            if self.confirm(input, &question, false)? != Answer::Yes {
+                return Ok(Err("Aborted".to_string()));
+            }
+            if let Err(e) = std::fs::create_dir_all(&dir) {
+                return Ok(Err(format!("Can't create {}: {}", dir.display(), e)));
+            }
+        }
+        let path = std::fs::canonicalize(&dir)?.join(name);
+        if !self.is_untouched() {
+            let buffer = self.new_buffer();
+            self.add_buffer(buffer);
+        }
+        self.bind_to_path(path.clone());
+        Ok(Ok(format!("New file {}", path.display())))
+    }
+
+    /// M-x delete: removes the file of the buffer from disk, after asking,
+    /// and closes the buffer.
+    fn delete_file(&mut self, input: &mut RawMode) -> io::Result<Result<String, String>> {
+        let path = match &self.buffer.filename {
+            Some(path) => path.clone(),
+            None => return Ok(Err("Buffer has no file".to_string())),
+        };
+        let question = format!("Delete {}?", path.display());
+        if self.confirm(input, &question, false)? != Answer::Yes {
+            return Ok(Err("Delete aborted".to_string()));
+        }
+        if let Err(e) = std::fs::remove_file(&path) {
+            return Ok(Err(format!("Can't delete {}: {}", path.display(), e)));
+        }
+        lock::release(&path);
+        self.close_buffer();
+        Ok(Ok(format!("Deleted {}", path.display())))
+    }
+
+    /// Drops the current buffer, showing the one displayed before it, or an
+    /// empty buffer when it was the last.
+    fn close_buffer(&mut self) {
+        let id = self.buffer.id;
+        if let Some(path) = &self.buffer.filename {
+            self.buffer.journal.discard(path);
+        }
+        self.buffers.retain(|b| b.undo_tree_source != Some(id));
+        self.buffer = if self.buffers.is_empty() {
+            self.new_buffer()
+        } else {
+            self.buffers.remove(0)
+        };
+        self.view_filter = None;
+    }
+
+    /// Makes `path` the file of the buffer, highlighting it for its type.
+    fn bind_to_path(&mut self, path: PathBuf) {
+        if self.buffer.filename.as_ref() == Some(&path) {
+            return;
+        }
+        record_recent_file(&path);
+        self.buffer.link_target = std::fs::read_link(&path).ok();
+        self.buffer.filename = Some(path.clone());
+        self.buffer.highlight = Highlight::new(&self.buffer.content_buffer, path);
+        for r in 0..self.buffer.content_buffer.len() {
+            self.editor_update_row(r);
+        }
+    }
+
+    /// Asks a question in the message bar, answered with a single key:
+    /// y or space for yes, n or backspace for no, ! for all (when `allow_all`),
+    /// and q, ESC or Enter to stop asking.
+    pub fn confirm(
+        &mut self,
+        input: &mut RawMode,
+        question: &str,
+        allow_all: bool,
+    ) -> io::Result<Answer> {
+        use crate::input::InputType::*;
+        let keys = if allow_all { "y, n, !, q" } else { "y, n" };
+        self.editor_set_status_mssage(format!("{} ({}) ", i18n::tr(question), keys));
+        self.refresh_screen()?;
+        let answer = loop {
+            match input.readkey()? {
+                Char(b'y') | Char(b'Y') | Char(b' ') => break Answer::Yes,
+                Char(b'n') | Char(b'N') | Backspace | Del => break Answer::No,
+                Char(b'!') if allow_all => break Answer::All,
+                Char(b'q') | Char(b'\x1b') | Char(b'\r') | Char(CTRL_G) => break Answer::Quit,
+                _ => {}
+            }
+        };
+        self.editor_set_status_mssage("");
+        Ok(answer)
+    }
+
+    /// The bytes to write to `filename`: `contents` encrypted again for an
+    /// encrypted buffer, or encrypted for the first time when saving to a
+    /// `.gpg` or `.age` file, after asking for a passphrase. None if the
+    /// save is aborted.
+    fn encrypt_for_save(
+        &mut self,
+        input: &mut RawMode,
+        filename: &Path,
+        contents: Vec<u8>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        if self.buffer.encrypted.is_none() {
+            let cipher = match Cipher::of(filename) {
+                Some(cipher) => cipher,
+                None => return Ok(Some(contents)),
+            };
+            let mut encrypted = Encrypted::new(cipher);
+            encrypted.decrypted = true;
+            encrypted.tried = true;
+            if !cipher.asks_itself() {
+                let prompt = format!("New passphrase for {}: ", filename.display());
+                let passphrase = self.prompt_passphrase(input, &prompt)?;
+                let again = match passphrase {
+                    Some(_) => self.prompt_passphrase(input, "Repeat the passphrase: ")?,
+                    None => None,
+                };
+                match (passphrase, again) {
+                    (Some(passphrase), Some(again)) if passphrase == again => {
+                        encrypted.passphrase = Some(passphrase)
+                    }
+                    (Some(_), Some(_)) => {
+                        self.editor_set_status_mssage("The passphrases differ; save aborted");
+                        return Ok(None);
+                    }
+                    _ => {
+                        self.editor_set_status_mssage("Save aborted");
+                        return Ok(None);
+                    }
+                }
+            }
+            // Don't leave the text in plain in the journal
+            if let Some(path) = &self.buffer.filename {
+                self.buffer.journal.discard(path);
+            }
+            self.buffer.encrypted = Some(encrypted);
+        }
+        let (cipher, passphrase, recipients) = match &self.buffer.encrypted {
+            Some(encrypted) => (
+                encrypted.cipher,
+                encrypted.passphrase.clone(),
+                encrypted.recipients.clone(),
+            ),
+            None => return Ok(Some(contents)),
+        };
+        let recipients = match recipients {
+            Ok(recipients) => recipients,
+            Err(e) => {
+                let message = format!(
+                    "Can't save {} encrypted the same way: {}",
+                    filename.display(),
+                    e
+                );
+                self.push_message(MessageLevel::Error, message);
+                return Ok(None);
+            }
+        };
+        match self.run_cipher(input, cipher, || {
+            cipher.encrypt(contents, passphrase.as_deref(), &recipients)
+        })? {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) => {
+                self.push_message(MessageLevel::Error, format!("Can't encrypt: {}", e));
+                Ok(None)
+            }
+        }
+    }
+
+    /// M-x decrypt: reads the text of an encrypted file into its buffer,
+    /// asking for the passphrase unless the tool asks for it itself. The
+    /// text stays in memory only.
+    fn decrypt_buffer(&mut self, input: &mut RawMode) -> io::Result<Result<String, String>> {
+        let (path, cipher) = match (&self.buffer.filename, &mut self.buffer.encrypted) {
+            (Some(path), Some(encrypted)) if !encrypted.decrypted => {
+                encrypted.tried = true;
+                (path.clone(), encrypted.cipher)
+            }
+            _ => return Ok(Err("Nothing to decrypt".to_string())),
+        };
+        let name = self.buffer.display_name();
+        // Saving encrypts to the same keys, or refuses to
+        let recipients = cipher.recipients(&path);
+        let passphrase = match cipher.asks_itself() {
+            true => None,
+            false => {
+                let prompt = format!("Passphrase for {}: ", name);
+                match self.prompt_passphrase(input, &prompt)? {
+                    Some(passphrase) => Some(passphrase),
+                    None => return Ok(Err("Not decrypted; M-x decrypt asks again".to_string())),
+                }
+            }
+        };
+        let decrypted = self.run_cipher(input, cipher, || {
+            cipher.decrypt(&path, passphrase.as_deref())
+        })?;
+        let text = match decrypted.map(String::from_utf8) {
+            Ok(Ok(text)) => text,
+            Ok(Err(_)) => return Ok(Err(format!("{} doesn't decrypt to text", name))),
+            Err(e) => return Ok(Err(e)),
+        };
+        self.load_text(&text);
+        if let Some(encrypted) = &mut self.buffer.encrypted {
+            encrypted.decrypted = true;
+            encrypted.passphrase = passphrase;
+            encrypted.recipients = recipients;
+        }
+        self.buffer.read_only = false;
+        self.finish_open();
+        Ok(Ok(format!(
+            "Decrypted {}; its edits aren't journaled or autosaved",
+            name
+        )))
+    }
+
+    /// Decrypts the file of the buffer when it is first shown.
+    pub fn poll_encrypted(&mut self, input: &mut RawMode) -> io::Result<()> {
+        match &self.buffer.encrypted {
+            Some(encrypted) if !encrypted.tried => {}
+            _ => return Ok(()),
+        }
+        match self.decrypt_buffer(input)? {
+            Ok(message) => self.push_message(MessageLevel::Warn, message),
+            Err(e) => self.push_message(MessageLevel::Error, e),
+        }
+        Ok(())
+    }
+
+    /// Runs `f`, which runs `cipher`, giving it the terminal if the tool
+    /// asks for the passphrase itself.
+    fn run_cipher<T>(
+        &mut self,
+        input: &mut RawMode,
+        cipher: Cipher,
+        f: impl FnOnce() -> T,
+    ) -> io::Result<T> {
+        if !cipher.asks_itself() {
+            return Ok(f());
+        }
+        write!(self.stdout, "\x1b[2J\x1b[H")?;
+        self.stdout.flush()?;
+        input.with_cooked_mode(f)
+    }
+
+    /// Asks for a passphrase, showing a `*` for every character typed. The
+    /// keys are kept out of the command log and of a macro being recorded.
+    fn prompt_passphrase(
+        &mut self,
+        input: &mut RawMode,
+        prompt: &str,
+    ) -> io::Result<Option<String>> {
+        let private = std::mem::replace(&mut input.private, true);
+        let passphrase = self.read_passphrase(input, prompt);
+        input.private = private;
+        passphrase
+    }
+
+    fn read_passphrase(&mut self, input: &mut RawMode, prompt: &str) -> io::Result<Option<String>> {
+        use crate::input::InputType::*;
+        let mut passphrase = String::new();
+        loop {
+            let stars = "*".repeat(passphrase.chars().count());
+            self.editor_set_status_mssage(format!("{}{}", prompt, stars));
+            self.refresh_screen()?;
+            match input.readkey()? {
+                Char(b'\r') => break,
+                Char(b'\x1b') | Char(CTRL_G) => {
+                    self.editor_set_status_mssage(String::new());
+                    return Ok(None);
+                }
+                Backspace | Del => {
+                    passphrase.pop();
+                }
+                Char(c) if !c.is_ascii_control() => passphrase.push(char::from(c)),
+                Text(c) => passphrase.push(c),
+                Paste(text) => passphrase.push_str(&text),
+                _ => {}
+            }
+        }
+        self.editor_set_status_mssage(String::new());
+        Ok(Some(passphrase))
+    }
+
+    /// Writes `contents` through `sudo tee` (or the `save.elevate_command`
+    /// from the config) after asking the user. Returns whether the file was written.
+    fn save_elevated(
+        &mut self,
+        input: &mut RawMode,
+        filename: &Path,
+        contents: &[u8],
+    ) -> io::Result<bool> {
+        use std::process::{Command, Stdio};
+        let command = self
+            .config
+            .get("save.elevate_command")
+            .unwrap_or("sudo")
+            .to_string();
+        let question = format!("Permission denied. Save with {}?", command);
+        if self.confirm(input, &question, false)? != Answer::Yes {
+            self.editor_set_status_mssage("Save aborted");
+            return Ok(false);
+        }
+        let mut words = command.split_whitespace();
+        let program = match words.next() {
+            Some(program) => program,
+            None => {
+                self.editor_set_status_mssage("save.elevate_command is empty");
+                return Ok(false);
+            }
+        };
+        // The command may ask for a password, so give it a clean, cooked terminal
+        write!(self.stdout, "\x1b[2J\x1b[H")?;
+        self.stdout.flush()?;
+        let result = input.with_cooked_mode(|| {
+            let mut child = Command::new(program)
+                .args(words)
+                .args(["tee", "--"])
+                .arg(filename)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()?;
+            // Closing stdin before waiting, also when writing failed, so
+            // that the command ends and is reaped
+            let written = match child.stdin.take() {
+                Some(mut stdin) => stdin.write_all(contents),
+                None => Ok(()),
+            };
+            let status = child.wait()?;
+            written.map(|()| status)
+        })?;
+        match result {
+            Ok(status) if status.success() => Ok(true),
+            Ok(status) => {
+                self.push_message(
+                    MessageLevel::Error,
+                    format!("{} failed: {}", command, status),
+                );
+                Ok(false)
+            }
+            Err(e) => {
+                self.push_message(MessageLevel::Error, format!("Can't run {}: {}", command, e));
+                Ok(false)
+            }
+        }
+    }
+
+    fn editor_find_callback(&mut self, query: &str, key: u8) {
+        match key {
+            b'\r' | b'\x1b' => {
+                self.search_direction = SearchDirection::Forward;
+                self.search_last_match = None;
+                self.view_filter = None;
+                return;
+            }
+            crate::input::CTRL_L => {
+                // Toggle showing only the lines which contain the query
+                self.view_filter = match self.view_filter {
+                    Some(_) => None,
+                    None => Some(self.matching_rows(query)),
+                };
+                self.buffer.row_offset = self.cursor_view_row();
+                return;
+            }
+            crate::input::CTRL_S => {
+                self.search_direction = SearchDirection::Forward;
+            }
+            crate::input::CTRL_R => {
+                self.search_direction = SearchDirection::Backward;
+            }
+            _ => {
+                self.search_direction = SearchDirection::Forward;
+                self.search_last_match = None;
+                self.prompt_info = match self.count_matches(query) {
+                    1 => "1 match".to_string(),
+                    n => format!("{} matches", n),
+                };
+                if self.view_filter.is_some() {
+                    self.view_filter = Some(self.matching_rows(query));
+                }
+            }
+        }
+        if self.search_last_match.is_none() {
+            self.search_direction = SearchDirection::Forward;
+        }
+        let mut current = self.search_last_match.unwrap_or(0);
+        for i in 0..self.buffer.render_buffer.len() {
+            if i == 0 && self.search_last_match.is_none() {
+                current = 0
+            } else {
+                if self.search_direction == SearchDirection::Forward {
+                    if current + 1 == self.buffer.content_buffer.len() {
+                        current = 0;
+                    } else {
+                        current += 1;
+                    }
+                } else {
+                    if current == 0 {
+                        current = self.buffer.content_buffer.len() - 1
+                    } else {
+                        current -= 1;
+                    }
+                }
+            }
+            if let Some(index) = self.find_in_row(current, query) {
+                self.search_last_match = Some(current);
+                self.buffer.cx = index;
+                self.buffer.cy = current;
+                self.buffer.row_offset = self.cursor_view_row();
+                // Highlights are indexed by chars
+                let from = self.buffer.content_buffer[current][..index].chars().count();
+                self.buffer
+                    .highlight
+                    .match_row(current, from, from + query.chars().count());
+                break;
+            }
+        }
+    }
+
+    /// Makes `buffer` the displayed one, moving the current buffer to the background.
+    pub fn add_buffer(&mut self, buffer: Buffer) {
+        self.run_hook(Hook::BufferLeave);
+        let previous = std::mem::replace(&mut self.buffer, buffer);
+        self.buffers.insert(0, previous);
+        self.view_filter = None;
+    }
+
+    /// Displays the background buffer with the given id. Returns false if there is none.
+    pub fn switch_to_buffer(&mut self, id: usize) -> bool {
+        match self.buffers.iter().position(|b| b.id == id) {
+            Some(index) => {
+                let buffer = self.buffers.remove(index);
+                self.add_buffer(buffer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// C-x b: displays a buffer chosen in a picker, the one shown last
+    /// first, so that Enter goes back to it.
+    fn choose_buffer(&mut self, input: &mut RawMode) -> io::Result<Result<String, String>> {
+        if self.buffers.is_empty() {
+            return Ok(Err("No other buffer".to_string()));
+        }
+        let items = self
+            .buffers
+            .iter()
+            .map(|b| {
+                let modified = if b.dirty { "*" } else { "" };
+                match &b.filename {
+                    Some(path) => format!("{}{}  {}", b.display_name(), modified, path.display()),
+                    None => format!("{}{}", b.display_name(), modified),
+                }
+            })
+            .collect();
+        let ids: Vec<usize> = self.buffers.iter().map(|b| b.id).collect();
+        Ok(match self.pick(input, "Buffers", items)? {
+            Some(index) => {
+                self.switch_to_buffer(ids[index]);
+                Ok(self.buffer.display_name())
+            }
+            None => Ok(String::new()),
+        })
+    }
+
+    /// C-x k: closes the buffer, after asking when it has unsaved changes,
+    /// and displays the one shown before it.
+    fn kill_buffer(&mut self, input: &mut RawMode) -> io::Result<Result<String, String>> {
+        let name = self.buffer.display_name();
+        if self.buffer.dirty {
+            let question = format!("{} has unsaved changes. Close it anyway?", name);
+            if self.confirm(input, &question, false)? != Answer::Yes {
+                return Ok(Err("Close aborted".to_string()));
+            }
+        }
+        if let Some(path) = &self.buffer.filename {
+            lock::release(path);
+            if self.remember_cursor_position() {
+                let mut positions = CursorPositions::load();
+                positions.set(path, self.buffer.cy, self.buffer.cx);
+                // Failing to write the state file must not prevent closing
+                let _ = positions.save();
+            }
+        }
+        self.close_buffer();
+        Ok(Ok(format!("Closed {}", name)))
+    }
+
+    /// C-x \: inserts the character of the digraph typed next, e.g. `a'` for á.
+    pub fn compose(&mut self, input: &mut RawMode) -> io::Result<()> {
+        self.editor_set_status_mssage("C-x \\ -");
+        self.refresh_screen()?;
+        let first = match self.read_char(input)? {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        self.editor_set_status_mssage(format!("C-x \\ {}", first));
+        self.refresh_screen()?;
+        let second = match self.read_char(input)? {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        match self.digraphs.get(first, second) {
+            Some(c) => {
+                self.editor_set_status_mssage("");
+                self.insert_char(c);
+            }
+            None => {
+                self.editor_set_status_mssage(format!("No digraph {}{}", first, second));
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for a printable key. Any other key cancels and yields None.
+    fn read_char(&mut self, input: &mut RawMode) -> io::Result<Option<char>> {
+        use crate::input::InputType::*;
+        loop {
+            match input.readkey()? {
+                NoOp => {}
+                Char(c) if !c.is_ascii_control() => return Ok(Some(char::from(c))),
+                Text(c) => return Ok(Some(c)),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Inserts a character given by its hex codepoint (`e9`, `U+00E9`) or
+    /// its Unicode name (`latin small letter e with acute`).
+    pub fn insert_unicode(&mut self, input: &mut RawMode) -> io::Result<()> {
+        let query = match self.editor_prompt(input, "Insert character (hex or name): {}", None)? {
+            Some(query) if !query.trim().is_empty() => query,
+            _ => return Ok(()),
+        };
+        match parse_character(query.trim()) {
+            Some(c) => self.insert_char(c),
+            None => self.editor_set_status_mssage(format!("No such character: {}", query)),
+        }
+        Ok(())
+    }
+
+    /// C-x =: the character under the cursor, its code point, name, UTF-8
+    /// bytes and width, and where it is: its byte offset in the file and
+    /// its column on screen.
+    pub fn what_cursor_position(&self) -> String {
+        if let Some(hex) = &self.buffer.hex {
+            return match hex.bytes.get(hex.cursor) {
+                Some(byte) => format!("Byte 0x{:02x} at offset {}", byte, hex.cursor),
+                None => format!("End of the buffer, offset {}", hex.cursor),
+            };
+        }
+        let (row, col) = (self.buffer.cy, self.buffer.cx);
+        let ending_len = |row: usize| match self.buffer.options.keep_line_endings {
+            true => self.buffer.line_ending(row).as_str().len(),
+            false => self.buffer.eol.as_str().len(),
+        };
+        let lines = &self.buffer.content_buffer;
+        let offset: usize = lines
+            .iter()
+            .take(row)
+            .enumerate()
+            .map(|(row, line)| line.len() + ending_len(row))
+            .sum::<usize>()
+            + col;
+        let line = match lines.get(row) {
+            Some(line) => line,
+            None => return format!("End of the buffer, offset {}", offset),
+        };
+        let column = self.cx_to_rx(line, col);
+        let c = match line[col..].chars().next() {
+            Some(c) => c,
+            None => return format!("End of line, offset {}, column {}", offset, column),
+        };
+        let bytes: Vec<String> = c
+            .to_string()
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let name = unicode_names2::name(c).map_or(String::new(), |name| format!(" {}", name));
+        let shown = if c.is_control() { ' ' } else { c };
+        format!(
+            "Char: {} (U+{:04X}{}), bytes {}, width {}, offset {}, column {}",
+            shown,
+            c as u32,
+            name,
+            bytes.join(" "),
+            char_cells(c, column, self.buffer.options.tab_width),
+            offset,
+            column
+        )
+    }
+
+    /// Handles a key while the start screen is shown: the arrows choose an
+    /// entry and Enter opens it. Any other key closes the screen and is
+    /// not consumed, so that typing starts editing right away.
+    pub fn start_screen_key(&mut self, input: &mut RawMode, key: &InputType) -> io::Result<bool> {
+        let start = match &mut self.start_screen {
+            Some(start) => start,
+            None => return Ok(false),
+        };
+        match key {
+            InputType::NoOp => {}
+            InputType::CursorMove(CursorMoveDirection::Up) => start.select_prev(),
+            InputType::CursorMove(CursorMoveDirection::Down) => start.select_next(),
+            InputType::Char(b'\r') => {
+                let path = match start.selected_item() {
+                    StartItem::NewFile => None,
+                    StartItem::OpenFile => Some(String::new()),
+                    StartItem::Recent(path) => Some(path.display().to_string()),
+                };
+                self.start_screen = None;
+                match path {
+                    None => match self.new_file(input, "")? {
+                        Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+                    },
+                    Some(path) => self.find_file(input, path)?,
+                }
+            }
+            _ => {
+                self.start_screen = None;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Displays the existing file `path`, asked for when empty.
+    fn find_file(&mut self, input: &mut RawMode, path: String) -> io::Result<()> {
+        let path = if path.is_empty() {
+            match self.editor_prompt(input, "Open file {} (ESC to cancel)", None)? {
+                Some(path) if !path.is_empty() => path,
+                _ => return Ok(()),
+            }
+        } else {
+            path
+        };
+        if let Err(e) = self.visit_file(path.clone()) {
+            self.push_message(MessageLevel::Error, format!("Can't open {}: {}", path, e));
+        }
+        Ok(())
+    }
+
+    /// Displays a new empty buffer without a file, e.g. for notes.
+    pub fn scratch_buffer(&mut self) {
+        let buffer = self.new_buffer();
+        self.add_buffer(buffer);
+        self.editor_set_status_mssage("New scratch buffer; C-x C-s saves it to a file");
+    }
+
+    /// M-x: runs a command typed at the prompt, e.g. `set tabwidth=4`.
+    pub fn execute_command(&mut self, input: &mut RawMode) -> io::Result<()> {
+        let line = match self.editor_prompt(input, "M-x {}", None)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let line = line.trim();
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let message = match command {
+            "" => return Ok(()),
+            "set" => self.set_options(args.trim()),
+            "hex" => {
+                self.toggle_hex();
+                return Ok(());
+            }
+            "run" => self.run_job(args.trim()),
+            "rerun" => match self.last_job.clone() {
+                Some(command) => self.run_job(&command),
+                None => Err("No command run yet".to_string()),
+            },
+            "kill" => match self.job.take() {
+                Some(mut job) => {
+                    job.kill();
+                    self.append_output(job.buffer, vec!["[killed]".to_string()]);
+                    Ok(format!("Killed {}", job.command))
+                }
+                None => Err("No command running".to_string()),
+            },
+            "blame" => self.toggle_blame(),
+            "new" => self.new_file(input, args.trim())?,
+            "rename" => self.rename_file(input, args.trim())?,
+            "delete" => self.delete_file(input)?,
+            "recover" => self.recover(),
+            "decrypt" => self.decrypt_buffer(input)?,
+            "eol" => self.convert_eol(args.trim()),
+            "strip-bom" => self.strip_bom(),
+            "preview" => self.preview_file_at_point(),
+            "switch-buffer" => self.choose_buffer(input)?,
+            "kill-buffer" => self.kill_buffer(input)?,
+            "format" => self.format_region(),
+            "goto-file" => self.goto_file(),
+            "next-file" => self.step_file(true),
+            "previous-file" => self.step_file(false),
+            "what-cursor-position" => Ok(self.what_cursor_position()),
+            "copy-region" => {
+                self.copy_region();
+                return Ok(());
+            }
+            "cut-region" => {
+                self.cut_region();
+                return Ok(());
+            }
+            "indent-region" => {
+                self.indent_region(false);
+                return Ok(());
+            }
+            "outdent-region" => {
+                self.indent_region(true);
+                return Ok(());
+            }
+            #[cfg(feature = "sync")]
+            "sync-host" => self.sync_host(args.trim()),
+            #[cfg(feature = "sync")]
+            "sync-join" => self.sync_join(args.trim()),
+            #[cfg(feature = "sync")]
+            "sync-stop" => self.sync_stop(),
+            "cargo-check" => self.cargo_check(),
+            "next-diagnostic" => self.step_diagnostic(true),
+            "previous-diagnostic" => self.step_diagnostic(false),
+            "kill-ring" => {
+                self.yank_from_ring(input)?;
+                return Ok(());
+            }
+            "theme" => self.choose_theme(input, args.trim())?,
+            "next-theme" => {
+                let themes = Theme::installed();
+                let next = themes
+                    .iter()
+                    .position(|name| *name == self.theme.name)
+                    .map_or(0, |index| (index + 1) % themes.len());
+                self.set_theme(&themes[next])
+            }
+            "history" => {
+                self.file_history(input)?;
+                return Ok(());
+            }
+            "stage-hunk" => self.apply_hunk(input, false)?,
+            "revert-hunk" => self.apply_hunk(input, true)?,
+            "save-command-log" => match args.trim() {
+                "" => Err("Usage: save-command-log FILE".to_string()),
+                path => match input.log.save(Path::new(path)) {
+                    Ok(()) => Ok(format!("Commands of the session written to {}", path)),
+                    Err(e) => Err(format!("Can't write {}: {}", path, e)),
+                },
+            },
+            "name-macro" => match args.trim() {
+                "" => Err("Usage: name-macro NAME".to_string()),
+                name => input
+                    .macros
+                    .name_last(name)
+                    .map(|()| format!("Keyboard macro saved as {}", name)),
+            },
+            #[cfg(feature = "scripting")]
+            _ if self.script_function(|s| s.command(command)).is_some() => {
+                if let Some(function) = self.script_function(|s| s.command(command)) {
+                    self.run_script(&function);
+                }
+                return Ok(());
+            }
+            _ if input.macros.get(command).is_some() => {
+                return self.run_macro(input, command);
+            }
+            _ => Err(format!("Unknown command: {}", command)),
+        };
+        match message {
+            Ok(message) | Err(message) => self.editor_set_status_mssage(message),
+        }
+        Ok(())
+    }
+
+    /// M-x theme: draws the text with the installed theme `name`, chosen
+    /// in a picker when not given.
+    fn choose_theme(
+        &mut self,
+        input: &mut RawMode,
+        name: &str,
+    ) -> io::Result<Result<String, String>> {
+        if !name.is_empty() {
+            return Ok(self.set_theme(name));
+        }
+        let themes = Theme::installed();
+        Ok(match self.pick(input, "Theme", themes.clone())? {
+            Some(index) => self.set_theme(&themes[index]),
+            None => Ok(String::new()),
+        })
+    }
+
+    fn set_theme(&mut self, name: &str) -> Result<String, String> {
+        self.theme = Theme::named(name, &self.config)?;
+        Ok(format!("Theme {}", name))
+    }
+
+    /// Starts `command` in the background, its output streaming into the
+    /// read-only `*Output*` buffer while editing goes on. A command still
+    /// running is killed first.
+    fn run_job(&mut self, command: &str) -> Result<String, String> {
+        if command.is_empty() {
+            return Err("Usage: run COMMAND".to_string());
+        }
+        if let Some(mut job) = self.job.take() {
+            job.kill();
+        }
+        let output = self
+            .buffers
+            .iter()
+            .chain(Some(&self.buffer))
+            .find(|b| b.name.as_deref() == Some("*Output*"))
+            .map(|b| b.id);
+        let id = match output {
+            Some(id) => {
+                self.with_buffer(id, |window| {
+                    window.load_text("");
+                    window.buffer.cy = 0;
+                });
+                if self.buffer.id != id {
+                    self.switch_to_buffer(id);
+                }
+                id
+            }
+            None => {
+                let mut buffer = self.new_buffer();
+                buffer.name = Some("*Output*".to_string());
+                buffer.read_only = true;
+                let id = buffer.id;
+                self.add_buffer(buffer);
+                id
+            }
+        };
+        let job = Job::start(command, id).map_err(|e| format!("Can't run {}: {}", command, e))?;
+        self.append_output(id, vec![format!("$ {}", command)]);
+        self.job = Some(job);
+        self.last_job = Some(command.to_string());
+        Ok(format!("Running {} (M-x kill to stop it)", command))
+    }
+
+    /// Appends what the background command wrote to its buffer, and its
+    /// exit status once it is done.
+    pub fn poll_job(&mut self) {
+        let job = match &mut self.job {
+            Some(job) => job,
+            None => return,
+        };
+        let (mut lines, running) = job.take_output();
+        let id = job.buffer;
+        if !running {
+            let status = match job.finish() {
+                Ok((status, time)) => format!("[{} after {:.1}s]", status, time.as_secs_f64()),
+                Err(e) => format!("[{}]", e),
+            };
+            let message = format!("{}: {}", job.command, status);
+            lines.push(status);
+            self.job = None;
+            self.editor_set_status_mssage(message);
+        }
+        if !lines.is_empty() {
+            self.append_output(id, lines);
+        }
+    }
+
+    /// M-x cargo-check: runs `cargo check` in the background for the
+    /// package of the file, whose errors and warnings are then underlined
+    /// in the buffers of its files. M-g n and M-g p move between them.
+    fn cargo_check(&mut self) -> Result<String, String> {
+        let path = match &self.buffer.filename {
+            Some(path) => path,
+            None => return Err("The buffer has no file to check".to_string()),
+        };
+        if self.buffer.dirty {
+            return Err("Save the buffer first: cargo checks the file on disk".to_string());
+        }
+        let manifest = cargo_check::find_manifest(path)
+            .ok_or_else(|| format!("{} is not in a cargo package", path.display()))?;
+        let check =
+            CargoCheck::start(&manifest).map_err(|e| format!("Can't run cargo check: {}", e))?;
+        self.cargo_check = Some(check);
+        Ok(format!("Checking {}...", manifest.display()))
+    }
+
+    /// Places the diagnostics of `cargo check` in the buffers once it is
+    /// done, in place of those of the check before.
+    pub fn poll_cargo_check(&mut self) {
+        let result = match self.cargo_check.as_mut().and_then(CargoCheck::take_result) {
+            Some(result) => result,
+            None => return,
+        };
+        self.cargo_check = None;
+        let reports = match result {
+            Ok(reports) => reports,
+            Err(e) => {
+                self.push_message(MessageLevel::Error, format!("cargo check: {}", e));
+                return;
+            }
+        };
+        for buffer in Some(&mut self.buffer).into_iter().chain(&mut self.buffers) {
+            let path = match &buffer.filename {
+                Some(path) => path,
+                None => continue,
+            };
+            let content = &buffer.content_buffer;
+            buffer.diagnostics = reports
+                .iter()
+                .filter(|report| report.is_for(path))
+                .map(|report| report.locate(content))
+                .collect();
+            buffer
+                .diagnostics
+                .sort_by_key(|diagnostic| diagnostic.start);
+        }
+        let count = |level: Level| {
+            self.buffer
+                .diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.level == level)
+                .count()
+        };
+        let message = match (count(Level::Error), count(Level::Warning)) {
+            (0, 0) => "cargo check: no errors or warnings in this file".to_string(),
+            (errors, warnings) => format!(
+                "cargo check: {} errors, {} warnings in this file (M-g n for the next one)",
+                errors, warnings
+            ),
+        };
+        self.editor_set_status_mssage(message);
+    }
+
+    /// Moves to the start of the next diagnostic after the cursor, or the
+    /// one before it, going around at the end, and shows its message.
+    fn step_diagnostic(&mut self, forward: bool) -> Result<String, String> {
+        let cursor = (self.buffer.cy, self.buffer.cx);
+        let diagnostics = &self.buffer.diagnostics;
+        if diagnostics.is_empty() {
+            return Err("No diagnostics (M-x cargo-check)".to_string());
+        }
+        let index = if forward {
+            diagnostics
+                .iter()
+                .position(|diagnostic| diagnostic.start > cursor)
+                .unwrap_or(0)
+        } else {
+            diagnostics
+                .iter()
+                .rposition(|diagnostic| diagnostic.start < cursor)
+                .unwrap_or(diagnostics.len() - 1)
+        };
+        let diagnostic = &diagnostics[index];
+        let message = format!(
+            "{}: {} ({}/{})",
+            diagnostic.level.as_str(),
+            diagnostic.message,
+            index + 1,
+            diagnostics.len()
+        );
+        self.restore_cursor(diagnostic.start);
+        Ok(message)
+    }
+
+    /// Waits until no buffer is being read or written, e.g. before a key
+    /// of a script, which can't wait for the file like the user would.
+    pub fn wait_file_io(&mut self) {
+        while Some(&self.buffer)
+            .into_iter()
+            .chain(&self.buffers)
+            .any(|b| b.io.is_some())
+        {
+            self.poll_file_io();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Takes in what the workers reading and writing files did, for every
+    /// buffer, for a limited time so that keys are still handled while a
+    /// large file loads.
+    pub fn poll_file_io(&mut self) {
+        let ids: Vec<usize> = Some(&self.buffer)
+            .into_iter()
+            .chain(&self.buffers)
+            .filter(|b| b.io.is_some())
+            .map(|b| b.id)
+            .collect();
+        let deadline = Instant::now() + IO_SLICE;
+        for id in ids {
+            self.with_buffer(id, |window| window.poll_buffer_io(deadline, false));
+        }
+    }
+
+    /// Takes in what the worker of the current buffer did until `deadline`.
+    /// With `wait`, waits for the worker until then instead of returning as
+    /// soon as it has nothing new.
+    fn poll_buffer_io(&mut self, deadline: Instant, wait: bool) {
+        while let Some(io) = &self.buffer.io {
+            let timeout = if wait {
+                deadline.saturating_duration_since(Instant::now())
+            } else {
+                Duration::ZERO
+            };
+            match io.next_event(timeout) {
+                Some(event) => self.handle_io_event(event),
+                None => return,
+            }
+            if Instant::now() >= deadline {
+                return;
+            }
+        }
+    }
+
+    fn handle_io_event(&mut self, event: IoEvent) {
+        match event {
+            IoEvent::Lines(lines, endings) => {
+                self.buffer.endings.extend(endings);
+                for line in lines {
+                    let render = self.render_row(&line);
+                    self.buffer.render_buffer.push(render);
+                    self.buffer.content_buffer.push(line);
+                }
+            }
+            IoEvent::Binary(bytes) => {
+                // Not UTF-8 text: edit the raw bytes instead
+                self.buffer.content_buffer.clear();
+                self.buffer.render_buffer.clear();
+                self.buffer.hex = Some(HexView::new(bytes));
+                self.editor_set_status_mssage("Binary file, opened in hex mode");
+            }
+            IoEvent::Done => match self.buffer.io.take().map(|io| io.kind) {
+                Some(IoKind::Loading | IoKind::Fetching) => self.finish_open(),
+                Some(IoKind::Uploading) => {
+                    let len = self.buffer_bytes().len();
+                    self.mark_saved();
+                    let name = self.buffer.display_name();
+                    self.editor_set_status_mssage(format!("{} bytes uploaded to {}", len, name));
+                    self.run_hook(Hook::AfterSave);
+                }
+                Some(IoKind::Saving { autosave: true, .. }) => {
+                    self.mark_saved();
+                    self.buffer.disk_mtime = disk_mtime(&self.buffer.filename);
+                    let message = format!("Autosaved {}", self.buffer.display_name());
+                    self.editor_set_status_mssage(message);
+                }
+                Some(IoKind::Saving { path, .. }) => {
+                    let len = self.buffer_bytes().len();
+                    if let Err(e) = self.finish_save(&path, len) {
+                        self.push_message(MessageLevel::Error, e);
+                    }
+                }
+                None => {}
+            },
+            IoEvent::Failed(e) => {
+                let message = match self.buffer.io.take().map(|io| io.kind) {
+                    Some(IoKind::Saving { path, .. }) => {
+                        format!("Can't write {}: {}", path.display(), e)
+                    }
+                    Some(IoKind::Uploading) => {
+                        format!("Can't upload {}: {}", self.buffer.display_name(), e)
+                    }
+                    Some(IoKind::Fetching) => {
+                        // Saving what was read would truncate the remote file
+                        self.buffer.remote = None;
+                        format!("Can't fetch {}: {}", self.buffer.display_name(), e)
+                    }
+                    _ => format!("Can't read {}: {}", self.buffer.display_name(), e),
+                };
+                self.push_message(MessageLevel::Error, message);
+            }
+        }
+    }
+
+    /// Waits until no file is being written, e.g. before quitting.
+    fn finish_saves(&mut self) {
+        let ids: Vec<usize> = Some(&self.buffer)
+            .into_iter()
+            .chain(&self.buffers)
+            .filter(|b| {
+                matches!(
+                    b.io.as_ref().map(|io| &io.kind),
+                    Some(IoKind::Saving { .. } | IoKind::Uploading)
+                )
+            })
+            .map(|b| b.id)
+            .collect();
+        for id in ids {
+            self.with_buffer(id, |window| {
+                while window.buffer.io.is_some() {
+                    window.poll_buffer_io(Instant::now() + Duration::from_secs(1), true);
+                }
+            });
+        }
+    }
+
+    /// Adds lines at the end of the buffer `id`. A cursor on the last line
+    /// follows the output.
+    fn append_output(&mut self, id: usize, lines: Vec<String>) {
+        self.with_buffer(id, |window| {
+            let follow = window.buffer.cy + 1 >= window.buffer.content_buffer.len();
+            let at = window.buffer.content_buffer.len();
+            let count = lines.len();
+            window.buffer.content_buffer.extend(lines);
+            window.editor_insert_rows(at, count);
+            if follow {
+                window.buffer.cy = window.buffer.content_buffer.len().saturating_sub(1);
+                window.buffer.cx = 0;
+            }
+        });
+    }
+
+    /// Runs `f` with the buffer `id` as the current buffer, e.g. to update a
+    /// background buffer. Returns None if there is no such buffer.
+    fn with_buffer<T>(&mut self, id: usize, f: impl FnOnce(&mut Window) -> T) -> Option<T> {
+        if self.buffer.id == id {
+            return Some(f(self));
+        }
+        let index = self.buffers.iter().position(|b| b.id == id)?;
+        std::mem::swap(&mut self.buffer, &mut self.buffers[index]);
+        let result = f(self);
+        std::mem::swap(&mut self.buffer, &mut self.buffers[index]);
+        Some(result)
+    }
+
+    /// Reads `git blame` again for a saved buffer showing it, e.g. to show
+    /// the commits made since it was turned on.
+    fn refresh_blame(&mut self) {
+        if self.buffer.blame.is_none() || self.buffer.dirty {
+            return;
+        }
+        if let Some(path) = &self.buffer.filename {
+            if let Ok(lines) = git::blame(path) {
+                self.buffer.blame = Some(lines.into_iter().map(Some).collect());
+            }
+        }
+    }
+
+    /// Shows or hides who last changed each line, from `git blame`.
+    fn toggle_blame(&mut self) -> Result<String, String> {
+        if self.buffer.blame.take().is_some() {
+            return Ok("Blame off".to_string());
+        }
+        let path = self.buffer.filename.clone().ok_or("Buffer has no file")?;
+        let mut lines: Vec<Option<BlameLine>> = git::blame(&path)?.into_iter().map(Some).collect();
+        if self.buffer.dirty {
+            // Lines edited since the save are not the ones blamed
+            for (row, change) in self.buffer.changes.iter().enumerate() {
+                match change {
+                    LineChange::Unchanged => {}
+                    LineChange::Changed => {
+                        if let Some(line) = lines.get_mut(row) {
+                            *line = None;
+                        }
+                    }
+                    LineChange::Added => lines.insert(row.min(lines.len()), None),
+                }
+            }
+        }
+        self.buffer.blame = Some(lines.into());
+        Ok("Blame on: C-x v shows the commit of the line".to_string())
+    }
+
+    /// Lists the commits which changed the file and opens the chosen
+    /// revision in a read-only buffer, at the line of the cursor. Enter
+    /// there returns to the working copy at the same line.
+    fn file_history(&mut self, input: &mut RawMode) -> io::Result<()> {
+        let path = match &self.buffer.filename {
+            Some(path) => path.clone(),
+            None => {
+                self.editor_set_status_mssage("Buffer has no file");
+                return Ok(());
+            }
+        };
+        let revisions = match git::file_log(&path) {
+            Ok(revisions) if revisions.is_empty() => {
+                self.editor_set_status_mssage("No commits of this file");
+                return Ok(());
+            }
+            Ok(revisions) => revisions,
+            Err(e) => {
+                self.push_message(MessageLevel::Error, e);
+                return Ok(());
+            }
+        };
+        let items = revisions
+            .iter()
+            .map(|revision| format!("{} {}", &revision.hash[..7], revision.summary))
+            .collect();
+        let title = format!("History of {}", self.buffer.display_name());
+        let revision = match self.pick(input, &title, items)? {
+            Some(index) => &revisions[index],
+            None => return Ok(()),
+        };
+        let text = match revision.content(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                self.push_message(MessageLevel::Error, e);
+                return Ok(());
+            }
+        };
+        let lines: Vec<String> = text.lines().map(|line| line.to_string()).collect();
+        let mut old = self.new_buffer();
+        old.name = Some(format!(
+            "{}@{}",
+            self.buffer.display_name(),
+            &revision.hash[..7]
+        ));
+        old.read_only = true;
+        old.revision_source = Some(self.buffer.id);
+        old.cy = self.buffer.cy.min(lines.len());
+        old.render_buffer = lines.iter().map(|l| self.render_row(l)).collect();
+        old.highlight = Highlight::new(&lines, path);
+        old.content_buffer = lines.into();
+        self.add_buffer(old);
+        self.editor_set_status_mssage(format!(
+            "{} (Enter: back to the working copy)",
+            revision.summary
+        ));
+        Ok(())
+    }
+
+    /// Shows the full message of the commit which last changed the line
+    /// under the cursor in a popup.
+    fn show_line_commit(&mut self) {
+        let path = match &self.buffer.filename {
+            Some(path) => path.clone(),
+            None => return self.editor_set_status_mssage("Buffer has no file"),
+        };
+        let blamed = match &self.buffer.blame {
+            Some(blame) => Ok(blame.get(self.buffer.cy).cloned().flatten()),
+            None => git::blame(&path).map(|blame| blame.get(self.buffer.cy).cloned()),
+        };
+        let line = match blamed {
+            Ok(Some(line)) if line.is_committed() => line,
+            Ok(_) => return self.editor_set_status_mssage("Line not committed yet"),
+            Err(e) => return self.push_message(MessageLevel::Error, e),
+        };
+        match git::commit_message(&path, &line.hash) {
+            Ok(message) => {
+                let lines = message.trim_end().lines().map(str::to_string).collect();
+                self.hover = Some(((self.buffer.cy, self.buffer.cx), lines));
+            }
+            Err(e) => self.push_message(MessageLevel::Error, e),
+        }
+    }
+
+    /// Stages the git hunk under the cursor, or with `revert` restores the
+    /// lines of the index there, so that changes can be committed piecemeal.
+    fn apply_hunk(
+        &mut self,
+        input: &mut RawMode,
+        revert: bool,
+    ) -> io::Result<Result<String, String>> {
+        let path = match &self.buffer.filename {
+            Some(path) => path.clone(),
+            None => return Ok(Err("Buffer has no file".to_string())),
+        };
+        if self.buffer.dirty {
+            return Ok(Err("Save the buffer first".to_string()));
+        }
+        let diff = match FileDiff::read(&path) {
+            Ok(diff) => diff,
+            Err(e) => return Ok(Err(e)),
+        };
+        let hunk = match diff.hunk_at(self.buffer.cy) {
+            Some(hunk) => hunk,
+            None => return Ok(Err("No change at the cursor".to_string())),
+        };
+        let lines = format!(
+            "lines {}-{}",
+            hunk.new_start,
+            hunk.new_start + hunk.new_len.max(1) - 1
+        );
+        if !revert {
+            return Ok(diff.stage(hunk).map(|()| format!("Staged {}", lines)));
+        }
+        let question = format!("Revert {} to the index?", lines);
+        if self.confirm(input, &question, false)? != Answer::Yes {
+            return Ok(Err("Revert aborted".to_string()));
+        }
+        if let Err(e) = diff.revert(hunk) {
+            return Ok(Err(e));
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                self.load_text(&text);
+                self.buffer.disk_mtime = disk_mtime(&self.buffer.filename);
+                Ok(Ok(format!("Reverted {}", lines)))
+            }
+            Err(e) => Ok(Err(format!("Can't reload {}: {}", path.display(), e))),
+        }
+    }
+
+    /// Runs what `key` is bound to: the plugin handling it, or else a named
+    /// macro. Returns false if nothing is bound to the key.
+    pub fn run_binding(&mut self, input: &mut RawMode, key: KeyBinding) -> io::Result<bool> {
+        if let Some(index) = self.plugins.bound_to(key) {
+            let mut params = self.plugin_params();
+            params["key"] = json!(key.to_string());
+            self.plugin_request(index, "key", params);
+            return Ok(true);
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(function) = self.script_function(|scripts| scripts.bound_to(key)) {
+            self.run_script(&function);
+            return Ok(true);
+        }
+        match input.macros.bound_to(key).map(str::to_string) {
+            Some(name) => self.run_macro(input, &name).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// C-x ?: tells which bytes the next key sequence is made of, how
+    /// they are read and what they run.
+    pub fn describe_key(&mut self, input: &mut RawMode) -> io::Result<()> {
+        let mut keys = vec![];
+        let mut bytes = vec![];
+        let names = loop {
+            let names: Vec<String> = keys.iter().map(describe::key_name).collect();
+            self.editor_set_status_mssage(format!("Describe key: {}", names.join(" ")));
+            self.refresh_screen()?;
+            let key = input.readkey()?;
+            if let InputType::NoOp = key {
+                continue;
+            }
+            bytes.extend_from_slice(input.last_key());
+            keys.push(key);
+            if !describe::is_prefix(&keys) {
+                break keys.iter().map(describe::key_name).collect::<Vec<_>>();
+            }
+        };
+        let command = match describe::builtin_command(&keys) {
+            Some(command) => command.to_string(),
+            None => self
+                .bound_command(input, &keys)
+                .unwrap_or_else(|| "nothing".to_string()),
+        };
+        self.editor_set_status_mssage(format!(
+            "{} (bytes {}, read as {:?}) runs {}",
+            names.join(" "),
+            bytes.escape_ascii(),
+            keys,
+            command
+        ));
+        Ok(())
+    }
 
-            let input_type = input.readkey()?;
-            match input_type {
-                Char(b'\x1b') => {
-                    self.editor_set_status_mssage(String::new());
-                    if let Some(cb) = callback {
-                        cb(self, &prompt_buffer, b'\x1b');
-                    }
-                    return Ok(None);
+    /// What the keys bound in the config, by plugins or scripts run.
+    fn bound_command(&self, input: &RawMode, keys: &[InputType]) -> Option<String> {
+        let key = match keys {
+            [InputType::Meta(c)] => KeyBinding::Meta(*c),
+            [InputType::ControlX, InputType::Char(c)] => KeyBinding::ControlX(*c),
+            _ => return None,
+        };
+        if let Some(index) = self.plugins.bound_to(key) {
+            return Some(format!("plugin {}", self.plugins.list[index].name));
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(function) = self
+            .scripts
+            .as_ref()
+            .and_then(|scripts| scripts.bound_to(key))
+        {
+            return Some(format!("script function {}", function));
+        }
+        let name = input.macros.bound_to(key)?;
+        Some(format!("macro {}", name))
+    }
+
+    /// The file and the cursor line, sent with requests to plugins.
+    fn plugin_params(&self) -> Value {
+        let line = self
+            .buffer
+            .content_buffer
+            .get(self.buffer.cy)
+            .map(String::as_str)
+            .unwrap_or("");
+        json!({
+            "path": self.buffer.filename.as_ref().map(|path| path.to_string_lossy()),
+            "line": self.buffer.cy,
+            "column": line[..self.buffer.cx.min(line.len())].chars().count(),
+            "text": line,
+        })
+    }
+
+    /// Sends a request to the plugin `index`, carrying out the commands it
+    /// sends until it answers.
+    fn plugin_request(&mut self, index: usize, method: &str, params: Value) {
+        match self.plugins.list[index].request(method, params) {
+            Ok((_, commands)) => {
+                for command in commands {
+                    self.run_plugin_command(command);
                 }
-                Char(b'\r') => {
-                    self.editor_set_status_mssage(String::new());
-                    if let Some(cb) = callback {
-                        cb(self, &prompt_buffer, b'\r');
-                    }
-                    return Ok(Some(prompt_buffer));
+            }
+            Err(e) => self.push_message(MessageLevel::Error, e),
+        }
+    }
+
+    /// Carries out the commands plugins sent on their own.
+    pub fn poll_plugins(&mut self) {
+        for index in 0..self.plugins.list.len() {
+            for command in self.plugins.list[index].take_commands() {
+                self.run_plugin_command(command);
+            }
+        }
+    }
+
+    fn run_plugin_command(&mut self, command: Result<PluginCommand, String>) {
+        match command {
+            Ok(PluginCommand::InsertText(text)) => self.paste(&text),
+            Ok(PluginCommand::SetStatus(text)) => self.editor_set_status_mssage(text),
+            Ok(PluginCommand::Highlight { line, start, end }) => {
+                if self.buffer.hex.is_none() && line < self.buffer.content_buffer.len() {
+                    self.buffer.highlight.match_row(line, start, end);
                 }
-                Backspace | Del => {
-                    if prompt_buffer.len() > 0 {
-                        prompt_buffer.pop();
+            }
+            Ok(PluginCommand::SetCursor {
+                name,
+                line,
+                column,
+                anchor,
+                color,
+            }) => {
+                let color = match Style::parse(&color) {
+                    Ok(style) => style.fg,
+                    Err(e) => {
+                        let message = format!("Plugin error: set_cursor {}: {}", name, e);
+                        return self.push_message(MessageLevel::Error, message);
                     }
+                };
+                let position = |(row, column): (usize, usize)| {
+                    let lines = &self.buffer.content_buffer;
+                    let row = row.min(lines.len());
+                    let line = lines.get(row).map_or("", |line| line.as_str());
+                    let col = line
+                        .char_indices()
+                        .nth(column)
+                        .map_or(line.len(), |(i, _)| i);
+                    (row, col)
+                };
+                let peer = PeerCursor {
+                    name,
+                    cursor: position((line, column)),
+                    anchor: anchor.map(position),
+                    color,
+                };
+                self.buffer.set_peer(peer);
+            }
+            Ok(PluginCommand::RemoveCursor(name)) => self.buffer.remove_peer(&name),
+            Err(e) => self.push_message(MessageLevel::Error, format!("Plugin error: {}", e)),
+        }
+    }
+
+    /// M-x sync-host: shares the buffer with another instance joining at
+    /// `address`, or at `port` of localhost, with the token shown.
+    #[cfg(feature = "sync")]
+    fn sync_host(&mut self, address: &str) -> Result<String, String> {
+        if self.sync.is_some() {
+            return Err("A buffer is already shared".to_string());
+        }
+        if self.buffer.encrypted.is_some() || self.buffer.hex.is_some() {
+            return Err("Only text buffers that aren't encrypted can be shared".to_string());
+        }
+        let address = match address {
+            "" => return Err("Usage: sync-host [ADDRESS:]PORT".to_string()),
+            port if port.bytes().all(|b| b.is_ascii_digit()) => format!("127.0.0.1:{}", port),
+            address => address.to_string(),
+        };
+        let token = sync::new_token().map_err(|e| format!("Can't make a token: {}", e))?;
+        let session = Session::host(&address, token.clone(), self.buffer.id)
+            .map_err(|e| format!("Can't listen at {}: {}", address, e))?;
+        self.sync = Some(session);
+        Ok(format!(
+            "Waiting at {} for someone to join with token {}",
+            address, token
+        ))
+    }
+
+    /// M-x sync-join: edits the buffer shared at `address` in a new buffer,
+    /// with the token sync-host showed.
+    #[cfg(feature = "sync")]
+    fn sync_join(&mut self, args: &str) -> Result<String, String> {
+        if self.sync.is_some() {
+            return Err("A buffer is already shared".to_string());
+        }
+        let (address, token) = match args.split_once(' ') {
+            Some((address, token)) if !token.trim().is_empty() => (address, token.trim()),
+            _ => return Err("Usage: sync-join HOST:PORT TOKEN".to_string()),
+        };
+        let mut buffer = self.new_buffer();
+        let session = Session::join(address, token, buffer.id)
+            .map_err(|e| format!("Can't connect to {}: {}", address, e))?;
+        buffer.name = Some(format!("*Shared {}*", address));
+        self.add_buffer(buffer);
+        self.sync = Some(session);
+        Ok(format!("Joined {}", address))
+    }
+
+    /// M-x sync-stop: stops sharing the buffer, which keeps its text.
+    #[cfg(feature = "sync")]
+    fn sync_stop(&mut self) -> Result<String, String> {
+        match self.sync.take() {
+            Some(session) => {
+                self.end_sync(session);
+                Ok("Stopped sharing".to_string())
+            }
+            None => Err("No buffer is shared".to_string()),
+        }
+    }
+
+    #[cfg(feature = "sync")]
+    fn end_sync(&mut self, session: Session) {
+        if let Some(peer) = &session.peer {
+            let buffer = match self.buffer.id == session.buffer {
+                true => Some(&mut self.buffer),
+                false => self.buffers.iter_mut().find(|b| b.id == session.buffer),
+            };
+            if let Some(buffer) = buffer {
+                buffer.remove_peer(peer);
+            }
+        }
+    }
+
+    /// Applies the edits of the other instance to the shared buffer, when
+    /// it is shown, and sends where the cursor is.
+    #[cfg(feature = "sync")]
+    pub fn poll_sync(&mut self) {
+        let mut session = match self.sync.take() {
+            Some(session) => session,
+            None => return,
+        };
+        if session.buffer != self.buffer.id {
+            if self.buffers.iter().any(|b| b.id == session.buffer) {
+                self.sync = Some(session);
+            }
+            return;
+        }
+        while let Some(event) = session.next_event() {
+            let result = match event {
+                SyncEvent::Joined(stream, peer) => {
+                    session.accept(stream, peer);
+                    self.start_sync(&mut session)
                 }
-                Char(c) => {
-                    prompt_buffer.push(char::from(c));
-                    if let Some(cb) = callback {
-                        cb(self, &prompt_buffer, c);
-                    }
+                SyncEvent::Message(message) => self.receive_sync(&mut session, &message),
+                SyncEvent::Closed(why) => Err(why),
+            };
+            if let Err(e) = result {
+                self.end_sync(session);
+                return self.push_message(MessageLevel::Warn, format!("Sharing ended: {}", e));
+            }
+        }
+        if session.replica.is_some() {
+            let lines = &self.buffer.content_buffer;
+            let cursor = sync::index_of(lines, (self.buffer.cy, self.buffer.cx));
+            if session.sent_cursor != Some(cursor) {
+                session.sent_cursor = Some(cursor);
+                if let Err(e) = session.send(json!({ "cursor": cursor })) {
+                    self.end_sync(session);
+                    return self.push_message(MessageLevel::Warn, format!("Sharing ended: {}", e));
                 }
-                ControlS => {
-                    if let Some(cb) = callback {
-                        cb(self, &prompt_buffer, crate::input::CTRL_S);
+            }
+        }
+        if let (Some(peer), Some(index)) = (&session.peer, session.peer_cursor) {
+            let peer = PeerCursor {
+                name: peer.clone(),
+                cursor: sync::position_of(&self.buffer.content_buffer, index),
+                anchor: None,
+                color: 35,
+            };
+            self.buffer.set_peer(peer);
+        }
+        self.sync = Some(session);
+    }
+
+    /// Sends the text of the shared buffer to the instance that joined,
+    /// from which both start.
+    #[cfg(feature = "sync")]
+    fn start_sync(&mut self, session: &mut Session) -> Result<(), String> {
+        let lines: Vec<&str> = self.buffer.content_buffer.all_lines().collect();
+        let text = lines.join("\n");
+        session.replica = Some(Replica::new(&text, session.site, session.site));
+        let peer = session.peer.clone().unwrap_or_default();
+        self.editor_set_status_mssage(format!("{} joined", peer));
+        session.send(json!({ "text": text, "site": session.site }))
+    }
+
+    #[cfg(feature = "sync")]
+    fn receive_sync(&mut self, session: &mut Session, message: &Value) -> Result<(), String> {
+        if let (Some(text), Some(origin)) = (message["text"].as_str(), message["site"].as_u64()) {
+            // Only the side joining takes the text, and only once
+            if !session.joining || session.replica.is_some() {
+                return Err("unexpected text from the other side".to_string());
+            }
+            // Every line gets an ending, so that the lines joined are `text`
+            self.load_text(&format!("{}\n", text));
+            session.replica = Some(Replica::new(text, origin, session.site));
+            return Ok(());
+        }
+        if let Some(cursor) = message["cursor"].as_u64() {
+            session.peer_cursor = Some(cursor as usize);
+            return Ok(());
+        }
+        let (ops, replica) = match (message["ops"].as_array(), &mut session.replica) {
+            (Some(ops), Some(replica)) => (ops, replica),
+            _ => return Err(format!("unexpected message {}", message)),
+        };
+        let ops = ops
+            .iter()
+            .map(|op| Op::from_json(op).ok_or_else(|| format!("invalid op {}", op)))
+            .collect::<Result<Vec<Op>, String>>()?;
+        let changes = replica.apply(&ops);
+        let lines = &self.buffer.content_buffer;
+        let mut cursor = sync::index_of(lines, (self.buffer.cy, self.buffer.cx));
+        for change in &changes {
+            let lines = &self.buffer.content_buffer;
+            let (at, removed, added) = match change {
+                Change::Inserted(index, text) => {
+                    let at = sync::position_of(lines, *index);
+                    self.buffer.undo.transform(&Edit::Insert {
+                        at,
+                        text: text.clone(),
+                    });
+                    self.apply_insert(at, text);
+                    (*index, 0, text.chars().count())
+                }
+                Change::Deleted(index, count) => {
+                    let start = sync::position_of(lines, *index);
+                    let end = sync::position_of(lines, index + count);
+                    let text = self.apply_delete(start, end);
+                    self.buffer
+                        .undo
+                        .transform(&Edit::Delete { at: start, text });
+                    (*index, *count, 0)
+                }
+            };
+            // Keeps the cursor on its char; the mark moves with the edits
+            cursor = sync::shift_index(cursor, at, removed, added);
+        }
+        if !changes.is_empty() {
+            let lines = &self.buffer.content_buffer;
+            (self.buffer.cy, self.buffer.cx) = sync::position_of(lines, cursor);
+        }
+        Ok(())
+    }
+
+    /// Sends an edit of the shared buffer to the other instance.
+    #[cfg(feature = "sync")]
+    fn share_edit(&mut self, edit: &Edit) {
+        let session = match &mut self.sync {
+            Some(session) if session.buffer == self.buffer.id => session,
+            _ => return,
+        };
+        let replica = match &mut session.replica {
+            Some(replica) => replica,
+            None => return,
+        };
+        let lines = &self.buffer.content_buffer;
+        let (index, removed, ops) = match edit {
+            // A line is added at the end for text inserted past it
+            Edit::Insert { at, text } if at.0 == lines.len() && !lines.is_empty() => {
+                let index = sync::index_of(lines, *at);
+                (index, 0, replica.insert(index, &format!("\n{}", text)))
+            }
+            Edit::Insert { at, text } => {
+                let index = sync::index_of(lines, *at);
+                (index, 0, replica.insert(index, text))
+            }
+            Edit::Delete { at, text } => {
+                let index = sync::index_of(lines, *at);
+                let count = text.chars().count();
+                (index, count, replica.delete(index, count))
+            }
+        };
+        let added = ops
+            .iter()
+            .filter(|op| matches!(op, Op::Insert { .. }))
+            .count();
+        session.peer_cursor = session
+            .peer_cursor
+            .map(|cursor| sync::shift_index(cursor, index, removed, added));
+        if let Err(e) = session.send_ops(&ops) {
+            if let Some(session) = self.sync.take() {
+                self.end_sync(session);
+            }
+            self.push_message(MessageLevel::Warn, format!("Sharing ended: {}", e));
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    fn script_function(&self, find: impl Fn(&Scripts) -> Option<&str>) -> Option<String> {
+        self.scripts.as_ref().and_then(find).map(str::to_string)
+    }
+
+    /// Runs the handlers attached to `hook`.
+    pub fn run_hook(&mut self, hook: Hook) {
+        for handler in self.hooks.handlers(hook) {
+            match handler {
+                Handler::Builtin(Builtin::StripTrailingWhitespace) => {
+                    self.strip_trailing_whitespace()
+                }
+                Handler::Builtin(Builtin::Format) => self.format_buffer(),
+                Handler::Builtin(Builtin::Autosave) => self.autosave(),
+                Handler::Plugin(index) => {
+                    let params = self.plugin_params();
+                    if hook == Hook::BeforeSave {
+                        // Wait for the changes the plugin makes before the save
+                        self.plugin_request(index, hook.name(), params);
+                    } else if let Err(e) = self.plugins.list[index].notify(hook.name(), params) {
+                        self.push_message(MessageLevel::Error, e);
                     }
                 }
-                ControlR => {
-                    if let Some(cb) = callback {
-                        cb(self, &prompt_buffer, crate::input::CTRL_R);
+                #[cfg(feature = "scripting")]
+                Handler::Script(function) => self.run_script(&function),
+            }
+        }
+    }
+
+    /// Runs the idle hook once when no key was pressed for
+    /// `editor.idle_delay` milliseconds.
+    pub fn poll_idle(&mut self, idle: Duration) {
+        let delay = self
+            .config
+            .get("editor.idle_delay")
+            .and_then(|delay| delay.parse().ok())
+            .unwrap_or(1000);
+        if idle < Duration::from_millis(delay) {
+            self.idle_hook_ran = false;
+            return;
+        }
+        if !self.idle_hook_ran {
+            self.idle_hook_ran = true;
+            self.run_hook(Hook::Idle);
+        }
+        self.run_idle_tasks(Instant::now() + IDLE_SLICE);
+    }
+
+    /// Attaches the handlers of plugins and scripts to `hooks` and makes
+    /// them the hooks run.
+    pub fn set_hooks(&mut self, mut hooks: Hooks) {
+        for (index, plugin) in self.plugins.list.iter().enumerate() {
+            for hook in &plugin.hooks {
+                hooks.add(*hook, Handler::Plugin(index));
+            }
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(scripts) = &self.scripts {
+            for (hook, function) in scripts.hooks() {
+                hooks.add(*hook, Handler::Script(function.clone()));
+            }
+        }
+        self.hooks = hooks;
+    }
+
+    /// Takes the project config of `dir` to apply, which main loaded already.
+    pub fn set_project_dir(&mut self, dir: PathBuf) {
+        self.config_mtime = config_mtime(&dir);
+        self.project_dir = dir;
+    }
+
+    /// Reads the config files again once one changed, telling what is wrong
+    /// with them in the message bar and keeping the settings in use if they
+    /// can't be applied.
+    pub fn poll_config(&mut self, macros: &mut Macros) {
+        if self.clean || self.config_checked.elapsed() < CONFIG_CHECK_INTERVAL {
+            return;
+        }
+        self.config_checked = Instant::now();
+        let mtime = config_mtime(&self.project_dir);
+        if mtime == self.config_mtime {
+            return;
+        }
+        self.config_mtime = mtime;
+        match self.reload_config(macros) {
+            Ok(()) => self.editor_set_status_mssage("Config reloaded"),
+            Err(e) => self.push_message(MessageLevel::Error, format!("Config error: {}", e)),
+        }
+    }
+
+    /// Applies the config files as they are now: the theme, cursor style,
+    /// digraphs, abbreviations, key bindings and hooks, and the buffer
+    /// settings not changed with `set`.
+    fn reload_config(&mut self, macros: &mut Macros) -> Result<(), String> {
+        let (config, warning) = Config::load_for(&self.project_dir)?;
+        let theme = Theme::from_config(&config, self.background)?;
+        let digraphs = Digraphs::from_config(&config)?;
+        let abbrevs = Abbrevs::from_config(&config)?;
+        let cursor_style = CursorStyle::from_config(&config)?;
+        let bindings = Macros::bindings_from_config(&config)?;
+        let hooks = Hooks::from_config(&config)?;
+        i18n::set_language(&config)?;
+        let old = BufferOptions::from_config(&self.config);
+        let new = BufferOptions::from_config(&config);
+        let ids: Vec<usize> = self
+            .buffers
+            .iter()
+            .chain(Some(&self.buffer))
+            .map(|b| b.id)
+            .collect();
+        for id in ids {
+            self.with_buffer(id, |window| {
+                let tab_width = window.buffer.options.tab_width;
+                window.buffer.options.rebase(&old, &new);
+                if window.buffer.options.tab_width != tab_width {
+                    window.render_all_rows();
+                }
+            });
+        }
+        self.theme = theme;
+        self.digraphs = digraphs;
+        self.abbrevs = abbrevs;
+        self.cursor_style = cursor_style;
+        self.shown_cursor_style = None;
+        macros.set_bindings(bindings);
+        self.set_hooks(hooks);
+        self.config = config;
+        if let Some(warning) = warning {
+            self.push_message(MessageLevel::Warn, warning);
+        }
+        Ok(())
+    }
+
+    /// Runs idle tasks until `deadline`, putting back the one unfinished then.
+    fn run_idle_tasks(&mut self, deadline: Instant) {
+        while Instant::now() < deadline {
+            let task = match self.idle_tasks.next() {
+                Some(task) => task,
+                None => return,
+            };
+            match task {
+                IdleTask::Highlight(id) => {
+                    let unfinished = self.with_buffer(id, |window| {
+                        let buffer = &mut window.buffer;
+                        let lines = &buffer.content_buffer;
+                        buffer.highlight.resync(lines, usize::MAX, Some(deadline));
+                        buffer.highlight.stale_from.is_some()
+                    });
+                    if unfinished == Some(true) {
+                        self.idle_tasks.resume(task);
                     }
                 }
-                _ => {}
+                IdleTask::Blame(id) => {
+                    self.with_buffer(id, Window::refresh_blame);
+                }
+                IdleTask::Journal(id) => {
+                    self.with_buffer(id, Window::flush_journal);
+                }
+            }
+        }
+    }
+
+    /// Whether hooks may change the text of the buffer.
+    fn hook_may_edit(&self) -> bool {
+        !self.buffer.read_only && self.buffer.hex.is_none() && self.buffer.io.is_none()
+    }
+
+    /// M-x eol: converts the line breaks of the buffer to `lf` or `crlf`,
+    /// which is written when it is saved. Tells the current ones without
+    /// an argument.
+    fn convert_eol(&mut self, name: &str) -> Result<String, String> {
+        let eol = match name {
+            "" => return Ok(format!("Line endings: {}", self.buffer.eol.name())),
+            "lf" | "unix" => LineEnding::Lf,
+            "crlf" | "dos" => LineEnding::CrLf,
+            _ => return Err(format!("Unknown line ending: {} (lf or crlf)", name)),
+        };
+        if !self.hook_may_edit() {
+            return Err("Buffer can't be edited".to_string());
+        }
+        self.buffer.convert_eol(eol);
+        self.set_dirty();
+        Ok(format!("Line endings converted to {}", eol.name()))
+    }
+
+    /// M-x strip-bom: removes the UTF-8 byte order mark starting the buffer.
+    fn strip_bom(&mut self) -> Result<String, String> {
+        if !self.hook_may_edit() {
+            return Err("Buffer can't be edited".to_string());
+        }
+        match self.buffer.content_buffer.first() {
+            Some(line) if line.starts_with(BOM) => {}
+            _ => return Err("No byte order mark".to_string()),
+        }
+        let (row, col) = (self.buffer.cy, self.buffer.cx);
+        self.delete_text((0, 0), (0, BOM.len_utf8()), EditKind::Other);
+        self.set_dirty();
+        let col = if row == 0 {
+            col.saturating_sub(BOM.len_utf8())
+        } else {
+            col
+        };
+        (self.buffer.cy, self.buffer.cx) = (row, col);
+        Ok("Byte order mark removed".to_string())
+    }
+
+    /// Removes the spaces and tabs ending lines, as a single undo step.
+    fn strip_trailing_whitespace(&mut self) {
+        if !self.hook_may_edit() {
+            return;
+        }
+        let cursor = (self.buffer.cy, self.buffer.cx);
+        self.buffer.undo.begin_group();
+        for row in 0..self.buffer.content_buffer.len() {
+            let line = &self.buffer.content_buffer[row];
+            let (end, len) = (line.trim_end_matches([' ', '\t']).len(), line.len());
+            if end < len {
+                self.delete_text((row, end), (row, len), EditKind::Other);
+                self.set_dirty();
+            }
+        }
+        self.buffer.undo.end_group();
+        self.restore_cursor(cursor);
+    }
+
+    /// The formatter of the file type of the buffer: its command in the
+    /// `[formatters]` section, or rustfmt for Rust. rustfmt is given the
+    /// edition of the package of the file, or 2021 outside of one, as it
+    /// formats for 2015 otherwise.
+    fn formatter(&self) -> Result<String, String> {
+        let ftype = self
+            .buffer
+            .highlight
+            .syntax
+            .ftype
+            .to_string()
+            .to_lowercase();
+        match self
+            .config
+            .section("formatters")
+            .find(|(name, _)| *name == ftype)
+        {
+            Some((_, command)) => Ok(command.to_string()),
+            None if ftype == "rust" => {
+                let edition = self
+                    .buffer
+                    .filename
+                    .as_deref()
+                    .and_then(cargo_check::find_manifest)
+                    .and_then(|manifest| cargo_check::manifest_edition(&manifest))
+                    .unwrap_or_else(|| "2021".to_string());
+                Ok(format!("rustfmt --edition {} --emit stdout", edition))
+            }
+            None => Err(format!("No formatter for {} in [formatters]", ftype)),
+        }
+    }
+
+    /// Pipes the buffer through its formatter and takes the output as the
+    /// new text, as a single undo step.
+    fn format_buffer(&mut self) {
+        if !self.hook_may_edit() {
+            return;
+        }
+        let command = match self.formatter() {
+            Ok(command) => command,
+            Err(_) => return,
+        };
+        let rows = 0..self.buffer.content_buffer.len();
+        if let Err(e) = self.format_rows(rows, &command) {
+            self.push_message(MessageLevel::Error, e);
+        }
+    }
+
+    /// M-x format: pipes the lines of the region, or the whole buffer
+    /// without one, through the formatter of its file type and takes the
+    /// output in their place as a single undo step.
+    fn format_region(&mut self) -> Result<String, String> {
+        if !self.check_writable() || self.buffer.hex.is_some() {
+            return Ok(String::new());
+        }
+        let command = self.formatter()?;
+        let rows = match self.region_text() {
+            // A region ending at the start of a line doesn't take it in
+            Some((region, _)) => match region.end {
+                (row, 0) if row > region.start.0 => region.start.0..row,
+                (row, _) => region.start.0..row + 1,
+            },
+            None => 0..self.buffer.content_buffer.len(),
+        };
+        let rows = rows.start..rows.end.min(self.buffer.content_buffer.len());
+        let count = rows.len();
+        match self.format_rows(rows, &command)? {
+            true => Ok(format!("Formatted {} lines", count)),
+            false => Ok("Already formatted".to_string()),
+        }
+    }
+
+    /// Pipes the lines `rows` through `command` and puts the output in
+    /// their place, indented as they were, as a single undo step. Returns
+    /// whether the text changed. It is left as it was when the command
+    /// fails, with its error returned.
+    fn format_rows(&mut self, rows: Range<usize>, command: &str) -> Result<bool, String> {
+        if rows.is_empty() {
+            return Ok(false);
+        }
+        let lines: Vec<&str> = self.buffer.content_buffer.lines_in(rows.clone()).collect();
+        // Taken off the lines, as a formatter starts them at the left edge,
+        // e.g. for the methods of an impl
+        let indent = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| &line[..line.len() - line.trim_start().len()])
+            .min_by_key(|indent| indent.len())
+            .unwrap_or("")
+            .to_string();
+        let text: Vec<&str> = lines
+            .iter()
+            .map(|line| line.strip_prefix(indent.as_str()).unwrap_or(line))
+            .collect();
+        let text = text.join("\n");
+        let formatted = pipe_through(command, &format!("{}\n", text))
+            .map_err(|e| format!("{}: {}", command, e))?;
+        let formatted = formatted.strip_suffix('\n').unwrap_or(&formatted);
+        if formatted == text {
+            return Ok(false);
+        }
+        let formatted: Vec<String> = formatted
+            .split('\n')
+            .map(|line| match line.is_empty() {
+                true => String::new(),
+                false => format!("{}{}", indent, line),
+            })
+            .collect();
+        let cursor = (self.buffer.cy, self.buffer.cx);
+        let last = rows.end - 1;
+        let end = self.buffer.content_buffer[last].len();
+        self.buffer.undo.begin_group();
+        self.delete_text((rows.start, 0), (last, end), EditKind::Other);
+        self.insert_text((rows.start, 0), &formatted.join("\n"), EditKind::Other);
+        self.buffer.undo.end_group();
+        self.set_dirty();
+        self.restore_cursor(cursor);
+        Ok(true)
+    }
+
+    /// Puts the cursor back where it was before the text around it changed.
+    fn restore_cursor(&mut self, (row, col): Position) {
+        self.buffer.cy = row.min(self.buffer.content_buffer.len().saturating_sub(1));
+        self.buffer.cx = match self.buffer.content_buffer.get(self.buffer.cy) {
+            Some(line) => grapheme_start(line, col.min(line.len())),
+            None => 0,
+        };
+    }
+
+    /// Saves a modified file without asking anything, unless the file
+    /// changed on disk since it was read.
+    fn autosave(&mut self) {
+        let path = match &self.buffer.filename {
+            Some(path)
+                if self.buffer.dirty
+                    && self.buffer.io.is_none()
+                    && !self.buffer.read_only
+                    && self.buffer.encrypted.is_none() =>
+            {
+                path.clone()
+            }
+            _ => return,
+        };
+        if disk_mtime(&self.buffer.filename) != self.buffer.disk_mtime {
+            return;
+        }
+        match File::create(&path) {
+            Ok(file) => {
+                self.buffer.io = Some(FileIo::save(file, self.buffer_bytes(), path, true));
+            }
+            Err(e) => self.push_message(
+                MessageLevel::Error,
+                format!("Can't autosave {}: {}", path.display(), e),
+            ),
+        }
+    }
+
+    /// The bytes of the buffer as they are written to its file.
+    fn buffer_bytes(&self) -> Vec<u8> {
+        match &self.buffer.hex {
+            Some(hex) => hex.bytes.clone(),
+            None if self.buffer.options.keep_line_endings => {
+                let lines = self.buffer.content_buffer.iter().enumerate();
+                lines
+                    .flat_map(|(row, line)| {
+                        let ending = self.buffer.line_ending(row).as_str();
+                        line.bytes().chain(ending.bytes())
+                    })
+                    .collect()
+            }
+            None => {
+                let eol = self.buffer.eol.as_str();
+                let lines = self.buffer.content_buffer.iter();
+                lines
+                    .flat_map(|line| line.bytes().chain(eol.bytes()))
+                    .collect()
+            }
+        }
+    }
+
+    /// Marks the buffer as matching its file.
+    fn mark_saved(&mut self) {
+        self.buffer.dirty = false;
+        self.buffer.changes.clear();
+        if !self.buffer.options.keep_line_endings {
+            // Every line was written with `eol`
+            self.buffer.endings.clear();
+        }
+        if let Some(path) = &self.buffer.filename {
+            lock::release(path);
+            self.buffer.journal.discard(path);
+        }
+    }
+
+    /// Writes the pending edits of the buffer to its journal.
+    fn flush_journal(&mut self) {
+        let path = match &self.buffer.filename {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        if let Err(e) = self.buffer.journal.flush(&path, self.buffer.disk_mtime) {
+            let message = format!("Can't write the journal of {}: {}", path.display(), e);
+            self.push_message(MessageLevel::Warn, message);
+        }
+    }
+
+    /// M-x recover: replays the edits an earlier session left in the journal
+    /// of the file, e.g. after a crash, as a single undo step.
+    fn recover(&mut self) -> Result<String, String> {
+        let path = match &self.buffer.filename {
+            Some(path) => path.clone(),
+            None => return Err("Buffer has no file".to_string()),
+        };
+        if !self.hook_may_edit() {
+            return Err("Buffer can't be edited".to_string());
+        }
+        if self.buffer.dirty {
+            return Err("Buffer has unsaved changes".to_string());
+        }
+        let edits = Journal::recover(&path)?.ok_or_else(|| "Nothing to recover".to_string())?;
+        let cursor = (self.buffer.cy, self.buffer.cx);
+        let mut applied = 0;
+        self.buffer.undo.begin_group();
+        for edit in &edits {
+            if !self.fits(edit) {
+                break;
+            }
+            match edit {
+                Edit::Insert { at, text } => self.insert_text(*at, text, EditKind::Other),
+                Edit::Delete { at, .. } => {
+                    self.delete_text(*at, edit.end(), EditKind::Other);
+                }
+            }
+            applied += 1;
+        }
+        self.buffer.undo.end_group();
+        if applied > 0 {
+            self.set_dirty();
+        }
+        self.restore_cursor(cursor);
+        if applied < edits.len() {
+            return Err(format!(
+                "Recovered {} of {} edits; the rest don't match the text",
+                applied,
+                edits.len()
+            ));
+        }
+        Ok(format!("Recovered {} edits", applied))
+    }
+
+    /// Whether a replayed edit applies to the text as it is.
+    fn fits(&self, edit: &Edit) -> bool {
+        let lines = &self.buffer.content_buffer;
+        let valid = |(row, col): Position| match lines.get(row) {
+            Some(line) => line.is_char_boundary(col),
+            None => row == lines.len() && col == 0,
+        };
+        match edit {
+            Edit::Insert { at, .. } => valid(*at),
+            Edit::Delete { at, .. } => valid(*at) && valid(edit.end()),
+        }
+    }
+
+    /// Calls the script function `name` and carries out the actions it asks
+    /// for, the edits as a single undo step.
+    #[cfg(feature = "scripting")]
+    fn run_script(&mut self, name: &str) {
+        let line = self
+            .buffer
+            .content_buffer
+            .get(self.buffer.cy)
+            .map(String::as_str)
+            .unwrap_or("");
+        let column = line[..self.buffer.cx.min(line.len())].chars().count();
+        let path = match &self.buffer.filename {
+            Some(path) => path.to_string_lossy().into_owned(),
+            None => String::new(),
+        };
+        let buffers = Some(&self.buffer)
+            .into_iter()
+            .chain(&self.buffers)
+            .map(|buffer| buffer.display_name())
+            .collect();
+        let snapshot = Snapshot::new(
+            self.buffer.content_buffer.iter().cloned().collect(),
+            (self.buffer.cy, column),
+            path,
+            buffers,
+        );
+        let result = match &mut self.scripts {
+            Some(scripts) => scripts.call(name, snapshot),
+            None => return,
+        };
+        match result {
+            Ok(actions) => {
+                self.buffer.undo.begin_group();
+                for action in actions {
+                    self.run_script_action(action);
+                }
+                self.buffer.undo.end_group();
+            }
+            Err(e) => self.push_message(MessageLevel::Error, format!("Script error: {}", e)),
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    fn run_script_action(&mut self, action: ScriptAction) {
+        let editable = |window: &mut Window| window.check_writable() && window.buffer.hex.is_none();
+        match action {
+            ScriptAction::Insert(text) => {
+                if editable(self) && !text.is_empty() {
+                    self.insert_text((self.buffer.cy, self.buffer.cx), &text, EditKind::Other);
+                    self.set_dirty();
+                }
+            }
+            ScriptAction::SetLine(row, text) => {
+                if row >= self.buffer.content_buffer.len() || !editable(self) {
+                    return;
+                }
+                let cursor = (self.buffer.cy, self.buffer.cx);
+                let end = self.buffer.content_buffer[row].len();
+                self.delete_text((row, 0), (row, end), EditKind::Other);
+                self.insert_text((row, 0), &text, EditKind::Other);
+                self.buffer.cy = cursor.0;
+                self.buffer.cx = cursor.1.min(self.buffer.content_buffer[cursor.0].len());
+                self.set_dirty();
+            }
+            ScriptAction::MoveTo(row, column) => {
+                self.buffer.cy = row.min(self.buffer.content_buffer.len().saturating_sub(1));
+                self.buffer.cx = match self.buffer.content_buffer.get(self.buffer.cy) {
+                    Some(line) => line
+                        .char_indices()
+                        .nth(column)
+                        .map_or(line.len(), |(index, _)| index),
+                    None => 0,
+                };
+            }
+            ScriptAction::Status(text) => self.editor_set_status_mssage(text),
+            ScriptAction::SwitchTo(name) => {
+                let id = self
+                    .buffers
+                    .iter()
+                    .find(|buffer| buffer.display_name() == name)
+                    .map(|buffer| buffer.id);
+                if !id.is_some_and(|id| self.switch_to_buffer(id)) {
+                    self.push_message(MessageLevel::Error, format!("No buffer named {}", name));
+                }
             }
         }
     }
 
-    pub fn set_control_x(&mut self, input: &mut RawMode) -> io::Result<()> {
-        use crate::input::InputType::*;
-        self.editor_set_status_mssage("C-x -");
-        self.refresh_screen()?;
-
-        loop {
-            let input_type = input.readkey()?;
-            match input_type {
-                Char(b'\x1b') => {
-                    self.editor_set_status_mssage("C-x esc");
-                    return Ok(());
-                }
-                ControlS => {
-                    self.editor_set_status_mssage("C-x C-s");
-                    return self.save_file(input);
-                }
-                NoOp => {}
-                _ => {
-                    self.editor_set_status_mssage("Command Not Found");
-                    return Ok(());
-                }
+    /// Replays the macro saved as `name`, as often as the C-u count says.
+    pub fn run_macro(&mut self, input: &mut RawMode, name: &str) -> io::Result<()> {
+        let count = input.take_count();
+        match input.macros.get(name).cloned() {
+            Some(keys) => input.replay(self, &keys, count),
+            None => {
+                self.push_message(MessageLevel::Error, format!("No macro named {}", name));
+                Ok(())
             }
         }
     }
 
-    pub fn save_file(&mut self, input: &mut RawMode) -> io::Result<()> {
-        use std::fs::canonicalize;
-        let mut filename;
-        if self.filename.is_some() {
-            filename = self.filename.clone().unwrap();
-        } else {
-            let result = self.editor_prompt(input, "Save as {} (ESC to cancel)", None)?;
-            if let Some(f) = result {
-                filename = PathBuf::new();
-                filename.push(f);
+    /// Changes buffer options like vim's `:set`: `name=value`, `name` and
+    /// `noname` for flags, and `name?` (or `name` for non-flags) to show a
+    /// value. Without arguments every option is shown.
+    fn set_options(&mut self, args: &str) -> Result<String, String> {
+        const NAMES: [&str; 6] = [
+            "tabwidth",
+            "expandtab",
+            "wrap",
+            "number",
+            "filetype",
+            "readonly",
+        ];
+        if args.is_empty() {
+            let values: Vec<String> = NAMES.iter().map(|name| self.show_option(name)).collect();
+            return Ok(values.join(" "));
+        }
+        let mut shown = vec![];
+        for arg in args.split_whitespace() {
+            if let Some((name, value)) = arg.split_once('=') {
+                self.set_option(name, value)?;
+                shown.push(self.show_option(name));
+            } else if let Some(name) = arg.strip_suffix('?') {
+                if !NAMES.contains(&name) {
+                    return Err(format!("Unknown option: {}", name));
+                }
+                shown.push(self.show_option(name));
+            } else if let Some(name) = arg.strip_prefix("no").filter(|n| is_flag_option(n)) {
+                self.set_option(name, "false")?;
+                shown.push(self.show_option(name));
+            } else if is_flag_option(arg) {
+                self.set_option(arg, "true")?;
+                shown.push(self.show_option(arg));
+            } else if NAMES.contains(&arg) {
+                shown.push(self.show_option(arg));
             } else {
-                self.editor_set_status_mssage("Save aborted");
-                return Ok(());
+                return Err(format!("Unknown option: {}", arg));
             }
         }
-        let mut file_writer = BufWriter::new(File::create(&filename)?);
-        let mut written_bytes = 0;
-        for line in &self.content_buffer {
-            file_writer.write(&format!("{}\n", &line).as_bytes())?;
-            written_bytes += format!("{}\n", &line).as_bytes().len();
+        Ok(shown.join(" "))
+    }
+
+    /// Renders every row again, e.g. after the tab width changed.
+    fn render_all_rows(&mut self) {
+        for row in 0..self.buffer.content_buffer.len() {
+            self.buffer.render_buffer[row] = self.render_row(&self.buffer.content_buffer[row]);
+        }
+    }
+
+    fn show_option(&self, name: &str) -> String {
+        let options = &self.buffer.options;
+        let flag = |on: bool| format!("{}{}", if on { "" } else { "no" }, name);
+        match name {
+            "tabwidth" => format!("tabwidth={}", options.tab_width),
+            "expandtab" => flag(options.expand_tab),
+            "wrap" => flag(options.wrap),
+            "number" => flag(options.line_numbers),
+            "changemarks" => flag(options.change_marks),
+            "abbrev" => flag(options.abbrevs),
+            "autoindent" => flag(options.auto_indent),
+            "comments" => flag(options.continue_comments),
+            "keependings" => flag(options.keep_line_endings),
+            "indentguides" => flag(options.indent_guides),
+            "scrolloff" => format!("scrolloff={}", options.scroll_margin),
+            "sidescrolloff" => format!("sidescrolloff={}", options.side_scroll_margin),
+            "filetype" => format!("filetype={}", self.buffer.highlight.syntax.ftype),
+            "readonly" => flag(self.buffer.read_only),
+            _ => format!("Unknown option: {}", name),
         }
-        file_writer.flush()?;
-        self.editor_set_status_mssage(format!("{} bytes written to disk", written_bytes));
-        self.dirty = false;
-        if self.filename.is_none() {
-            let canonicalized_path = canonicalize(filename)?;
-            self.filename = Some(canonicalized_path.clone());
-            self.highlight = Highlight::new(&self.content_buffer, canonicalized_path);
-            for r in 0..self.content_buffer.len() {
-                self.editor_update_row(r);
+    }
+
+    fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let flag = || match value {
+            "true" | "on" | "1" => Ok(true),
+            "false" | "off" | "0" => Ok(false),
+            _ => Err(format!("{}: expected true or false, got {}", name, value)),
+        };
+        match name {
+            "tabwidth" => {
+                self.buffer.options.tab_width = value
+                    .parse()
+                    .ok()
+                    .filter(|width| *width > 0)
+                    .ok_or_else(|| format!("tabwidth: invalid width {}", value))?;
+                self.render_all_rows();
+            }
+            "expandtab" => self.buffer.options.expand_tab = flag()?,
+            "wrap" => self.buffer.options.wrap = flag()?,
+            "number" => self.buffer.options.line_numbers = flag()?,
+            "changemarks" => self.buffer.options.change_marks = flag()?,
+            "abbrev" => self.buffer.options.abbrevs = flag()?,
+            "autoindent" => self.buffer.options.auto_indent = flag()?,
+            "comments" => self.buffer.options.continue_comments = flag()?,
+            "keependings" => self.buffer.options.keep_line_endings = flag()?,
+            "indentguides" => self.buffer.options.indent_guides = flag()?,
+            "scrolloff" => {
+                self.buffer.options.scroll_margin = value
+                    .parse()
+                    .map_err(|_| format!("scrolloff: invalid number {}", value))?
+            }
+            "sidescrolloff" => {
+                self.buffer.options.side_scroll_margin = value
+                    .parse()
+                    .map_err(|_| format!("sidescrolloff: invalid number {}", value))?
+            }
+            "readonly" => {
+                self.buffer.read_only = flag()?;
+                self.run_hook(Hook::ModeChange);
             }
+            "filetype" => {
+                let syntax = FileSyntax::by_name(value)
+                    .ok_or_else(|| format!("filetype: unknown file type {}", value))?;
+                self.buffer.highlight = Highlight::with_syntax(&self.buffer.content_buffer, syntax);
+                self.run_hook(Hook::ModeChange);
+            }
+            _ => return Err(format!("Unknown option: {}", name)),
         }
         Ok(())
     }
 
-    fn editor_find_callback(&mut self, query: &str, key: u8) {
-        match key {
-            b'\r' | b'\x1b' => {
-                self.search_direction = SearchDirection::Forward;
-                self.search_last_match = None;
-                return;
+    /// Sets the mark at the cursor, or clears it when it is already set.
+    pub fn set_mark(&mut self) {
+        if self.buffer.mark.take().is_some() {
+            self.editor_set_status_mssage("Mark deactivated");
+        } else {
+            self.buffer.mark = Some((self.buffer.cy, self.buffer.cx));
+            self.editor_set_status_mssage("Mark set");
+        }
+    }
+
+    /// The region, with an end past the last line moved to the end of the
+    /// text and ends inside a line snapped to a grapheme boundary, and the
+    /// text it covers.
+    fn region_text(&self) -> Option<(Region, String)> {
+        let mut region = self.buffer.region()?;
+        let lines = &self.buffer.content_buffer;
+        for position in [&mut region.start, &mut region.end] {
+            *position = match lines.get(position.0) {
+                Some(line) => (position.0, grapheme_start(line, position.1)),
+                None => lines
+                    .last()
+                    .map_or((0, 0), |line| (lines.len() - 1, line.len())),
+            };
+        }
+        let text = (region.start.0..=region.end.0)
+            .filter_map(|row| {
+                let line = lines.get(row)?;
+                let (from, to) = region.columns(row, line.len())?;
+                Some(&line[from..to])
+            })
+            .collect::<Vec<&str>>()
+            .join("\n");
+        Some((region, text))
+    }
+
+    /// Puts the text of the region in the kill ring and deactivates the mark.
+    pub fn copy_region(&mut self) {
+        match self.region_text() {
+            Some((_, text)) => {
+                self.kill_ring.push(&text);
+                self.buffer.mark = None;
+                self.editor_set_status_mssage(format!("Copied {} chars", text.chars().count()));
             }
-            crate::input::CTRL_S => {
-                self.search_direction = SearchDirection::Forward;
+            None => self.editor_set_status_mssage("The mark is not set"),
+        }
+    }
+
+    /// Removes the text of the region as a single edit and puts it in the
+    /// kill ring.
+    pub fn cut_region(&mut self) {
+        if !self.check_writable() || self.buffer.hex.is_some() {
+            return;
+        }
+        let (region, text) = match self.region_text() {
+            Some(region) => region,
+            None => return self.editor_set_status_mssage("The mark is not set"),
+        };
+        self.buffer.undo.break_coalescing();
+        self.delete_text(region.start, region.end, EditKind::Other);
+        self.buffer.undo.break_coalescing();
+        self.buffer.mark = None;
+        self.kill_ring.kill(&text);
+        self.set_dirty();
+    }
+
+    /// C-k: removes the rest of the line into the kill ring, or the line
+    /// break at its end.
+    pub fn kill_line(&mut self) {
+        if !self.check_writable() || self.buffer.hex.is_some() {
+            return;
+        }
+        let (row, col) = (self.buffer.cy, self.buffer.cx);
+        let lines = &self.buffer.content_buffer;
+        let end = match lines.get(row) {
+            Some(line) if col < line.len() => (row, line.len()),
+            Some(_) if row + 1 < lines.len() => (row + 1, 0),
+            _ => return self.editor_set_status_mssage("End of buffer"),
+        };
+        self.buffer.undo.break_coalescing();
+        let text = self.delete_text((row, col), end, EditKind::Other);
+        self.buffer.undo.break_coalescing();
+        self.kill_ring.kill(&text);
+        self.set_dirty();
+    }
+
+    /// C-y: pastes the newest entry of the kill ring at the cursor.
+    pub fn yank(&mut self) {
+        let text = match self.kill_ring.get(0) {
+            Some(text) => text.to_string(),
+            None => return self.editor_set_status_mssage("The kill ring is empty"),
+        };
+        self.yank_entry(0, &text);
+    }
+
+    /// M-y right after C-y or M-y: replaces the text pasted with the entry
+    /// of the kill ring after it, going around to the newest.
+    pub fn yank_pop(&mut self) {
+        let (start, end, index) = match self.kill_ring.last_action() {
+            Some(KillAction::Yanked { start, end, index })
+                if end == (self.buffer.cy, self.buffer.cx) =>
+            {
+                (start, end, index)
             }
-            crate::input::CTRL_R => {
-                self.search_direction = SearchDirection::Backward;
+            _ => return self.editor_set_status_mssage("The previous key didn't yank"),
+        };
+        if !self.check_writable() {
+            return;
+        }
+        let index = (index + 1) % self.kill_ring.len();
+        let text = self.kill_ring.get(index).unwrap_or_default().to_string();
+        self.buffer.undo.begin_group();
+        self.delete_text(start, end, EditKind::Other);
+        self.yank_entry(index, &text);
+        self.buffer.undo.end_group();
+        self.editor_set_status_mssage(format!(
+            "Kill ring entry {}/{}",
+            index + 1,
+            self.kill_ring.len()
+        ));
+    }
+
+    fn yank_entry(&mut self, index: usize, text: &str) {
+        if !self.check_writable() || self.buffer.hex.is_some() {
+            return;
+        }
+        let start = (self.buffer.cy, self.buffer.cx);
+        self.paste(text);
+        let end = (self.buffer.cy, self.buffer.cx);
+        self.kill_ring
+            .set_action(KillAction::Yanked { start, end, index });
+    }
+
+    /// TAB with the mark set: indents the lines of the region by one level,
+    /// or takes one level of indentation off them, as one undo step. The
+    /// mark stays, so that it can be done again.
+    pub fn indent_region(&mut self, outdent: bool) {
+        if !self.check_writable() || self.buffer.hex.is_some() {
+            return;
+        }
+        let (region, _) = match self.region_text() {
+            Some(region) => region,
+            None => return self.editor_set_status_mssage("The mark is not set"),
+        };
+        // A region ending at the start of a line doesn't take it in
+        let last = match region.end {
+            (row, 0) if row > region.start.0 => row - 1,
+            (row, _) => row,
+        };
+        let unit = self.buffer.options.indent_unit();
+        let tab_width = self.buffer.options.tab_width;
+        let (cursor, mark) = ((self.buffer.cy, self.buffer.cx), self.buffer.mark);
+        let mut shifts = vec![];
+        self.buffer.undo.begin_group();
+        for row in region.start.0..=last.min(self.buffer.content_buffer.len().saturating_sub(1)) {
+            let line = &self.buffer.content_buffer[row];
+            if !outdent && !line.is_empty() {
+                self.insert_text((row, 0), &unit, EditKind::Other);
+                shifts.push((row, unit.len() as isize));
+                continue;
             }
-            _ => {
-                self.search_direction = SearchDirection::Forward;
-                self.search_last_match = None;
+            let width = match line.starts_with('\t') {
+                true => 1,
+                false => line
+                    .bytes()
+                    .take(tab_width)
+                    .take_while(|&b| b == b' ')
+                    .count(),
+            };
+            if outdent && width > 0 {
+                self.delete_text((row, 0), (row, width), EditKind::Other);
+                shifts.push((row, -(width as isize)));
             }
         }
-        if self.search_last_match.is_none() {
-            self.search_direction = SearchDirection::Forward;
+        self.buffer.undo.end_group();
+        let shift = |(row, col): Position| match shifts.iter().find(|(r, _)| *r == row) {
+            Some((_, by)) => (row, (col as isize + by).max(0) as usize),
+            None => (row, col),
+        };
+        (self.buffer.cy, self.buffer.cx) = shift(cursor);
+        self.buffer.mark = mark.map(shift);
+        if !shifts.is_empty() {
+            self.set_dirty();
         }
-        let mut current = self.search_last_match.unwrap_or(0);
-        for i in 0..self.render_buffer.len() {
-            if i == 0 && self.search_last_match.is_none() {
-                current = 0
-            } else {
-                if self.search_direction == SearchDirection::Forward {
-                    if current + 1 == self.content_buffer.len() {
-                        current = 0;
-                    } else {
-                        current += 1;
-                    }
-                } else {
-                    if current == 0 {
-                        current = self.content_buffer.len() - 1
-                    } else {
-                        current -= 1;
+    }
+
+    pub fn new_buffer(&mut self) -> Buffer {
+        let buffer = Buffer::new(
+            self.next_buffer_id,
+            BufferOptions::from_config(&self.config),
+        );
+        self.next_buffer_id += 1;
+        buffer
+    }
+
+    /// Lists every line of the current buffer containing a query in a
+    /// read-only `*Occur*` buffer. Enter on a listed line jumps to it.
+    pub fn occur(&mut self, input: &mut RawMode) -> io::Result<()> {
+        let query = match self.editor_prompt(input, "Occur {} (ESC to cancel)", None)? {
+            Some(query) if !query.is_empty() => query,
+            _ => return Ok(()),
+        };
+        let mut lines = vec![];
+        let mut matches = vec![];
+        for (index, line) in self.buffer.content_buffer.iter().enumerate() {
+            if let Some(at) = line.find(&query) {
+                let prefix = format!("{:>6}: ", index + 1);
+                let from = prefix.len() + line[..at].chars().count();
+                matches.push((lines.len() + 1, from, from + query.chars().count()));
+                lines.push(format!("{}{}", prefix, line));
+            }
+        }
+        if lines.is_empty() {
+            self.editor_set_status_mssage(format!("No matches for \"{}\"", query));
+            return Ok(());
+        }
+        lines.insert(
+            0,
+            format!(
+                "{} matches for \"{}\" in {}",
+                lines.len(),
+                query,
+                self.buffer.display_name()
+            ),
+        );
+
+        self.buffers.retain(|b| b.occur_source.is_none());
+        let mut occur = self.new_buffer();
+        occur.name = Some("*Occur*".to_string());
+        occur.read_only = true;
+        occur.occur_source = Some(self.buffer.id);
+        occur.cy = 1;
+        occur.render_buffer = lines.iter().map(|l| self.render_row(l)).collect();
+        occur.highlight = Highlight::new(&lines, PathBuf::new());
+        for (row, from, to) in matches {
+            occur.highlight.match_row(row, from, to);
+        }
+        occur.content_buffer = lines.into();
+        self.add_buffer(occur);
+        Ok(())
+    }
+
+    /// Jumps from the occur buffer to the source line under the cursor.
+    fn occur_goto(&mut self) {
+        let row = self
+            .buffer
+            .content_buffer
+            .get(self.buffer.cy)
+            .and_then(|line| line.split(':').next())
+            .and_then(|number| number.trim().parse::<usize>().ok());
+        let (source, row) = match (self.buffer.occur_source, row) {
+            (Some(source), Some(row)) => (source, row - 1),
+            _ => return,
+        };
+        if !self.switch_to_buffer(source) {
+            self.editor_set_status_mssage("The buffer of this occur list is gone");
+            return;
+        }
+        self.buffer.cy = row.min(self.buffer.content_buffer.len());
+        self.buffer.cx = 0;
+    }
+
+    /// The part of a row searched, i.e. the whole line or the columns within
+    /// `search_scope`; rows outside the narrowed ones aren't searched.
+    fn searched_columns(&self, row: usize) -> Option<(usize, usize)> {
+        if self
+            .buffer
+            .narrow
+            .as_ref()
+            .is_some_and(|narrow| !narrow.contains(&row))
+        {
+            return None;
+        }
+        let len = self.buffer.content_buffer.get(row)?.len();
+        match &self.search_scope {
+            Some(region) => region.columns(row, len),
+            None => Some((0, len)),
+        }
+    }
+
+    /// Column of the first occurrence of `query` in the searched part of a row.
+    fn find_in_row(&self, row: usize, query: &str) -> Option<usize> {
+        let (from, to) = self.searched_columns(row)?;
+        self.buffer.content_buffer[row][from..to]
+            .find(query)
+            .map(|index| index + from)
+    }
+
+    /// Number of (non-overlapping) occurrences of `query` in the searched part of the buffer.
+    fn count_matches(&self, query: &str) -> usize {
+        if query.is_empty() {
+            return 0;
+        }
+        (0..self.buffer.content_buffer.len())
+            .filter_map(|row| {
+                let (from, to) = self.searched_columns(row)?;
+                Some(
+                    self.buffer.content_buffer[row][from..to]
+                        .matches(query)
+                        .count(),
+                )
+            })
+            .sum()
+    }
+
+    /// Replaces occurrences of a string in the buffer, asking for each one
+    /// whether to replace it (y), skip it (n), replace the rest (!) or stop (q).
+    pub fn replace(&mut self, input: &mut RawMode) -> io::Result<()> {
+        if !self.check_writable() {
+            return Ok(());
+        }
+        let query = match self.editor_prompt(input, "Replace: {} (ESC to cancel)", None)? {
+            Some(query) if !query.is_empty() => query,
+            _ => return Ok(()),
+        };
+        let replacement = match self.editor_prompt(
+            input,
+            &format!("Replace {} with: {{}} (ESC to cancel)", query),
+            None,
+        )? {
+            Some(replacement) => replacement,
+            None => return Ok(()),
+        };
+        // With an active region only the text inside of it is replaced
+        self.search_scope = self.buffer.region();
+        let cursor = (self.buffer.cy, self.buffer.cx);
+        let mut replaced = 0;
+        let mut replace_all = false;
+        // All replacements are undone at once
+        self.buffer.undo.begin_group();
+        'rows: for row in 0..self.buffer.content_buffer.len() {
+            let (mut from, mut to) = match self.searched_columns(row) {
+                Some(columns) => columns,
+                None => continue,
+            };
+            while let Some(offset) = self.buffer.content_buffer[row][from..to].find(&query) {
+                let at = from + offset;
+                if !replace_all {
+                    // Show the occurrence as a region with the cursor at its start
+                    (self.buffer.cy, self.buffer.cx) = (row, at);
+                    self.buffer.mark = Some((row, at + query.len()));
+                    let question = format!("Replace {} with {}?", query, replacement);
+                    match self.confirm(input, &question, true)? {
+                        Answer::Yes => {}
+                        Answer::No => {
+                            from = at + query.len();
+                            continue;
+                        }
+                        Answer::All => replace_all = true,
+                        Answer::Quit => break 'rows,
                     }
                 }
+                self.delete_text((row, at), (row, at + query.len()), EditKind::Other);
+                self.insert_text((row, at), &replacement, EditKind::Other);
+                replaced += 1;
+                from = at + replacement.len();
+                to = to + replacement.len() - query.len();
             }
-            let line = &self.render_buffer[current];
-            if let Some(index) = line.find(&query) {
-                self.search_last_match = Some(current);
-                self.cx = self.rx_to_cx(index, &self.content_buffer[current]);
-                self.cy = current;
-                self.row_offset = current;
-                self.highlight
-                    .match_row(current, self.cx, self.cx + query.len());
-                break;
+        }
+        self.buffer.undo.end_group();
+        (self.buffer.cy, self.buffer.cx) = cursor;
+        self.search_scope = None;
+        self.buffer.mark = None;
+        if replaced > 0 {
+            self.set_dirty();
+            if let Some(line) = self.buffer.content_buffer.get(self.buffer.cy) {
+                self.buffer.cx = self.buffer.cx.min(line.len());
             }
         }
+        self.editor_set_status_mssage(match replaced {
+            1 => "Replaced 1 occurrence".to_string(),
+            n => format!("Replaced {} occurrences", n),
+        });
+        Ok(())
+    }
+
+    fn matching_rows(&self, query: &str) -> Vec<usize> {
+        (0..self.buffer.content_buffer.len())
+            .filter(|row| self.find_in_row(*row, query).is_some())
+            .collect()
     }
 
     pub fn editor_find(&mut self, input: &mut RawMode, direction_forward: bool) -> io::Result<()> {
-        let saved_cx = self.cx;
-        let saved_cy = self.cy;
-        let saved_col_offset = self.col_offset;
-        let saved_row_offset = self.row_offset;
-        let saved_highlight = self.highlight.highlights.clone();
+        let saved_cx = self.buffer.cx;
+        let saved_cy = self.buffer.cy;
+        let saved_col_offset = self.buffer.col_offset;
+        let saved_row_offset = self.buffer.row_offset;
+        let saved_highlight = self.buffer.highlight.highlights.clone();
+        self.search_scope = self.buffer.region();
         self.search_direction = if direction_forward {
             SearchDirection::Forward
         } else {
@@ -564,79 +6194,146 @@ impl Window {
         };
         let query = self.editor_prompt(
             input,
-            "Search {} (cancel: ESC, forward: C-s, backward: C-r)",
+            "Search {} (cancel: ESC, forward: C-s, backward: C-r, only matching lines: C-l)",
             Some(Window::editor_find_callback),
         )?;
         if query.is_none() {
-            self.cx = saved_cx;
-            self.cy = saved_cy;
-            self.col_offset = saved_col_offset;
-            self.row_offset = saved_row_offset;
+            self.buffer.cx = saved_cx;
+            self.buffer.cy = saved_cy;
+            self.buffer.col_offset = saved_col_offset;
+            self.buffer.row_offset = saved_row_offset;
         }
-        self.highlight.highlights = saved_highlight;
+        self.search_scope = None;
+        self.buffer.highlight.highlights = saved_highlight;
         Ok(())
     }
 
-    fn to_render_line(&self, line: &String) -> String {
+    fn render_row(&self, line: &str) -> RenderRow {
+        RenderRow::new(line, self.buffer.options.tab_width)
+    }
+
+    /// `line` as drawn, with tabs expanded to the next tab stop.
+    fn to_render_line(&self, line: &str) -> String {
+        let tab_width = self.buffer.options.tab_width;
         let mut string = String::new();
-        for (char_index, char) in line.chars().enumerate() {
+        let mut column = 0;
+        for char in line.chars() {
+            let cells = char_cells(char, column, tab_width);
             if char == '\t' {
-                string.push(' ');
-                let mut m = char_index + 1;
-                while m % KILO_TAB_STOP != 0 {
-                    string.push(' ');
-                    m += 1;
-                }
+                string.extend(std::iter::repeat_n(' ', cells));
             } else {
                 string.push(char);
             }
+            column += cells;
         }
         string
     }
 
     fn editor_update_row(&mut self, at: usize) {
-        self.render_buffer[at] = self.to_render_line(&self.content_buffer[at]);
-        if let Some(need_to_update_index) = self.highlight.update_row(at, &self.content_buffer[at])
-        {
-            if need_to_update_index < self.content_buffer.len()
-                && need_to_update_index < self.highlight.highlights.len()
-            {
-                self.editor_update_row(need_to_update_index);
-            }
-        }
+        self.buffer.render_buffer[at] = self.render_row(&self.buffer.content_buffer[at]);
+        let next = self
+            .buffer
+            .highlight
+            .update_row(at, &self.buffer.content_buffer[at]);
+        self.propagate_highlight(next);
     }
 
-    fn editor_insert_row(&mut self, at: usize) {
-        self.render_buffer
-            .insert(at, self.to_render_line(&self.content_buffer[at]));
-        if let Some(need_to_update_index) = self.highlight.insert_row(at, &self.content_buffer[at])
-        {
-            if need_to_update_index < self.content_buffer.len()
-                && need_to_update_index < self.highlight.highlights.len()
+    /// Highlights the rows after an edited one again, as far as the edit
+    /// changed them. Rows below the screen are left for when the editor is
+    /// idle.
+    fn propagate_highlight(&mut self, mut next: Option<usize>) {
+        let screen_end = self.buffer.row_offset + self.rows;
+        while let Some(row) = next {
+            if row >= self.buffer.content_buffer.len()
+                || row >= self.buffer.highlight.highlights.len()
             {
-                self.editor_update_row(need_to_update_index);
+                break;
+            }
+            if row >= screen_end {
+                self.buffer.highlight.mark_stale(row);
+                self.idle_tasks
+                    .schedule(IdleTask::Highlight(self.buffer.id));
+                break;
             }
+            next = self
+                .buffer
+                .highlight
+                .update_row(row, &self.buffer.content_buffer[row]);
         }
     }
 
-    pub fn quit(&mut self) -> io::Result<LoopStatus> {
-        if self.dirty && !self.quit_confirming {
-            self.editor_set_status_mssage(
-                "WARNING!!! File has unsaved changed. Press Ctrl-q to quit",
-            );
-            self.quit_confirming = true;
-            return Ok(LoopStatus::CONTINUE);
+    /// Renders and highlights the `count` lines added at `at`.
+    fn editor_insert_rows(&mut self, at: usize, count: usize) {
+        let lines: Vec<&str> = self
+            .buffer
+            .content_buffer
+            .lines_in(at..at + count)
+            .collect();
+        let rendered: Vec<RenderRow> = lines.iter().map(|line| self.render_row(line)).collect();
+        self.buffer.render_buffer.insert_rows(at, rendered);
+        let next = self.buffer.highlight.insert_rows(at, &lines);
+        self.propagate_highlight(next);
+    }
+
+    /// C-q: asks about every modified buffer in turn whether to save it
+    /// (y), discard its changes (n), save it and all the others (!) or not
+    /// to quit after all (q), then quits.
+    pub fn quit(&mut self, input: &mut RawMode) -> io::Result<LoopStatus> {
+        self.finish_saves();
+        let dirty: Vec<usize> = Some(&self.buffer)
+            .into_iter()
+            .chain(&self.buffers)
+            .filter(|b| b.dirty)
+            .map(|b| b.id)
+            .collect();
+        let mut save_all = false;
+        for id in dirty {
+            if self.buffer.id != id {
+                self.switch_to_buffer(id);
+            }
+            if !save_all {
+                let question = format!("Save changes to {}?", self.buffer.display_name());
+                match self.confirm(input, &question, true)? {
+                    Answer::Yes => {}
+                    Answer::No => continue,
+                    Answer::All => save_all = true,
+                    Answer::Quit => {
+                        self.editor_set_status_mssage("Quit cancelled");
+                        return Ok(LoopStatus::Continue);
+                    }
+                }
+            }
+            self.save_file(input)?;
+            self.finish_saves();
+            if self.buffer.dirty {
+                // The save failed or was aborted
+                return Ok(LoopStatus::Continue);
+            }
         }
+        self.leave();
         write!(self.stdout, "\x1b[2J")?;
         write!(self.stdout, "\x1b[H")?;
         self.stdout.flush()?;
-        Ok(LoopStatus::STOP)
+        Ok(LoopStatus::Stop)
+    }
+
+    /// Discards the changes left on purpose and lets other instances have
+    /// the files.
+    pub fn leave(&mut self) {
+        for buffer in Some(&mut self.buffer).into_iter().chain(&mut self.buffers) {
+            if let Some(path) = &buffer.filename {
+                buffer.journal.discard(path);
+            }
+        }
+        // Failing to write the state file must not prevent quitting
+        let _ = self.save_cursor_positions();
+        lock::release_all();
     }
 }
 
 fn get_cursor_position(stdin: &mut io::Stdin) -> io::Result<Option<(u16, u16)>> {
     let mut bytes: Vec<u8> = vec![];
-    for b in stdin.bytes() {
+    for b in stdin.lock().bytes() {
         bytes.push(b.unwrap_or(0));
     }
     if bytes[0] != b'\x1b' || bytes[1] != b'[' {
@@ -657,21 +6354,438 @@ fn get_window_size(
     stdin: &mut io::Stdin,
     stdout: &mut io::Stdout,
 ) -> io::Result<Option<(u16, u16)>> {
-    use libc::{ioctl, winsize, STDOUT_FILENO, TIOCGWINSZ};
-    use std::{mem, os::unix::io::IntoRawFd};
+    match terminal_size() {
+        Some(size) => Ok(Some(size)),
+        None => {
+            write!(stdout, "\x1b[9999C\x1b[9999B\x1b[6n")?;
+            stdout.flush()?;
+            get_cursor_position(stdin)
+        }
+    }
+}
 
-    let fd = if let Ok(file) = File::open("/dev/tty") {
-        file.into_raw_fd()
-    } else {
-        STDOUT_FILENO
-    };
+/// Columns and rows of the terminal, as the kernel knows them.
+fn terminal_size() -> Option<(u16, u16)> {
+    use libc::{ioctl, winsize, STDOUT_FILENO, TIOCGWINSZ};
+    use std::{mem, os::unix::io::AsRawFd};
 
+    let tty = File::open("/dev/tty").ok();
+    let fd = tty.as_ref().map_or(STDOUT_FILENO, |file| file.as_raw_fd());
     let mut ws: winsize = unsafe { mem::zeroed() };
     if unsafe { ioctl(fd, TIOCGWINSZ, &mut ws) } == -1 {
-        write!(stdout, "\x1b[9999C\x1b[9999B\x1b[6n")?;
-        stdout.flush()?;
-        get_cursor_position(stdin)
+        return None;
+    }
+    Some((ws.ws_col, ws.ws_row))
+}
+
+fn record_recent_file(path: &Path) {
+    let mut recent = RecentFiles::load();
+    recent.add(path);
+    // The recent files list is a convenience; failing to update it is not an error
+    let _ = recent.save();
+}
+
+fn is_flag_option(name: &str) -> bool {
+    matches!(
+        name,
+        "expandtab"
+            | "wrap"
+            | "number"
+            | "changemarks"
+            | "readonly"
+            | "abbrev"
+            | "autoindent"
+            | "comments"
+            | "keependings"
+            | "indentguides"
+    )
+}
+
+/// Fails for directories, devices, FIFOs and sockets, which would block or
+/// never end when read line by line.
+fn check_regular_file(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = std::fs::metadata(path)?.file_type();
+    let kind = if file_type.is_dir() {
+        "a directory"
+    } else if file_type.is_char_device() {
+        "a character device"
+    } else if file_type.is_block_device() {
+        "a block device"
+    } else if file_type.is_fifo() {
+        "a FIFO"
+    } else if file_type.is_socket() {
+        "a socket"
     } else {
-        Ok(Some((ws.ws_col, ws.ws_row)))
+        return Ok(());
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is {}, not a regular file", path.display(), kind),
+    ))
+}
+
+/// Style of a separator between two parts of the status bar: drawn in the
+/// background color of the part before it on that of the part after it.
+fn separator_style(before: Style, after: Style) -> Style {
+    let background = |style: Style| if style.reverse { style.fg } else { style.bg };
+    Style {
+        bg: background(after),
+        ..Style::fg(background(before))
+    }
+}
+
+/// Modification time of the config file, if there is one.
+/// Latest modification time of the user config and the project config of
+/// `project_dir`.
+fn config_mtime(project_dir: &Path) -> Option<SystemTime> {
+    let mtime = |path: PathBuf| std::fs::metadata(path).ok()?.modified().ok();
+    let user = Config::path().and_then(mtime);
+    let project = Config::project_path(project_dir).and_then(mtime);
+    user.max(project)
+}
+
+/// Modification time of a buffer's file, if it has one on disk.
+fn disk_mtime(filename: &Option<PathBuf>) -> Option<SystemTime> {
+    std::fs::metadata(filename.as_ref()?).ok()?.modified().ok()
+}
+
+/// The file whose name comes after or before that of `file` among the
+/// files in its directory, leaving out hidden ones.
+fn sibling_file(file: &Path, forward: bool) -> Result<PathBuf, String> {
+    let (dir, name) = match (file.parent(), file.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Err(format!("{} is not in a directory", file.display())),
+    };
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("Can't list {}: {}", dir.display(), e))?;
+    let mut names: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name())
+        .filter(|other| !other.to_string_lossy().starts_with('.'))
+        .collect();
+    names.sort();
+    let next = match forward {
+        true => names.into_iter().find(|other| other.as_os_str() > name),
+        false => names
+            .into_iter()
+            .rev()
+            .find(|other| other.as_os_str() < name),
+    };
+    let edge = if forward { "last" } else { "first" };
+    next.map(|next| dir.join(next)).ok_or_else(|| {
+        format!(
+            "{} is the {} file in {}",
+            name.to_string_lossy(),
+            edge,
+            dir.display()
+        )
+    })
+}
+
+/// The temporary file a save replacing `path` writes to first, hidden in
+/// the same directory so that renaming it over `path` is atomic.
+fn replacement_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+/// Whether the user may write `filename`; true if it doesn't exist yet.
+fn disk_writable(filename: &Option<PathBuf>) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let path = match filename {
+        Some(path) if path.exists() => path,
+        _ => return true,
+    };
+    match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => true,
+    }
+}
+
+/// Byte range of the word around `index`, or of the single character there
+/// if it is not part of a word.
+fn word_bounds(line: &str, index: usize) -> (usize, usize) {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    match line[index..].chars().next() {
+        Some(c) if is_word(c) => {
+            let start = line[..index]
+                .char_indices()
+                .rev()
+                .take_while(|(_, c)| is_word(*c))
+                .last()
+                .map_or(index, |(i, _)| i);
+            let end = line[index..]
+                .find(|c: char| !is_word(c))
+                .map_or(line.len(), |i| index + i);
+            (start, end)
+        }
+        Some(c) => (index, index + c.len_utf8()),
+        None => (index, index),
+    }
+}
+
+/// Byte index where the word ending at `end` starts, `end` if no word ends there.
+fn word_start(line: &str, end: usize) -> usize {
+    line[..end]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| abbrev::is_word_char(*c))
+        .last()
+        .map_or(end, |(index, _)| index)
+}
+
+/// Byte index of the grapheme cluster before the one at `index`, so that
+/// the cursor steps over a letter with its accents or an emoji with its
+/// modifiers at once.
+fn previous_grapheme_boundary(line: &str, index: usize) -> usize {
+    line[..index]
+        .grapheme_indices(true)
+        .next_back()
+        .map_or(0, |(start, _)| start)
+}
+
+/// Byte index of the grapheme cluster after the one at `index`.
+fn next_grapheme_boundary(line: &str, index: usize) -> usize {
+    line[index..]
+        .graphemes(true)
+        .next()
+        .map_or(index, |cluster| index + cluster.len())
+}
+
+/// Byte index of the start of the grapheme cluster containing `index`, or
+/// the end of the line for an index at or past it.
+fn grapheme_start(line: &str, index: usize) -> usize {
+    if index >= line.len() {
+        return line.len();
+    }
+    line.grapheme_indices(true)
+        .map(|(start, _)| start)
+        .take_while(|start| *start <= index)
+        .last()
+        .unwrap_or(0)
+}
+
+fn parse_character(query: &str) -> Option<char> {
+    let hex = ["U+", "u+", "0x"]
+        .iter()
+        .find_map(|prefix| query.strip_prefix(prefix))
+        .unwrap_or(query);
+    if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        if let Some(c) = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+            return Some(c);
+        }
+    }
+    unicode_names2::character(query)
+}
+
+/// Runs `command` with `sh -c`, `text` on its stdin, and returns its output.
+fn pipe_through(command: &str, text: &str) -> Result<String, String> {
+    use std::process::{Command, Stdio};
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Written on a thread so that a full stdout pipe can't block both sides
+        let text = text.to_string();
+        std::thread::spawn(move || stdin.write_all(text.as_bytes()));
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(message.lines().next().unwrap_or("failed").to_string());
+    }
+    String::from_utf8(output.stdout).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A window of 80x24 cells with `text` in its buffer.
+    fn window_with(text: &str) -> Window {
+        let config = Config::parse("[editor]\nmouse = false\n").unwrap();
+        let mut window = Window::new(
+            &mut io::stdin(),
+            Some((80, 24)),
+            config.clone(),
+            Theme::default(),
+            Digraphs::from_config(&config).unwrap(),
+            Abbrevs::default(),
+            CursorStyle::from_config(&config).unwrap(),
+        )
+        .unwrap();
+        window.load_text(text);
+        window
+    }
+
+    fn lines(window: &Window) -> Vec<&str> {
+        window
+            .buffer
+            .content_buffer
+            .iter()
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn tabs_around_wide_characters_render_to_the_tab_stops() {
+        let window = window_with("");
+        // The second tab starts at column 10, after the two cells of 漢
+        assert_eq!(window.to_render_line("\t漢\tx"), "        漢      x");
+        assert_eq!(window.to_render_line("a\t漢字\tb"), "a       漢字    b");
+        let row = window.render_row("\t漢\tx");
+        assert_eq!(row.width(), 17);
+        let columns: Vec<(&str, usize, usize)> = row
+            .clusters("\t漢\tx", 0)
+            .map(|cluster| (cluster.text, cluster.column, cluster.cells))
+            .collect();
+        assert_eq!(
+            columns,
+            [("\t", 0, 8), ("漢", 8, 2), ("\t", 10, 6), ("x", 16, 1)]
+        );
+        // From a cell inside the second tab
+        let first = row.clusters("\t漢\tx", 12).next().unwrap();
+        assert_eq!((first.text, first.column), ("\t", 10));
+    }
+
+    #[test]
+    fn cursor_columns_across_tabs_and_wide_characters() {
+        let window = window_with("");
+        let line = "\t漢\tx";
+        // Bytes: the tab at 0, 漢 at 1..4, the tab at 4, x at 5
+        let rx: Vec<usize> = [0, 1, 4, 5, 6]
+            .iter()
+            .map(|&cx| window.cx_to_rx(line, cx))
+            .collect();
+        assert_eq!(rx, [0, 8, 10, 16, 17]);
+        let cx: Vec<usize> = [0, 7, 8, 9, 10, 15, 16, 17]
+            .iter()
+            .map(|&rx| window.rx_to_cx(line, rx))
+            .collect();
+        assert_eq!(cx, [0, 0, 1, 1, 4, 4, 5, 6]);
+        for cx in [0, 1, 4, 5, 6] {
+            assert_eq!(window.rx_to_cx(line, window.cx_to_rx(line, cx)), cx);
+        }
+    }
+
+    #[test]
+    fn backspace_past_the_last_line_goes_to_the_end_of_the_text() {
+        let mut window = window_with("ab\ncd");
+        (window.buffer.cy, window.buffer.cx) = (2, 0);
+        window.delete_char();
+        assert_eq!(lines(&window), ["ab", "cd"]);
+        assert_eq!((window.buffer.cy, window.buffer.cx), (1, 2));
+    }
+
+    #[test]
+    fn backspace_below_the_screen_height_joins_lines() {
+        let text: Vec<String> = (0..40).map(|row| row.to_string()).collect();
+        let mut window = window_with(&text.join("\n"));
+        let row = window.rows;
+        (window.buffer.cy, window.buffer.cx) = (row, 0);
+        window.delete_char();
+        assert_eq!(window.buffer.content_buffer.len(), 39);
+        assert_eq!(
+            window.buffer.content_buffer[row - 1],
+            format!("{}{}", row - 1, row)
+        );
+        assert_eq!((window.buffer.cy, window.buffer.cx), (row - 1, 2));
+    }
+
+    #[test]
+    fn del_at_the_end_of_the_last_line_does_nothing() {
+        let mut window = window_with("ab\ncd");
+        (window.buffer.cy, window.buffer.cx) = (1, 2);
+        window.delete_char_forward();
+        assert_eq!(lines(&window), ["ab", "cd"]);
+        assert!(!window.buffer.dirty);
+        (window.buffer.cy, window.buffer.cx) = (2, 0);
+        window.delete_char_forward();
+        assert_eq!(lines(&window), ["ab", "cd"]);
+    }
+
+    #[test]
+    fn del_at_the_end_of_a_line_joins_the_next() {
+        let mut window = window_with("ab\ncd\nef");
+        (window.buffer.cy, window.buffer.cx) = (0, 2);
+        window.delete_char_forward();
+        assert_eq!(lines(&window), ["abcd", "ef"]);
+        assert_eq!((window.buffer.cy, window.buffer.cx), (0, 2));
+    }
+
+    #[test]
+    fn deleting_stays_within_the_narrowed_lines() {
+        let mut window = window_with("ab\ncd\nef\ngh");
+        window.buffer.narrow = Some(1..3);
+        // Del at the end of the last narrowed line
+        (window.buffer.cy, window.buffer.cx) = (2, 2);
+        window.delete_char_forward();
+        assert_eq!(lines(&window), ["ab", "cd", "ef", "gh"]);
+        // Backspace at the start of the first narrowed line
+        (window.buffer.cy, window.buffer.cx) = (1, 0);
+        window.delete_char();
+        assert_eq!(lines(&window), ["ab", "cd", "ef", "gh"]);
+        // Del joining two narrowed lines
+        (window.buffer.cy, window.buffer.cx) = (1, 2);
+        window.delete_char_forward();
+        assert_eq!(lines(&window), ["ab", "cdef", "gh"]);
+        assert_eq!(window.buffer.narrow, Some(1..2));
+    }
+
+    #[test]
+    fn the_mark_moves_with_text_deleted_before_it() {
+        let mut window = window_with("hello world example!");
+        (window.buffer.cy, window.buffer.cx) = (0, 20);
+        window.set_mark();
+        for _ in 0..3 {
+            window.delete_char();
+        }
+        assert_eq!(window.buffer.mark, Some((0, 17)));
+        window.buffer.cx = 6;
+        window.cut_region();
+        assert_eq!(lines(&window), ["hello "]);
+        assert_eq!(window.kill_ring.get(0), Some("world examp"));
+    }
+
+    #[test]
+    fn the_region_stays_on_char_boundaries() {
+        let mut window = window_with("ab");
+        (window.buffer.cy, window.buffer.cx) = (0, 1);
+        window.set_mark();
+        window.buffer.cx = 2;
+        window.delete_char();
+        window.delete_char();
+        window.insert_char('é');
+        assert_eq!(window.buffer.mark, Some((0, 0)));
+        window.copy_region();
+        assert_eq!(window.kill_ring.get(0), Some("é"));
+        // A mark left inside a character covers all of it
+        window.buffer.mark = Some((0, 1));
+        window.copy_region();
+        assert_eq!(window.kill_ring.get(0), Some("é"));
+    }
+
+    #[test]
+    fn indenting_the_region_keeps_the_mark_and_cursor_on_their_text() {
+        let mut window = window_with("a\nb\nc");
+        window.set_mark();
+        (window.buffer.cy, window.buffer.cx) = (1, 1);
+        window.indent_region(false);
+        let unit = window.buffer.options.indent_unit();
+        assert_eq!(
+            lines(&window),
+            [&format!("{}a", unit), &format!("{}b", unit), "c"]
+        );
+        assert_eq!(window.buffer.mark, Some((0, unit.len())));
+        assert_eq!((window.buffer.cy, window.buffer.cx), (1, unit.len() + 1));
+        window.indent_region(true);
+        assert_eq!(lines(&window), ["a", "b", "c"]);
     }
 }